@@ -3,8 +3,9 @@
 use core::sync::atomic::{compiler_fence, Ordering};
 
 use super::{
+    completion::{Completion, WaitAsync},
     single_channel::{ChannelConfig, SingleChannel},
-    Pace, ReadTarget, WriteTarget,
+    DMAIrq, Pace, ReadTarget, WriteTarget,
 };
 
 /// DMA configuration for sending and receiving data simultaneously
@@ -157,4 +158,14 @@ where
         // TODO: Use a tuple type?
         ((self.ch.0, self.ch.1), self.from, self.bidi, self.to)
     }
+
+    /// Wait for the transfer to complete
+    ///
+    /// The future is woken by the interrupt `irq`, whose handler must call
+    /// [`dma::on_interrupt`](super::on_interrupt). The transfer is aborted if the future is dropped
+    /// before completion.
+    pub fn wait_async(self, irq: DMAIrq) -> WaitAsync<Self, ((CH1, CH2), FROM, BIDI, TO)> {
+        let completion = Completion::new(1 << self.ch.0.id() | 1 << self.ch.1.id(), irq);
+        WaitAsync::new(self, completion, Self::is_done, Self::wait)
+    }
 }
@@ -8,8 +8,8 @@
 //! * Simple RX/TX transfers filling a single buffer or transferring data from one peripheral to
 //!   another.
 //! * RX/TX transfers that use multiple chained buffers: These transfers require two channels to
-//!   be combined, where the first DMA channel configures the second DMA channel. An example for
-//!   this transfer type can be found in the datasheet.
+//!   be combined, where the first DMA channel configures the second DMA channel. These are
+//!   provided by [`scatter_gather`].
 //! * Repeated transfers from/to a set of buffers: By allocating one channel per buffer and
 //!   chaining the channels together, continuous transfers to a set of ring buffers can be
 //!   achieved. Note, however, that the MCU manually needs to reconfigure the DMA units unless the
@@ -21,6 +21,9 @@
 //! where the user can specify the next buffer while the previous is being transferred, and
 //! automatic continuous ring buffers consisting of two aligned buffers being read or written
 //! alternatingly.
+//!
+//! Each transfer can be waited for asynchronously with `wait_async`, the future being woken by a
+//! DMA interrupt whose handler calls [`on_interrupt`].
 
 use core::marker::PhantomData;
 use embedded_dma::{ReadBuffer, WriteBuffer};
@@ -31,13 +34,24 @@ use crate::{
     typelevel::Sealed,
 };
 // Export these types for easier use by external code
+pub use crate::dma::completion::{on_interrupt, DMAIrq, WaitAsync};
+pub use crate::dma::pacing_timer::{
+    PacingTimer, PacingTimerId, PacingTimerIndex, TIMER0, TIMER1, TIMER2, TIMER3,
+};
+pub use crate::dma::pool::{claim, DynChannel};
 pub use crate::dma::single_channel::SingleChannel;
 
 // Bring in our submodules
 pub mod bidirectional;
+mod completion;
 pub mod double_buffer;
+mod pacing_timer;
+pub mod pool;
+pub mod ring_buffer;
+pub mod scatter_gather;
 pub mod single_buffer;
 mod single_channel;
+pub mod sniffer;
 
 /// DMA unit.
 pub trait DMAExt: Sealed {
@@ -73,6 +87,10 @@ macro_rules! channels {
                             _phantom: PhantomData,
                         },
                     )+
+                    timer0: PacingTimer::new(),
+                    timer1: PacingTimer::new(),
+                    timer2: PacingTimer::new(),
+                    timer3: PacingTimer::new(),
                 }
             }
 
@@ -86,6 +104,10 @@ macro_rules! channels {
                             _phantom: PhantomData,
                         }),
                     )+
+                    timer0: Some(PacingTimer::new()),
+                    timer1: Some(PacingTimer::new()),
+                    timer2: Some(PacingTimer::new()),
+                    timer3: Some(PacingTimer::new()),
                 }
             }
         }
@@ -98,6 +120,14 @@ macro_rules! channels {
                 /// DMA channel.
                 pub $chX: Channel<$CHX>,
             )+
+            /// DMA pacing timer.
+            pub timer0: PacingTimer<TIMER0>,
+            /// DMA pacing timer.
+            pub timer1: PacingTimer<TIMER1>,
+            /// DMA pacing timer.
+            pub timer2: PacingTimer<TIMER2>,
+            /// DMA pacing timer.
+            pub timer3: PacingTimer<TIMER3>,
         }
         $(
             /// DMA channel identifier.
@@ -117,6 +147,14 @@ macro_rules! channels {
                 /// DMA channel.
                 pub $chX: Option<Channel<$CHX>>,
             )+
+            /// DMA pacing timer.
+            pub timer0: Option<PacingTimer<TIMER0>>,
+            /// DMA pacing timer.
+            pub timer1: Option<PacingTimer<TIMER1>>,
+            /// DMA pacing timer.
+            pub timer2: Option<PacingTimer<TIMER2>>,
+            /// DMA pacing timer.
+            pub timer3: Option<PacingTimer<TIMER3>>,
         }
     }
 }
@@ -282,7 +320,11 @@ pub enum Pace {
     /// The DREQ signal from the sink is used, if available. If not, the source's DREQ signal is
     /// used.
     PreferSink,
-    // TODO: Timers?
+    /// The transfers are paced by a DMA pacing timer, ignoring the DREQ signals of the source and
+    /// the sink.
+    ///
+    /// See [`PacingTimer`].
+    Timer(PacingTimerId),
 }
 
 /// Error during DMA configuration.
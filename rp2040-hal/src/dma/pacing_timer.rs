@@ -0,0 +1,174 @@
+//! DMA pacing timers
+
+use core::marker::PhantomData;
+
+use fugit::HertzU32;
+
+use crate::{pac, typelevel::Sealed};
+
+/// Largest numerator or denominator of a timer fraction.
+const MAX_TERM: u64 = u16::MAX as u64;
+
+/// DMA pacing timer, generating transfer requests at a fraction `X/Y` of `clk_sys`.
+///
+/// Selected with [`Pace::Timer`](super::Pace::Timer) and [`PacingTimer::id`], it paces transfers
+/// to or from targets without a DREQ signal, like memory or SIO registers, at a fixed rate.
+///
+/// ```no_run
+/// use fugit::RateExtU32;
+/// use rp2040_hal::{
+///     dma::{single_buffer, DMAExt, Pace},
+///     pac,
+///     pwm::{CcFormat, SliceDmaWrite, Slices},
+/// };
+/// let mut pac = pac::Peripherals::take().unwrap();
+/// let mut dma = pac.DMA.split(&mut pac.RESETS);
+/// let pwm_slices = Slices::new(pac.PWM, &mut pac.RESETS);
+/// let mut pwm = pwm_slices.pwm4;
+/// pwm.enable();
+/// let pwm = SliceDmaWrite::from(pwm);
+///
+/// static SAMPLES: [CcFormat; 480] = [CcFormat { a: 0, b: 0 }; 480];
+/// // Write a sample every 1/48000 s, instead of every PWM period.
+/// dma.timer0.set_rate(48.kHz(), 125.MHz());
+/// let mut config = single_buffer::Config::new(dma.ch0, &SAMPLES, pwm.cc);
+/// config.pace(Pace::Timer(dma.timer0.id()));
+/// let (_ch0, _samples, _cc) = config.start().wait();
+/// ```
+pub struct PacingTimer<T: PacingTimerIndex> {
+    _phantom: PhantomData<T>,
+}
+
+/// DMA pacing timer identifier.
+pub trait PacingTimerIndex: Sealed {
+    /// Numerical index of the pacing timer (0..3).
+    fn id() -> u8;
+}
+
+/// Pacing timer selected by [`Pace::Timer`](super::Pace::Timer), obtained from an owned
+/// [`PacingTimer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PacingTimerId(u8);
+
+impl PacingTimerId {
+    /// DREQ number of the timer.
+    pub(crate) fn treq(self) -> u8 {
+        0x3b + self.0
+    }
+}
+
+macro_rules! pacing_timers {
+    ($($TIMERX:ident: ($timerX:ident, $x:literal),)+) => {
+        $(
+            /// DMA pacing timer identifier.
+            pub struct $TIMERX;
+            impl PacingTimerIndex for $TIMERX {
+                fn id() -> u8 {
+                    $x
+                }
+            }
+
+            impl Sealed for $TIMERX {}
+        )+
+
+        impl<T: PacingTimerIndex> PacingTimer<T> {
+            pub(crate) fn new() -> Self {
+                Self {
+                    _phantom: PhantomData,
+                }
+            }
+
+            /// Identifier of the timer, to select it with [`Pace::Timer`](super::Pace::Timer).
+            pub fn id(&self) -> PacingTimerId {
+                PacingTimerId(T::id())
+            }
+
+            /// Generate `x` transfer requests every `y` cycles of `clk_sys`.
+            ///
+            /// The timer stops when `x` is 0, and `x` must not be greater than `y`.
+            pub fn set_fraction(&mut self, x: u16, y: u16) {
+                let bits = u32::from(x) << 16 | u32::from(y);
+                // Safety: The timer is owned, the register is only written here.
+                let dma = unsafe { &*pac::DMA::ptr() };
+                match T::id() {
+                    $($x => dma.$timerX().write(|w| unsafe { w.bits(bits) }),)+
+                    _ => unreachable!(),
+                };
+            }
+        }
+    };
+}
+
+pacing_timers! {
+    TIMER0: (timer0, 0),
+    TIMER1: (timer1, 1),
+    TIMER2: (timer2, 2),
+    TIMER3: (timer3, 3),
+}
+
+impl<T: PacingTimerIndex> PacingTimer<T> {
+    /// Generate transfer requests at the closest rate to `rate` that can be derived from
+    /// `system_clock`, returning that rate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` is 0 or greater than `system_clock`.
+    pub fn set_rate(&mut self, rate: HertzU32, system_clock: HertzU32) -> HertzU32 {
+        let (rate, system_clock) = (rate.to_Hz(), system_clock.to_Hz());
+        assert!(rate != 0 && rate <= system_clock);
+        let (x, y) = fraction(rate, system_clock);
+        self.set_fraction(x, y);
+        HertzU32::from_raw((u64::from(system_clock) * u64::from(x) / u64::from(y)) as u32)
+    }
+}
+
+/// Closest fraction to `rate / system_clock` with 16-bit terms, from its continued fraction.
+fn fraction(rate: u32, system_clock: u32) -> (u16, u16) {
+    let (mut p0, mut q0, mut p1, mut q1) = (0u64, 1u64, 1u64, 0u64);
+    let (mut n, mut d) = (u64::from(rate), u64::from(system_clock));
+    while d != 0 {
+        let a = n / d;
+        let (p2, q2) = (a * p1 + p0, a * q1 + q0);
+        if p2 > MAX_TERM || q2 > MAX_TERM {
+            // The best approximation is either the last convergent or the largest
+            // semiconvergent fitting in the terms.
+            let k = (MAX_TERM - p0)
+                .checked_div(p1)
+                .unwrap_or(u64::MAX)
+                .min((MAX_TERM - q0) / q1);
+            let (ps, qs) = (k * p1 + p0, k * q1 + q0);
+            let error = |p: u64, q: u64| {
+                (u128::from(p) * u128::from(system_clock))
+                    .abs_diff(u128::from(rate) * u128::from(q))
+            };
+            if k != 0 && error(ps, qs) * u128::from(q1) < error(p1, q1) * u128::from(qs) {
+                (p1, q1) = (ps, qs);
+            }
+            break;
+        }
+        (p0, q0, p1, q1) = (p1, q1, p2, q2);
+        (n, d) = (d, n - a * d);
+    }
+    if p1 == 0 {
+        // The slowest rate, the timer would stop otherwise.
+        return (1, u16::MAX);
+    }
+    (p1 as u16, q1 as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fractions() {
+        assert_eq!(fraction(48_000, 125_000_000), (6, 15625));
+        assert_eq!(fraction(125_000_000, 125_000_000), (1, 1));
+        assert_eq!(fraction(1, 125_000_000), (1, 65535));
+        // 44.1 kHz has no exact fraction with 16-bit terms.
+        let (x, y) = fraction(44_100, 125_000_000);
+        let rate = 125_000_000.0 * f64::from(x) / f64::from(y);
+        assert!((rate - 44_100.0).abs() < 0.1, "{x}/{y}");
+    }
+}
@@ -0,0 +1,424 @@
+//! Continuous DMA transfers wrapping around a ring buffer
+//!
+//! A [`RingBuffer`] is a buffer aligned to its size, a power of two up to 32 KiB, whose address
+//! is wrapped by the DMA channel. Capture transfers write the data read from a peripheral into the
+//! ring, overwriting the oldest data, while playback transfers send the ring to a peripheral over
+//! and over.
+//!
+//! The position of the channel in the ring is reported, so that the CPU can consume captured
+//! data, or replace played data, as the single consumer or producer of a lock-free queue. The
+//! CPU must keep up with the channel: data overwritten, or played again, before the CPU caught up
+//! is not detected.
+//!
+//! A transfer moves `u32::MAX` words, or runs forever with [`Config::start_endless`], a second
+//! channel reloading the transfer count of the first one each time it completes.
+//!
+//! ```no_run
+//! use rp2040_hal::{
+//!     adc::{Adc, AdcPin},
+//!     dma::{ring_buffer, DMAExt},
+//!     gpio::Pins,
+//!     pac, Sio,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let sio = Sio::new(pac.SIO);
+//! let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+//! let dma = pac.DMA.split(&mut pac.RESETS);
+//! let mut adc = Adc::new(pac.ADC, &mut pac.RESETS);
+//! let mut adc_pin = AdcPin::new(pins.gpio26).unwrap();
+//! let mut adc_fifo = adc
+//!     .build_fifo()
+//!     .set_channel(&mut adc_pin)
+//!     .enable_dma()
+//!     .start_paused();
+//!
+//! #[repr(C, align(2048))]
+//! struct Buffer([u16; 1024]);
+//! static mut BUFFER: Buffer = Buffer([0; 1024]);
+//! let buffer = unsafe { &mut (*core::ptr::addr_of_mut!(BUFFER)).0 };
+//! let ring = ring_buffer::RingBuffer::new(buffer).unwrap();
+//!
+//! let mut transfer =
+//!     ring_buffer::Config::capture(dma.ch0, adc_fifo.dma_read_target(), ring)
+//!         .start_endless(dma.ch1);
+//! adc_fifo.resume();
+//! let mut samples = [0; 64];
+//! loop {
+//!     let count = transfer.read(&mut samples);
+//!     // Process `samples[..count]`.
+//! }
+//! ```
+
+use core::{
+    marker::PhantomData,
+    mem,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+use super::{
+    single_channel::{IsValidWordSize, SingleChannel},
+    DMAError, Pace, ReadTarget, WriteTarget,
+};
+use crate::typelevel::Sealed;
+
+/// Largest ring supported by the DMA, in bytes.
+const MAX_RING_BYTES: usize = 1 << 15;
+
+/// Transfer count written by the reload channel.
+static RELOAD_COUNT: u32 = u32::MAX;
+
+/// Buffer aligned to its size, whose address is wrapped around by a DMA channel.
+pub struct RingBuffer<WORD: 'static> {
+    buffer: &'static mut [WORD],
+}
+
+impl<WORD> RingBuffer<WORD> {
+    /// Wrap `buffer`, whose size in bytes must be a power of two up to 32 KiB.
+    ///
+    /// Returns [`DMAError::IllegalConfig`] if the size isn't valid, and [`DMAError::Alignment`]
+    /// if `buffer` isn't aligned to its size.
+    pub fn new(buffer: &'static mut [WORD]) -> Result<Self, DMAError> {
+        let _ = IsValidWordSize::<WORD>::OK;
+        let bytes = mem::size_of_val(buffer);
+        if !bytes.is_power_of_two() || !(2..=MAX_RING_BYTES).contains(&bytes) {
+            return Err(DMAError::IllegalConfig);
+        }
+        if buffer.as_ptr() as usize % bytes != 0 {
+            return Err(DMAError::Alignment);
+        }
+        Ok(Self { buffer })
+    }
+
+    /// Number of words of the ring.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Check if the ring is empty, which never happens.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Release the buffer.
+    pub fn free(self) -> &'static mut [WORD] {
+        self.buffer
+    }
+
+    fn ring_size(&self) -> u8 {
+        mem::size_of_val(self.buffer).trailing_zeros() as u8
+    }
+}
+
+/// Direction of a transfer, from a peripheral into the ring.
+pub struct Capture;
+/// Direction of a transfer, from the ring to a peripheral.
+pub struct Playback;
+
+/// Direction of a ring buffer transfer, [`Capture`] or [`Playback`].
+pub trait Direction: Sealed {
+    /// Whether the ring is written.
+    const WRITE: bool;
+}
+
+impl Sealed for Capture {}
+impl Direction for Capture {
+    const WRITE: bool = true;
+}
+
+impl Sealed for Playback {}
+impl Direction for Playback {
+    const WRITE: bool = false;
+}
+
+/// Channel reloading the transfer count of an endless transfer, or [`NoReload`].
+pub trait Reload: Sealed {
+    /// Mask of the channel.
+    fn mask(&self) -> u16;
+}
+
+/// No reload channel, the transfer stops after `u32::MAX` words.
+pub struct NoReload;
+
+impl Sealed for NoReload {}
+impl Reload for NoReload {
+    fn mask(&self) -> u16 {
+        0
+    }
+}
+
+impl<CH: SingleChannel> Reload for CH {
+    fn mask(&self) -> u16 {
+        1 << self.id()
+    }
+}
+
+/// Configuration for a ring buffer DMA transfer
+pub struct Config<CH: SingleChannel, P, WORD: 'static, DIR: Direction> {
+    ch: CH,
+    peripheral: P,
+    ring: RingBuffer<WORD>,
+    pace: Pace,
+    bswap: bool,
+    direction: PhantomData<DIR>,
+}
+
+impl<CH, FROM, WORD> Config<CH, FROM, WORD, Capture>
+where
+    CH: SingleChannel,
+    FROM: ReadTarget<ReceivedWord = WORD>,
+{
+    /// Create a configuration capturing the data read from `from` into `ring`.
+    pub fn capture(ch: CH, from: FROM, ring: RingBuffer<WORD>) -> Self {
+        Self::new(ch, from, ring)
+    }
+}
+
+impl<CH, TO, WORD> Config<CH, TO, WORD, Playback>
+where
+    CH: SingleChannel,
+    TO: WriteTarget<TransmittedWord = WORD>,
+{
+    /// Create a configuration sending `ring` to `to` in a loop.
+    pub fn playback(ch: CH, ring: RingBuffer<WORD>, to: TO) -> Self {
+        Self::new(ch, to, ring)
+    }
+}
+
+impl<CH: SingleChannel, P, WORD, DIR: Direction> Config<CH, P, WORD, DIR> {
+    fn new(ch: CH, peripheral: P, ring: RingBuffer<WORD>) -> Self {
+        Self {
+            ch,
+            peripheral,
+            ring,
+            pace: Pace::PreferSource,
+            bswap: false,
+            direction: PhantomData,
+        }
+    }
+
+    /// Sets the pace for the DMA transfers.
+    ///
+    /// The DREQ signal of the peripheral is used by default, [`Pace::Timer`] selects a pacing
+    /// timer instead, to play the ring at a fixed rate.
+    pub fn pace(&mut self, pace: Pace) {
+        self.pace = pace;
+    }
+
+    /// Enable/disable byteswapping for the DMA transfers, default value is false.
+    ///
+    /// For byte data, this has no effect. For halfword data, the two bytes of
+    /// each halfword are swapped. For word data, the four bytes of each word
+    /// are swapped to reverse order.
+    pub fn bswap(&mut self, bswap: bool) {
+        self.bswap = bswap;
+    }
+
+    /// Configure the channel, chained to `chain_to`, without starting it.
+    fn configure(&mut self, treq: Option<u8>, address: u32, chain_to: u8) {
+        const TREQ_UNPACED: u8 = 0x3f;
+        let treq = match self.pace {
+            Pace::Timer(timer) => timer.treq(),
+            _ => treq.unwrap_or(TREQ_UNPACED),
+        };
+        let ring = self.ring.buffer.as_ptr() as u32;
+        let (read, write) = if DIR::WRITE {
+            (address, ring)
+        } else {
+            (ring, address)
+        };
+        let regs = self.ch.ch();
+        regs.ch_read_addr().write(|w| unsafe { w.bits(read) });
+        regs.ch_write_addr().write(|w| unsafe { w.bits(write) });
+        regs.ch_trans_count()
+            .write(|w| unsafe { w.bits(RELOAD_COUNT) });
+        regs.ch_al1_ctrl().write(|w| unsafe {
+            w.data_size().bits(mem::size_of::<WORD>() as u8 >> 1);
+            w.incr_read().bit(!DIR::WRITE);
+            w.incr_write().bit(DIR::WRITE);
+            w.ring_sel().bit(DIR::WRITE);
+            w.ring_size().bits(self.ring.ring_size());
+            w.treq_sel().bits(treq);
+            w.bswap().bit(self.bswap);
+            w.chain_to().bits(chain_to);
+            w.en().bit(true);
+            w
+        });
+    }
+}
+
+impl<CH, FROM, WORD> Config<CH, FROM, WORD, Capture>
+where
+    CH: SingleChannel,
+    FROM: ReadTarget<ReceivedWord = WORD>,
+{
+    /// Start the DMA transfer, stopping after `u32::MAX` words.
+    pub fn start(self) -> Transfer<CH, NoReload, FROM, WORD, Capture> {
+        self.start_with(NoReload, FROM::rx_treq(), |from| from.rx_address_count().0)
+    }
+
+    /// Start the DMA transfer, running until stopped, `reload` reloading the transfer count.
+    pub fn start_endless<RELOAD: SingleChannel>(
+        self,
+        reload: RELOAD,
+    ) -> Transfer<CH, RELOAD, FROM, WORD, Capture> {
+        self.start_with(reload, FROM::rx_treq(), |from| from.rx_address_count().0)
+    }
+}
+
+impl<CH, TO, WORD> Config<CH, TO, WORD, Playback>
+where
+    CH: SingleChannel,
+    TO: WriteTarget<TransmittedWord = WORD>,
+{
+    /// Start the DMA transfer, stopping after `u32::MAX` words.
+    pub fn start(self) -> Transfer<CH, NoReload, TO, WORD, Playback> {
+        self.start_with(NoReload, TO::tx_treq(), |to| to.tx_address_count().0)
+    }
+
+    /// Start the DMA transfer, running until stopped, `reload` reloading the transfer count.
+    pub fn start_endless<RELOAD: SingleChannel>(
+        self,
+        reload: RELOAD,
+    ) -> Transfer<CH, RELOAD, TO, WORD, Playback> {
+        self.start_with(reload, TO::tx_treq(), |to| to.tx_address_count().0)
+    }
+}
+
+impl<CH: SingleChannel, P, WORD, DIR: Direction> Config<CH, P, WORD, DIR> {
+    fn start_with<R: Reload>(
+        mut self,
+        reload: R,
+        treq: Option<u8>,
+        address: impl FnOnce(&mut P) -> u32,
+    ) -> Transfer<CH, R, P, WORD, DIR> {
+        let address = address(&mut self.peripheral);
+
+        // Make sure that memory contents reflect what the user intended.
+        cortex_m::asm::dsb();
+        compiler_fence(Ordering::SeqCst);
+
+        let chain_to = match reload.mask() {
+            0 => self.ch.id(),
+            mask => mask.trailing_zeros() as u8,
+        };
+        self.configure(treq, address, chain_to);
+        if chain_to != self.ch.id() {
+            // The reload channel writes the transfer count of the data channel, triggering it.
+            let regs = unsafe { &*crate::pac::DMA::ptr() }.ch(chain_to as usize);
+            let count = self.ch.ch().ch_al1_trans_count_trig().as_ptr() as u32;
+            regs.ch_read_addr()
+                .write(|w| unsafe { w.bits(&RELOAD_COUNT as *const u32 as u32) });
+            regs.ch_write_addr().write(|w| unsafe { w.bits(count) });
+            regs.ch_trans_count().write(|w| unsafe { w.bits(1) });
+            regs.ch_al1_ctrl().write(|w| unsafe {
+                w.data_size().bits(2);
+                w.incr_read().bit(false);
+                w.incr_write().bit(false);
+                w.treq_sel().bits(0x3f);
+                w.chain_to().bits(chain_to);
+                w.en().bit(true);
+                w
+            });
+        }
+        // Safety: The write does not interfere with any other writes, it only affects this
+        // channel.
+        unsafe { &*crate::pac::DMA::ptr() }
+            .multi_chan_trigger()
+            .write(|w| unsafe { w.bits(1 << self.ch.id()) });
+
+        Transfer {
+            ch: self.ch,
+            reload,
+            peripheral: self.peripheral,
+            ring: self.ring,
+            index: 0,
+            direction: PhantomData,
+        }
+    }
+}
+
+/// Instance of a ring buffer DMA transfer
+pub struct Transfer<CH: SingleChannel, R: Reload, P, WORD: 'static, DIR: Direction> {
+    ch: CH,
+    reload: R,
+    peripheral: P,
+    ring: RingBuffer<WORD>,
+    index: usize,
+    direction: PhantomData<DIR>,
+}
+
+impl<CH: SingleChannel, R: Reload, P, WORD: Copy, DIR: Direction> Transfer<CH, R, P, WORD, DIR> {
+    /// Index of the next word of the ring the channel transfers.
+    pub fn position(&self) -> usize {
+        let address = if DIR::WRITE {
+            self.ch.ch().ch_write_addr().read().bits()
+        } else {
+            self.ch.ch().ch_read_addr().read().bits()
+        };
+        let offset = address.wrapping_sub(self.ring.buffer.as_ptr() as u32) as usize;
+        offset / mem::size_of::<WORD>() % self.ring.len()
+    }
+
+    /// Number of words the channel transferred since the CPU last caught up with it.
+    fn pending(&self) -> usize {
+        (self.position() + self.ring.len() - self.index) % self.ring.len()
+    }
+
+    /// Check if the transfer stopped, after `u32::MAX` words without a reload channel.
+    pub fn is_done(&self) -> bool {
+        !self.ch.ch().ch_ctrl_trig().read().busy().bit_is_set()
+    }
+
+    /// Stop the transfer, returning the channels, the peripheral and the ring.
+    pub fn stop(self) -> (CH, R, P, RingBuffer<WORD>) {
+        let chan_abort = unsafe { &*crate::pac::DMA::ptr() }.chan_abort();
+        let abort_mask = 1 << self.ch.id() | self.reload.mask();
+        chan_abort.write(|w| unsafe { w.chan_abort().bits(abort_mask) });
+        while chan_abort.read().chan_abort().bits() != 0 {}
+        while !self.is_done() {}
+
+        // Make sure that memory contents reflect what the user intended.
+        cortex_m::asm::dsb();
+        compiler_fence(Ordering::SeqCst);
+
+        (self.ch, self.reload, self.peripheral, self.ring)
+    }
+}
+
+impl<CH: SingleChannel, R: Reload, FROM, WORD: Copy> Transfer<CH, R, FROM, WORD, Capture> {
+    /// Number of captured words which were not read yet.
+    pub fn available(&self) -> usize {
+        self.pending()
+    }
+
+    /// Read captured words into `buffer`, returning the number of words read.
+    pub fn read(&mut self, buffer: &mut [WORD]) -> usize {
+        let count = self.available().min(buffer.len());
+        compiler_fence(Ordering::SeqCst);
+        for word in &mut buffer[..count] {
+            // Safety: The word was written by the channel, which is now writing other words.
+            *word = unsafe { core::ptr::read_volatile(&self.ring.buffer[self.index]) };
+            self.index = (self.index + 1) % self.ring.len();
+        }
+        count
+    }
+}
+
+impl<CH: SingleChannel, R: Reload, TO, WORD: Copy> Transfer<CH, R, TO, WORD, Playback> {
+    /// Number of words which were sent and can be replaced.
+    pub fn writable(&self) -> usize {
+        self.pending()
+    }
+
+    /// Replace sent words with `data`, returning the number of words written.
+    pub fn write(&mut self, data: &[WORD]) -> usize {
+        let count = self.writable().min(data.len());
+        for word in &data[..count] {
+            // Safety: The word was read by the channel, which is now reading other words.
+            unsafe { core::ptr::write_volatile(&mut self.ring.buffer[self.index], *word) };
+            self.index = (self.index + 1) % self.ring.len();
+        }
+        compiler_fence(Ordering::SeqCst);
+        count
+    }
+}
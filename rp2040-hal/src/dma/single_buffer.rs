@@ -3,7 +3,12 @@
 use core::sync::atomic::{compiler_fence, Ordering};
 
 use super::{
-    single_channel::ChannelConfig, single_channel::SingleChannel, Pace, ReadTarget, WriteTarget,
+    completion::{Completion, WaitAsync},
+    single_channel::ChannelConfig,
+    single_channel::SingleChannel,
+    sniffer,
+    sniffer::Sniffer,
+    DMAIrq, Pace, ReadTarget, WriteTarget,
 };
 
 /// Configuration for single-buffered DMA transfer
@@ -13,6 +18,7 @@ pub struct Config<CH: SingleChannel, FROM: ReadTarget, TO: WriteTarget> {
     to: TO,
     pace: Pace,
     bswap: bool,
+    sniffer: Option<Sniffer>,
 }
 
 impl<CH, FROM, TO, WORD> Config<CH, FROM, TO>
@@ -29,6 +35,7 @@ where
             to,
             pace: Pace::PreferSource,
             bswap: false,
+            sniffer: None,
         }
     }
 
@@ -52,6 +59,14 @@ where
         self.bswap = bswap;
     }
 
+    /// Calculate a checksum over the transferred data with the DMA sniffer.
+    ///
+    /// The sniffer is shared by all channels, only the last transfer started with a sniffer
+    /// configuration is observed.
+    pub fn sniff(&mut self, sniffer: Sniffer) {
+        self.sniffer = Some(sniffer);
+    }
+
     /// Start the DMA transfer
     pub fn start(mut self) -> Transfer<CH, FROM, TO> {
         // TODO: Do we want to call any callbacks to configure source/sink?
@@ -63,7 +78,11 @@ where
 
         // Configure the DMA channel and start it.
         self.ch
-            .config(&self.from, &mut self.to, self.pace, self.bswap, None, true);
+            .config(&self.from, &mut self.to, self.pace, self.bswap, None, false);
+        if let Some(sniffer) = &self.sniffer {
+            sniffer.attach(&self.ch);
+        }
+        self.ch.start();
 
         Transfer {
             ch: self.ch,
@@ -102,6 +121,14 @@ where
         !self.ch.ch().ch_ctrl_trig().read().busy().bit_is_set()
     }
 
+    /// Result of the DMA sniffer, the checksum of the transferred data once the transfer is
+    /// done.
+    ///
+    /// See [`Config::sniff`].
+    pub fn sniff_result(&self) -> u32 {
+        sniffer::result()
+    }
+
     /// Block until the transfer is complete, returning the channel and targets
     pub fn wait(self) -> (CH, FROM, TO) {
         while !self.is_done() {}
@@ -113,6 +140,16 @@ where
         (self.ch, self.from, self.to)
     }
 
+    /// Wait for the transfer to complete, returning the channel and targets
+    ///
+    /// The future is woken by the interrupt `irq`, whose handler must call
+    /// [`dma::on_interrupt`](super::on_interrupt). The transfer is aborted if the future is dropped
+    /// before completion.
+    pub fn wait_async(self, irq: DMAIrq) -> WaitAsync<Self, (CH, FROM, TO)> {
+        let completion = Completion::new(1 << self.ch.id(), irq);
+        WaitAsync::new(self, completion, Self::is_done, Self::wait)
+    }
+
     /// Aborts the current transfer, returning the channel and targets
     pub fn abort(mut self) -> (CH, FROM, TO) {
         let irq0_was_enabled = self.ch.is_enabled_irq0();
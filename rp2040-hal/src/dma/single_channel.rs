@@ -158,12 +158,12 @@ pub(crate) trait ChannelConfig {
 
 // RP2040's DMA engine only works with certain word sizes. Make sure that other
 // word sizes will fail to compile.
-struct IsValidWordSize<WORD> {
+pub(crate) struct IsValidWordSize<WORD> {
     w: core::marker::PhantomData<WORD>,
 }
 
 impl<WORD> IsValidWordSize<WORD> {
-    const OK: usize = {
+    pub(crate) const OK: usize = {
         match mem::size_of::<WORD>() {
             1 | 2 | 4 => 0, // ok
             _ => panic!("Unsupported DMA word size"),
@@ -195,6 +195,7 @@ impl<CH: SingleChannel> ChannelConfig for CH {
         let treq = match pace {
             Pace::PreferSource => FROM::rx_treq().or_else(TO::tx_treq).unwrap_or(TREQ_UNPACED),
             Pace::PreferSink => TO::tx_treq().or_else(FROM::rx_treq).unwrap_or(TREQ_UNPACED),
+            Pace::Timer(timer) => timer.treq(),
         };
         let len = u32::min(src_count, dest_count);
         self.ch().ch_al1_ctrl().write(|w| unsafe {
@@ -0,0 +1,188 @@
+//! Checksums calculated by the DMA sniffer
+//!
+//! The DMA engine has a single sniffer, which calculates a CRC, parity or sum over the data read
+//! by one channel as it is transferred. A [`Sniffer`] configuration is attached to a transfer with
+//! [`single_buffer::Config::sniff`](super::single_buffer::Config::sniff) or
+//! [`double_buffer::Config::sniff`](super::double_buffer::Config::sniff), and the result is read
+//! from the transfer once done. [`checksum`] calculates the checksum of a buffer with a
+//! memory-to-memory transfer.
+//!
+//! CRCs are calculated over the data as transferred, most significant bit first. To get the
+//! checksum of a byte stream stored in words, use byte transfers, a bit-reversed calculation or
+//! [`Sniffer::bswap`].
+//!
+//! ```no_run
+//! use rp2040_hal::{
+//!     dma::{sniffer::{self, Sniffer}, DMAExt},
+//!     pac,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let mut dma = pac.DMA.split(&mut pac.RESETS);
+//! let crc = sniffer::checksum(&mut dma.ch0, Sniffer::crc32(), b"123456789");
+//! assert_eq!(crc, 0xcbf4_3926);
+//! ```
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use super::single_channel::{ChannelConfig, IsValidWordSize, SingleChannel};
+
+/// Calculation performed by the sniffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Calculation {
+    /// CRC-32 with the polynomial 0x04c11db7.
+    Crc32,
+    /// CRC-32 with the polynomial 0x04c11db7, over bit-reversed data.
+    Crc32BitReversed,
+    /// CRC-16-CCITT with the polynomial 0x1021.
+    Crc16Ccitt,
+    /// CRC-16-CCITT with the polynomial 0x1021, over bit-reversed data.
+    Crc16CcittBitReversed,
+    /// XOR reduction over all data, 1 if the number of bits set is odd.
+    Parity,
+    /// 32-bit sum of the data.
+    Sum,
+}
+
+/// Configuration of the DMA sniffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sniffer {
+    calculation: Calculation,
+    seed: u32,
+    bswap: bool,
+    reverse_output: bool,
+    invert_output: bool,
+}
+
+impl Sniffer {
+    /// Create a configuration for `calculation`, with a seed of 0.
+    pub fn new(calculation: Calculation) -> Self {
+        Self {
+            calculation,
+            seed: 0,
+            bswap: false,
+            reverse_output: false,
+            invert_output: false,
+        }
+    }
+
+    /// CRC-32 used by Ethernet, zlib and PNG, over byte transfers or little-endian words.
+    pub fn crc32() -> Self {
+        Self::new(Calculation::Crc32BitReversed)
+            .seed(u32::MAX)
+            .reverse_output(true)
+            .invert_output(true)
+    }
+
+    /// CRC-16/CCITT-FALSE, CRC-16-CCITT with a seed of 0xffff, over byte transfers.
+    pub fn crc16_ccitt() -> Self {
+        Self::new(Calculation::Crc16Ccitt).seed(0xffff)
+    }
+
+    /// Set the initial value of the calculation.
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Reverse the order of the bytes of (half-)words before feeding them to the calculation.
+    ///
+    /// This is applied after the byteswap of the channel, both cancelling each other out.
+    pub fn bswap(mut self, bswap: bool) -> Self {
+        self.bswap = bswap;
+        self
+    }
+
+    /// Bit-reverse the result.
+    pub fn reverse_output(mut self, reverse: bool) -> Self {
+        self.reverse_output = reverse;
+        self
+    }
+
+    /// Bitwise invert the result.
+    pub fn invert_output(mut self, invert: bool) -> Self {
+        self.invert_output = invert;
+        self
+    }
+
+    /// Point the sniffer at the configured channel `ch`, and make its transfers visible.
+    pub(crate) fn attach<CH: SingleChannel>(&self, ch: &CH) {
+        // Safety: The sniffer registers are only written here, when starting a transfer which
+        // uses the sniffer, and any seed value is valid.
+        let dma = unsafe { &*crate::pac::DMA::ptr() };
+        dma.sniff_data().write(|w| unsafe { w.bits(self.seed) });
+        dma.sniff_ctrl().write(|w| {
+            match self.calculation {
+                Calculation::Crc32 => w.calc().crc32(),
+                Calculation::Crc32BitReversed => w.calc().crc32r(),
+                Calculation::Crc16Ccitt => w.calc().crc16(),
+                Calculation::Crc16CcittBitReversed => w.calc().crc16r(),
+                Calculation::Parity => w.calc().even(),
+                Calculation::Sum => w.calc().sum(),
+            };
+            // Safety: `ch` is an existing channel.
+            unsafe { w.dmach().bits(ch.id()) };
+            w.bswap().bit(self.bswap);
+            w.out_rev().bit(self.reverse_output);
+            w.out_inv().bit(self.invert_output);
+            w.en().set_bit()
+        });
+        enable_channel(ch);
+    }
+}
+
+/// Make the transfers of `ch` visible to the sniffer, once configured.
+pub(crate) fn enable_channel<CH: SingleChannel>(ch: &CH) {
+    ch.ch().ch_al1_ctrl().modify(|_, w| w.sniff_en().set_bit());
+}
+
+/// Current result of the sniffer.
+pub(crate) fn result() -> u32 {
+    // Safety: Read only access without side effect.
+    unsafe { &*crate::pac::DMA::ptr() }
+        .sniff_data()
+        .read()
+        .bits()
+}
+
+/// Calculate the checksum of `data` with a memory-to-memory transfer on `ch`, blocking until
+/// done.
+pub fn checksum<CH: SingleChannel, WORD>(ch: &mut CH, sniffer: Sniffer, data: &[WORD]) -> u32 {
+    let _ = IsValidWordSize::<WORD>::OK;
+    // The data is written to a single word, only the reads matter.
+    let mut sink = 0u32;
+
+    cortex_m::asm::dsb();
+    compiler_fence(Ordering::SeqCst);
+
+    // Safety: The channel is owned, and `data` and `sink` outlive the transfer, which is waited
+    // for.
+    ch.ch()
+        .ch_read_addr()
+        .write(|w| unsafe { w.bits(data.as_ptr() as u32) });
+    ch.ch()
+        .ch_write_addr()
+        .write(|w| unsafe { w.bits(core::ptr::addr_of_mut!(sink) as u32) });
+    ch.ch()
+        .ch_trans_count()
+        .write(|w| unsafe { w.bits(data.len() as u32) });
+    ch.ch().ch_al1_ctrl().write(|w| unsafe {
+        w.data_size().bits(core::mem::size_of::<WORD>() as u8 >> 1);
+        w.incr_read().bit(true);
+        w.incr_write().bit(false);
+        w.treq_sel().bits(0x3f);
+        w.chain_to().bits(ch.id());
+        w.en().bit(true);
+        w
+    });
+    sniffer.attach(ch);
+    if !data.is_empty() {
+        ch.start();
+        while ch.ch().ch_ctrl_trig().read().busy().bit_is_set() {}
+    }
+
+    cortex_m::asm::dsb();
+    compiler_fence(Ordering::SeqCst);
+    result()
+}
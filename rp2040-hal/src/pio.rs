@@ -2,18 +2,38 @@
 //!
 //! See [Chapter 3 of the datasheet](https://datasheets.raspberrypi.org/rp2040/rp2040-datasheet.pdf#section_pio) for more details.
 
-use core::ops::Deref;
+use core::{ops::Deref, task::Poll};
 use pio::{Instruction, InstructionOperands, Program, SideSet, Wrap};
 
 use crate::{
+    async_utils::{
+        sealed::{IrqWaker, Wakeable},
+        AsyncPeripheral, CancellablePollFn as CPFn,
+    },
     atomic_register_access::{write_bitmask_clear, write_bitmask_set},
     dma::{EndlessReadTarget, EndlessWriteTarget, ReadTarget, TransferSize, Word, WriteTarget},
-    gpio::{Function, FunctionPio0, FunctionPio1},
+    gpio::{Function, FunctionPio0, FunctionPio1, Pin, PinId, PullType},
     pac::{self, dma::ch::ch_ctrl_trig::TREQ_SEL_A, pio0::RegisterBlock, PIO0, PIO1},
     resets::SubsystemReset,
     typelevel::Sealed,
 };
 
+pub mod can;
+pub mod debug;
+pub use debug::*;
+pub mod dyn_state_machine;
+pub use dyn_state_machine::*;
+pub mod emulator;
+pub mod i2s;
+pub mod logic_analyser;
+pub mod one_wire;
+pub mod parallel;
+pub mod pdm;
+pub mod quadrature;
+pub mod spi;
+pub mod uart;
+pub mod ws2812;
+
 const PIO_INSTRUCTION_COUNT: usize = 32;
 
 impl Sealed for PIO0 {}
@@ -62,6 +82,7 @@ pub trait PIOExt: Deref<Target = RegisterBlock> + SubsystemReset + Sized + Send
         (
             PIO {
                 used_instruction_space: 0,
+                instructions: [0; PIO_INSTRUCTION_COUNT],
                 pio: self,
             },
             sm0,
@@ -71,6 +92,14 @@ pub trait PIOExt: Deref<Target = RegisterBlock> + SubsystemReset + Sized + Send
         )
     }
 
+    /// Create a new PIO wrapper and hand the state machines out at runtime.
+    ///
+    /// See [`DynStateMachines`] for more information.
+    fn dyn_split(self, resets: &mut crate::pac::RESETS) -> (PIO<Self>, DynStateMachines<Self>) {
+        let (pio, sm0, sm1, sm2, sm3) = self.split(resets);
+        (pio, DynStateMachines::new(sm0, sm1, sm2, sm3))
+    }
+
     /// Number of this PIO (0..1).
     fn id() -> usize;
 }
@@ -92,6 +121,7 @@ impl PIOExt for PIO1 {
 /// Programmable IO Block
 pub struct PIO<P: PIOExt> {
     used_instruction_space: u32, // bit for each PIO_INSTRUCTION_COUNT
+    instructions: [u16; PIO_INSTRUCTION_COUNT], // copy of the write-only instruction memory
     pio: P,
 }
 
@@ -180,6 +210,35 @@ impl<P: PIOExt> PIO<P> {
             .write(|w| unsafe { w.irq_force().bits(flags) });
     }
 
+    /// Wait until the state machine IRQ flag `flag` (0..=3) is raised, then clear it.
+    ///
+    /// Clearing the flag releases a state machine blocked on `irq wait`. The future is woken by
+    /// the interrupt `irq` of this block, whose handler must call [`AsyncPeripheral::on_interrupt`].
+    pub async fn wait_irq(&mut self, irq: PioIRQ, flag: u8) {
+        assert!(flag < 4, "invalid state machine interrupt number");
+        let mask = 1 << (flag + 8);
+        CPFn::new(
+            self,
+            |pio: &mut Self| {
+                if pio.get_irq_raw() & (1 << flag) != 0 {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            },
+            // Safety: Atomic write to a single bit of the interrupt enable register.
+            |pio: &mut Self| unsafe {
+                write_bitmask_set(pio.pio.sm_irq(irq.to_index()).irq_inte().as_ptr(), mask);
+            },
+            // Safety: Atomic write to a single bit of the interrupt enable register.
+            |pio: &mut Self| unsafe {
+                write_bitmask_clear(pio.pio.sm_irq(irq.to_index()).irq_inte().as_ptr(), mask);
+            },
+        )
+        .await;
+        self.clear_irq(1 << flag);
+    }
+
     /// Calculates a mask with the `len` right-most bits set.
     fn instruction_mask(len: usize) -> u32 {
         if len < 32 {
@@ -190,14 +249,18 @@ impl<P: PIOExt> PIO<P> {
     }
 
     /// Tries to find an appropriate offset for the instructions, in range 0..=31.
-    fn find_offset_for_instructions(&self, i: &[u16], origin: Option<u8>) -> Option<u8> {
+    fn find_offset_for_instructions(
+        used_instruction_space: u32,
+        i: &[u16],
+        origin: Option<u8>,
+    ) -> Option<u8> {
         if i.len() > PIO_INSTRUCTION_COUNT || i.is_empty() {
             None
         } else {
             let mask = Self::instruction_mask(i.len());
             if let Some(origin) = origin {
                 if origin as usize > PIO_INSTRUCTION_COUNT - i.len()
-                    || self.used_instruction_space & (mask << origin) != 0
+                    || used_instruction_space & (mask << origin) != 0
                 {
                     None
                 } else {
@@ -205,7 +268,7 @@ impl<P: PIOExt> PIO<P> {
                 }
             } else {
                 for i in (0..=32 - (i.len() as u8)).rev() {
-                    if self.used_instruction_space & (mask << i) == 0 {
+                    if used_instruction_space & (mask << i) == 0 {
                         return Some(i);
                     }
                 }
@@ -214,6 +277,23 @@ impl<P: PIOExt> PIO<P> {
         }
     }
 
+    /// Adjusts the target of JMP instructions for a program placed at `offset`.
+    fn relocate_instruction(instr: u16, offset: u8) -> u16 {
+        if instr & 0b1110_0000_0000_0000 == 0 {
+            // this is a JMP instruction -> add offset to address
+            let address = (instr & 0b11111) as u8;
+            let address = address + offset;
+            assert!(
+                address < pio::RP2040_MAX_PROGRAM_SIZE as u8,
+                "Invalid JMP out of the program after offset addition"
+            );
+            instr & (!0b11111) | address as u16
+        } else {
+            // this is not a JMP instruction -> keep it unchanged
+            instr
+        }
+    }
+
     /// Allocates space in instruction memory and installs the program.
     ///
     /// The function returns a handle to the installed program that can be used to configure a
@@ -224,27 +304,15 @@ impl<P: PIOExt> PIO<P> {
         &mut self,
         p: &Program<{ pio::RP2040_MAX_PROGRAM_SIZE }>,
     ) -> Result<InstalledProgram<P>, InstallError> {
-        if let Some(offset) = self.find_offset_for_instructions(&p.code, p.origin) {
+        if let Some(offset) =
+            Self::find_offset_for_instructions(self.used_instruction_space, &p.code, p.origin)
+        {
             p.code
                 .iter()
-                .cloned()
-                .map(|instr| {
-                    if instr & 0b1110_0000_0000_0000 == 0 {
-                        // this is a JMP instruction -> add offset to address
-                        let address = (instr & 0b11111) as u8;
-                        let address = address + offset;
-                        assert!(
-                            address < pio::RP2040_MAX_PROGRAM_SIZE as u8,
-                            "Invalid JMP out of the program after offset addition"
-                        );
-                        instr & (!0b11111) | address as u16
-                    } else {
-                        // this is not a JMP instruction -> keep it unchanged
-                        instr
-                    }
-                })
+                .map(|&instr| Self::relocate_instruction(instr, offset))
                 .enumerate()
                 .for_each(|(i, instr)| {
+                    self.instructions[i + offset as usize] = instr;
                     self.pio
                         .instr_mem(i + offset as usize)
                         .write(|w| unsafe { w.instr_mem0().bits(instr) })
@@ -589,6 +657,78 @@ impl PioIRQ {
     }
 }
 
+/// Number of wakers per PIO block: one per RX FIFO, one per TX FIFO and one for the IRQ flags.
+const WAKERS_PER_BLOCK: usize = 9;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const WAKER_INIT: IrqWaker = IrqWaker::new();
+static WAKERS: [IrqWaker; 2 * WAKERS_PER_BLOCK] = [WAKER_INIT; 2 * WAKERS_PER_BLOCK];
+
+/// Waker for the given PIO block and interrupt source.
+///
+/// The source uses the same numbering as the INTR register: 0..=3 for RX not empty, 4..=7 for TX
+/// not full and 8 for any of the state machine IRQ flags.
+fn waker(pio: usize, source: usize) -> &'static IrqWaker {
+    &WAKERS[pio * WAKERS_PER_BLOCK + source]
+}
+
+fn block_ptr<P: PIOExt>() -> *const RegisterBlock {
+    match P::id() {
+        0 => PIO0::ptr(),
+        _ => PIO1::ptr(),
+    }
+}
+
+impl<P: PIOExt> Wakeable for PIO<P> {
+    fn waker() -> &'static IrqWaker {
+        waker(P::id(), 8)
+    }
+}
+
+impl<SM: ValidStateMachine, RxSize> Wakeable for Rx<SM, RxSize> {
+    fn waker() -> &'static IrqWaker {
+        waker(SM::PIO::id(), SM::id())
+    }
+}
+
+impl<SM: ValidStateMachine, TxSize> Wakeable for Tx<SM, TxSize> {
+    fn waker() -> &'static IrqWaker {
+        waker(SM::PIO::id(), SM::id() + 4)
+    }
+}
+
+impl<P: PIOExt> AsyncPeripheral for PIO<P> {
+    /// Wakes the async FIFO accesses and IRQ flag waits of this PIO block.
+    ///
+    /// This must be called from the block's IRQ0 and IRQ1 interrupt handlers, depending on which
+    /// [`PioIRQ`] the async functions were given. The sources that fired are masked, they are
+    /// unmasked again by the futures when needed.
+    fn on_interrupt() {
+        // Safety: Only the interrupt enables of the sources that fired are modified, using atomic
+        // accesses.
+        let block = unsafe { &*block_ptr::<P>() };
+        for irq in 0..2 {
+            let sm_irq = block.sm_irq(irq);
+            let ints = sm_irq.irq_ints().read().bits() & 0xfff;
+            if ints == 0 {
+                continue;
+            }
+            // Safety: Atomic write, only clears the bits which are set in `ints`.
+            unsafe {
+                write_bitmask_clear(sm_irq.irq_inte().as_ptr(), ints);
+            }
+            for source in 0..8 {
+                if ints & (1 << source) != 0 {
+                    waker(P::id(), source).wake();
+                }
+            }
+            if ints & 0xf00 != 0 {
+                waker(P::id(), 8).wake();
+            }
+        }
+    }
+}
+
 impl<SM: ValidStateMachine, State> StateMachine<SM, State> {
     /// Stops the state machine if it is still running and returns its program.
     ///
@@ -640,6 +780,48 @@ impl<SM: ValidStateMachine, State> StateMachine<SM, State> {
         unsafe { self.sm.sm().sm_execctrl().read().exec_stalled().bit() }
     }
 
+    /// Capture the state of the state machine, to find out why a program does not behave.
+    pub fn debug_snapshot(&self) -> DebugSnapshot {
+        let sm_id = SM::id();
+        let mask = 1 << sm_id;
+        // Safety: Read only accesses without side effect
+        unsafe {
+            let sm = self.sm.sm();
+            let pio = self.sm.pio();
+            let flevel = pio.flevel().read().bits() >> (8 * sm_id);
+            let fdebug = pio.fdebug().read().bits();
+            DebugSnapshot {
+                pc: sm.sm_addr().read().bits() as u8,
+                instruction: DisassembledInstruction::new(
+                    sm.sm_instr().read().sm0_instr().bits(),
+                    self.program.side_set,
+                ),
+                stalled: sm.sm_execctrl().read().exec_stalled().bit(),
+                tx_level: (flevel & 0xf) as u8,
+                rx_level: ((flevel >> 4) & 0xf) as u8,
+                tx_stall: fdebug & (mask << 24) != 0,
+                tx_overflow: fdebug & (mask << 16) != 0,
+                rx_underflow: fdebug & (mask << 8) != 0,
+                rx_stall: fdebug & mask != 0,
+                pad_out: pio.dbg_padout().read().bits(),
+                pad_oe: pio.dbg_padoe().read().bits(),
+            }
+        }
+    }
+
+    /// Clear the sticky FDEBUG flags of this state machine reported by
+    /// [`debug_snapshot`](Self::debug_snapshot).
+    pub fn clear_debug_flags(&mut self) {
+        let mask = 1 << SM::id();
+        // Safety: FDEBUG is write-1-to-clear, only the flags of this state machine are cleared.
+        unsafe {
+            self.sm
+                .pio()
+                .fdebug()
+                .write(|w| w.bits(mask | mask << 8 | mask << 16 | mask << 24));
+        }
+    }
+
     /// Clear both TX and RX FIFOs
     pub fn clear_fifos(&mut self) {
         // Safety: all accesses to these registers are controlled by this instance
@@ -730,6 +912,68 @@ impl<SM: ValidStateMachine, State> StateMachine<SM, State> {
     pub fn clock_divisor_fixed_point(&mut self, int: u16, frac: u8) {
         self.sm.set_clock_divisor(int, frac);
     }
+
+    /// Switch the state machine to another program, returning the previous one.
+    ///
+    /// The state machine is paused for a few system clock cycles while its wrap bounds and side-set
+    /// configuration are updated, then it continues at the start of `program`. Pin mappings, pin
+    /// states, FIFO contents, the clock divider and the shift configuration are kept, which allows
+    /// switching protocols without glitches on the bus.
+    ///
+    /// The previous program can be uninstalled once it is not used by any state machine anymore.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rp2040_hal::{pac, pio::{PIOBuilder, PIOExt}};
+    /// # let mut pac = pac::Peripherals::take().unwrap();
+    /// # let (mut pio, sm0, ..) = pac.PIO0.split(&mut pac.RESETS);
+    /// let single_program = pio_proc::pio_asm!("out pins, 1").program;
+    /// let quad_program = pio_proc::pio_asm!("out pins, 4").program;
+    /// let single = pio.install(&single_program).unwrap();
+    /// let (sm, _rx, _tx) = PIOBuilder::from_installed_program(single).build(sm0);
+    /// let mut sm = sm.start();
+    /// let quad = pio.install(&quad_program).unwrap();
+    /// let single = sm.switch_program(quad);
+    /// pio.uninstall(single);
+    /// ```
+    pub fn switch_program(
+        &mut self,
+        program: InstalledProgram<SM::PIO>,
+    ) -> InstalledProgram<SM::PIO> {
+        let offset = program.offset;
+        let mask = 1 << SM::id();
+        // Safety: Read only access without side effect
+        let enabled = unsafe { self.sm.pio().ctrl().read().bits() & mask != 0 };
+        self.sm.set_enabled(false);
+
+        // Safety: all accesses to these registers are controlled by this instance
+        unsafe {
+            let sm = self.sm.sm();
+            sm.sm_execctrl().modify(|_, w| {
+                w.side_en().bit(program.side_set.optional());
+                w.side_pindir().bit(program.side_set.pindirs());
+                w.wrap_top().bits(offset + program.wrap.source);
+                w.wrap_bottom().bits(offset + program.wrap.target)
+            });
+
+            // Jump without side-set, so that the pins keep their state.
+            sm.sm_pinctrl().modify(|_, w| w.sideset_count().bits(0));
+            let instruction = InstructionOperands::JMP {
+                condition: pio::JmpCondition::Always,
+                address: offset,
+            }
+            .encode();
+            sm.sm_instr().write(|w| w.sm0_instr().bits(instruction));
+            sm.sm_pinctrl()
+                .modify(|_, w| w.sideset_count().bits(program.side_set.bits()));
+        }
+
+        if enabled {
+            self.sm.set_enabled(true);
+        }
+        core::mem::replace(&mut self.program, program)
+    }
 }
 
 // Safety: All shared register accesses are atomic.
@@ -1357,6 +1601,29 @@ impl<SM: ValidStateMachine, RxSize: TransferSize> Rx<SM, RxSize> {
         Some(unsafe { core::ptr::read_volatile(self.fifo_address()) })
     }
 
+    /// Wait for the next element from RX FIFO.
+    ///
+    /// The future is woken by the interrupt `irq` of this block, whose handler must call
+    /// [`PIO::on_interrupt`](AsyncPeripheral::on_interrupt).
+    pub async fn read_async(&mut self, irq: PioIRQ) -> u32 {
+        CPFn::new(
+            self,
+            |rx: &mut Self| rx.read().map_or(Poll::Pending, Poll::Ready),
+            |rx: &mut Self| rx.enable_rx_not_empty_interrupt(irq),
+            |rx: &mut Self| rx.disable_rx_not_empty_interrupt(irq),
+        )
+        .await
+    }
+
+    /// Fill `buffer` with elements from RX FIFO, waiting for them as needed.
+    ///
+    /// See [`Self::read_async`].
+    pub async fn read_slice_async(&mut self, irq: PioIRQ, buffer: &mut [u32]) {
+        for word in buffer {
+            *word = self.read_async(irq).await;
+        }
+    }
+
     /// Enable/Disable the autopush feature of the state machine.
     // Safety: This register is read by Rx, this is the only write.
     pub fn enable_autopush(&mut self, enable: bool) {
@@ -1382,6 +1649,26 @@ impl<SM: ValidStateMachine, RxSize: TransferSize> Rx<SM, RxSize> {
         unsafe { self.block().fstat().read().rxfull().bits() & (1 << SM::id()) != 0 }
     }
 
+    /// Checks if the state machine has stalled on full RX FIFO during a blocking PUSH, or an IN
+    /// with autopush enabled.
+    ///
+    /// **Note this is a sticky flag and may not reflect the current state of the machine.**
+    pub fn has_stalled(&self) -> bool {
+        let mask = 1 << SM::id();
+        // Safety: read-only access without side-effect
+        unsafe { self.block().fdebug().read().rxstall().bits() & mask == mask }
+    }
+
+    /// Clears the `rx_stalled` flag.
+    pub fn clear_stalled_flag(&self) {
+        let mask = 1 << SM::id();
+
+        // Safety: These bits are WC, only the one corresponding to this SM is set.
+        unsafe {
+            self.block().fdebug().write(|w| w.rxstall().bits(mask));
+        }
+    }
+
     /// Enable RX FIFO not empty interrupt.
     ///
     /// This interrupt is raised when the RX FIFO is not empty, i.e. one could read more data from it.
@@ -1514,6 +1801,35 @@ impl<SM: ValidStateMachine, TxSize: TransferSize> Tx<SM, TxSize> {
         self.write_generic(value)
     }
 
+    /// Write a u32 value to TX FIFO, waiting for room as needed.
+    ///
+    /// The future is woken by the interrupt `irq` of this block, whose handler must call
+    /// [`PIO::on_interrupt`](AsyncPeripheral::on_interrupt).
+    pub async fn write_async(&mut self, irq: PioIRQ, value: u32) {
+        CPFn::new(
+            self,
+            |tx: &mut Self| {
+                if tx.write(value) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            },
+            |tx: &mut Self| tx.enable_tx_not_full_interrupt(irq),
+            |tx: &mut Self| tx.disable_tx_not_full_interrupt(irq),
+        )
+        .await
+    }
+
+    /// Write all `values` to TX FIFO, waiting for room as needed.
+    ///
+    /// See [`Self::write_async`].
+    pub async fn write_slice_async(&mut self, irq: PioIRQ, values: &[u32]) {
+        for &value in values {
+            self.write_async(irq, value).await;
+        }
+    }
+
     /// Write a replicated u8 value to TX FIFO.
     ///
     /// Memory mapped register writes that are smaller than 32bits will trigger
@@ -1892,6 +2208,41 @@ impl ShiftDirection {
     }
 }
 
+/// A GPIO pin whose function is assigned to the PIO block `P`.
+///
+/// This is implemented for every [`Pin`] in `P`'s [`PinFunction`](PIOExt::PinFunction), and is
+/// accepted by the `*_checked` methods of [`PIOBuilder`] so that a pin configured for another
+/// function (or another PIO block) is rejected at compile time.
+pub trait PioPin<P: PIOExt>: Sealed {
+    /// GPIO number of the pin.
+    fn pin_num(&self) -> u8;
+}
+
+impl<I, F, PT, P> PioPin<P> for Pin<I, F, PT>
+where
+    I: PinId,
+    F: Function,
+    PT: PullType,
+    P: PIOExt<PinFunction = F>,
+{
+    fn pin_num(&self) -> u8 {
+        self.id().num
+    }
+}
+
+/// Check that `pins` are consecutive GPIOs (modulo 32) and return the number of the first one.
+fn consecutive_pin_base<P: PIOExt>(pins: &[&dyn PioPin<P>]) -> u8 {
+    assert!(!pins.is_empty(), "at least one pin is required");
+    let base = pins[0].pin_num();
+    for (i, pin) in pins.iter().enumerate() {
+        assert!(
+            pin.pin_num() == (base + i as u8) % 32,
+            "PIO pins must be consecutive GPIOs"
+        );
+    }
+    base
+}
+
 /// Builder to deploy a fully configured PIO program on one of the state
 /// machines.
 #[derive(Debug)]
@@ -1961,6 +2312,15 @@ pub enum InstallError {
     NoSpace,
 }
 
+/// Errors that occurred during `DynStateMachines::claim_with_program`.
+#[derive(Debug)]
+pub enum ClaimError {
+    /// All state machines of the selected PIO are already claimed.
+    NoStateMachine,
+    /// There was not enough space for the instructions on the selected PIO.
+    NoSpace,
+}
+
 impl<P: PIOExt> PIOBuilder<P> {
     /// Set config settings based on information from the given [`InstalledProgram`].
     /// Additional configuration may be needed in addition to this.
@@ -2085,6 +2445,73 @@ impl<P: PIOExt> PIOBuilder<P> {
     }
     // TODO: Update documentation above.
 
+    /// Set the pins asserted by `SET` instruction from a list of consecutive GPIO pins.
+    ///
+    /// This is the typed counterpart of [`set_pins`](Self::set_pins): the pins must be set to this
+    /// PIO block's function, and the call panics if they are not consecutive or more than 5 pins
+    /// are given.
+    ///
+    /// ```no_run
+    /// # use rp2040_hal::{gpio::Pins, pac, pio::{PIOBuilder, PIOExt}, Sio};
+    /// # let mut pac = pac::Peripherals::take().unwrap();
+    /// # let sio = Sio::new(pac.SIO);
+    /// # let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+    /// # let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+    /// # let program = pio_proc::pio_asm!("set pins, 3").program;
+    /// # let installed = pio.install(&program).unwrap();
+    /// let led0 = pins.gpio2.into_function::<rp2040_hal::gpio::FunctionPio0>();
+    /// let led1 = pins.gpio3.into_function::<rp2040_hal::gpio::FunctionPio0>();
+    /// let (sm, _, _) = PIOBuilder::from_installed_program(installed)
+    ///     .set_pins_checked(&[&led0, &led1])
+    ///     .build(sm0);
+    /// ```
+    pub fn set_pins_checked(self, pins: &[&dyn PioPin<P>]) -> Self {
+        let base = consecutive_pin_base(pins);
+        self.set_pins(base, pins.len() as u8)
+    }
+
+    /// Set the pins asserted by `OUT` instruction from a list of consecutive GPIO pins.
+    ///
+    /// This is the typed counterpart of [`out_pins`](Self::out_pins). It panics if the pins are not
+    /// consecutive.
+    pub fn out_pins_checked(self, pins: &[&dyn PioPin<P>]) -> Self {
+        let base = consecutive_pin_base(pins);
+        self.out_pins(base, pins.len() as u8)
+    }
+
+    /// Set the pins used by `IN` instruction from a list of consecutive GPIO pins.
+    ///
+    /// This is the typed counterpart of [`in_pin_base`](Self::in_pin_base). It panics if the pins
+    /// are not consecutive.
+    pub fn in_pins_checked(self, pins: &[&dyn PioPin<P>]) -> Self {
+        let base = consecutive_pin_base(pins);
+        self.in_pin_base(base)
+    }
+
+    /// Set the pin used by `JMP PIN` instruction.
+    ///
+    /// This is the typed counterpart of [`jmp_pin`](Self::jmp_pin).
+    pub fn jmp_pin_checked(self, pin: &dyn PioPin<P>) -> Self {
+        self.jmp_pin(pin.pin_num())
+    }
+
+    /// Set the pins used by side-set instructions from a list of consecutive GPIO pins.
+    ///
+    /// This is the typed counterpart of [`side_set_pin_base`](Self::side_set_pin_base). It panics
+    /// if the pins are not consecutive, or if their number does not match the number of side-set
+    /// data bits of the installed program (excluding the optional enable bit).
+    pub fn side_set_pins_checked(self, pins: &[&dyn PioPin<P>]) -> Self {
+        let side_set = self.program.side_set;
+        let data_bits = side_set.bits() - u8::from(side_set.optional());
+        assert_eq!(
+            pins.len(),
+            usize::from(data_bits),
+            "side-set pin count must match the program's side-set bits"
+        );
+        let base = consecutive_pin_base(pins);
+        self.side_set_pin_base(base)
+    }
+
     /// Set buffer sharing.
     ///
     /// See [`Buffers`] for more information.
@@ -2295,4 +2722,30 @@ impl<P: PIOExt> PIOBuilder<P> {
             tx,
         )
     }
+
+    /// Build the config and deploy it to a state machine whose index is known at runtime.
+    #[allow(clippy::type_complexity)] // The return type cannot really be simplified.
+    pub fn build_dyn(
+        self,
+        sm: DynUninitStateMachine<P>,
+    ) -> (DynStateMachine<P, Stopped>, DynRx<P>, DynTx<P>) {
+        match sm {
+            DynUninitStateMachine::Sm0(sm) => {
+                let (sm, rx, tx) = self.build(sm);
+                (DynStateMachine::Sm0(sm), DynRx::Sm0(rx), DynTx::Sm0(tx))
+            }
+            DynUninitStateMachine::Sm1(sm) => {
+                let (sm, rx, tx) = self.build(sm);
+                (DynStateMachine::Sm1(sm), DynRx::Sm1(rx), DynTx::Sm1(tx))
+            }
+            DynUninitStateMachine::Sm2(sm) => {
+                let (sm, rx, tx) = self.build(sm);
+                (DynStateMachine::Sm2(sm), DynRx::Sm2(rx), DynTx::Sm2(tx))
+            }
+            DynUninitStateMachine::Sm3(sm) => {
+                let (sm, rx, tx) = self.build(sm);
+                (DynStateMachine::Sm3(sm), DynRx::Sm3(rx), DynTx::Sm3(tx))
+            }
+        }
+    }
 }
@@ -0,0 +1,904 @@
+//! Software model of a PIO state machine
+//!
+//! This allows running PIO programs without hardware, for example in unit tests on the host. The
+//! emulator is configured with the same [`PIOBuilder`] used to deploy a program on a real state
+//! machine, and models the program counter, scratch registers, shift registers with
+//! autopush/autopull, FIFOs, side-set, delays, clock divisor and the pins of the state machine.
+//!
+//! ```no_run
+//! use rp2040_hal::{pac, pio::emulator::Emulator, pio::PIOBuilder};
+//!
+//! let program = pio_proc::pio_asm!(
+//!     ".side_set 1",
+//!     ".wrap_target",
+//!     "    out pins, 1   side 0",
+//!     "    nop           side 1",
+//!     ".wrap"
+//! )
+//! .program;
+//!
+//! let mut pio = Emulator::<pac::PIO0>::new();
+//! let installed = pio.install(&program).unwrap();
+//! let mut sm = pio.build(
+//!     PIOBuilder::from_installed_program(installed)
+//!         .out_pins(0, 1)
+//!         .side_set_pin_base(1)
+//!         .autopull(true),
+//! );
+//! sm.set_pindirs(0b11);
+//! sm.push_tx(0b01);
+//! sm.step();
+//! assert_eq!(sm.pin_levels() & 0b11, 0b01);
+//! sm.step();
+//! assert_eq!(sm.pin_levels() & 0b11, 0b11);
+//! ```
+//!
+//! Limitations:
+//! - Each [`EmulatedStateMachine`] runs in isolation, the IRQ flags are not shared between state
+//!   machines and `out_sticky`/`inline_out` have no effect.
+//! - Pin levels are resolved as the driven value for pins configured as outputs, and the value
+//!   given to [`EmulatedStateMachine::set_inputs`] otherwise.
+
+use core::marker::PhantomData;
+
+use pio::Program;
+
+use super::{
+    Buffers, InstallError, InstalledProgram, MovStatusConfig, PIOBuilder, PIOExt, ShiftDirection,
+    PIO, PIO_INSTRUCTION_COUNT,
+};
+
+/// Emulated PIO block, holding the instruction memory.
+#[derive(Debug)]
+pub struct Emulator<P> {
+    used_instruction_space: u32,
+    instr_mem: [u16; PIO_INSTRUCTION_COUNT],
+    _phantom: PhantomData<P>,
+}
+
+impl<P: PIOExt> Default for Emulator<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: PIOExt> Emulator<P> {
+    /// Create an emulated PIO block with empty instruction memory.
+    pub fn new() -> Self {
+        Self {
+            used_instruction_space: 0,
+            instr_mem: [0; PIO_INSTRUCTION_COUNT],
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Allocates space in instruction memory and installs the program.
+    ///
+    /// This places the program at the same offset as [`PIO::install`] would on an empty block.
+    pub fn install(
+        &mut self,
+        p: &Program<{ pio::RP2040_MAX_PROGRAM_SIZE }>,
+    ) -> Result<InstalledProgram<P>, InstallError> {
+        let offset =
+            PIO::<P>::find_offset_for_instructions(self.used_instruction_space, &p.code, p.origin)
+                .ok_or(InstallError::NoSpace)?;
+        for (i, &instr) in p.code.iter().enumerate() {
+            self.instr_mem[i + offset as usize] = PIO::<P>::relocate_instruction(instr, offset);
+        }
+        self.used_instruction_space |= PIO::<P>::instruction_mask(p.code.len()) << offset;
+        Ok(InstalledProgram {
+            offset,
+            length: p.code.len() as u8,
+            side_set: p.side_set,
+            wrap: p.wrap,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Removes the specified program from instruction memory, freeing the allocated space.
+    pub fn uninstall(&mut self, p: InstalledProgram<P>) {
+        let instr_mask = PIO::<P>::instruction_mask(p.length as usize) << p.offset as u32;
+        self.used_instruction_space &= !instr_mask;
+    }
+
+    /// Build the config and deploy it to an emulated state machine.
+    ///
+    /// Like [`PIOBuilder::build`], the state machine starts at the beginning of the program with
+    /// cleared registers and FIFOs. All pins are inputs and low.
+    pub fn build(&self, builder: PIOBuilder<P>) -> EmulatedStateMachine {
+        let program = &builder.program;
+        let (rx_capacity, tx_capacity) = match builder.fifo_join {
+            Buffers::RxTx => (4, 4),
+            Buffers::OnlyTx => (0, 8),
+            Buffers::OnlyRx => (8, 0),
+        };
+        let divisor = match builder.clock_divisor {
+            (0, _) => 65536 << 8,
+            (int, frac) => (u32::from(int) << 8) | u32::from(frac),
+        };
+        EmulatedStateMachine {
+            instr_mem: self.instr_mem,
+            config: Config {
+                divisor,
+                wrap_top: program.offset + program.wrap.source,
+                wrap_bottom: program.offset + program.wrap.target,
+                side_set_bits: program.side_set.bits(),
+                side_set_optional: program.side_set.optional(),
+                side_set_pindirs: program.side_set.pindirs(),
+                side_set_base: builder.side_set_base,
+                jmp_pin: builder.jmp_pin,
+                mov_status: builder.mov_status,
+                pull_threshold: threshold(builder.pull_threshold),
+                push_threshold: threshold(builder.push_threshold),
+                out_shiftdir: builder.out_shiftdir,
+                in_shiftdir: builder.in_shiftdir,
+                autopull: builder.autopull,
+                autopush: builder.autopush,
+                set_base: builder.set_base,
+                set_count: builder.set_count,
+                out_base: builder.out_base,
+                out_count: builder.out_count,
+                in_base: builder.in_base,
+            },
+            pc: program.offset,
+            x: 0,
+            y: 0,
+            isr: 0,
+            isr_count: 0,
+            osr: 0,
+            osr_count: 32,
+            delay: 0,
+            exec: None,
+            irq_wait: None,
+            stalled: false,
+            irq_flags: 0,
+            clock_accumulator: 0,
+            sys_cycles: 0,
+            sm_cycles: 0,
+            pin_values: 0,
+            pin_dirs: 0,
+            inputs: 0,
+            rx: Fifo::new(rx_capacity),
+            tx: Fifo::new(tx_capacity),
+        }
+    }
+}
+
+/// A shift threshold of 0 stands for 32 bits.
+fn threshold(bits: u8) -> u8 {
+    if bits == 0 {
+        32
+    } else {
+        bits
+    }
+}
+
+/// Mask with the `count` right-most bits set.
+fn mask(count: u8) -> u32 {
+    if count >= 32 {
+        u32::MAX
+    } else {
+        (1 << count) - 1
+    }
+}
+
+#[derive(Debug)]
+struct Config {
+    /// Clock divisor in 24.8 fixed point.
+    divisor: u32,
+    wrap_top: u8,
+    wrap_bottom: u8,
+    side_set_bits: u8,
+    side_set_optional: bool,
+    side_set_pindirs: bool,
+    side_set_base: u8,
+    jmp_pin: u8,
+    mov_status: MovStatusConfig,
+    pull_threshold: u8,
+    push_threshold: u8,
+    out_shiftdir: ShiftDirection,
+    in_shiftdir: ShiftDirection,
+    autopull: bool,
+    autopush: bool,
+    set_base: u8,
+    set_count: u8,
+    out_base: u8,
+    out_count: u8,
+    in_base: u8,
+}
+
+#[derive(Debug)]
+struct Fifo {
+    data: [u32; 8],
+    start: usize,
+    len: usize,
+    capacity: usize,
+}
+
+impl Fifo {
+    fn new(capacity: usize) -> Self {
+        Self {
+            data: [0; 8],
+            start: 0,
+            len: 0,
+            capacity,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == self.capacity
+    }
+
+    fn push(&mut self, value: u32) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.data[(self.start + self.len) % self.data.len()] = value;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u32> {
+        if self.len == 0 {
+            return None;
+        }
+        let value = self.data[self.start];
+        self.start = (self.start + 1) % self.data.len();
+        self.len -= 1;
+        Some(value)
+    }
+}
+
+/// Outcome of executing an instruction for one cycle.
+enum Exec {
+    /// The instruction completed, execution continues with the next one.
+    Done,
+    /// The instruction completed and changed the program counter.
+    Jump(u8),
+    /// The instruction could not complete and will be retried on the next cycle.
+    Stall,
+}
+
+/// Emulated state machine, created by [`Emulator::build`].
+#[derive(Debug)]
+pub struct EmulatedStateMachine {
+    instr_mem: [u16; PIO_INSTRUCTION_COUNT],
+    config: Config,
+
+    pc: u8,
+    x: u32,
+    y: u32,
+    isr: u32,
+    isr_count: u8,
+    osr: u32,
+    osr_count: u8,
+    /// Remaining delay cycles of the last instruction.
+    delay: u8,
+    /// Instruction from `OUT EXEC`/`MOV EXEC` to run on the next cycle.
+    exec: Option<u16>,
+    /// IRQ flag an `IRQ WAIT` instruction is waiting for to be cleared.
+    irq_wait: Option<u8>,
+    stalled: bool,
+    irq_flags: u8,
+
+    /// Fractional clock divider state, in 24.8 fixed point.
+    clock_accumulator: u32,
+    sys_cycles: u64,
+    sm_cycles: u64,
+
+    pin_values: u32,
+    pin_dirs: u32,
+    inputs: u32,
+
+    rx: Fifo,
+    tx: Fifo,
+}
+
+impl EmulatedStateMachine {
+    /// Advance by one system clock cycle.
+    ///
+    /// The state machine executes a cycle whenever the clock divisor allows it. Returns `true` if
+    /// it did.
+    pub fn tick(&mut self) -> bool {
+        self.sys_cycles += 1;
+        self.clock_accumulator += 1 << 8;
+        if self.clock_accumulator >= self.config.divisor {
+            self.clock_accumulator -= self.config.divisor;
+            self.step();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Advance by `cycles` system clock cycles.
+    pub fn run(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.tick();
+        }
+    }
+
+    /// Execute a single state machine cycle, ignoring the clock divisor.
+    pub fn step(&mut self) {
+        self.sm_cycles += 1;
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        let exec = self.exec.take();
+        let instr = exec.unwrap_or(self.instr_mem[self.pc as usize]);
+        let delay = self.apply_side_set(instr);
+
+        let result = if let Some(flag) = self.irq_wait {
+            // Second phase of `IRQ WAIT`: wait for the flag to be cleared.
+            if self.irq_flags & (1 << flag) != 0 {
+                Exec::Stall
+            } else {
+                self.irq_wait = None;
+                Exec::Done
+            }
+        } else {
+            self.execute(instr)
+        };
+
+        match result {
+            Exec::Stall => {
+                self.stalled = true;
+                if exec.is_some() {
+                    self.exec = exec;
+                }
+            }
+            Exec::Done | Exec::Jump(_) => {
+                self.stalled = false;
+                // The delay of `OUT EXEC` and `MOV EXEC` is ignored.
+                if self.exec.is_none() {
+                    self.delay = delay;
+                }
+                if let Exec::Jump(target) = result {
+                    self.pc = target;
+                } else if exec.is_none() {
+                    self.pc = if self.pc == self.config.wrap_top {
+                        self.config.wrap_bottom
+                    } else {
+                        (self.pc + 1) % PIO_INSTRUCTION_COUNT as u8
+                    };
+                }
+            }
+        }
+    }
+
+    /// Immediately execute an instruction on the next cycle, like writing to `SMx_INSTR`.
+    pub fn exec_instruction(&mut self, instruction: u16) {
+        self.exec = Some(instruction);
+    }
+
+    /// Push a value into the TX FIFO.
+    ///
+    /// Returns `false` if the FIFO is full.
+    pub fn push_tx(&mut self, value: u32) -> bool {
+        self.tx.push(value)
+    }
+
+    /// Pop a value from the RX FIFO.
+    pub fn pop_rx(&mut self) -> Option<u32> {
+        self.rx.pop()
+    }
+
+    /// Number of entries in the TX FIFO.
+    pub fn tx_level(&self) -> usize {
+        self.tx.len
+    }
+
+    /// Number of entries in the RX FIFO.
+    pub fn rx_level(&self) -> usize {
+        self.rx.len
+    }
+
+    /// Set the level of the pins as driven from outside of the PIO, one bit per pin.
+    pub fn set_inputs(&mut self, inputs: u32) {
+        self.inputs = inputs;
+    }
+
+    /// Set the level of a single pin as driven from outside of the PIO.
+    pub fn set_input(&mut self, pin: u8, high: bool) {
+        let bit = 1 << (pin % 32);
+        if high {
+            self.inputs |= bit;
+        } else {
+            self.inputs &= !bit;
+        }
+    }
+
+    /// Set pin directions, one bit per pin, `1` meaning output.
+    ///
+    /// This is the equivalent of [`StateMachine::set_pindirs`](super::StateMachine::set_pindirs).
+    pub fn set_pindirs(&mut self, dirs: u32) {
+        self.pin_dirs = dirs;
+    }
+
+    /// Output values driven by the state machine, one bit per pin, regardless of direction.
+    pub fn pin_values(&self) -> u32 {
+        self.pin_values
+    }
+
+    /// Pin directions, one bit per pin, `1` meaning output.
+    pub fn pin_dirs(&self) -> u32 {
+        self.pin_dirs
+    }
+
+    /// Level of all pins: the state machine's value for outputs, the external input otherwise.
+    pub fn pin_levels(&self) -> u32 {
+        (self.pin_values & self.pin_dirs) | (self.inputs & !self.pin_dirs)
+    }
+
+    /// The address of the instruction currently being executed.
+    pub fn instruction_address(&self) -> u8 {
+        self.pc
+    }
+
+    /// Check if the current instruction is stalled.
+    pub fn stalled(&self) -> bool {
+        self.stalled
+    }
+
+    /// Content of the X scratch register.
+    pub fn x(&self) -> u32 {
+        self.x
+    }
+
+    /// Content of the Y scratch register.
+    pub fn y(&self) -> u32 {
+        self.y
+    }
+
+    /// Content of the input shift register and number of bits shifted into it.
+    pub fn isr(&self) -> (u32, u8) {
+        (self.isr, self.isr_count)
+    }
+
+    /// Content of the output shift register and number of bits shifted out of it.
+    pub fn osr(&self) -> (u32, u8) {
+        (self.osr, self.osr_count)
+    }
+
+    /// Raw IRQ flags of the block, as seen by this state machine.
+    pub fn irq_flags(&self) -> u8 {
+        self.irq_flags
+    }
+
+    /// Clear IRQ flags indicated by the bits, like [`PIO::clear_irq`].
+    pub fn clear_irq(&mut self, flags: u8) {
+        self.irq_flags &= !flags;
+    }
+
+    /// Force IRQ flags indicated by the bits, like [`PIO::force_irq`].
+    pub fn force_irq(&mut self, flags: u8) {
+        self.irq_flags |= flags;
+    }
+
+    /// Number of system clock cycles elapsed in [`Self::tick`] and [`Self::run`].
+    pub fn sys_cycles(&self) -> u64 {
+        self.sys_cycles
+    }
+
+    /// Number of state machine cycles executed so far.
+    pub fn sm_cycles(&self) -> u64 {
+        self.sm_cycles
+    }
+
+    /// Applies the side-set of `instr` and returns its delay.
+    fn apply_side_set(&mut self, instr: u16) -> u8 {
+        let field = ((instr >> 8) & 0x1f) as u8;
+        let bits = self.config.side_set_bits;
+        let delay = field & (mask(5 - bits) as u8);
+        if bits == 0 {
+            return delay;
+        }
+        let mut side_set = field >> (5 - bits);
+        let mut count = bits;
+        if self.config.side_set_optional {
+            count -= 1;
+            if side_set & (1 << count) == 0 {
+                return delay;
+            }
+            side_set &= mask(count) as u8;
+        }
+        let (base, value) = (self.config.side_set_base, u32::from(side_set));
+        if self.config.side_set_pindirs {
+            Self::write_pins(&mut self.pin_dirs, base, count, value);
+        } else {
+            Self::write_pins(&mut self.pin_values, base, count, value);
+        }
+        delay
+    }
+
+    /// Writes `count` bits of `value` to `pins`, starting at `base` and wrapping around.
+    fn write_pins(pins: &mut u32, base: u8, count: u8, value: u32) {
+        let mask = mask(count).rotate_left(u32::from(base));
+        *pins = (*pins & !mask) | (value.rotate_left(u32::from(base)) & mask);
+    }
+
+    /// Pins as seen from `IN PINS`/`MOV x, PINS`, rotated so that `in_base` is bit 0.
+    fn in_pins(&self) -> u32 {
+        self.pin_levels()
+            .rotate_right(u32::from(self.config.in_base))
+    }
+
+    fn irq_index(&self, index: u8) -> u8 {
+        // The emulated state machine is always SM0, so relative indices are unchanged.
+        index & 0x7
+    }
+
+    fn status(&self) -> u32 {
+        let all = match self.config.mov_status {
+            MovStatusConfig::Tx(n) => self.tx.len < n as usize,
+            MovStatusConfig::Rx(n) => self.rx.len < n as usize,
+        };
+        if all {
+            u32::MAX
+        } else {
+            0
+        }
+    }
+
+    fn shift_in(&mut self, data: u32, count: u8) {
+        let data = data & mask(count);
+        self.isr = match (self.config.in_shiftdir, count) {
+            (_, 32) => data,
+            (ShiftDirection::Left, _) => (self.isr << count) | data,
+            (ShiftDirection::Right, _) => (self.isr >> count) | (data << (32 - count)),
+        };
+        self.isr_count = (self.isr_count + count).min(32);
+    }
+
+    fn shift_out(&mut self, count: u8) -> u32 {
+        let data = match self.config.out_shiftdir {
+            ShiftDirection::Left => self.osr.checked_shr(32 - u32::from(count)).unwrap_or(0),
+            ShiftDirection::Right => self.osr & mask(count),
+        };
+        self.osr = match self.config.out_shiftdir {
+            ShiftDirection::Left => self.osr.checked_shl(u32::from(count)).unwrap_or(0),
+            ShiftDirection::Right => self.osr.checked_shr(u32::from(count)).unwrap_or(0),
+        };
+        self.osr_count = (self.osr_count + count).min(32);
+        data
+    }
+
+    fn pull(&mut self) -> bool {
+        match self.tx.pop() {
+            Some(value) => {
+                self.osr = value;
+                self.osr_count = 0;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn execute(&mut self, instr: u16) -> Exec {
+        let arg1 = ((instr >> 5) & 0x7) as u8;
+        let arg2 = (instr & 0x1f) as u8;
+        let bit_count = if arg2 == 0 { 32 } else { arg2 };
+        match instr >> 13 {
+            // JMP
+            0b000 => {
+                let condition = match arg1 {
+                    0b000 => true,
+                    0b001 => self.x == 0,
+                    0b010 => {
+                        let taken = self.x != 0;
+                        self.x = self.x.wrapping_sub(1);
+                        taken
+                    }
+                    0b011 => self.y == 0,
+                    0b100 => {
+                        let taken = self.y != 0;
+                        self.y = self.y.wrapping_sub(1);
+                        taken
+                    }
+                    0b101 => self.x != self.y,
+                    0b110 => self.pin_levels() & (1 << (self.config.jmp_pin % 32)) != 0,
+                    _ => self.osr_count < self.config.pull_threshold,
+                };
+                if condition {
+                    Exec::Jump(arg2)
+                } else {
+                    Exec::Done
+                }
+            }
+            // WAIT
+            0b001 => {
+                let polarity = instr & 0x80 != 0;
+                let level = match (instr >> 5) & 0x3 {
+                    0b00 => self.pin_levels() & (1 << arg2) != 0,
+                    0b01 => self.in_pins() & (1 << arg2) != 0,
+                    0b10 => {
+                        let flag = self.irq_index(arg2);
+                        let set = self.irq_flags & (1 << flag) != 0;
+                        if polarity && set {
+                            self.irq_flags &= !(1 << flag);
+                        }
+                        set
+                    }
+                    _ => panic!("reserved WAIT source"),
+                };
+                if level == polarity {
+                    Exec::Done
+                } else {
+                    Exec::Stall
+                }
+            }
+            // IN
+            0b010 => {
+                let data = match arg1 {
+                    0b000 => self.in_pins(),
+                    0b001 => self.x,
+                    0b010 => self.y,
+                    0b011 => 0,
+                    0b110 => self.isr,
+                    0b111 => self.osr,
+                    _ => panic!("reserved IN source"),
+                };
+                let push_needed = self.config.autopush
+                    && (self.isr_count + bit_count).min(32) >= self.config.push_threshold;
+                if push_needed && self.rx.is_full() {
+                    return Exec::Stall;
+                }
+                self.shift_in(data, bit_count);
+                if push_needed {
+                    self.rx.push(self.isr);
+                    self.isr = 0;
+                    self.isr_count = 0;
+                }
+                Exec::Done
+            }
+            // OUT
+            0b011 => {
+                if self.config.autopull
+                    && self.osr_count >= self.config.pull_threshold
+                    && !self.pull()
+                {
+                    return Exec::Stall;
+                }
+                let data = self.shift_out(bit_count);
+                let result = match arg1 {
+                    0b000 => {
+                        let (base, count) = (self.config.out_base, self.config.out_count);
+                        Self::write_pins(&mut self.pin_values, base, count, data);
+                        Exec::Done
+                    }
+                    0b001 => {
+                        self.x = data;
+                        Exec::Done
+                    }
+                    0b010 => {
+                        self.y = data;
+                        Exec::Done
+                    }
+                    0b011 => Exec::Done,
+                    0b100 => {
+                        let (base, count) = (self.config.out_base, self.config.out_count);
+                        Self::write_pins(&mut self.pin_dirs, base, count, data);
+                        Exec::Done
+                    }
+                    0b101 => Exec::Jump(data as u8 & 0x1f),
+                    0b110 => {
+                        self.isr = data;
+                        self.isr_count = bit_count;
+                        Exec::Done
+                    }
+                    _ => {
+                        self.exec = Some(data as u16);
+                        Exec::Done
+                    }
+                };
+                if self.config.autopull && self.osr_count >= self.config.pull_threshold {
+                    self.pull();
+                }
+                result
+            }
+            // PUSH/PULL
+            0b100 => {
+                let if_flag = instr & 0x40 != 0;
+                let block = instr & 0x20 != 0;
+                if instr & 0x80 == 0 {
+                    // PUSH
+                    if if_flag && self.isr_count < self.config.push_threshold {
+                        return Exec::Done;
+                    }
+                    if self.rx.is_full() && block {
+                        return Exec::Stall;
+                    }
+                    self.rx.push(self.isr);
+                    self.isr = 0;
+                    self.isr_count = 0;
+                } else {
+                    // PULL
+                    let below_threshold = self.osr_count < self.config.pull_threshold;
+                    if (if_flag || self.config.autopull) && below_threshold {
+                        return Exec::Done;
+                    }
+                    if !self.pull() {
+                        if block {
+                            return Exec::Stall;
+                        }
+                        self.osr = self.x;
+                        self.osr_count = 0;
+                    }
+                }
+                Exec::Done
+            }
+            // MOV
+            0b101 => {
+                let data = match instr & 0x7 {
+                    0b000 => self.in_pins(),
+                    0b001 => self.x,
+                    0b010 => self.y,
+                    0b011 => 0,
+                    0b101 => self.status(),
+                    0b110 => self.isr,
+                    0b111 => self.osr,
+                    _ => panic!("reserved MOV source"),
+                };
+                let data = match (instr >> 3) & 0x3 {
+                    0b00 => data,
+                    0b01 => !data,
+                    0b10 => data.reverse_bits(),
+                    _ => panic!("reserved MOV operation"),
+                };
+                match arg1 {
+                    0b000 => {
+                        let (base, count) = (self.config.out_base, self.config.out_count);
+                        Self::write_pins(&mut self.pin_values, base, count, data);
+                    }
+                    0b001 => self.x = data,
+                    0b010 => self.y = data,
+                    0b100 => self.exec = Some(data as u16),
+                    0b101 => return Exec::Jump(data as u8 & 0x1f),
+                    0b110 => {
+                        self.isr = data;
+                        self.isr_count = 0;
+                    }
+                    0b111 => {
+                        self.osr = data;
+                        self.osr_count = 0;
+                    }
+                    _ => panic!("reserved MOV destination"),
+                }
+                Exec::Done
+            }
+            // IRQ
+            0b110 => {
+                let flag = self.irq_index(arg2);
+                if instr & 0x40 != 0 {
+                    self.irq_flags &= !(1 << flag);
+                } else {
+                    self.irq_flags |= 1 << flag;
+                    if instr & 0x20 != 0 {
+                        self.irq_wait = Some(flag);
+                        return Exec::Stall;
+                    }
+                }
+                Exec::Done
+            }
+            // SET
+            _ => {
+                let (base, count) = (self.config.set_base, self.config.set_count);
+                match arg1 {
+                    0b000 => Self::write_pins(&mut self.pin_values, base, count, arg2.into()),
+                    0b001 => self.x = arg2.into(),
+                    0b010 => self.y = arg2.into(),
+                    0b100 => Self::write_pins(&mut self.pin_dirs, base, count, arg2.into()),
+                    _ => panic!("reserved SET destination"),
+                }
+                Exec::Done
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pac::PIO0;
+    use crate::pio::ShiftDirection;
+
+    #[test]
+    fn square_wave_with_delay_and_divisor() {
+        let program = pio_proc::pio_asm!(
+            ".wrap_target",
+            "    set pins, 1 [1]",
+            "    set pins, 0 [1]",
+            ".wrap"
+        )
+        .program;
+        let mut pio = Emulator::<PIO0>::new();
+        let installed = pio.install(&program).unwrap();
+        let mut sm = pio.build(
+            PIOBuilder::from_installed_program(installed)
+                .set_pins(3, 1)
+                .clock_divisor_fixed_point(2, 0),
+        );
+        sm.set_pindirs(1 << 3);
+
+        let mut levels = [0; 8];
+        for level in levels.iter_mut() {
+            sm.run(2);
+            *level = (sm.pin_levels() >> 3) & 1;
+        }
+        assert_eq!(levels, [1, 1, 0, 0, 1, 1, 0, 0]);
+        assert_eq!(sm.sys_cycles(), 16);
+        assert_eq!(sm.sm_cycles(), 8);
+    }
+
+    #[test]
+    fn autopull_and_autopush_loopback() {
+        let program =
+            pio_proc::pio_asm!(".wrap_target", "    out x, 8", "    in x, 8", ".wrap").program;
+        let mut pio = Emulator::<PIO0>::new();
+        let installed = pio.install(&program).unwrap();
+        let offset = installed.offset();
+        let mut sm = pio.build(
+            PIOBuilder::from_installed_program(installed)
+                .autopull(true)
+                .pull_threshold(32)
+                .autopush(true)
+                .push_threshold(32)
+                .out_shift_direction(ShiftDirection::Right)
+                .in_shift_direction(ShiftDirection::Right),
+        );
+
+        // Nothing to pull yet.
+        sm.step();
+        assert!(sm.stalled());
+        assert_eq!(sm.instruction_address(), offset);
+
+        assert!(sm.push_tx(0x1234_5678));
+        for _ in 0..8 {
+            sm.step();
+        }
+        assert_eq!(sm.pop_rx(), Some(0x1234_5678));
+        assert_eq!(sm.pop_rx(), None);
+    }
+
+    #[test]
+    fn side_set_and_jmp_pin() {
+        let program = pio_proc::pio_asm!(
+            ".side_set 1 opt",
+            "    jmp pin high side 1",
+            "    jmp 0 side 0",
+            "high:",
+            "    set x, 7",
+            "    jmp x-- 3",
+        )
+        .program;
+        let mut pio = Emulator::<PIO0>::new();
+        let installed = pio.install(&program).unwrap();
+        let offset = installed.offset();
+        let mut sm = pio.build(
+            PIOBuilder::from_installed_program(installed)
+                .side_set_pin_base(5)
+                .jmp_pin(2),
+        );
+        sm.set_pindirs(1 << 5);
+
+        sm.step();
+        assert_eq!(sm.pin_levels() & (1 << 5), 1 << 5);
+        assert_eq!(sm.instruction_address(), offset + 1);
+        sm.step();
+        assert_eq!(sm.pin_levels() & (1 << 5), 0);
+        assert_eq!(sm.instruction_address(), offset);
+
+        sm.set_input(2, true);
+        sm.step();
+        assert_eq!(sm.instruction_address(), offset + 2);
+        sm.step();
+        assert_eq!(sm.x(), 7);
+        // `jmp x-- 3` is taken 7 times, then falls through after x reached 0.
+        for _ in 0..8 {
+            sm.step();
+        }
+        assert_eq!(sm.x(), u32::MAX);
+    }
+}
@@ -0,0 +1,612 @@
+//! Dallas 1-Wire bus master implemented with PIO
+//!
+//! [`OneWire`] generates the reset, presence detect and read/write slots in a state machine
+//! running at 1 MHz, so the bus timing doesn't depend on the CPU and interrupts don't need to be
+//! disabled. Each operation has a blocking and an `async` variant, the latter being woken by the
+//! FIFO interrupts of the PIO block (see [`Rx::read_async`]).
+//!
+//! The bus is driven open-drain and needs an external pull-up resistor, typically 4.7 kΩ.
+//! Devices powered parasitically, like a DS18B20 converting a temperature, can be supplied with
+//! [`OneWire::write_byte_with_strong_pullup`]: the bus is driven high right after the last bit of
+//! the byte, until the next operation.
+//!
+//! ```no_run
+//! use fugit::RateExtU32;
+//! use rp2040_hal::{
+//!     gpio::{FunctionPio0, Pins},
+//!     pac,
+//!     pio::{
+//!         one_wire::{OneWire, RomSearch},
+//!         PIOExt,
+//!     },
+//!     Sio,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let sio = Sio::new(pac.SIO);
+//! let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+//! let pin = pins.gpio15.into_function::<FunctionPio0>();
+//! let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+//! let mut bus = OneWire::new(&mut pio, sm0, &pin, 125.MHz()).unwrap();
+//!
+//! let mut search = RomSearch::new();
+//! while let Ok(Some(rom)) = bus.search_next(&mut search) {
+//!     // Start a temperature conversion on the DS18B20 sensor found, powering it while it
+//!     // converts.
+//!     bus.reset();
+//!     bus.write_byte(0x55);
+//!     bus.write_bytes(&rom.to_le_bytes());
+//!     bus.write_byte_with_strong_pullup(0x44);
+//! }
+//! ```
+use fugit::HertzU32;
+use pio::{Assembler, InSource, JmpCondition, OutDestination, SetDestination};
+
+use super::{
+    InstallError, PIOBuilder, PIOExt, PinDir, PinState, PioIRQ, PioPin, Running, Rx,
+    ShiftDirection, StateMachine, StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+
+/// Frequency of the state machine, each cycle lasts 1 µs.
+const SM_CLOCK: u32 = 1_000_000;
+
+/// Command word requesting a reset and presence detect.
+const RESET: u32 = 1;
+
+/// `SEARCH ROM` command.
+const SEARCH_ROM: u8 = 0xf0;
+
+/// `ALARM SEARCH` command.
+const ALARM_SEARCH: u8 = 0xec;
+
+/// Bus master program.
+///
+/// Each command word pulled from the TX FIFO is read LSB first:
+/// - 1 bit: reset flag. If set, a reset is generated and the presence bit is pushed (0 if a device
+///   answered), the rest of the word is ignored.
+/// - 3 bits: number of slots minus 1.
+/// - 1 bit per slot: the bus is released after 6 µs if set (writing 1 or reading), or kept low for
+///   the whole slot otherwise (writing 0). The bits sampled in the slots are pushed.
+/// - 1 bit: strong pull-up flag. If set, the bus is driven high until the next command.
+fn program() -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut slots = a.label();
+    let mut reset_low = a.label();
+    let mut reset_high = a.label();
+    let mut slot = a.label();
+
+    a.bind(&mut wrap_target);
+    a.pull(false, true);
+    // Stop any strong pull-up.
+    a.set(SetDestination::PINDIRS, 0);
+    a.set(SetDestination::PINS, 0);
+    a.out(OutDestination::Y, 1);
+    a.jmp(JmpCondition::YIsZero, &mut slots);
+
+    // Reset: 480 µs low, sample the presence pulse 70 µs after releasing the bus, and leave the
+    // devices 410 µs more to recover.
+    a.set(SetDestination::PINDIRS, 1);
+    a.set(SetDestination::X, 14);
+    a.bind(&mut reset_low);
+    a.jmp_with_delay(JmpCondition::XDecNonZero, &mut reset_low, 31);
+    a.set_with_delay(SetDestination::PINDIRS, 0, 31);
+    a.nop_with_delay(31);
+    a.nop_with_delay(5);
+    a.r#in(InSource::PINS, 1);
+    a.push(false, true);
+    a.set(SetDestination::X, 12);
+    a.bind(&mut reset_high);
+    a.jmp_with_delay(JmpCondition::XDecNonZero, &mut reset_high, 31);
+    a.jmp(JmpCondition::Always, &mut wrap_target);
+
+    // Slots of 72 µs: 6 µs low, the bit to write until 61 µs, sampled at 15 µs, and 11 µs of
+    // recovery.
+    a.bind(&mut slots);
+    a.out(OutDestination::X, 3);
+    a.bind(&mut slot);
+    a.set_with_delay(SetDestination::PINDIRS, 1, 5);
+    a.out_with_delay(OutDestination::PINDIRS, 1, 8);
+    a.in_with_delay(InSource::PINS, 1, 31);
+    a.nop_with_delay(13);
+    a.set_with_delay(SetDestination::PINDIRS, 0, 9);
+    a.jmp(JmpCondition::XDecNonZero, &mut slot);
+    a.push(false, true);
+    a.out(OutDestination::Y, 1);
+    a.jmp(JmpCondition::YIsZero, &mut wrap_target);
+    a.set(SetDestination::PINS, 1);
+    a.set(SetDestination::PINDIRS, 1);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Command word for `count` (1 to 8) slots sending the bits of `data`, LSB first.
+fn slots_command(data: u8, count: u8, strong_pullup: bool) -> u32 {
+    let mask = (1 << count) - 1;
+    // The state machine drives the bus low for the bits set.
+    let dirs = u32::from(!data) & mask;
+    u32::from(count - 1) << 1 | dirs << 4 | u32::from(strong_pullup) << (4 + count)
+}
+
+/// Bits sampled by a command of `count` slots, LSB first.
+fn slots_response(word: u32, count: u8) -> u8 {
+    (word >> (32 - count)) as u8
+}
+
+/// Compute the Dallas/Maxim CRC-8 of `data`, as used by ROM codes and scratchpads.
+///
+/// The CRC of data ending with its own CRC is 0.
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 1 != 0 {
+                crc >> 1 ^ 0x8c
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// 1-Wire error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// No device answered the reset pulse.
+    NoPresence,
+    /// The ROM code read has an invalid CRC.
+    CrcMismatch,
+}
+
+/// State of a ROM search, enumerating the devices of a bus one by one.
+///
+/// See [`OneWire::search_next`].
+#[derive(Debug, Clone)]
+pub struct RomSearch {
+    command: u8,
+    rom: u64,
+    last_discrepancy: u8,
+    last_zero: u8,
+    done: bool,
+}
+
+impl RomSearch {
+    /// Search all the devices.
+    pub fn new() -> Self {
+        Self::with_command(SEARCH_ROM)
+    }
+
+    /// Search only the devices with an alarm condition.
+    pub fn alarm() -> Self {
+        Self::with_command(ALARM_SEARCH)
+    }
+
+    fn with_command(command: u8) -> Self {
+        Self {
+            command,
+            rom: 0,
+            last_discrepancy: 0,
+            last_zero: 0,
+            done: false,
+        }
+    }
+
+    /// Choose the direction to take at `bit` (0 to 63) from the bit of the devices still
+    /// participating and its complement. Returns `None` if no device answered.
+    fn choose(&mut self, bit: u8, id_bit: bool, cmp_bit: bool) -> Option<bool> {
+        let direction = match (id_bit, cmp_bit) {
+            (true, true) => return None,
+            (id_bit, cmp_bit) if id_bit != cmp_bit => id_bit,
+            // Discrepancy: follow the previous ROM code up to the last discrepancy, then take
+            // the other branch at the last discrepancy, and the 0 branch past it.
+            _ => {
+                let direction = match (bit + 1).cmp(&self.last_discrepancy) {
+                    core::cmp::Ordering::Less => self.rom & (1 << bit) != 0,
+                    core::cmp::Ordering::Equal => true,
+                    core::cmp::Ordering::Greater => false,
+                };
+                if !direction {
+                    self.last_zero = bit + 1;
+                }
+                direction
+            }
+        };
+        if direction {
+            self.rom |= 1 << bit;
+        } else {
+            self.rom &= !(1 << bit);
+        }
+        Some(direction)
+    }
+
+    /// Complete a pass, returning the ROM code found.
+    fn finish(&mut self) -> Result<u64, Error> {
+        if crc8(&self.rom.to_le_bytes()) != 0 {
+            return Err(Error::CrcMismatch);
+        }
+        self.last_discrepancy = self.last_zero;
+        self.done = self.last_discrepancy == 0;
+        Ok(self.rom)
+    }
+}
+
+impl Default for RomSearch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 1-Wire bus master on a PIO state machine.
+pub struct OneWire<P: PIOExt, SM: StateMachineIndex> {
+    sm: StateMachine<(P, SM), Running>,
+    rx: Rx<(P, SM)>,
+    tx: Tx<(P, SM)>,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> OneWire<P, SM> {
+    /// Install the bus master program and start it on `sm`.
+    ///
+    /// `system_clock` is the frequency of the system clock, used to derive the bus timing.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        pin: &dyn PioPin<P>,
+        system_clock: HertzU32,
+    ) -> Result<Self, InstallError> {
+        let installed = pio.install(&program())?;
+        let divisor = (u64::from(system_clock.to_Hz()) << 8) / u64::from(SM_CLOCK);
+        let (mut sm, rx, tx) = PIOBuilder::from_installed_program(installed)
+            .set_pins_checked(&[pin])
+            .out_pins_checked(&[pin])
+            .in_pins_checked(&[pin])
+            .out_shift_direction(ShiftDirection::Right)
+            .in_shift_direction(ShiftDirection::Right)
+            .clock_divisor_fixed_point((divisor >> 8) as u16, divisor as u8)
+            .build(sm);
+        // Release the bus: it is pulled low by switching the pin to an output.
+        sm.set_pins([(pin.pin_num(), PinState::Low)]);
+        sm.set_pindirs([(pin.pin_num(), PinDir::Input)]);
+
+        Ok(Self {
+            sm: sm.start(),
+            rx,
+            tx,
+        })
+    }
+
+    /// Stop the bus master, uninstall its program and return the state machine.
+    pub fn free(self, pio: &mut PIO<P>) -> UninitStateMachine<(P, SM)> {
+        let (sm, program) = self.sm.uninit(self.rx, self.tx);
+        pio.uninstall(program);
+        sm
+    }
+
+    fn command(&mut self, word: u32) -> u32 {
+        while !self.tx.write(word) {}
+        loop {
+            if let Some(response) = self.rx.read() {
+                return response;
+            }
+        }
+    }
+
+    async fn command_async(&mut self, irq: PioIRQ, word: u32) -> u32 {
+        self.tx.write_async(irq, word).await;
+        self.rx.read_async(irq).await
+    }
+
+    /// Send a reset pulse, returning whether a device answered with a presence pulse.
+    pub fn reset(&mut self) -> bool {
+        self.command(RESET) & (1 << 31) == 0
+    }
+
+    /// Write a bit.
+    pub fn write_bit(&mut self, bit: bool) {
+        self.command(slots_command(u8::from(bit), 1, false));
+    }
+
+    /// Read a bit.
+    pub fn read_bit(&mut self) -> bool {
+        slots_response(self.command(slots_command(1, 1, false)), 1) != 0
+    }
+
+    /// Write a byte, LSB first.
+    pub fn write_byte(&mut self, byte: u8) {
+        self.command(slots_command(byte, 8, false));
+    }
+
+    /// Write a byte, then drive the bus high until the next operation.
+    ///
+    /// The bus is driven high as soon as the last bit is sent, to power parasitically powered
+    /// devices during a conversion or an EEPROM write.
+    pub fn write_byte_with_strong_pullup(&mut self, byte: u8) {
+        self.command(slots_command(byte, 8, true));
+    }
+
+    /// Read a byte, LSB first.
+    pub fn read_byte(&mut self) -> u8 {
+        slots_response(self.command(slots_command(0xff, 8, false)), 8)
+    }
+
+    /// Write all of `bytes`.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_byte(byte);
+        }
+    }
+
+    /// Fill `buffer` with bytes read from the bus.
+    pub fn read_bytes(&mut self, buffer: &mut [u8]) {
+        for byte in buffer {
+            *byte = self.read_byte();
+        }
+    }
+
+    /// Find the next device of a ROM search.
+    ///
+    /// Returns `Ok(None)` once all the devices were found. On error, the search can be resumed
+    /// from the same point by calling this again.
+    pub fn search_next(&mut self, search: &mut RomSearch) -> Result<Option<u64>, Error> {
+        if search.done {
+            return Ok(None);
+        }
+        if !self.reset() {
+            return Err(Error::NoPresence);
+        }
+        self.write_byte(search.command);
+        let mut pass = search.clone();
+        pass.last_zero = 0;
+        for bit in 0..64 {
+            let bits = slots_response(self.command(slots_command(0b11, 2, false)), 2);
+            let Some(direction) = pass.choose(bit, bits & 1 != 0, bits & 2 != 0) else {
+                // All the devices left the bus.
+                return Err(Error::NoPresence);
+            };
+            self.write_bit(direction);
+        }
+        let rom = pass.finish()?;
+        *search = pass;
+        Ok(Some(rom))
+    }
+
+    /// Send a reset pulse, returning whether a device answered with a presence pulse.
+    ///
+    /// See [`Self::reset`], the future is woken by the interrupt `irq` of the PIO block.
+    pub async fn reset_async(&mut self, irq: PioIRQ) -> bool {
+        self.command_async(irq, RESET).await & (1 << 31) == 0
+    }
+
+    /// Write a bit.
+    ///
+    /// See [`Self::write_bit`], the future is woken by the interrupt `irq` of the PIO block.
+    pub async fn write_bit_async(&mut self, irq: PioIRQ, bit: bool) {
+        self.command_async(irq, slots_command(u8::from(bit), 1, false))
+            .await;
+    }
+
+    /// Read a bit.
+    ///
+    /// See [`Self::read_bit`], the future is woken by the interrupt `irq` of the PIO block.
+    pub async fn read_bit_async(&mut self, irq: PioIRQ) -> bool {
+        let response = self.command_async(irq, slots_command(1, 1, false)).await;
+        slots_response(response, 1) != 0
+    }
+
+    /// Write a byte, LSB first.
+    ///
+    /// See [`Self::write_byte`], the future is woken by the interrupt `irq` of the PIO block.
+    pub async fn write_byte_async(&mut self, irq: PioIRQ, byte: u8) {
+        self.command_async(irq, slots_command(byte, 8, false)).await;
+    }
+
+    /// Write a byte, then drive the bus high until the next operation.
+    ///
+    /// See [`Self::write_byte_with_strong_pullup`], the future is woken by the interrupt `irq` of
+    /// the PIO block.
+    pub async fn write_byte_with_strong_pullup_async(&mut self, irq: PioIRQ, byte: u8) {
+        self.command_async(irq, slots_command(byte, 8, true)).await;
+    }
+
+    /// Read a byte, LSB first.
+    ///
+    /// See [`Self::read_byte`], the future is woken by the interrupt `irq` of the PIO block.
+    pub async fn read_byte_async(&mut self, irq: PioIRQ) -> u8 {
+        let response = self.command_async(irq, slots_command(0xff, 8, false)).await;
+        slots_response(response, 8)
+    }
+
+    /// Write all of `bytes`.
+    ///
+    /// See [`Self::write_bytes`], the future is woken by the interrupt `irq` of the PIO block.
+    pub async fn write_bytes_async(&mut self, irq: PioIRQ, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_byte_async(irq, byte).await;
+        }
+    }
+
+    /// Fill `buffer` with bytes read from the bus.
+    ///
+    /// See [`Self::read_bytes`], the future is woken by the interrupt `irq` of the PIO block.
+    pub async fn read_bytes_async(&mut self, irq: PioIRQ, buffer: &mut [u8]) {
+        for byte in buffer {
+            *byte = self.read_byte_async(irq).await;
+        }
+    }
+
+    /// Find the next device of a ROM search.
+    ///
+    /// See [`Self::search_next`], the future is woken by the interrupt `irq` of the PIO block.
+    pub async fn search_next_async(
+        &mut self,
+        irq: PioIRQ,
+        search: &mut RomSearch,
+    ) -> Result<Option<u64>, Error> {
+        if search.done {
+            return Ok(None);
+        }
+        if !self.reset_async(irq).await {
+            return Err(Error::NoPresence);
+        }
+        self.write_byte_async(irq, search.command).await;
+        let mut pass = search.clone();
+        pass.last_zero = 0;
+        for bit in 0..64 {
+            let response = self.command_async(irq, slots_command(0b11, 2, false)).await;
+            let bits = slots_response(response, 2);
+            let Some(direction) = pass.choose(bit, bits & 1 != 0, bits & 2 != 0) else {
+                return Err(Error::NoPresence);
+            };
+            self.write_bit_async(irq, direction).await;
+        }
+        let rom = pass.finish()?;
+        *search = pass;
+        Ok(Some(rom))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pac;
+    use crate::pio::emulator::{EmulatedStateMachine, Emulator};
+
+    /// Simulated bus, with devices answering the slots of the bus master.
+    #[derive(Default)]
+    struct Bus {
+        cycle: u32,
+        master_low: bool,
+        fell_at: u32,
+        rose_at: u32,
+        /// Low pulses sent by the master, in µs.
+        pulses: [u32; 32],
+        count: usize,
+    }
+
+    impl Bus {
+        /// Step the master by 1 µs. The devices answer with a presence pulse after a reset if
+        /// `presence` is set, and `answer(slot)` in the slots.
+        fn step(
+            &mut self,
+            sm: &mut EmulatedStateMachine,
+            presence: bool,
+            answer: fn(usize) -> bool,
+        ) {
+            sm.step();
+            self.cycle += 1;
+            let master_low = sm.pin_dirs() & 1 != 0 && sm.pin_values() & 1 == 0;
+            if master_low && !self.master_low {
+                self.fell_at = self.cycle;
+            } else if !master_low && self.master_low {
+                self.pulses[self.count] = self.cycle - self.fell_at;
+                self.count += 1;
+                self.rose_at = self.cycle;
+            }
+            self.master_low = master_low;
+            let device_low = if self.count > 0 && self.pulses[self.count - 1] >= 480 {
+                presence && !master_low && (20..120).contains(&(self.cycle - self.rose_at))
+            } else {
+                self.cycle - self.fell_at < 30 && !answer(self.count.saturating_sub(1))
+            };
+            sm.set_input(0, !(master_low || device_low));
+        }
+    }
+
+    fn build(pio: &mut Emulator<pac::PIO0>) -> EmulatedStateMachine {
+        let installed = pio.install(&program()).unwrap();
+        pio.build(
+            PIOBuilder::from_installed_program(installed)
+                .set_pins(0, 1)
+                .out_pins(0, 1)
+                .in_pin_base(0)
+                .out_shift_direction(ShiftDirection::Right)
+                .in_shift_direction(ShiftDirection::Right),
+        )
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(slots_command(0xa5, 8, false), 0x5a << 4 | 7 << 1);
+        assert_eq!(slots_command(1, 1, true), 1 << 5);
+        // The first bit sampled ends up in the LSB.
+        assert_eq!(slots_response(1 << 30, 2), 0b01);
+        // Example ROM code of Maxim's application note 27.
+        assert_eq!(crc8(&[0x02, 0x1c, 0xb8, 0x01, 0x00, 0x00, 0x00]), 0xa2);
+    }
+
+    #[test]
+    fn reset() {
+        for presence in [true, false] {
+            let mut pio = Emulator::<pac::PIO0>::new();
+            let mut sm = build(&mut pio);
+            let mut bus = Bus::default();
+            sm.push_tx(RESET);
+            for _ in 0..1000 {
+                bus.step(&mut sm, presence, |_| true);
+            }
+            assert_eq!(bus.count, 1);
+            assert!((480..490).contains(&bus.pulses[0]), "{} µs", bus.pulses[0]);
+            assert_eq!(sm.pop_rx().unwrap() & (1 << 31) == 0, presence);
+        }
+    }
+
+    #[test]
+    fn write_and_read_byte() {
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let mut sm = build(&mut pio);
+        let mut bus = Bus::default();
+        sm.push_tx(slots_command(0xa5, 8, false));
+        sm.push_tx(slots_command(0xff, 8, true));
+        // The device answers 0x3c to the second byte.
+        for _ in 0..16 * 72 + 20 {
+            bus.step(&mut sm, false, |slot| {
+                slot < 8 || (0x3c >> (slot - 8)) & 1 != 0
+            });
+        }
+        let mut written = 0;
+        for (i, &pulse) in bus.pulses[..8].iter().enumerate() {
+            assert!(pulse <= 7 || pulse > 60, "slot {i}: {pulse} µs");
+            written |= u8::from(pulse <= 7) << i;
+        }
+        assert_eq!(written, 0xa5);
+        assert_eq!(slots_response(sm.pop_rx().unwrap(), 8), 0xa5);
+        assert_eq!(slots_response(sm.pop_rx().unwrap(), 8), 0x3c);
+        // Strong pull-up after the last slot.
+        assert_eq!(sm.pin_dirs() & 1, 1);
+        assert_eq!(sm.pin_values() & 1, 1);
+    }
+
+    #[test]
+    fn search() {
+        let rom = |serial: u64| {
+            let crc = crc8(&serial.to_le_bytes()[..7]);
+            serial | u64::from(crc) << 56
+        };
+        let mut roms = [rom(0x28_0a), rom(0x28_06), rom(0x28_0b), rom(0x10_0b)];
+        let mut search = RomSearch::new();
+        let mut found = [0; 4];
+        for found in &mut found {
+            // Simulate the passes of `OneWire::search_next`, with the devices matching the
+            // directions taken.
+            let mut pass = search.clone();
+            pass.last_zero = 0;
+            let mut active = [true; 4];
+            for bit in 0..64 {
+                let (mut id_bit, mut cmp_bit) = (true, true);
+                for (rom, _) in roms.iter().zip(active).filter(|(_, active)| *active) {
+                    id_bit &= rom & (1 << bit) != 0;
+                    cmp_bit &= rom & (1 << bit) == 0;
+                }
+                let direction = pass.choose(bit, id_bit, cmp_bit).unwrap();
+                for (rom, active) in roms.iter().zip(&mut active) {
+                    *active &= (rom & (1 << bit) != 0) == direction;
+                }
+            }
+            *found = pass.finish().unwrap();
+            search = pass;
+        }
+        assert!(search.done);
+        found.sort_unstable();
+        roms.sort_unstable();
+        assert_eq!(found, roms);
+    }
+}
@@ -0,0 +1,446 @@
+//! Parallel display bus implemented with PIO
+//!
+//! [`ParallelBus`] drives the 8- or 16-bit parallel interface of display controllers, either
+//! Intel 8080 style (active-low WR and RD strobes) or Motorola 6800 style (E strobe and R/W
+//! line), with the strobes generated by side-set. The D/C (register select) line is driven by the
+//! state machine too, so that it only changes once the previous words were written.
+//!
+//! Commands and their parameters are written with [`ParallelBus::write_command`]. The D/C line
+//! is left in data mode, so that pixel data can then be streamed with DMA, using the bus as the
+//! target of a transfer. The chip select line, if any, is left to the application.
+//!
+//! Each word is written in 4 state machine cycles, with the strobe active for half of them. Reads
+//! take 16 cycles, to leave the panel time to drive the bus.
+//!
+//! ```no_run
+//! use fugit::RateExtU32;
+//! use rp2040_hal::{
+//!     dma::{single_buffer, DMAExt},
+//!     gpio::{FunctionPio0, Pins},
+//!     pac,
+//!     pio::{
+//!         parallel::{BusType, ParallelBus},
+//!         PIOExt,
+//!     },
+//!     Sio,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let sio = Sio::new(pac.SIO);
+//! let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+//! let d0 = pins.gpio0.into_function::<FunctionPio0>();
+//! let d1 = pins.gpio1.into_function::<FunctionPio0>();
+//! let d2 = pins.gpio2.into_function::<FunctionPio0>();
+//! let d3 = pins.gpio3.into_function::<FunctionPio0>();
+//! let d4 = pins.gpio4.into_function::<FunctionPio0>();
+//! let d5 = pins.gpio5.into_function::<FunctionPio0>();
+//! let d6 = pins.gpio6.into_function::<FunctionPio0>();
+//! let d7 = pins.gpio7.into_function::<FunctionPio0>();
+//! let wr = pins.gpio8.into_function::<FunctionPio0>();
+//! let rd = pins.gpio9.into_function::<FunctionPio0>();
+//! let dc = pins.gpio10.into_function::<FunctionPio0>();
+//! let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+//! let dma = pac.DMA.split(&mut pac.RESETS);
+//!
+//! let mut bus = ParallelBus::<_, _, u8>::new(
+//!     &mut pio,
+//!     sm0,
+//!     BusType::Intel8080,
+//!     &[&d0, &d1, &d2, &d3, &d4, &d5, &d6, &d7],
+//!     [&wr, &rd],
+//!     &dc,
+//!     10.MHz(),
+//!     125.MHz(),
+//! )
+//! .unwrap();
+//! // Memory write
+//! bus.write_command(0x2c, &[]);
+//! let frame = cortex_m::singleton!(: [u8; 320 * 2] = [0; 320 * 2]).unwrap();
+//! let (ch0, frame, mut bus) = single_buffer::Config::new(dma.ch0, frame, bus).start().wait();
+//! bus.wait_idle();
+//! ```
+use core::marker::PhantomData;
+
+use fugit::HertzU32;
+use pio::{
+    Assembler, InSource, Instruction, InstructionOperands, MovDestination, MovOperation, MovSource,
+    OutDestination, SetDestination,
+};
+
+use super::{
+    InstallError, InstalledProgram, PIOBuilder, PIOExt, PinDir, PinState, PioPin, Running, Rx,
+    ShiftDirection, StateMachine, StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+use crate::dma::{EndlessWriteTarget, WriteTarget};
+use crate::typelevel::Sealed;
+
+/// State machine cycles per word written.
+const CYCLES_PER_WRITE: u32 = 4;
+
+/// Bus protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BusType {
+    /// Intel 8080: data is latched on the rising edge of the active-low WR strobe, and driven by
+    /// the panel while the active-low RD strobe is low.
+    ///
+    /// The control pins are WR, then RD.
+    Intel8080,
+    /// Motorola 6800: data is latched on the falling edge of the E strobe, R/W is high for reads
+    /// and low for writes.
+    ///
+    /// The control pins are E, then R/W.
+    Motorola6800,
+}
+
+impl BusType {
+    /// Side-set values of the control pins for writes, idle and active.
+    fn write_side_set(self) -> (u8, u8) {
+        match self {
+            BusType::Intel8080 => (0b11, 0b10),
+            BusType::Motorola6800 => (0b00, 0b01),
+        }
+    }
+
+    /// Side-set values of the control pins for reads, idle and active.
+    fn read_side_set(self) -> (u8, u8) {
+        match self {
+            BusType::Intel8080 => (0b11, 0b01),
+            BusType::Motorola6800 => (0b10, 0b11),
+        }
+    }
+}
+
+/// Width of the data bus, `u8` or `u16`.
+pub trait BusWord: Sealed + Copy {
+    /// Number of data pins.
+    const BITS: u8;
+
+    #[doc(hidden)]
+    fn to_word(self) -> u32;
+
+    #[doc(hidden)]
+    fn from_word(word: u32) -> Self;
+}
+
+impl BusWord for u8 {
+    const BITS: u8 = 8;
+
+    fn to_word(self) -> u32 {
+        u32::from(self)
+    }
+
+    fn from_word(word: u32) -> Self {
+        word as u8
+    }
+}
+
+impl BusWord for u16 {
+    const BITS: u8 = 16;
+
+    fn to_word(self) -> u32 {
+        u32::from(self)
+    }
+
+    fn from_word(word: u32) -> Self {
+        word as u16
+    }
+}
+
+/// Program writing each word pulled, `bits` bits wide.
+fn write_program(bus: BusType, bits: u8) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let (idle, active) = bus.write_side_set();
+    let mut a = Assembler::new_with_side_set(pio::SideSet::new(false, 2, false));
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    a.bind(&mut wrap_target);
+    a.pull_with_side_set(false, true, idle);
+    a.out_with_delay_and_side_set(OutDestination::PINS, bits, 1, active);
+    a.nop_with_side_set(idle);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Program reading a word, `bits` bits wide, for each word pulled.
+fn read_program(bus: BusType, bits: u8) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let (idle, active) = bus.read_side_set();
+    let mut a = Assembler::new_with_side_set(pio::SideSet::new(false, 2, false));
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    a.bind(&mut wrap_target);
+    a.pull_with_side_set(false, true, idle);
+    a.nop_with_delay_and_side_set(5, active);
+    a.in_with_side_set(InSource::PINS, bits, active);
+    a.push_with_delay_and_side_set(false, true, 7, idle);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Parallel display bus on a PIO state machine, `W` bits wide.
+pub struct ParallelBus<P: PIOExt, SM: StateMachineIndex, W: BusWord = u8> {
+    sm: StateMachine<(P, SM), Running>,
+    rx: Rx<(P, SM)>,
+    tx: Tx<(P, SM)>,
+    read_program: Option<InstalledProgram<P>>,
+    write_side_set: u8,
+    _word: PhantomData<W>,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex, W: BusWord> ParallelBus<P, SM, W> {
+    /// Install the bus programs and start the write program on `sm`.
+    ///
+    /// `data` are the data pins, D0 first, and `control` the strobe pins (see [`BusType`]). Both
+    /// must be consecutive GPIOs. `write_rate` is the maximum number of words written per second,
+    /// `system_clock` the frequency of the system clock.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        bus: BusType,
+        data: &[&dyn PioPin<P>],
+        control: [&dyn PioPin<P>; 2],
+        dc: &dyn PioPin<P>,
+        write_rate: HertzU32,
+        system_clock: HertzU32,
+    ) -> Result<Self, InstallError> {
+        assert_eq!(
+            data.len(),
+            usize::from(W::BITS),
+            "wrong number of data pins"
+        );
+        let write_program = pio.install(&write_program(bus, W::BITS))?;
+        let read_program = match pio.install(&read_program(bus, W::BITS)) {
+            Ok(program) => program,
+            Err(e) => {
+                pio.uninstall(write_program);
+                return Err(e);
+            }
+        };
+        // Round the divisor up, so that the bus never runs faster than `write_rate`.
+        let sm_clock = u64::from(write_rate.to_Hz()) * u64::from(CYCLES_PER_WRITE);
+        let divisor = (u64::from(system_clock.to_Hz()) * 256).div_ceil(sm_clock);
+        let divisor = divisor.clamp(0x100, 0xff_ffff);
+
+        let (mut sm, rx, tx) = PIOBuilder::from_installed_program(write_program)
+            .out_pins_checked(data)
+            .in_pins_checked(data)
+            .side_set_pins_checked(&control)
+            .set_pins_checked(&[dc])
+            .out_shift_direction(ShiftDirection::Right)
+            .in_shift_direction(ShiftDirection::Left)
+            .clock_divisor_fixed_point((divisor >> 8) as u16, divisor as u8)
+            .build(sm);
+        let (idle, _) = bus.write_side_set();
+        let level = |bit: u8| match idle & (1 << bit) {
+            0 => PinState::Low,
+            _ => PinState::High,
+        };
+        sm.set_pins([
+            (control[0].pin_num(), level(0)),
+            (control[1].pin_num(), level(1)),
+            (dc.pin_num(), PinState::High),
+        ]);
+        sm.set_pindirs(
+            data.iter()
+                .chain(&control)
+                .chain([&dc])
+                .map(|pin| (pin.pin_num(), PinDir::Output)),
+        );
+
+        Ok(Self {
+            sm: sm.start(),
+            rx,
+            tx,
+            read_program: Some(read_program),
+            write_side_set: idle,
+            _word: PhantomData,
+        })
+    }
+
+    /// Stop the bus, uninstall its programs and return the state machine.
+    pub fn free(self, pio: &mut PIO<P>) -> UninitStateMachine<(P, SM)> {
+        let (sm, program) = self.sm.uninit(self.rx, self.tx);
+        pio.uninstall(program);
+        pio.uninstall(self.read_program.expect("read program installed"));
+        sm
+    }
+
+    /// Block until all the words written, by the CPU or with DMA, are on the bus.
+    pub fn wait_idle(&mut self) {
+        while !self.tx.is_empty() {}
+        // The flag is set again as soon as the state machine waits for the next word.
+        self.tx.clear_stalled_flag();
+        while !self.tx.has_stalled() {}
+    }
+
+    /// Execute an instruction with the control pins idle.
+    fn exec(&mut self, operands: InstructionOperands) {
+        self.sm.exec_instruction(Instruction {
+            operands,
+            delay: 0,
+            side_set: Some(self.write_side_set),
+        });
+    }
+
+    /// Set the D/C line, once the words written so far are on the bus.
+    fn set_dc(&mut self, data: bool) {
+        self.wait_idle();
+        self.exec(InstructionOperands::SET {
+            destination: SetDestination::PINS,
+            data: u8::from(data),
+        });
+    }
+
+    /// Set the direction of the data pins.
+    fn set_data_dirs(&mut self, output: bool) {
+        let op = if output {
+            MovOperation::Invert
+        } else {
+            MovOperation::None
+        };
+        self.exec(InstructionOperands::MOV {
+            destination: MovDestination::OSR,
+            op,
+            source: MovSource::NULL,
+        });
+        self.exec(InstructionOperands::OUT {
+            destination: OutDestination::PINDIRS,
+            bit_count: W::BITS,
+        });
+    }
+
+    /// Write `command` with D/C low, then its `parameters` with D/C high.
+    ///
+    /// D/C stays high afterwards, ready to send data.
+    pub fn write_command(&mut self, command: W, parameters: &[W]) {
+        self.set_dc(false);
+        self.write_words(&[command]);
+        self.set_dc(true);
+        self.write_words(parameters);
+    }
+
+    /// Write data words.
+    pub fn write_data(&mut self, words: &[W]) {
+        self.write_words(words);
+    }
+
+    /// Read data words.
+    pub fn read_data(&mut self, words: &mut [W]) {
+        self.wait_idle();
+        self.set_data_dirs(false);
+        let read_program = self.read_program.take().expect("read program installed");
+        let write_program = self.sm.switch_program(read_program);
+        for word in words {
+            while !self.tx.write(0) {}
+            *word = loop {
+                if let Some(value) = self.rx.read() {
+                    break W::from_word(value);
+                }
+            };
+        }
+        self.wait_idle();
+        self.read_program = Some(self.sm.switch_program(write_program));
+        self.set_data_dirs(true);
+    }
+
+    fn write_words(&mut self, words: &[W]) {
+        for word in words {
+            while !self.tx.write(word.to_word()) {}
+        }
+    }
+}
+
+// Safety: This only writes to the TX fifo, so it doesn't
+// interact with rust-managed memory.
+unsafe impl<P: PIOExt, SM: StateMachineIndex, W: BusWord> WriteTarget for ParallelBus<P, SM, W> {
+    type TransmittedWord = W;
+
+    fn tx_treq() -> Option<u8> {
+        <Tx<(P, SM)> as WriteTarget>::tx_treq()
+    }
+
+    fn tx_address_count(&mut self) -> (u32, u32) {
+        self.tx.tx_address_count()
+    }
+
+    fn tx_increment(&self) -> bool {
+        false
+    }
+}
+
+impl<P: PIOExt, SM: StateMachineIndex, W: BusWord> EndlessWriteTarget for ParallelBus<P, SM, W> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pac;
+    use crate::pio::emulator::Emulator;
+
+    // Data on pins 0 to 7, control pins 8 and 9.
+    const STROBE: u32 = 1 << 8;
+
+    #[test]
+    fn write() {
+        for bus in [BusType::Intel8080, BusType::Motorola6800] {
+            let mut pio = Emulator::<pac::PIO0>::new();
+            let installed = pio.install(&write_program(bus, 8)).unwrap();
+            let mut sm = pio.build(
+                PIOBuilder::from_installed_program(installed)
+                    .out_pins(0, 8)
+                    .side_set_pin_base(8)
+                    .out_shift_direction(ShiftDirection::Right),
+            );
+            sm.set_pindirs(0x3ff);
+            for word in [0x5a, 0xc3, 0x81] {
+                sm.push_tx(word);
+            }
+            // The latching edge is the rising edge of WR or the falling edge of E.
+            let latch_level = match bus {
+                BusType::Intel8080 => STROBE,
+                BusType::Motorola6800 => 0,
+            };
+            let mut latched = [0; 3];
+            let mut count = 0;
+            // The strobe idles at the level it latches to.
+            let mut strobe = latch_level;
+            for _ in 0..16 {
+                sm.step();
+                let levels = sm.pin_levels();
+                if levels & STROBE != strobe && levels & STROBE == latch_level {
+                    latched[count] = levels & 0xff;
+                    count += 1;
+                }
+                strobe = levels & STROBE;
+            }
+            assert_eq!(latched[..count], [0x5a, 0xc3, 0x81]);
+            // R/W or RD stays in its write state.
+            assert_eq!(
+                sm.pin_levels() >> 9 & 1,
+                u32::from(bus == BusType::Intel8080)
+            );
+        }
+    }
+
+    #[test]
+    fn read() {
+        for bus in [BusType::Intel8080, BusType::Motorola6800] {
+            let mut pio = Emulator::<pac::PIO0>::new();
+            let installed = pio.install(&read_program(bus, 16)).unwrap();
+            let mut sm = pio.build(
+                PIOBuilder::from_installed_program(installed)
+                    .in_pin_base(0)
+                    .side_set_pin_base(16)
+                    .in_shift_direction(ShiftDirection::Left),
+            );
+            sm.set_pindirs(0x3 << 16);
+            sm.push_tx(0);
+            let (_, active) = bus.read_side_set();
+            for _ in 0..16 {
+                sm.step();
+                // The panel drives the bus while the strobe is active.
+                let driven = sm.pin_levels() >> 16 & 0b11 == u32::from(active);
+                sm.set_inputs(if driven { 0xbeef } else { 0 });
+            }
+            assert_eq!(sm.pop_rx(), Some(0xbeef));
+        }
+    }
+}
@@ -0,0 +1,410 @@
+//! SPI controller implemented with PIO
+//!
+//! Adds SPI controllers on top of the two hardware ones. [`PioSpi`] supports the four CPOL/CPHA
+//! modes and any word length from 1 to 32 bits, set with the `DS` parameter like the data size of
+//! [`Spi`](crate::spi::Spi). Words are right-aligned: `u8` words are used up to 8 bits, `u16` words
+//! up to 16 bits and `u32` words above.
+//!
+//! [`PioSpi`] implements `embedded_hal::spi::SpiBus` and `embedded_hal_nb::spi::FullDuplex`, and
+//! can be used as a source and target of DMA transfers for long transfers. When only writing
+//! with DMA, received words are dropped once the RX FIFO is full instead of stalling the bus:
+//! call `SpiBus::flush` after such a transfer to wait for its end and discard them.
+//!
+//! The bus runs at 4 state machine cycles per bit, words are separated by 3 cycles with the clock
+//! idle.
+//!
+//! ```no_run
+//! use embedded_hal::spi::{SpiBus, MODE_0};
+//! use fugit::RateExtU32;
+//! use rp2040_hal::{
+//!     dma::{single_buffer, DMAExt},
+//!     gpio::{FunctionPio0, Pins},
+//!     pac,
+//!     pio::{spi::PioSpi, PIOExt},
+//!     Sio,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let sio = Sio::new(pac.SIO);
+//! let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+//! let sck = pins.gpio10.into_function::<FunctionPio0>();
+//! let mosi = pins.gpio11.into_function::<FunctionPio0>();
+//! let miso = pins.gpio12.into_function::<FunctionPio0>();
+//! let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+//! let dma = pac.DMA.split(&mut pac.RESETS);
+//!
+//! // 9-bit words, as used by some LCD controllers.
+//! let mut spi = PioSpi::<_, _, 9>::new(
+//!     &mut pio,
+//!     sm0,
+//!     &sck,
+//!     &mosi,
+//!     Some(&miso),
+//!     MODE_0,
+//!     1.MHz(),
+//!     125.MHz(),
+//! )
+//! .unwrap();
+//! let mut words = [0x12a_u16, 0x055];
+//! spi.transfer_in_place(&mut words).unwrap();
+//!
+//! let buffer = cortex_m::singleton!(: [u16; 64] = [0x100; 64]).unwrap();
+//! let transfer = single_buffer::Config::new(dma.ch0, buffer, spi).start();
+//! let (ch0, buffer, mut spi) = transfer.wait();
+//! spi.flush().unwrap();
+//! ```
+use core::convert::Infallible;
+
+use embedded_hal::spi::{self, Phase, Polarity};
+use embedded_hal_nb::spi::FullDuplex;
+use fugit::HertzU32;
+use pio::{Assembler, InSource, JmpCondition, OutDestination, SetDestination};
+
+use super::{
+    InstallError, PIOBuilder, PIOExt, PinDir, PinState, PioPin, Running, Rx, ShiftDirection,
+    StateMachine, StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+use crate::dma::{EndlessReadTarget, EndlessWriteTarget, ReadTarget, WriteTarget};
+
+/// State machine cycles per bit.
+const CYCLES_PER_BIT: u32 = 4;
+
+/// Number of words the RX FIFO can hold.
+const FIFO_DEPTH: usize = 4;
+
+/// Program shifting `bits` bits per word, MSB first, in the given mode.
+///
+/// `miso` selects whether the input pin is sampled, zeros are shifted in otherwise.
+fn program(
+    bits: u8,
+    mode: spi::Mode,
+    miso: bool,
+) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let idle = u8::from(mode.polarity == Polarity::IdleHigh);
+    let active = idle ^ 1;
+    let input = if miso { InSource::PINS } else { InSource::NULL };
+    let mut a = Assembler::new_with_side_set(pio::SideSet::new(false, 1, false));
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut bit_loop = a.label();
+    a.bind(&mut wrap_target);
+    // Drop the bits above the word length, so that narrower DMA writes (which are replicated
+    // across the FIFO register) and CPU writes both use right-aligned words. This stalls with
+    // the clock idle until the next word is written.
+    if bits < 32 {
+        a.out_with_side_set(OutDestination::NULL, 32 - bits, idle);
+    } else {
+        // Nothing to drop, wait for the word with an explicit `pull` instead, which only stalls
+        // once the OSR is empty with autopull.
+        a.pull_with_side_set(false, true, idle);
+    }
+    a.set_with_side_set(SetDestination::X, bits - 1, idle);
+    a.bind(&mut bit_loop);
+    match mode.phase {
+        Phase::CaptureOnFirstTransition => {
+            a.out_with_delay_and_side_set(OutDestination::PINS, 1, 1, idle);
+            a.in_with_side_set(input, 1, active);
+            a.jmp_with_side_set(JmpCondition::XDecNonZero, &mut bit_loop, active);
+        }
+        Phase::CaptureOnSecondTransition => {
+            a.out_with_delay_and_side_set(OutDestination::PINS, 1, 1, active);
+            a.in_with_side_set(input, 1, idle);
+            a.jmp_with_side_set(JmpCondition::XDecNonZero, &mut bit_loop, idle);
+        }
+    }
+    // Drop the word rather than stalling the bus if the RX FIFO is not read.
+    a.push_with_side_set(false, false, idle);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Clock divisor for `baudrate`, as integer and 1/256th parts.
+///
+/// The divisor is rounded up, so the bus never runs faster than `baudrate`.
+fn clock_divisor(baudrate: HertzU32, system_clock: HertzU32) -> (u16, u8) {
+    let sm_clock = u64::from(baudrate.to_Hz()) * u64::from(CYCLES_PER_BIT);
+    let divisor = (u64::from(system_clock.to_Hz()) * 256).div_ceil(sm_clock);
+    let divisor = divisor.clamp(0x100, 0xff_ffff);
+    ((divisor >> 8) as u16, divisor as u8)
+}
+
+/// SPI controller implemented on a PIO state machine.
+///
+/// `DS` is the number of bits per word, from 1 to 32. Defaults to 8.
+pub struct PioSpi<P: PIOExt, SM: StateMachineIndex, const DS: u8 = 8u8> {
+    sm: StateMachine<(P, SM), Running>,
+    rx: Rx<(P, SM)>,
+    tx: Tx<(P, SM)>,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex, const DS: u8> PioSpi<P, SM, DS> {
+    /// Install the controller program and start it on `sm`.
+    ///
+    /// Without `miso`, words read from the bus are all zeros. `system_clock` is the frequency of
+    /// the system clock, used to derive the baudrate.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        sck: &dyn PioPin<P>,
+        mosi: &dyn PioPin<P>,
+        miso: Option<&dyn PioPin<P>>,
+        mode: spi::Mode,
+        baudrate: HertzU32,
+        system_clock: HertzU32,
+    ) -> Result<Self, InstallError> {
+        assert!((1..=32).contains(&DS), "word length must be 1 to 32 bits");
+        let installed = pio.install(&program(DS, mode, miso.is_some()))?;
+        let (int, frac) = clock_divisor(baudrate, system_clock);
+        let mut builder = PIOBuilder::from_installed_program(installed)
+            .out_pins_checked(&[mosi])
+            .side_set_pins_checked(&[sck])
+            .out_shift_direction(ShiftDirection::Left)
+            .in_shift_direction(ShiftDirection::Left)
+            .autopull(true)
+            .clock_divisor_fixed_point(int, frac);
+        if let Some(miso) = miso {
+            builder = builder.in_pins_checked(&[miso]);
+        }
+        let (mut sm, rx, tx) = builder.build(sm);
+        let idle = match mode.polarity {
+            Polarity::IdleLow => PinState::Low,
+            Polarity::IdleHigh => PinState::High,
+        };
+        sm.set_pins([(sck.pin_num(), idle), (mosi.pin_num(), PinState::Low)]);
+        sm.set_pindirs([
+            (sck.pin_num(), PinDir::Output),
+            (mosi.pin_num(), PinDir::Output),
+        ]);
+
+        Ok(Self {
+            sm: sm.start(),
+            rx,
+            tx,
+        })
+    }
+
+    /// Stop the controller, uninstall its program and return the state machine.
+    pub fn free(self, pio: &mut PIO<P>) -> UninitStateMachine<(P, SM)> {
+        let (sm, program) = self.sm.uninit(self.rx, self.tx);
+        pio.uninstall(program);
+        sm
+    }
+
+    /// Block until all the words written were shifted out.
+    pub fn wait_idle(&mut self) {
+        while !self.tx.is_empty() {}
+        // The flag is set again as soon as the state machine waits for the next word.
+        self.tx.clear_stalled_flag();
+        while !self.tx.has_stalled() {}
+    }
+
+    /// Shift `len` words out, taking them from `write`, and hand the received ones to `read`.
+    ///
+    /// At most [`FIFO_DEPTH`] words are in flight, so none are dropped by the state machine.
+    fn transfer_words(
+        &mut self,
+        len: usize,
+        mut write: impl FnMut(usize) -> u32,
+        mut read: impl FnMut(usize, u32),
+    ) {
+        let (mut sent, mut received) = (0, 0);
+        while received < len {
+            if sent < len && sent - received < FIFO_DEPTH && !self.tx.is_full() {
+                self.tx.write(write(sent));
+                sent += 1;
+            }
+            if let Some(word) = self.rx.read() {
+                read(received, word);
+                received += 1;
+            }
+        }
+    }
+}
+
+macro_rules! impl_spi {
+    ($type:ident, [$($nr:expr),+]) => {
+        $(
+        impl<P: PIOExt, SM: StateMachineIndex> spi::ErrorType for PioSpi<P, SM, $nr> {
+            type Error = Infallible;
+        }
+
+        impl<P: PIOExt, SM: StateMachineIndex> spi::SpiBus<$type> for PioSpi<P, SM, $nr> {
+            fn read(&mut self, words: &mut [$type]) -> Result<(), Self::Error> {
+                self.transfer_words(words.len(), |_| 0, |i, word| words[i] = word as $type);
+                Ok(())
+            }
+
+            fn write(&mut self, words: &[$type]) -> Result<(), Self::Error> {
+                self.transfer_words(words.len(), |i| u32::from(words[i]), |_, _| {});
+                Ok(())
+            }
+
+            fn transfer(&mut self, read: &mut [$type], write: &[$type]) -> Result<(), Self::Error> {
+                // Send empty words past the end of `write`, drop words past the end of `read`.
+                let len = read.len().max(write.len());
+                self.transfer_words(
+                    len,
+                    |i| write.get(i).copied().map_or(0, u32::from),
+                    |i, word| {
+                        if let Some(r) = read.get_mut(i) {
+                            *r = word as $type;
+                        }
+                    },
+                );
+                Ok(())
+            }
+
+            fn transfer_in_place(&mut self, words: &mut [$type]) -> Result<(), Self::Error> {
+                // Words are only overwritten once they were sent.
+                let words = core::cell::Cell::from_mut(words).as_slice_of_cells();
+                self.transfer_words(
+                    words.len(),
+                    |i| u32::from(words[i].get()),
+                    |i, word| words[i].set(word as $type),
+                );
+                Ok(())
+            }
+
+            fn flush(&mut self) -> Result<(), Self::Error> {
+                self.wait_idle();
+                // Drop the words left by writes that did not read the bus, such as DMA writes.
+                while self.rx.read().is_some() {}
+                Ok(())
+            }
+        }
+
+        impl<P: PIOExt, SM: StateMachineIndex> FullDuplex<$type> for PioSpi<P, SM, $nr> {
+            fn read(&mut self) -> Result<$type, nb::Error<Infallible>> {
+                match self.rx.read() {
+                    Some(word) => Ok(word as $type),
+                    None => Err(nb::Error::WouldBlock),
+                }
+            }
+
+            fn write(&mut self, word: $type) -> Result<(), nb::Error<Infallible>> {
+                // Words received while the RX FIFO is full are dropped.
+                if !self.tx.write(u32::from(word)) {
+                    return Err(nb::Error::WouldBlock);
+                }
+                Ok(())
+            }
+        }
+
+        // Safety: This only reads from the RX fifo, so it doesn't
+        // interact with rust-managed memory.
+        unsafe impl<P: PIOExt, SM: StateMachineIndex> ReadTarget for PioSpi<P, SM, $nr> {
+            type ReceivedWord = $type;
+
+            fn rx_treq() -> Option<u8> {
+                <Rx<(P, SM)> as ReadTarget>::rx_treq()
+            }
+
+            fn rx_address_count(&self) -> (u32, u32) {
+                self.rx.rx_address_count()
+            }
+
+            fn rx_increment(&self) -> bool {
+                false
+            }
+        }
+
+        impl<P: PIOExt, SM: StateMachineIndex> EndlessReadTarget for PioSpi<P, SM, $nr> {}
+
+        // Safety: This only writes to the TX fifo, so it doesn't
+        // interact with rust-managed memory.
+        unsafe impl<P: PIOExt, SM: StateMachineIndex> WriteTarget for PioSpi<P, SM, $nr> {
+            type TransmittedWord = $type;
+
+            fn tx_treq() -> Option<u8> {
+                <Tx<(P, SM)> as WriteTarget>::tx_treq()
+            }
+
+            fn tx_address_count(&mut self) -> (u32, u32) {
+                self.tx.tx_address_count()
+            }
+
+            fn tx_increment(&self) -> bool {
+                false
+            }
+        }
+
+        impl<P: PIOExt, SM: StateMachineIndex> EndlessWriteTarget for PioSpi<P, SM, $nr> {}
+        )+
+    };
+}
+
+impl_spi!(u8, [1, 2, 3, 4, 5, 6, 7, 8]);
+impl_spi!(u16, [9, 10, 11, 12, 13, 14, 15, 16]);
+impl_spi!(
+    u32,
+    [17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32]
+);
+
+#[cfg(test)]
+mod tests {
+    use fugit::RateExtU32;
+
+    use super::*;
+    use crate::pac;
+    use crate::pio::emulator::Emulator;
+
+    /// Run one word of `bits` bits through a loopback, returning the word received and the MOSI
+    /// bits seen on the capture edges of the clock.
+    fn loopback(bits: u8, mode: spi::Mode, word: u32) -> (u32, u32) {
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let installed = pio.install(&program(bits, mode, true)).unwrap();
+        // MOSI on pin 0, SCK on pin 1, MISO on pin 2.
+        let mut sm = pio.build(
+            PIOBuilder::from_installed_program(installed)
+                .out_pins(0, 1)
+                .side_set_pin_base(1)
+                .in_pin_base(2)
+                .out_shift_direction(ShiftDirection::Left)
+                .in_shift_direction(ShiftDirection::Left)
+                .autopull(true),
+        );
+        sm.set_pindirs(0b11);
+        // Write a word with garbage above the word length, which must be ignored.
+        sm.push_tx(u32::MAX.checked_shl(u32::from(bits)).unwrap_or(0) | word);
+        let idle = mode.polarity == Polarity::IdleHigh;
+        let mut sck = idle;
+        let mut edges = 0;
+        let mut captured = 0;
+        // Run past the end of the word, while the state machine waits for the next one.
+        for _ in 0..16 + 8 * u32::from(bits) {
+            sm.step();
+            let levels = sm.pin_levels();
+            sm.set_input(2, levels & 1 != 0);
+            let level = levels & 2 != 0;
+            if level != sck {
+                sck = level;
+                edges += 1;
+                // Odd edges are the leading ones.
+                let leading = edges % 2 == 1;
+                if leading == (mode.phase == Phase::CaptureOnFirstTransition) {
+                    captured = captured << 1 | (levels & 1);
+                }
+            }
+        }
+        assert_eq!(edges, 2 * bits);
+        assert_eq!(sck, idle);
+        (sm.pop_rx().unwrap(), captured)
+    }
+
+    #[test]
+    fn modes() {
+        for mode in [spi::MODE_0, spi::MODE_1, spi::MODE_2, spi::MODE_3] {
+            assert_eq!(loopback(9, mode, 0x12a), (0x12a, 0x12a));
+            assert_eq!(loopback(9, mode, 0x0d5), (0x0d5, 0x0d5));
+            assert_eq!(loopback(32, mode, 0x8000_00a5), (0x8000_00a5, 0x8000_00a5));
+            assert_eq!(loopback(32, mode, 0x5a00_0001), (0x5a00_0001, 0x5a00_0001));
+        }
+    }
+
+    #[test]
+    fn divisor() {
+        assert_eq!(clock_divisor(1.MHz(), 125.MHz()), (31, 64));
+        assert_eq!(clock_divisor(3.MHz(), 125.MHz()), (10, 107));
+        assert_eq!(clock_divisor(50.MHz(), 125.MHz()), (1, 0));
+    }
+}
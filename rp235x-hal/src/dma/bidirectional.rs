@@ -3,8 +3,9 @@
 use core::sync::atomic::{compiler_fence, Ordering};
 
 use super::{
+    completion::{Completion, WaitAsync},
     single_channel::{ChannelConfig, SingleChannel},
-    Pace, ReadTarget, WriteTarget,
+    DMAIrq, Pace, ReadTarget, TransferCountMode, WriteTarget,
 };
 
 /// DMA configuration for sending and receiving data simultaneously
@@ -81,6 +82,7 @@ where
             self.from_pace,
             self.bswap,
             None,
+            TransferCountMode::Normal,
             false,
         );
         self.ch.1.config(
@@ -89,6 +91,7 @@ where
             self.to_pace,
             self.bswap,
             None,
+            TransferCountMode::Normal,
             false,
         );
         self.ch.0.start_both(&mut self.ch.1);
@@ -139,6 +142,20 @@ where
         a | b
     }
 
+    /// Check if an interrupt is pending for either channel and clear the corresponding pending bit
+    pub fn check_irq2(&mut self) -> bool {
+        let a = self.ch.0.check_irq2();
+        let b = self.ch.1.check_irq2();
+        a | b
+    }
+
+    /// Check if an interrupt is pending for either channel and clear the corresponding pending bit
+    pub fn check_irq3(&mut self) -> bool {
+        let a = self.ch.0.check_irq3();
+        let b = self.ch.1.check_irq3();
+        a | b
+    }
+
     /// Check if the transfer is completed
     pub fn is_done(&self) -> bool {
         let a = self.ch.1.ch().ch_ctrl_trig().read().busy().bit_is_set();
@@ -157,4 +174,14 @@ where
         // TODO: Use a tuple type?
         ((self.ch.0, self.ch.1), self.from, self.bidi, self.to)
     }
+
+    /// Wait for the transfer to complete
+    ///
+    /// The future is woken by the interrupt `irq`, whose handler must call
+    /// [`dma::on_interrupt`](super::on_interrupt). The transfer is aborted if the future is dropped
+    /// before completion.
+    pub fn wait_async(self, irq: DMAIrq) -> WaitAsync<Self, ((CH1, CH2), FROM, BIDI, TO)> {
+        let completion = Completion::new(1 << self.ch.0.id() | 1 << self.ch.1.id(), irq);
+        WaitAsync::new(self, completion, Self::is_done, Self::wait)
+    }
 }
@@ -0,0 +1,222 @@
+//! Completion of DMA transfers as futures
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{
+    async_utils::sealed::IrqWaker,
+    atomic_register_access::{write_bitmask_clear, write_bitmask_set},
+    pac::DMA,
+};
+
+/// Number of DMA channels.
+const CHANNELS: usize = 16;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const WAKER_INIT: IrqWaker = IrqWaker::new();
+static WAKERS: [IrqWaker; CHANNELS] = [WAKER_INIT; CHANNELS];
+
+/// DMA interrupt line waking the async transfers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DMAIrq {
+    /// `DMA_IRQ_0`
+    Irq0,
+    /// `DMA_IRQ_1`
+    Irq1,
+    /// `DMA_IRQ_2`
+    Irq2,
+    /// `DMA_IRQ_3`
+    Irq3,
+}
+
+impl DMAIrq {
+    fn inte(self) -> *mut u32 {
+        // Safety: Only used for atomic accesses to the channels' bits.
+        let dma = unsafe { &*DMA::ptr() };
+        match self {
+            DMAIrq::Irq0 => dma.inte0().as_ptr(),
+            DMAIrq::Irq1 => dma.inte1().as_ptr(),
+            DMAIrq::Irq2 => dma.inte2().as_ptr(),
+            DMAIrq::Irq3 => dma.inte3().as_ptr(),
+        }
+    }
+
+    /// Pending interrupts of the channels.
+    fn pending(self) -> u32 {
+        // Safety: Read only access without side effect.
+        let dma = unsafe { &*DMA::ptr() };
+        match self {
+            DMAIrq::Irq0 => dma.ints0().read().bits(),
+            DMAIrq::Irq1 => dma.ints1().read().bits(),
+            DMAIrq::Irq2 => dma.ints2().read().bits(),
+            DMAIrq::Irq3 => dma.ints3().read().bits(),
+        }
+    }
+}
+
+/// Clear the raw interrupts of the channels of the mask `channels`, on all the lines.
+fn clear_interrupts(channels: u32) {
+    // Safety: Writing 1 only clears the bits of the channels given.
+    let dma = unsafe { &*DMA::ptr() };
+    dma.intr().write(|w| unsafe { w.bits(channels) });
+}
+
+/// Wakes the async DMA transfers waiting for an interrupt of the channels that fired.
+///
+/// This must be called from the `DMA_IRQ_0` to `DMA_IRQ_3` interrupt handlers, with the
+/// corresponding `irq`. The channels that fired are masked, they are unmasked again by the
+/// futures when needed. Channels whose interrupts are handled synchronously, with
+/// [`SingleChannel::check_irq0`](super::SingleChannel::check_irq0), should use another line.
+pub fn on_interrupt(irq: DMAIrq) {
+    let ints = irq.pending() & ((1 << CHANNELS) - 1);
+    // Safety: Atomic write, only clears the bits which are set in `ints`.
+    unsafe { write_bitmask_clear(irq.inte(), ints) };
+    for (ch, waker) in WAKERS.iter().enumerate() {
+        if ints & (1 << ch) != 0 {
+            waker.wake();
+        }
+    }
+}
+
+/// Completion of a transfer, woken by the interrupts of the channels of the mask `channels`.
+///
+/// If dropped before, the channels of the mask `abort` are aborted, so that the buffers of the
+/// transfer can be released.
+pub(crate) struct Completion {
+    channels: u16,
+    abort: u16,
+    abort_first: u16,
+    irq: DMAIrq,
+    done: bool,
+}
+
+impl Completion {
+    pub(crate) fn new(channels: u16, irq: DMAIrq) -> Self {
+        Self {
+            channels,
+            abort: channels,
+            abort_first: 0,
+            irq,
+            done: false,
+        }
+    }
+
+    /// Also abort the channels of `abort` if dropped before completion.
+    pub(crate) fn aborting(mut self, abort: u16) -> Self {
+        self.abort |= abort;
+        self
+    }
+
+    /// Abort the channels of `first` before the others if dropped before completion, so that
+    /// they do not restart them.
+    pub(crate) fn aborting_first(mut self, first: u16) -> Self {
+        self.abort |= first;
+        self.abort_first = first;
+        self
+    }
+
+    fn channels(mask: u16) -> impl Iterator<Item = usize> {
+        (0..CHANNELS).filter(move |ch| mask & (1 << ch) != 0)
+    }
+
+    /// Mask the interrupts of the channels and clear their pending interrupts.
+    fn disable_interrupts(&self, channels: u16) {
+        // Safety: Atomic write to the bits of the channels of the transfer.
+        unsafe { write_bitmask_clear(self.irq.inte(), u32::from(channels)) };
+        clear_interrupts(u32::from(channels));
+    }
+
+    /// Check with `is_done` if the transfer completed, or wait for the next interrupt.
+    fn poll(&mut self, cx: &mut Context<'_>, is_done: impl FnOnce() -> bool) -> Poll<()> {
+        // Clear the interrupts before checking the transfer: one raised by its completion in the
+        // meantime stays pending, and fires once unmasked.
+        clear_interrupts(u32::from(self.channels));
+        if is_done() {
+            self.done = true;
+            self.disable_interrupts(self.channels);
+            return Poll::Ready(());
+        }
+        for ch in Self::channels(self.channels) {
+            WAKERS[ch].register(cx.waker());
+        }
+        // Safety: Atomic write to the bits of the channels of the transfer.
+        unsafe { write_bitmask_set(self.irq.inte(), u32::from(self.channels)) };
+        Poll::Pending
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        for ch in Self::channels(self.channels) {
+            WAKERS[ch].clear();
+        }
+        let chan_abort = unsafe { &*DMA::ptr() }.chan_abort();
+        for abort in [self.abort_first, self.abort] {
+            if abort != 0 {
+                chan_abort.write(|w| unsafe { w.chan_abort().bits(abort) });
+                while chan_abort.read().bits() != 0 {}
+            }
+        }
+        // Safety: Read only access without side effect.
+        let dma = unsafe { &*DMA::ptr() };
+        for ch in Self::channels(self.abort) {
+            while dma.ch(ch).ch_ctrl_trig().read().busy().bit_is_set() {}
+        }
+        // Aborting may raise the completion interrupts, clear them.
+        self.disable_interrupts(self.abort);
+    }
+}
+
+/// Future returned by the `wait_async` methods of the transfers.
+///
+/// It completes with the channels and buffers of the transfer once it is done. The transfer is
+/// aborted if the future is dropped before, even if it was never polled.
+#[must_use = "Future do nothing unless they are polled on."]
+pub struct WaitAsync<T, O> {
+    // Dropped first, so that the transfer is aborted before its buffers are released.
+    completion: Completion,
+    transfer: Option<T>,
+    is_done: fn(&T) -> bool,
+    finish: fn(T) -> O,
+}
+
+impl<T, O> WaitAsync<T, O> {
+    /// Wait for `transfer` with `completion` until `is_done`, then return the result of `finish`.
+    pub(crate) fn new(
+        transfer: T,
+        completion: Completion,
+        is_done: fn(&T) -> bool,
+        finish: fn(T) -> O,
+    ) -> Self {
+        Self {
+            completion,
+            transfer: Some(transfer),
+            is_done,
+            finish,
+        }
+    }
+}
+
+// The transfer is never pinned.
+impl<T, O> Unpin for WaitAsync<T, O> {}
+
+impl<T, O> Future for WaitAsync<T, O> {
+    type Output = O;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<O> {
+        let this = self.get_mut();
+        let transfer = this.transfer.as_ref().expect("polled after completion");
+        let is_done = this.is_done;
+        match this.completion.poll(cx, || is_done(transfer)) {
+            Poll::Ready(()) => Poll::Ready((this.finish)(this.transfer.take().unwrap())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
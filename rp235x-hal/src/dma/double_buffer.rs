@@ -3,8 +3,13 @@
 use core::sync::atomic::{compiler_fence, Ordering};
 
 use super::{
-    single_channel::ChannelConfig, single_channel::SingleChannel, EndlessReadTarget,
-    EndlessWriteTarget, Pace, ReadTarget, WriteTarget,
+    completion::{Completion, WaitAsync},
+    single_channel::ChannelConfig,
+    single_channel::SingleChannel,
+    sniffer,
+    sniffer::Sniffer,
+    DMAIrq, EndlessReadTarget, EndlessWriteTarget, Pace, ReadTarget, TransferCountMode,
+    WriteTarget,
 };
 
 /// Configuration for double-buffered DMA transfer
@@ -14,6 +19,7 @@ pub struct Config<CH1: SingleChannel, CH2: SingleChannel, FROM: ReadTarget, TO:
     to: TO,
     bswap: bool,
     pace: Pace,
+    sniffer: Option<Sniffer>,
 }
 
 impl<CH1, CH2, FROM, TO, WORD> Config<CH1, CH2, FROM, TO>
@@ -31,6 +37,7 @@ where
             to,
             bswap: false,
             pace: Pace::PreferSource,
+            sniffer: None,
         }
     }
 
@@ -54,6 +61,16 @@ where
         self.bswap = bswap;
     }
 
+    /// Calculate a checksum over the transferred data with the DMA sniffer.
+    ///
+    /// The sniffer observes a single channel: only the buffers transferred by the first channel
+    /// of the pair, the first buffer and every other buffer after it, are included. The sniffer
+    /// is shared by all channels, only the last transfer started with a sniffer configuration is
+    /// observed.
+    pub fn sniff(&mut self, sniffer: Sniffer) {
+        self.sniffer = Some(sniffer);
+    }
+
     /// Start the DMA transfer
     pub fn start(mut self) -> Transfer<CH1, CH2, FROM, TO, ()> {
         // TODO: Do we want to call any callbacks to configure source/sink?
@@ -64,9 +81,19 @@ where
         compiler_fence(Ordering::SeqCst);
 
         // Configure the DMA channel and start it.
-        self.ch
-            .0
-            .config(&self.from, &mut self.to, self.pace, self.bswap, None, true);
+        self.ch.0.config(
+            &self.from,
+            &mut self.to,
+            self.pace,
+            self.bswap,
+            None,
+            TransferCountMode::Normal,
+            false,
+        );
+        if let Some(sniffer) = &self.sniffer {
+            sniffer.attach(&self.ch.0);
+        }
+        self.ch.0.start();
 
         Transfer {
             ch: self.ch,
@@ -76,6 +103,7 @@ where
             bswap: self.bswap,
             state: (),
             second_ch: false,
+            sniff: self.sniffer.is_some(),
         }
     }
 }
@@ -100,6 +128,7 @@ where
     bswap: bool,
     state: STATE,
     second_ch: bool,
+    sniff: bool,
 }
 
 impl<CH1, CH2, FROM, TO, WORD, STATE> Transfer<CH1, CH2, FROM, TO, STATE>
@@ -127,6 +156,31 @@ where
         }
     }
 
+    /// Check if an interrupt is pending for the active channel and clear the corresponding pending bit
+    pub fn check_irq2(&mut self) -> bool {
+        if self.second_ch {
+            self.ch.1.check_irq2()
+        } else {
+            self.ch.0.check_irq2()
+        }
+    }
+
+    /// Check if an interrupt is pending for the active channel and clear the corresponding pending bit
+    pub fn check_irq3(&mut self) -> bool {
+        if self.second_ch {
+            self.ch.1.check_irq3()
+        } else {
+            self.ch.0.check_irq3()
+        }
+    }
+
+    /// Result of the DMA sniffer, the checksum of the data transferred by the first channel.
+    ///
+    /// See [`Config::sniff`].
+    pub fn sniff_result(&self) -> u32 {
+        sniffer::result()
+    }
+
     /// Check if the transfer is completed
     pub fn is_done(&self) -> bool {
         if self.second_ch {
@@ -135,6 +189,15 @@ where
             !self.ch.0.ch().ch_ctrl_trig().read().busy().bit_is_set()
         }
     }
+
+    /// Id of the channel performing the current transfer.
+    fn active_id(&self) -> u8 {
+        if self.second_ch {
+            self.ch.1.id()
+        } else {
+            self.ch.0.id()
+        }
+    }
 }
 
 impl<CH1, CH2, FROM, TO, WORD> Transfer<CH1, CH2, FROM, TO, ()>
@@ -142,7 +205,7 @@ where
     CH1: SingleChannel,
     CH2: SingleChannel,
     FROM: ReadTarget<ReceivedWord = WORD>,
-    TO: WriteTarget<TransmittedWord = WORD> + EndlessWriteTarget,
+    TO: WriteTarget<TransmittedWord = WORD>,
 {
     /// Block until transfer completed
     pub fn wait(self) -> (CH1, CH2, FROM, TO) {
@@ -155,6 +218,16 @@ where
         // TODO: Use a tuple type?
         (self.ch.0, self.ch.1, self.from, self.to)
     }
+
+    /// Wait for the transfer to complete
+    ///
+    /// The future is woken by the interrupt `irq`, whose handler must call
+    /// [`dma::on_interrupt`](super::on_interrupt). The transfer is aborted if the future is dropped
+    /// before completion.
+    pub fn wait_async(self, irq: DMAIrq) -> WaitAsync<Self, (CH1, CH2, FROM, TO)> {
+        let completion = Completion::new(1 << self.active_id(), irq);
+        WaitAsync::new(self, completion, Self::is_done, Self::wait)
+    }
 }
 
 impl<CH1, CH2, FROM, TO, WORD> Transfer<CH1, CH2, FROM, TO, ()>
@@ -176,13 +249,28 @@ where
 
         // Configure the _other_ DMA channel, but do not start it yet.
         if self.second_ch {
-            self.ch
-                .0
-                .config(&buf, &mut self.to, self.pace, self.bswap, None, false);
+            self.ch.0.config(
+                &buf,
+                &mut self.to,
+                self.pace,
+                self.bswap,
+                None,
+                TransferCountMode::Normal,
+                false,
+            );
+            if self.sniff {
+                sniffer::enable_channel(&self.ch.0);
+            }
         } else {
-            self.ch
-                .1
-                .config(&buf, &mut self.to, self.pace, self.bswap, None, false);
+            self.ch.1.config(
+                &buf,
+                &mut self.to,
+                self.pace,
+                self.bswap,
+                None,
+                TransferCountMode::Normal,
+                false,
+            );
         }
 
         // Chain the first channel to the second.
@@ -200,6 +288,7 @@ where
             bswap: self.bswap,
             state: ReadNext(buf),
             second_ch: self.second_ch,
+            sniff: self.sniff,
         }
     }
 }
@@ -223,13 +312,28 @@ where
 
         // Configure the _other_ DMA channel, but do not start it yet.
         if self.second_ch {
-            self.ch
-                .0
-                .config(&self.from, &mut buf, self.pace, self.bswap, None, false);
+            self.ch.0.config(
+                &self.from,
+                &mut buf,
+                self.pace,
+                self.bswap,
+                None,
+                TransferCountMode::Normal,
+                false,
+            );
+            if self.sniff {
+                sniffer::enable_channel(&self.ch.0);
+            }
         } else {
-            self.ch
-                .1
-                .config(&self.from, &mut buf, self.pace, self.bswap, None, false);
+            self.ch.1.config(
+                &self.from,
+                &mut buf,
+                self.pace,
+                self.bswap,
+                None,
+                TransferCountMode::Normal,
+                false,
+            );
         }
 
         // Chain the first channel to the second.
@@ -247,6 +351,7 @@ where
             bswap: self.bswap,
             state: WriteNext(buf),
             second_ch: self.second_ch,
+            sniff: self.sniff,
         }
     }
 }
@@ -278,9 +383,27 @@ where
                 bswap: self.bswap,
                 state: (),
                 second_ch: !self.second_ch,
+                sniff: self.sniff,
             },
         )
     }
+
+    /// Wait for the transfer to complete
+    ///
+    /// The future is woken by the interrupt `irq`, whose handler must call
+    /// [`dma::on_interrupt`](super::on_interrupt). The transfer is aborted if the future is dropped
+    /// before completion.
+    ///
+    /// The next transfer, which starts once this one completes, is also aborted.
+    #[allow(clippy::type_complexity)]
+    pub fn wait_async(
+        self,
+        irq: DMAIrq,
+    ) -> WaitAsync<Self, (FROM, Transfer<CH1, CH2, NEXT, TO, ()>)> {
+        let both = 1 << self.ch.0.id() | 1 << self.ch.1.id();
+        let completion = Completion::new(1 << self.active_id(), irq).aborting(both);
+        WaitAsync::new(self, completion, Self::is_done, Self::wait)
+    }
 }
 
 impl<CH1, CH2, FROM, TO, NEXT, WORD> Transfer<CH1, CH2, FROM, TO, WriteNext<NEXT>>
@@ -310,7 +433,25 @@ where
                 bswap: self.bswap,
                 state: (),
                 second_ch: !self.second_ch,
+                sniff: self.sniff,
             },
         )
     }
+
+    /// Wait for the transfer to complete
+    ///
+    /// The future is woken by the interrupt `irq`, whose handler must call
+    /// [`dma::on_interrupt`](super::on_interrupt). The transfer is aborted if the future is dropped
+    /// before completion.
+    ///
+    /// The next transfer, which starts once this one completes, is also aborted.
+    #[allow(clippy::type_complexity)]
+    pub fn wait_async(
+        self,
+        irq: DMAIrq,
+    ) -> WaitAsync<Self, (TO, Transfer<CH1, CH2, FROM, NEXT, ()>)> {
+        let both = 1 << self.ch.0.id() | 1 << self.ch.1.id();
+        let completion = Completion::new(1 << self.active_id(), irq).aborting(both);
+        WaitAsync::new(self, completion, Self::is_done, Self::wait)
+    }
 }
@@ -0,0 +1,203 @@
+//! Runtime pool of DMA channels
+//!
+//! Channels given to the pool with [`give`] can be claimed by any code, on either core, with
+//! [`claim`], as a [`DynChannel`] which returns to the pool when dropped. Drivers can then borrow
+//! a channel when needed, without having it threaded through by the application. The channels
+//! which are not given to the pool keep their static ownership.
+//!
+//! ```no_run
+//! use rp235x_hal::{
+//!     dma::{self, pool, DMAExt},
+//!     pac,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let dma = pac.DMA.split(&mut pac.RESETS);
+//! pool::give(dma.ch10);
+//! pool::give(dma.ch11);
+//!
+//! let mut buffer = [0u32; 256];
+//! pool::memset(&mut buffer, 0xdead_beef);
+//! let ch = dma::claim().unwrap();
+//! // Use the channel, which returns to the pool once dropped.
+//! drop(ch);
+//! ```
+
+use core::{
+    cell::Cell,
+    mem,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+use critical_section::Mutex;
+
+use super::{
+    single_channel::{ChannelConfig, SingleChannel},
+    Channel, ChannelIndex,
+};
+use crate::typelevel::Sealed;
+
+/// Mask of the channels available in the pool.
+static FREE: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+
+/// Element of the buffers handled by [`memcpy`] and [`memset`], `u8`, `u16` or `u32`.
+pub trait MemWord: Sealed + Copy {}
+
+impl MemWord for u8 {}
+impl MemWord for u16 {}
+impl MemWord for u32 {}
+impl Sealed for u32 {}
+
+/// DMA channel claimed from the pool, identified at runtime.
+///
+/// The channel returns to the pool when dropped.
+pub struct DynChannel {
+    id: u8,
+}
+
+impl SingleChannel for DynChannel {
+    fn ch(&self) -> &crate::pac::dma::CH {
+        // Safety: The channel was claimed from the pool, nothing else accesses its registers.
+        unsafe { &*crate::pac::DMA::ptr() }.ch(usize::from(self.id))
+    }
+
+    fn id(&self) -> u8 {
+        self.id
+    }
+}
+
+impl Sealed for DynChannel {}
+
+impl Drop for DynChannel {
+    fn drop(&mut self) {
+        // The next owner does not expect the interrupts of the channel to be enabled.
+        self.disable_irq0();
+        self.disable_irq1();
+        self.disable_irq2();
+        self.disable_irq3();
+        // Nor the channel to still be running, if its last transfer was forgotten.
+        // Safety: Writing 1 only aborts this channel, the other bits are ignored.
+        let chan_abort = unsafe { &*crate::pac::DMA::ptr() }.chan_abort();
+        chan_abort.write(|w| unsafe { w.chan_abort().bits(1 << self.id) });
+        while chan_abort.read().bits() != 0 {}
+        while self.ch().ch_ctrl_trig().read().busy().bit_is_set() {}
+
+        critical_section::with(|cs| release(FREE.borrow(cs), self.id));
+    }
+}
+
+/// Give the channel `ch` to the pool, for good.
+pub fn give<CH: ChannelIndex>(_ch: Channel<CH>) {
+    // The channel is now only tracked by the pool.
+    critical_section::with(|cs| release(FREE.borrow(cs), CH::id()));
+}
+
+/// Claim a channel from the pool, if any is available.
+pub fn claim() -> Option<DynChannel> {
+    critical_section::with(|cs| take(FREE.borrow(cs))).map(|id| DynChannel { id })
+}
+
+/// Mark the channel `id` as available in `free`.
+fn release(free: &Cell<u16>, id: u8) {
+    free.set(free.get() | 1 << id);
+}
+
+/// Remove the available channel with the lowest index from `free`.
+fn take(free: &Cell<u16>) -> Option<u8> {
+    let mask = free.get();
+    if mask == 0 {
+        return None;
+    }
+    let id = mask.trailing_zeros() as u8;
+    free.set(mask & !(1 << id));
+    Some(id)
+}
+
+/// Copy `src` into `dst` with a channel claimed from the pool, blocking until done.
+///
+/// The copy is done by the CPU if no channel is available.
+///
+/// # Panics
+///
+/// Panics if `src` and `dst` have different lengths.
+pub fn memcpy<WORD: MemWord>(dst: &mut [WORD], src: &[WORD]) {
+    assert_eq!(dst.len(), src.len());
+    match claim() {
+        Some(mut ch) => transfer(&mut ch, src.as_ptr(), true, dst),
+        None => dst.copy_from_slice(src),
+    }
+}
+
+/// Fill `dst` with `value` with a channel claimed from the pool, blocking until done.
+///
+/// The buffer is filled by the CPU if no channel is available.
+pub fn memset<WORD: MemWord>(dst: &mut [WORD], value: WORD) {
+    match claim() {
+        Some(mut ch) => transfer(&mut ch, &value, false, dst),
+        None => dst.fill(value),
+    }
+}
+
+/// Transfer from `src`, incremented or not, to `dst` on `ch`, blocking until done.
+fn transfer<WORD: MemWord>(
+    ch: &mut DynChannel,
+    src: *const WORD,
+    incr_read: bool,
+    dst: &mut [WORD],
+) {
+    if dst.is_empty() {
+        return;
+    }
+
+    // Make sure that memory contents reflect what the user intended.
+    crate::arch::dsb();
+    compiler_fence(Ordering::SeqCst);
+
+    // Safety: The channel is owned, and `src` and `dst` outlive the transfer, which is waited
+    // for.
+    ch.ch()
+        .ch_read_addr()
+        .write(|w| unsafe { w.bits(src as u32) });
+    ch.ch()
+        .ch_write_addr()
+        .write(|w| unsafe { w.bits(dst.as_mut_ptr() as u32) });
+    ch.ch().ch_trans_count().write(|w| unsafe {
+        w.count().bits(dst.len() as u32);
+        w.mode().normal();
+        w
+    });
+    ch.ch().ch_al1_ctrl().write(|w| unsafe {
+        w.data_size().bits(mem::size_of::<WORD>() as u8 >> 1);
+        w.incr_read().bit(incr_read);
+        w.incr_write().bit(true);
+        w.treq_sel().bits(0x3f);
+        w.chain_to().bits(ch.id());
+        w.en().bit(true);
+        w
+    });
+    ch.start();
+    while ch.ch().ch_ctrl_trig().read().busy().bit_is_set() {}
+
+    // Make sure that memory contents reflect what the user intended.
+    crate::arch::dsb();
+    compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bookkeeping() {
+        let free = Cell::new(0);
+        assert_eq!(take(&free), None);
+        release(&free, 11);
+        release(&free, 4);
+        release(&free, 7);
+        assert_eq!(take(&free), Some(4));
+        assert_eq!(take(&free), Some(7));
+        release(&free, 4);
+        assert_eq!(take(&free), Some(4));
+        assert_eq!(take(&free), Some(11));
+        assert_eq!(take(&free), None);
+    }
+}
@@ -0,0 +1,346 @@
+//! Scatter-gather transfers reprogrammed from a list of control blocks
+//!
+//! A list of [`ControlBlock`]s in RAM describes the transfers of a data channel, one block each.
+//! A control channel writes each block to the last alias of the registers of the data channel,
+//! which starts the data channel. Once the data channel is done, it is chained to the control
+//! channel, which loads the next block. The list is terminated by [`ControlBlock::END`], whose
+//! null trigger stops the chain and raises the interrupt of the data channel.
+//!
+//! A packet made of a header, a payload and a CRC from separate buffers can then be sent without
+//! CPU intervention:
+//!
+//! ```no_run
+//! use rp235x_hal::{
+//!     dma::{
+//!         scatter_gather::{Config, ControlBlock},
+//!         Byte, DMAExt, Pace,
+//!     },
+//!     pac,
+//!     pio::{PIOBuilder, PIOExt},
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let dma = pac.DMA.split(&mut pac.RESETS);
+//! let (mut pio, sm0, ..) = pac.PIO0.split(&mut pac.RESETS);
+//! let program = pio_proc::pio_asm!("out pins, 8").program;
+//! let installed = pio.install(&program).unwrap();
+//! let (_sm, _rx, tx) = PIOBuilder::from_installed_program(installed).build(sm0);
+//! let mut tx = tx.transfer_size(Byte);
+//!
+//! static HEADER: [u8; 4] = [0xaa, 0x55, 0, 64];
+//! static PAYLOAD: [u8; 64] = [0; 64];
+//! static CRC: [u8; 4] = [0; 4];
+//! static mut BLOCKS: [ControlBlock; 4] = [ControlBlock::END; 4];
+//! let blocks = unsafe { &mut *core::ptr::addr_of_mut!(BLOCKS) };
+//! // Safety: The buffers are static, and `tx` is not used until the transfer is done.
+//! unsafe {
+//!     blocks[0] = ControlBlock::new(&&HEADER, &mut tx, Pace::PreferSink);
+//!     blocks[1] = ControlBlock::new(&&PAYLOAD, &mut tx, Pace::PreferSink);
+//!     blocks[2] = ControlBlock::new(&&CRC, &mut tx, Pace::PreferSink);
+//! }
+//! let transfer = Config::new(dma.ch0, dma.ch1, blocks).unwrap().start();
+//! let (_ch0, _ch1, _blocks) = transfer.wait();
+//! ```
+
+use core::{
+    mem,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+use super::{
+    completion::{Completion, WaitAsync},
+    single_channel::{ChannelConfig, IsValidWordSize, SingleChannel},
+    DMAError, DMAIrq, Pace, ReadTarget, WriteTarget,
+};
+
+/// Bit offsets of the fields of the channel control register.
+const CTRL_EN: u32 = 0;
+const CTRL_DATA_SIZE: u32 = 2;
+const CTRL_INCR_READ: u32 = 4;
+const CTRL_INCR_WRITE: u32 = 6;
+const CTRL_CHAIN_TO: u32 = 13;
+const CTRL_TREQ_SEL: u32 = 17;
+const CTRL_IRQ_QUIET: u32 = 23;
+const CTRL_BSWAP: u32 = 24;
+const CTRL_CHAIN_TO_MASK: u32 = 0xf << CTRL_CHAIN_TO;
+
+/// Transfer of the data channel, written to its `AL3` register alias by the control channel.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ControlBlock {
+    ctrl: u32,
+    write_addr: u32,
+    trans_count: u32,
+    read_addr: u32,
+}
+
+impl ControlBlock {
+    /// Block terminating the list, stopping the chain.
+    pub const END: Self = Self {
+        ctrl: 1 << CTRL_IRQ_QUIET,
+        write_addr: 0,
+        trans_count: 0,
+        read_addr: 0,
+    };
+
+    /// Create a block transferring from `from` to `to`, with the pace `pace`.
+    ///
+    /// # Safety
+    ///
+    /// `from` and `to` must stay valid, and must not be accessed, until the transfers of all the
+    /// lists containing this block are done.
+    pub unsafe fn new<WORD, FROM, TO>(from: &FROM, to: &mut TO, pace: Pace) -> Self
+    where
+        FROM: ReadTarget<ReceivedWord = WORD>,
+        TO: WriteTarget<TransmittedWord = WORD>,
+    {
+        let _ = IsValidWordSize::<WORD>::OK;
+
+        const TREQ_UNPACED: u8 = 0x3f;
+        let treq = match pace {
+            Pace::PreferSource => FROM::rx_treq().or_else(TO::tx_treq).unwrap_or(TREQ_UNPACED),
+            Pace::PreferSink => TO::tx_treq().or_else(FROM::rx_treq).unwrap_or(TREQ_UNPACED),
+            Pace::Timer(timer) => timer.treq(),
+        };
+        let (src, src_count) = from.rx_address_count();
+        let (dest, dest_count) = to.tx_address_count();
+        let ctrl = 1 << CTRL_EN
+            | (mem::size_of::<WORD>() as u32 >> 1) << CTRL_DATA_SIZE
+            | u32::from(from.rx_increment()) << CTRL_INCR_READ
+            | u32::from(to.tx_increment()) << CTRL_INCR_WRITE
+            | u32::from(treq) << CTRL_TREQ_SEL
+            | 1 << CTRL_IRQ_QUIET;
+        Self {
+            ctrl,
+            write_addr: dest,
+            // The mode bits are left to 0, the normal mode.
+            trans_count: u32::min(src_count, dest_count).min(u32::MAX >> 4),
+            read_addr: src,
+        }
+    }
+
+    /// Enable/disable byteswapping for the transfer, default value is false.
+    ///
+    /// For byte data, this has no effect. For halfword data, the two bytes of
+    /// each halfword are swapped. For word data, the four bytes of each word
+    /// are swapped to reverse order.
+    pub fn bswap(mut self, bswap: bool) -> Self {
+        self.ctrl = self.ctrl & !(1 << CTRL_BSWAP) | u32::from(bswap) << CTRL_BSWAP;
+        self
+    }
+
+    /// Check if the block terminates the list.
+    pub fn is_end(&self) -> bool {
+        self.read_addr == 0 && self.trans_count == 0
+    }
+}
+
+/// Configuration for a scatter-gather DMA transfer
+pub struct Config<CH1: SingleChannel, CH2: SingleChannel> {
+    data: CH1,
+    control: CH2,
+    blocks: &'static mut [ControlBlock],
+}
+
+impl<CH1: SingleChannel, CH2: SingleChannel> Config<CH1, CH2> {
+    /// Create a configuration for the transfers of `blocks`, performed by the channel `data` and
+    /// loaded by the channel `control`.
+    ///
+    /// Returns [`DMAError::IllegalConfig`] if `blocks` does not contain [`ControlBlock::END`].
+    pub fn new(
+        data: CH1,
+        control: CH2,
+        blocks: &'static mut [ControlBlock],
+    ) -> Result<Self, DMAError> {
+        if !blocks.iter().any(ControlBlock::is_end) {
+            return Err(DMAError::IllegalConfig);
+        }
+        Ok(Self {
+            data,
+            control,
+            blocks,
+        })
+    }
+
+    /// Start the DMA transfer
+    pub fn start(mut self) -> Transfer<CH1, CH2> {
+        // The data channel loads the next block once done, and nothing after the end.
+        let mut end = 0;
+        for (i, block) in self.blocks.iter_mut().enumerate() {
+            let chain_to = if block.is_end() {
+                self.data.id()
+            } else {
+                self.control.id()
+            };
+            block.ctrl = block.ctrl & !CTRL_CHAIN_TO_MASK | u32::from(chain_to) << CTRL_CHAIN_TO;
+            if block.is_end() {
+                end = i + 1;
+                break;
+            }
+        }
+        let end = self.blocks[end..].as_ptr() as u32;
+
+        // Make sure that memory contents reflect what the user intended.
+        crate::arch::dsb();
+        compiler_fence(Ordering::SeqCst);
+
+        // Each block is written to the 4 registers of the last alias, from its control register
+        // to the read address, which triggers the data channel.
+        let registers = self.data.ch().ch_al3_ctrl().as_ptr() as u32;
+        let regs = self.control.ch();
+        regs.ch_read_addr()
+            .write(|w| unsafe { w.bits(self.blocks.as_ptr() as u32) });
+        regs.ch_write_addr().write(|w| unsafe { w.bits(registers) });
+        regs.ch_trans_count().write(|w| unsafe {
+            w.count().bits(4);
+            w.mode().normal();
+            w
+        });
+        regs.ch_al1_ctrl().write(|w| unsafe {
+            w.data_size().bits(2);
+            w.incr_read().bit(true);
+            w.incr_write().bit(true);
+            w.ring_sel().bit(true);
+            w.ring_size().bits(4);
+            w.treq_sel().bits(0x3f);
+            w.chain_to().bits(self.control.id());
+            w.en().bit(true);
+            w
+        });
+        self.control.start();
+
+        Transfer {
+            data: self.data,
+            control: self.control,
+            blocks: self.blocks,
+            end,
+        }
+    }
+}
+
+/// Instance of a scatter-gather DMA transfer
+pub struct Transfer<CH1: SingleChannel, CH2: SingleChannel> {
+    data: CH1,
+    control: CH2,
+    blocks: &'static mut [ControlBlock],
+    /// Address of the control channel once the end block is loaded.
+    end: u32,
+}
+
+impl<CH1: SingleChannel, CH2: SingleChannel> Transfer<CH1, CH2> {
+    /// Check if an interrupt is pending for the data channel and clear the corresponding pending
+    /// bit
+    ///
+    /// The interrupt is only raised once the end of the list is reached.
+    pub fn check_irq0(&mut self) -> bool {
+        self.data.check_irq0()
+    }
+
+    /// Check if an interrupt is pending for the data channel and clear the corresponding pending
+    /// bit
+    ///
+    /// The interrupt is only raised once the end of the list is reached.
+    pub fn check_irq1(&mut self) -> bool {
+        self.data.check_irq1()
+    }
+
+    /// Check if an interrupt is pending for the data channel and clear the corresponding pending
+    /// bit
+    ///
+    /// The interrupt is only raised once the end of the list is reached.
+    pub fn check_irq2(&mut self) -> bool {
+        self.data.check_irq2()
+    }
+
+    /// Check if an interrupt is pending for the data channel and clear the corresponding pending
+    /// bit
+    ///
+    /// The interrupt is only raised once the end of the list is reached.
+    pub fn check_irq3(&mut self) -> bool {
+        self.data.check_irq3()
+    }
+
+    /// Check if the transfers of all the blocks have completed.
+    pub fn is_done(&self) -> bool {
+        let control = self.control.ch();
+        control.ch_read_addr().read().bits() == self.end
+            && !control.ch_ctrl_trig().read().busy().bit_is_set()
+            && !self.data.ch().ch_ctrl_trig().read().busy().bit_is_set()
+    }
+
+    /// Check if the end block was loaded, after which the control channel is done within a few
+    /// cycles.
+    fn end_loaded(&self) -> bool {
+        self.control.ch().ch_read_addr().read().bits() == self.end
+            && !self.data.ch().ch_ctrl_trig().read().busy().bit_is_set()
+    }
+
+    /// Index of the block being transferred.
+    pub fn block_index(&self) -> usize {
+        let read_addr = self.control.ch().ch_read_addr().read().bits();
+        let loaded =
+            (read_addr - self.blocks.as_ptr() as u32) as usize / mem::size_of::<ControlBlock>();
+        loaded.saturating_sub(1)
+    }
+
+    /// Block until the transfer is complete, returning the channels and the blocks
+    pub fn wait(self) -> (CH1, CH2, &'static mut [ControlBlock]) {
+        while !self.is_done() {}
+
+        // Make sure that memory contents reflect what the user intended.
+        crate::arch::dsb();
+        compiler_fence(Ordering::SeqCst);
+
+        (self.data, self.control, self.blocks)
+    }
+
+    /// Wait for the transfer to complete, returning the channels and the blocks
+    ///
+    /// The future is woken by the interrupt `irq`, whose handler must call
+    /// [`dma::on_interrupt`](super::on_interrupt). The transfer is aborted if the future is dropped
+    /// before completion.
+    pub fn wait_async(
+        self,
+        irq: DMAIrq,
+    ) -> WaitAsync<Self, (CH1, CH2, &'static mut [ControlBlock])> {
+        // Only the end block raises the interrupt of the data channel. The control channel is
+        // aborted first if the future is dropped, so that it does not restart the data channel.
+        let completion =
+            Completion::new(1 << self.data.id(), irq).aborting_first(1 << self.control.id());
+        WaitAsync::new(self, completion, Self::end_loaded, Self::wait)
+    }
+
+    /// Aborts the current transfer, returning the channels and the blocks
+    pub fn abort(self) -> (CH1, CH2, &'static mut [ControlBlock]) {
+        let chan_abort = unsafe { &*crate::pac::DMA::ptr() }.chan_abort();
+        let abort_mask = 1 << self.data.id() | 1 << self.control.id();
+        // The control channel is aborted first, so that it does not restart the data channel.
+        chan_abort.write(|w| unsafe { w.chan_abort().bits(1 << self.control.id()) });
+        while chan_abort.read().bits() != 0 {}
+        chan_abort.write(|w| unsafe { w.chan_abort().bits(abort_mask) });
+        while chan_abort.read().bits() != 0 {}
+        while self.control.ch().ch_ctrl_trig().read().busy().bit_is_set()
+            || self.data.ch().ch_ctrl_trig().read().busy().bit_is_set()
+        {}
+
+        // Make sure that memory contents reflect what the user intended.
+        crate::arch::dsb();
+        compiler_fence(Ordering::SeqCst);
+
+        (self.data, self.control, self.blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_layout() {
+        // The block is written to the registers CTRL, WRITE_ADDR, TRANS_COUNT and READ_ADDR_TRIG
+        // of the last alias, with a 16-byte ring.
+        assert_eq!(mem::size_of::<ControlBlock>(), 16);
+        assert_eq!(mem::align_of::<ControlBlock>(), 16);
+        assert!(ControlBlock::END.is_end());
+    }
+}
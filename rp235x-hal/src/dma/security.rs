@@ -0,0 +1,232 @@
+//! Security attributes of the DMA channels and memory protection unit
+//!
+//! Each channel is Secure or Non-secure, and Privileged or Unprivileged, see
+//! [`SingleChannel::set_security`](super::SingleChannel::set_security). The DMA memory protection
+//! unit assigns the same attributes to address ranges, and a channel can only access addresses
+//! whose attributes it has. Secure firmware can then confine Non-secure channels to the memory of
+//! the Non-secure firmware.
+
+use super::DMAError;
+use crate::pac;
+
+/// Number of MPU regions.
+const MPU_REGIONS: usize = 8;
+/// Granularity of the MPU regions, in bytes.
+const MPU_GRANULE: u32 = 32;
+
+/// Evaluate `$body` with `$reg` the `SECCFG_CHx` register of the channel `$id`.
+macro_rules! seccfg_ch {
+    ($dma:ident, $id:expr, |$reg:ident| $body:expr) => {
+        seccfg_ch!(@ $dma, $id, $reg, $body, [
+            0: seccfg_ch0, 1: seccfg_ch1, 2: seccfg_ch2, 3: seccfg_ch3,
+            4: seccfg_ch4, 5: seccfg_ch5, 6: seccfg_ch6, 7: seccfg_ch7,
+            8: seccfg_ch8, 9: seccfg_ch9, 10: seccfg_ch10, 11: seccfg_ch11,
+            12: seccfg_ch12, 13: seccfg_ch13, 14: seccfg_ch14, 15: seccfg_ch15
+        ])
+    };
+    (@ $dma:ident, $id:expr, $reg:ident, $body:expr, [$($x:literal: $seccfg_chX:ident),+]) => {
+        match $id {
+            $($x => {
+                let $reg = $dma.$seccfg_chX();
+                $body
+            })+
+            _ => unreachable!(),
+        }
+    };
+}
+
+/// Evaluate `$body` with `$bar` and `$lar` the `MPU_BARx` and `MPU_LARx` registers of the
+/// region `$index`.
+macro_rules! mpu_region {
+    ($dma:ident, $index:expr, |$bar:ident, $lar:ident| $body:expr) => {
+        mpu_region!(@ $dma, $index, $bar, $lar, $body, [
+            0: mpu_bar0 mpu_lar0, 1: mpu_bar1 mpu_lar1, 2: mpu_bar2 mpu_lar2,
+            3: mpu_bar3 mpu_lar3, 4: mpu_bar4 mpu_lar4, 5: mpu_bar5 mpu_lar5,
+            6: mpu_bar6 mpu_lar6, 7: mpu_bar7 mpu_lar7
+        ])
+    };
+    (@ $dma:ident, $index:expr, $bar:ident, $lar:ident, $body:expr,
+        [$($x:literal: $mpu_barX:ident $mpu_larX:ident),+]) => {
+        match $index {
+            $($x => {
+                let ($bar, $lar) = ($dma.$mpu_barX(), $dma.$mpu_larX());
+                $body
+            })+
+            _ => unreachable!(),
+        }
+    };
+}
+
+/// Security attributes of a channel or of an address range.
+///
+/// After reset, all channels and addresses are Secure and Privileged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SecurityAttributes {
+    /// Secure, or Non-secure.
+    pub secure: bool,
+    /// Privileged, or Unprivileged.
+    pub privileged: bool,
+}
+
+impl Default for SecurityAttributes {
+    fn default() -> Self {
+        Self {
+            secure: true,
+            privileged: true,
+        }
+    }
+}
+
+/// Read the attributes of the channel `id`, and whether they are locked.
+pub(crate) fn channel_security(id: u8) -> (SecurityAttributes, bool) {
+    // Safety: Read only access without side effect.
+    let dma = unsafe { &*pac::DMA::ptr() };
+    seccfg_ch!(dma, id, |seccfg| {
+        let r = seccfg.read();
+        let attributes = SecurityAttributes {
+            secure: r.s().bit_is_set(),
+            privileged: r.p().bit_is_set(),
+        };
+        (attributes, r.lock().bit_is_set())
+    })
+}
+
+/// Set the attributes of the channel `id`, unless they are locked.
+pub(crate) fn set_channel_security(id: u8, attributes: SecurityAttributes) -> Result<(), DMAError> {
+    if channel_security(id).1 {
+        return Err(DMAError::IllegalConfig);
+    }
+    // Safety: The register of the channel, which is owned by the caller.
+    let dma = unsafe { &*pac::DMA::ptr() };
+    seccfg_ch!(dma, id, |seccfg| seccfg.write(|w| {
+        w.s().bit(attributes.secure);
+        w.p().bit(attributes.privileged)
+    }));
+    Ok(())
+}
+
+/// Address range of the DMA memory protection unit, with its attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MpuRegion {
+    base: u32,
+    limit: u32,
+    attributes: SecurityAttributes,
+}
+
+impl MpuRegion {
+    /// Create a region of `size` bytes from `base`, with `attributes`.
+    ///
+    /// Returns [`DMAError::Alignment`] if `base` or `size` isn't a multiple of 32 bytes, and
+    /// [`DMAError::IllegalConfig`] if the region is empty or wraps around the address space.
+    pub fn new(base: u32, size: u32, attributes: SecurityAttributes) -> Result<Self, DMAError> {
+        if base % MPU_GRANULE != 0 || size % MPU_GRANULE != 0 {
+            return Err(DMAError::Alignment);
+        }
+        let limit = size
+            .checked_sub(1)
+            .and_then(|last| base.checked_add(last))
+            .ok_or(DMAError::IllegalConfig)?;
+        Ok(Self {
+            base,
+            limit,
+            attributes,
+        })
+    }
+}
+
+/// DMA memory protection unit, with 8 regions.
+///
+/// The attributes of an address are those of the enabled region containing it, or the default
+/// attributes if none does. The registers are only writable from Secure Privileged code.
+pub struct Mpu {
+    _private: (),
+}
+
+impl Mpu {
+    pub(crate) fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// Set the attributes of the addresses not covered by any enabled region.
+    pub fn set_default_attributes(&mut self, attributes: SecurityAttributes) {
+        self.regs().mpu_ctrl().modify(|_, w| {
+            w.s().bit(attributes.secure);
+            w.p().bit(attributes.privileged)
+        });
+    }
+
+    /// Hide the addresses of the Non-secure regions from Non-secure code, which reads them as 0.
+    pub fn hide_addresses(&mut self, hide: bool) {
+        self.regs()
+            .mpu_ctrl()
+            .modify(|_, w| w.ns_hide_addr().bit(hide));
+    }
+
+    /// Enable the region `index` (0..8) with the range and attributes of `region`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than 7.
+    pub fn set_region(&mut self, index: usize, region: MpuRegion) {
+        assert!(index < MPU_REGIONS);
+        let dma = self.regs();
+        // The region is disabled while its range changes.
+        mpu_region!(dma, index, |bar, lar| {
+            lar.write(|w| w.en().clear_bit());
+            bar.write(|w| unsafe { w.addr().bits(region.base / MPU_GRANULE) });
+            lar.write(|w| unsafe {
+                w.addr().bits(region.limit / MPU_GRANULE);
+                w.s().bit(region.attributes.secure);
+                w.p().bit(region.attributes.privileged);
+                w.en().set_bit()
+            });
+        });
+    }
+
+    /// Disable the region `index` (0..8).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than 7.
+    pub fn disable_region(&mut self, index: usize) {
+        assert!(index < MPU_REGIONS);
+        let dma = self.regs();
+        mpu_region!(dma, index, |_bar, lar| {
+            lar.write(|w| w.en().clear_bit());
+        });
+    }
+
+    fn regs(&self) -> &pac::dma::RegisterBlock {
+        // Safety: The MPU registers are owned by the MPU.
+        unsafe { &*pac::DMA::ptr() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions() {
+        let attributes = SecurityAttributes {
+            secure: false,
+            privileged: false,
+        };
+        let region = MpuRegion::new(0x2004_0000, 0x1_0000, attributes).unwrap();
+        assert_eq!(region.limit, 0x2004_ffff);
+        assert_eq!(
+            MpuRegion::new(0x2004_0010, 0x20, attributes),
+            Err(DMAError::Alignment)
+        );
+        assert_eq!(
+            MpuRegion::new(0x2004_0000, 0, attributes),
+            Err(DMAError::IllegalConfig)
+        );
+        assert_eq!(
+            MpuRegion::new(0xffff_ffe0, 0x40, attributes),
+            Err(DMAError::IllegalConfig)
+        );
+    }
+}
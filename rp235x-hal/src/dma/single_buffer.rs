@@ -3,7 +3,12 @@
 use core::sync::atomic::{compiler_fence, Ordering};
 
 use super::{
-    single_channel::ChannelConfig, single_channel::SingleChannel, Pace, ReadTarget, WriteTarget,
+    completion::{Completion, WaitAsync},
+    single_channel::ChannelConfig,
+    single_channel::SingleChannel,
+    sniffer,
+    sniffer::Sniffer,
+    DMAIrq, Pace, ReadTarget, TransferCountMode, WriteTarget,
 };
 
 /// Configuration for single-buffered DMA transfer
@@ -13,6 +18,8 @@ pub struct Config<CH: SingleChannel, FROM: ReadTarget, TO: WriteTarget> {
     to: TO,
     pace: Pace,
     bswap: bool,
+    sniffer: Option<Sniffer>,
+    count_mode: TransferCountMode,
 }
 
 impl<CH, FROM, TO, WORD> Config<CH, FROM, TO>
@@ -29,6 +36,8 @@ where
             to,
             pace: Pace::PreferSource,
             bswap: false,
+            sniffer: None,
+            count_mode: TransferCountMode::Normal,
         }
     }
 
@@ -52,6 +61,23 @@ where
         self.bswap = bswap;
     }
 
+    /// Calculate a checksum over the transferred data with the DMA sniffer.
+    ///
+    /// The sniffer is shared by all channels, only the last transfer started with a sniffer
+    /// configuration is observed.
+    pub fn sniff(&mut self, sniffer: Sniffer) {
+        self.sniffer = Some(sniffer);
+    }
+
+    /// Sets the mode of the transfer count, default value is [`TransferCountMode::Normal`].
+    ///
+    /// With [`TransferCountMode::Endless`], the transfer never completes and must be aborted.
+    /// With [`TransferCountMode::TriggerSelf`], the transfer restarts once complete, and the
+    /// addresses must wrap or stay fixed, as they are not reloaded.
+    pub fn count_mode(&mut self, mode: TransferCountMode) {
+        self.count_mode = mode;
+    }
+
     /// Start the DMA transfer
     pub fn start(mut self) -> Transfer<CH, FROM, TO> {
         // TODO: Do we want to call any callbacks to configure source/sink?
@@ -62,8 +88,19 @@ where
         compiler_fence(Ordering::SeqCst);
 
         // Configure the DMA channel and start it.
-        self.ch
-            .config(&self.from, &mut self.to, self.pace, self.bswap, None, true);
+        self.ch.config(
+            &self.from,
+            &mut self.to,
+            self.pace,
+            self.bswap,
+            None,
+            self.count_mode,
+            false,
+        );
+        if let Some(sniffer) = &self.sniffer {
+            sniffer.attach(&self.ch);
+        }
+        self.ch.start();
 
         Transfer {
             ch: self.ch,
@@ -97,11 +134,29 @@ where
         self.ch.check_irq1()
     }
 
+    /// Check if an interrupt is pending for this channel and clear the corresponding pending bit
+    pub fn check_irq2(&mut self) -> bool {
+        self.ch.check_irq2()
+    }
+
+    /// Check if an interrupt is pending for this channel and clear the corresponding pending bit
+    pub fn check_irq3(&mut self) -> bool {
+        self.ch.check_irq3()
+    }
+
     /// Check if the transfer has completed.
     pub fn is_done(&self) -> bool {
         !self.ch.ch().ch_ctrl_trig().read().busy().bit_is_set()
     }
 
+    /// Result of the DMA sniffer, the checksum of the transferred data once the transfer is
+    /// done.
+    ///
+    /// See [`Config::sniff`].
+    pub fn sniff_result(&self) -> u32 {
+        sniffer::result()
+    }
+
     /// Block until the transfer is complete, returning the channel and targets
     pub fn wait(self) -> (CH, FROM, TO) {
         while !self.is_done() {}
@@ -113,12 +168,26 @@ where
         (self.ch, self.from, self.to)
     }
 
+    /// Wait for the transfer to complete, returning the channel and targets
+    ///
+    /// The future is woken by the interrupt `irq`, whose handler must call
+    /// [`dma::on_interrupt`](super::on_interrupt). The transfer is aborted if the future is dropped
+    /// before completion.
+    pub fn wait_async(self, irq: DMAIrq) -> WaitAsync<Self, (CH, FROM, TO)> {
+        let completion = Completion::new(1 << self.ch.id(), irq);
+        WaitAsync::new(self, completion, Self::is_done, Self::wait)
+    }
+
     /// Aborts the current transfer, returning the channel and targets
     pub fn abort(mut self) -> (CH, FROM, TO) {
         let irq0_was_enabled = self.ch.is_enabled_irq0();
         let irq1_was_enabled = self.ch.is_enabled_irq1();
+        let irq2_was_enabled = self.ch.is_enabled_irq2();
+        let irq3_was_enabled = self.ch.is_enabled_irq3();
         self.ch.disable_irq0();
         self.ch.disable_irq1();
+        self.ch.disable_irq2();
+        self.ch.disable_irq3();
 
         let chan_abort = unsafe { &*crate::pac::DMA::ptr() }.chan_abort();
         let abort_mask = (1 << self.ch.id()) as u16;
@@ -131,6 +200,8 @@ where
 
         self.ch.check_irq0();
         self.ch.check_irq1();
+        self.ch.check_irq2();
+        self.ch.check_irq3();
 
         if irq0_was_enabled {
             self.ch.enable_irq0();
@@ -140,6 +211,14 @@ where
             self.ch.enable_irq1();
         }
 
+        if irq2_was_enabled {
+            self.ch.enable_irq2();
+        }
+
+        if irq3_was_enabled {
+            self.ch.enable_irq3();
+        }
+
         // Make sure that memory contents reflect what the user intended.
         crate::arch::dsb();
         compiler_fence(Ordering::SeqCst);
@@ -1,6 +1,9 @@
 use crate::pac::DMA;
 
-use super::{Channel, ChannelIndex, Pace, ReadTarget, WriteTarget};
+use super::{
+    security::{self, SecurityAttributes},
+    Channel, ChannelIndex, DMAError, Pace, ReadTarget, TransferCountMode, WriteTarget,
+};
 use crate::{
     atomic_register_access::{write_bitmask_clear, write_bitmask_set},
     dma::ChannelRegs,
@@ -114,6 +117,93 @@ pub trait SingleChannel: Sealed {
             }
         }
     }
+
+    /// Enables the DMA_IRQ_2 signal for this channel.
+    fn enable_irq2(&mut self) {
+        // Safety: We only use the atomic alias of the register.
+        unsafe {
+            write_bitmask_set((*DMA::ptr()).inte2().as_ptr(), 1 << self.id());
+        }
+    }
+
+    /// Check if the DMA_IRQ_2 signal for this channel is enabled.
+    fn is_enabled_irq2(&mut self) -> bool {
+        unsafe { ((*DMA::ptr()).inte2().read().bits() & (1 << self.id())) != 0 }
+    }
+
+    /// Disables the DMA_IRQ_2 signal for this channel.
+    fn disable_irq2(&mut self) {
+        // Safety: We only use the atomic alias of the register.
+        unsafe {
+            write_bitmask_clear((*DMA::ptr()).inte2().as_ptr(), 1 << self.id());
+        }
+    }
+
+    /// Check if an interrupt is pending for this channel and clear the corresponding pending bit
+    fn check_irq2(&mut self) -> bool {
+        // Safety: The following is race-free as we only ever clear the bit for this channel.
+        // Nobody else modifies that bit.
+        unsafe {
+            let status = (*DMA::ptr()).ints2().read().bits();
+            if (status & (1 << self.id())) != 0 {
+                // Clear the interrupt.
+                (*DMA::ptr()).ints2().write(|w| w.bits(1 << self.id()));
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    /// Enables the DMA_IRQ_3 signal for this channel.
+    fn enable_irq3(&mut self) {
+        // Safety: We only use the atomic alias of the register.
+        unsafe {
+            write_bitmask_set((*DMA::ptr()).inte3().as_ptr(), 1 << self.id());
+        }
+    }
+
+    /// Check if the DMA_IRQ_3 signal for this channel is enabled.
+    fn is_enabled_irq3(&mut self) -> bool {
+        unsafe { ((*DMA::ptr()).inte3().read().bits() & (1 << self.id())) != 0 }
+    }
+
+    /// Disables the DMA_IRQ_3 signal for this channel.
+    fn disable_irq3(&mut self) {
+        // Safety: We only use the atomic alias of the register.
+        unsafe {
+            write_bitmask_clear((*DMA::ptr()).inte3().as_ptr(), 1 << self.id());
+        }
+    }
+
+    /// Check if an interrupt is pending for this channel and clear the corresponding pending bit
+    fn check_irq3(&mut self) -> bool {
+        // Safety: The following is race-free as we only ever clear the bit for this channel.
+        // Nobody else modifies that bit.
+        unsafe {
+            let status = (*DMA::ptr()).ints3().read().bits();
+            if (status & (1 << self.id())) != 0 {
+                // Clear the interrupt.
+                (*DMA::ptr()).ints3().write(|w| w.bits(1 << self.id()));
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    /// Sets the security attributes of the channel, Secure and Privileged after reset.
+    ///
+    /// The attributes are locked once the channel is configured, this must be called before the
+    /// first transfer. Returns [`DMAError::IllegalConfig`] if they are locked.
+    fn set_security(&mut self, attributes: SecurityAttributes) -> Result<(), DMAError> {
+        security::set_channel_security(self.id(), attributes)
+    }
+
+    /// Returns the security attributes of the channel.
+    fn security(&self) -> SecurityAttributes {
+        security::channel_security(self.id()).0
+    }
 }
 
 impl<CH: ChannelIndex> SingleChannel for Channel<CH> {
@@ -139,6 +229,7 @@ impl<CH1: ChannelIndex, CH2: ChannelIndex> SingleChannel for (Channel<CH1>, Chan
 }
 
 pub(crate) trait ChannelConfig {
+    #[allow(clippy::too_many_arguments)]
     fn config<WORD, FROM, TO>(
         &mut self,
         from: &FROM,
@@ -146,6 +237,7 @@ pub(crate) trait ChannelConfig {
         pace: Pace,
         bswap: bool,
         chain_to: Option<u8>,
+        mode: TransferCountMode,
         start: bool,
     ) where
         FROM: ReadTarget<ReceivedWord = WORD>,
@@ -158,12 +250,12 @@ pub(crate) trait ChannelConfig {
 
 // rp235x's DMA engine only works with certain word sizes. Make sure that other
 // word sizes will fail to compile.
-struct IsValidWordSize<WORD> {
+pub(crate) struct IsValidWordSize<WORD> {
     w: core::marker::PhantomData<WORD>,
 }
 
 impl<WORD> IsValidWordSize<WORD> {
-    const OK: usize = {
+    pub(crate) const OK: usize = {
         match mem::size_of::<WORD>() {
             1 | 2 | 4 => 0, // ok
             _ => panic!("Unsupported DMA word size"),
@@ -172,6 +264,7 @@ impl<WORD> IsValidWordSize<WORD> {
 }
 
 impl<CH: SingleChannel> ChannelConfig for CH {
+    #[allow(clippy::too_many_arguments)]
     fn config<WORD, FROM, TO>(
         &mut self,
         from: &FROM,
@@ -179,6 +272,7 @@ impl<CH: SingleChannel> ChannelConfig for CH {
         pace: Pace,
         bswap: bool,
         chain_to: Option<u8>,
+        mode: TransferCountMode,
         start: bool,
     ) where
         FROM: ReadTarget<ReceivedWord = WORD>,
@@ -195,6 +289,7 @@ impl<CH: SingleChannel> ChannelConfig for CH {
         let treq = match pace {
             Pace::PreferSource => FROM::rx_treq().or_else(TO::tx_treq).unwrap_or(TREQ_UNPACED),
             Pace::PreferSink => TO::tx_treq().or_else(FROM::rx_treq).unwrap_or(TREQ_UNPACED),
+            Pace::Timer(timer) => timer.treq(),
         };
         let len = u32::min(src_count, dest_count);
         self.ch().ch_al1_ctrl().write(|w| unsafe {
@@ -210,8 +305,11 @@ impl<CH: SingleChannel> ChannelConfig for CH {
         self.ch().ch_read_addr().write(|w| unsafe { w.bits(src) });
         self.ch().ch_trans_count().write(|w| unsafe {
             w.count().bits(len);
-            w.mode().normal();
-            w
+            match mode {
+                TransferCountMode::Normal => w.mode().normal(),
+                TransferCountMode::TriggerSelf => w.mode().trigger_self(),
+                TransferCountMode::Endless => w.mode().endless(),
+            }
         });
         if start {
             self.ch()
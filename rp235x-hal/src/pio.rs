@@ -3,22 +3,43 @@
 //! See [Chapter 11](https://rptl.io/rp2350-datasheet#section_pio) of the RP2350
 //! datasheet for more details.
 
-use core::ops::Deref;
+use core::{ops::Deref, task::Poll};
 use pio::{Instruction, InstructionOperands, Program, SideSet, Wrap};
 
 use crate::{
+    async_utils::{
+        sealed::{IrqWaker, Wakeable},
+        AsyncPeripheral, CancellablePollFn as CPFn,
+    },
     atomic_register_access::{write_bitmask_clear, write_bitmask_set},
     dma::{EndlessReadTarget, EndlessWriteTarget, ReadTarget, TransferSize, Word, WriteTarget},
-    gpio::{Function, FunctionPio0, FunctionPio1},
-    pac::{self, dma::ch::ch_ctrl_trig::TREQ_SEL_A, pio0::RegisterBlock, PIO0, PIO1},
+    gpio::{Function, FunctionPio0, FunctionPio1, FunctionPio2, Pin, PinId, PullType},
+    pac::{self, dma::ch::ch_ctrl_trig::TREQ_SEL_A, pio0::RegisterBlock, PIO0, PIO1, PIO2},
     resets::SubsystemReset,
     typelevel::Sealed,
 };
 
+pub mod can;
+pub mod debug;
+pub use debug::*;
+pub mod dyn_state_machine;
+pub use dyn_state_machine::*;
+pub mod emulator;
+pub mod i2s;
+pub mod logic_analyser;
+pub mod one_wire;
+pub mod parallel;
+pub mod pdm;
+pub mod quadrature;
+pub mod spi;
+pub mod uart;
+pub mod ws2812;
+
 const PIO_INSTRUCTION_COUNT: usize = 32;
 
 impl Sealed for PIO0 {}
 impl Sealed for PIO1 {}
+impl Sealed for PIO2 {}
 
 /// PIO Instance
 pub trait PIOExt: Deref<Target = RegisterBlock> + SubsystemReset + Sized + Send + Sealed {
@@ -63,6 +84,7 @@ pub trait PIOExt: Deref<Target = RegisterBlock> + SubsystemReset + Sized + Send
         (
             PIO {
                 used_instruction_space: 0,
+                instructions: [0; PIO_INSTRUCTION_COUNT],
                 pio: self,
             },
             sm0,
@@ -72,7 +94,15 @@ pub trait PIOExt: Deref<Target = RegisterBlock> + SubsystemReset + Sized + Send
         )
     }
 
-    /// Number of this PIO (0..1).
+    /// Create a new PIO wrapper and hand the state machines out at runtime.
+    ///
+    /// See [`DynStateMachines`] for more information.
+    fn dyn_split(self, resets: &mut crate::pac::RESETS) -> (PIO<Self>, DynStateMachines<Self>) {
+        let (pio, sm0, sm1, sm2, sm3) = self.split(resets);
+        (pio, DynStateMachines::new(sm0, sm1, sm2, sm3))
+    }
+
+    /// Number of this PIO (0..2).
     fn id() -> usize;
 }
 
@@ -88,11 +118,38 @@ impl PIOExt for PIO1 {
         1
     }
 }
+impl PIOExt for PIO2 {
+    type PinFunction = FunctionPio2;
+    fn id() -> usize {
+        2
+    }
+}
+
+/// First GPIO visible to a PIO block, see [`PIO::set_gpio_base`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GpioBase {
+    /// The block accesses GPIO0 to GPIO31 (reset value).
+    Gpio0,
+    /// The block accesses GPIO16 to GPIO47.
+    Gpio16,
+}
+
+impl GpioBase {
+    /// Number of the GPIO that pin 0 of the PIO block is mapped to.
+    pub const fn offset(self) -> u8 {
+        match self {
+            GpioBase::Gpio0 => 0,
+            GpioBase::Gpio16 => 16,
+        }
+    }
+}
 
 #[allow(clippy::upper_case_acronyms)]
 /// Programmable IO Block
 pub struct PIO<P: PIOExt> {
     used_instruction_space: u32, // bit for each PIO_INSTRUCTION_COUNT
+    instructions: [u16; PIO_INSTRUCTION_COUNT], // copy of the write-only instruction memory
     pio: P,
 }
 
@@ -151,6 +208,30 @@ impl<P: PIOExt> PIO<P> {
         self.irq()
     }
 
+    /// Select the window of 32 GPIOs this PIO block can access.
+    ///
+    /// All pin numbers used by the state machines of this block (`in_pin_base`, `jmp_pin`,
+    /// `set_pins`, etc.) are relative to this base. On the RP2350B this is required to drive
+    /// GPIO32 to GPIO47 from a PIO program.
+    ///
+    /// This setting is shared by all state machines of the block and should be changed before
+    /// any of them are configured.
+    pub fn set_gpio_base(&mut self, base: GpioBase) {
+        // Safety: PIOExt provides exclusive access to the pio.gpiobase register.
+        self.pio
+            .gpiobase()
+            .write(|w| unsafe { w.bits(base.offset() as u32) });
+    }
+
+    /// The window of 32 GPIOs this PIO block can currently access.
+    pub fn gpio_base(&self) -> GpioBase {
+        if self.pio.gpiobase().read().bits() & 0x10 != 0 {
+            GpioBase::Gpio16
+        } else {
+            GpioBase::Gpio0
+        }
+    }
+
     /// Get raw irq flags.
     ///
     /// The PIO has 8 IRQ flags, of which 4 are visible to the host processor. Each bit of `flags` corresponds to one of
@@ -181,6 +262,35 @@ impl<P: PIOExt> PIO<P> {
             .write(|w| unsafe { w.irq_force().bits(flags) });
     }
 
+    /// Wait until the state machine IRQ flag `flag` (0..=3) is raised, then clear it.
+    ///
+    /// Clearing the flag releases a state machine blocked on `irq wait`. The future is woken by
+    /// the interrupt `irq` of this block, whose handler must call [`AsyncPeripheral::on_interrupt`].
+    pub async fn wait_irq(&mut self, irq: PioIRQ, flag: u8) {
+        assert!(flag < 4, "invalid state machine interrupt number");
+        let mask = 1 << (flag + 8);
+        CPFn::new(
+            self,
+            |pio: &mut Self| {
+                if pio.get_irq_raw() & (1 << flag) != 0 {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            },
+            // Safety: Atomic write to a single bit of the interrupt enable register.
+            |pio: &mut Self| unsafe {
+                write_bitmask_set(pio.pio.sm_irq(irq.to_index()).irq_inte().as_ptr(), mask);
+            },
+            // Safety: Atomic write to a single bit of the interrupt enable register.
+            |pio: &mut Self| unsafe {
+                write_bitmask_clear(pio.pio.sm_irq(irq.to_index()).irq_inte().as_ptr(), mask);
+            },
+        )
+        .await;
+        self.clear_irq(1 << flag);
+    }
+
     /// Calculates a mask with the `len` right-most bits set.
     fn instruction_mask(len: usize) -> u32 {
         if len < 32 {
@@ -191,14 +301,18 @@ impl<P: PIOExt> PIO<P> {
     }
 
     /// Tries to find an appropriate offset for the instructions, in range 0..=31.
-    fn find_offset_for_instructions(&self, i: &[u16], origin: Option<u8>) -> Option<u8> {
+    fn find_offset_for_instructions(
+        used_instruction_space: u32,
+        i: &[u16],
+        origin: Option<u8>,
+    ) -> Option<u8> {
         if i.len() > PIO_INSTRUCTION_COUNT || i.is_empty() {
             None
         } else {
             let mask = Self::instruction_mask(i.len());
             if let Some(origin) = origin {
                 if origin as usize > PIO_INSTRUCTION_COUNT - i.len()
-                    || self.used_instruction_space & (mask << origin) != 0
+                    || used_instruction_space & (mask << origin) != 0
                 {
                     None
                 } else {
@@ -206,7 +320,7 @@ impl<P: PIOExt> PIO<P> {
                 }
             } else {
                 for i in (0..=32 - (i.len() as u8)).rev() {
-                    if self.used_instruction_space & (mask << i) == 0 {
+                    if used_instruction_space & (mask << i) == 0 {
                         return Some(i);
                     }
                 }
@@ -215,6 +329,23 @@ impl<P: PIOExt> PIO<P> {
         }
     }
 
+    /// Adjusts the target of JMP instructions for a program placed at `offset`.
+    fn relocate_instruction(instr: u16, offset: u8) -> u16 {
+        if instr & 0b1110_0000_0000_0000 == 0 {
+            // this is a JMP instruction -> add offset to address
+            let address = (instr & 0b11111) as u8;
+            let address = address + offset;
+            assert!(
+                address < pio::RP2040_MAX_PROGRAM_SIZE as u8,
+                "Invalid JMP out of the program after offset addition"
+            );
+            instr & (!0b11111) | address as u16
+        } else {
+            // this is not a JMP instruction -> keep it unchanged
+            instr
+        }
+    }
+
     /// Allocates space in instruction memory and installs the program.
     ///
     /// The function returns a handle to the installed program that can be used
@@ -233,27 +364,15 @@ impl<P: PIOExt> PIO<P> {
         &mut self,
         p: &Program<{ pio::RP2040_MAX_PROGRAM_SIZE }>,
     ) -> Result<InstalledProgram<P>, InstallError> {
-        if let Some(offset) = self.find_offset_for_instructions(&p.code, p.origin) {
+        if let Some(offset) =
+            Self::find_offset_for_instructions(self.used_instruction_space, &p.code, p.origin)
+        {
             p.code
                 .iter()
-                .cloned()
-                .map(|instr| {
-                    if instr & 0b1110_0000_0000_0000 == 0 {
-                        // this is a JMP instruction -> add offset to address
-                        let address = (instr & 0b11111) as u8;
-                        let address = address + offset;
-                        assert!(
-                            address < pio::RP2040_MAX_PROGRAM_SIZE as u8,
-                            "Invalid JMP out of the program after offset addition"
-                        );
-                        instr & (!0b11111) | address as u16
-                    } else {
-                        // this is not a JMP instruction -> keep it unchanged
-                        instr
-                    }
-                })
+                .map(|&instr| Self::relocate_instruction(instr, offset))
                 .enumerate()
                 .for_each(|(i, instr)| {
+                    self.instructions[i + offset as usize] = instr;
                     self.pio
                         .instr_mem(i + offset as usize)
                         .write(|w| unsafe { w.instr_mem0().bits(instr) })
@@ -463,6 +582,14 @@ pub type PIO1SM1 = (PIO1, SM1);
 pub type PIO1SM2 = (PIO1, SM2);
 /// Fourth state machine of the second PIO block.
 pub type PIO1SM3 = (PIO1, SM3);
+/// First state machine of the third PIO block.
+pub type PIO2SM0 = (PIO2, SM0);
+/// Second state machine of the third PIO block.
+pub type PIO2SM1 = (PIO2, SM1);
+/// Third state machine of the third PIO block.
+pub type PIO2SM2 = (PIO2, SM2);
+/// Fourth state machine of the third PIO block.
+pub type PIO2SM3 = (PIO2, SM3);
 
 impl<P: PIOExt, SM: StateMachineIndex> ValidStateMachine for (P, SM) {
     type PIO = P;
@@ -599,6 +726,79 @@ impl PioIRQ {
     }
 }
 
+/// Number of wakers per PIO block: one per RX FIFO, one per TX FIFO and one for the IRQ flags.
+const WAKERS_PER_BLOCK: usize = 9;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const WAKER_INIT: IrqWaker = IrqWaker::new();
+static WAKERS: [IrqWaker; 3 * WAKERS_PER_BLOCK] = [WAKER_INIT; 3 * WAKERS_PER_BLOCK];
+
+/// Waker for the given PIO block and interrupt source.
+///
+/// The source uses the same numbering as the INTR register: 0..=3 for RX not empty, 4..=7 for TX
+/// not full and 8 for any of the state machine IRQ flags.
+fn waker(pio: usize, source: usize) -> &'static IrqWaker {
+    &WAKERS[pio * WAKERS_PER_BLOCK + source]
+}
+
+fn block_ptr<P: PIOExt>() -> *const RegisterBlock {
+    match P::id() {
+        0 => PIO0::ptr(),
+        1 => PIO1::ptr(),
+        _ => PIO2::ptr(),
+    }
+}
+
+impl<P: PIOExt> Wakeable for PIO<P> {
+    fn waker() -> &'static IrqWaker {
+        waker(P::id(), 8)
+    }
+}
+
+impl<SM: ValidStateMachine, RxSize> Wakeable for Rx<SM, RxSize> {
+    fn waker() -> &'static IrqWaker {
+        waker(SM::PIO::id(), SM::id())
+    }
+}
+
+impl<SM: ValidStateMachine, TxSize> Wakeable for Tx<SM, TxSize> {
+    fn waker() -> &'static IrqWaker {
+        waker(SM::PIO::id(), SM::id() + 4)
+    }
+}
+
+impl<P: PIOExt> AsyncPeripheral for PIO<P> {
+    /// Wakes the async FIFO accesses and IRQ flag waits of this PIO block.
+    ///
+    /// This must be called from the block's IRQ0 and IRQ1 interrupt handlers, depending on which
+    /// [`PioIRQ`] the async functions were given. The sources that fired are masked, they are
+    /// unmasked again by the futures when needed.
+    fn on_interrupt() {
+        // Safety: Only the interrupt enables of the sources that fired are modified, using atomic
+        // accesses.
+        let block = unsafe { &*block_ptr::<P>() };
+        for irq in 0..2 {
+            let sm_irq = block.sm_irq(irq);
+            let ints = sm_irq.irq_ints().read().bits() & 0xfff;
+            if ints == 0 {
+                continue;
+            }
+            // Safety: Atomic write, only clears the bits which are set in `ints`.
+            unsafe {
+                write_bitmask_clear(sm_irq.irq_inte().as_ptr(), ints);
+            }
+            for source in 0..8 {
+                if ints & (1 << source) != 0 {
+                    waker(P::id(), source).wake();
+                }
+            }
+            if ints & 0xf00 != 0 {
+                waker(P::id(), 8).wake();
+            }
+        }
+    }
+}
+
 impl<SM: ValidStateMachine, State> StateMachine<SM, State> {
     /// Stops the state machine if it is still running and returns its program.
     ///
@@ -650,6 +850,48 @@ impl<SM: ValidStateMachine, State> StateMachine<SM, State> {
         unsafe { self.sm.sm().sm_execctrl().read().exec_stalled().bit() }
     }
 
+    /// Capture the state of the state machine, to find out why a program does not behave.
+    pub fn debug_snapshot(&self) -> DebugSnapshot {
+        let sm_id = SM::id();
+        let mask = 1 << sm_id;
+        // Safety: Read only accesses without side effect
+        unsafe {
+            let sm = self.sm.sm();
+            let pio = self.sm.pio();
+            let flevel = pio.flevel().read().bits() >> (8 * sm_id);
+            let fdebug = pio.fdebug().read().bits();
+            DebugSnapshot {
+                pc: sm.sm_addr().read().bits() as u8,
+                instruction: DisassembledInstruction::new(
+                    sm.sm_instr().read().sm0_instr().bits(),
+                    self.program.side_set,
+                ),
+                stalled: sm.sm_execctrl().read().exec_stalled().bit(),
+                tx_level: (flevel & 0xf) as u8,
+                rx_level: ((flevel >> 4) & 0xf) as u8,
+                tx_stall: fdebug & (mask << 24) != 0,
+                tx_overflow: fdebug & (mask << 16) != 0,
+                rx_underflow: fdebug & (mask << 8) != 0,
+                rx_stall: fdebug & mask != 0,
+                pad_out: pio.dbg_padout().read().bits(),
+                pad_oe: pio.dbg_padoe().read().bits(),
+            }
+        }
+    }
+
+    /// Clear the sticky FDEBUG flags of this state machine reported by
+    /// [`debug_snapshot`](Self::debug_snapshot).
+    pub fn clear_debug_flags(&mut self) {
+        let mask = 1 << SM::id();
+        // Safety: FDEBUG is write-1-to-clear, only the flags of this state machine are cleared.
+        unsafe {
+            self.sm
+                .pio()
+                .fdebug()
+                .write(|w| w.bits(mask | mask << 8 | mask << 16 | mask << 24));
+        }
+    }
+
     /// Clear both TX and RX FIFOs
     pub fn clear_fifos(&mut self) {
         // Safety: all accesses to these registers are controlled by this instance
@@ -740,6 +982,68 @@ impl<SM: ValidStateMachine, State> StateMachine<SM, State> {
     pub fn clock_divisor_fixed_point(&mut self, int: u16, frac: u8) {
         self.sm.set_clock_divisor(int, frac);
     }
+
+    /// Switch the state machine to another program, returning the previous one.
+    ///
+    /// The state machine is paused for a few system clock cycles while its wrap bounds and side-set
+    /// configuration are updated, then it continues at the start of `program`. Pin mappings, pin
+    /// states, FIFO contents, the clock divider and the shift configuration are kept, which allows
+    /// switching protocols without glitches on the bus.
+    ///
+    /// The previous program can be uninstalled once it is not used by any state machine anymore.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rp235x_hal::{pac, pio::{PIOBuilder, PIOExt}};
+    /// # let mut pac = pac::Peripherals::take().unwrap();
+    /// # let (mut pio, sm0, ..) = pac.PIO0.split(&mut pac.RESETS);
+    /// let single_program = pio_proc::pio_asm!("out pins, 1").program;
+    /// let quad_program = pio_proc::pio_asm!("out pins, 4").program;
+    /// let single = pio.install(&single_program).unwrap();
+    /// let (sm, _rx, _tx) = PIOBuilder::from_installed_program(single).build(sm0);
+    /// let mut sm = sm.start();
+    /// let quad = pio.install(&quad_program).unwrap();
+    /// let single = sm.switch_program(quad);
+    /// pio.uninstall(single);
+    /// ```
+    pub fn switch_program(
+        &mut self,
+        program: InstalledProgram<SM::PIO>,
+    ) -> InstalledProgram<SM::PIO> {
+        let offset = program.offset;
+        let mask = 1 << SM::id();
+        // Safety: Read only access without side effect
+        let enabled = unsafe { self.sm.pio().ctrl().read().bits() & mask != 0 };
+        self.sm.set_enabled(false);
+
+        // Safety: all accesses to these registers are controlled by this instance
+        unsafe {
+            let sm = self.sm.sm();
+            sm.sm_execctrl().modify(|_, w| {
+                w.side_en().bit(program.side_set.optional());
+                w.side_pindir().bit(program.side_set.pindirs());
+                w.wrap_top().bits(offset + program.wrap.source);
+                w.wrap_bottom().bits(offset + program.wrap.target)
+            });
+
+            // Jump without side-set, so that the pins keep their state.
+            sm.sm_pinctrl().modify(|_, w| w.sideset_count().bits(0));
+            let instruction = InstructionOperands::JMP {
+                condition: pio::JmpCondition::Always,
+                address: offset,
+            }
+            .encode();
+            sm.sm_instr().write(|w| w.sm0_instr().bits(instruction));
+            sm.sm_pinctrl()
+                .modify(|_, w| w.sideset_count().bits(program.side_set.bits()));
+        }
+
+        if enabled {
+            self.sm.set_enabled(true);
+        }
+        core::mem::replace(&mut self.program, program)
+    }
 }
 
 // Safety: All shared register accesses are atomic.
@@ -1345,14 +1649,15 @@ impl<SM: ValidStateMachine, RxSize: TransferSize> Rx<SM, RxSize> {
 
     /// Gets the FIFO's `DREQ` value.
     ///
-    /// This is a value between 0 and 39. Each FIFO on each state machine on
+    /// This is a value between 0 and 23. Each FIFO on each state machine on
     /// each PIO has a unique value.
     pub fn dreq_value(&self) -> u8 {
-        if self.block as usize == 0x5020_0000usize {
-            TREQ_SEL_A::PIO0_RX0 as u8 + (SM::id() as u8)
-        } else {
-            TREQ_SEL_A::PIO1_RX0 as u8 + (SM::id() as u8)
-        }
+        let base = match SM::PIO::id() {
+            0 => TREQ_SEL_A::PIO0_RX0,
+            1 => TREQ_SEL_A::PIO1_RX0,
+            _ => TREQ_SEL_A::PIO2_RX0,
+        };
+        base as u8 + (SM::id() as u8)
     }
 
     /// Get the next element from RX FIFO.
@@ -1367,6 +1672,57 @@ impl<SM: ValidStateMachine, RxSize: TransferSize> Rx<SM, RxSize> {
         Some(unsafe { core::ptr::read_volatile(self.fifo_address()) })
     }
 
+    /// Wait for the next element from RX FIFO.
+    ///
+    /// The future is woken by the interrupt `irq` of this block, whose handler must call
+    /// [`PIO::on_interrupt`](AsyncPeripheral::on_interrupt).
+    pub async fn read_async(&mut self, irq: PioIRQ) -> u32 {
+        CPFn::new(
+            self,
+            |rx: &mut Self| rx.read().map_or(Poll::Pending, Poll::Ready),
+            |rx: &mut Self| rx.enable_rx_not_empty_interrupt(irq),
+            |rx: &mut Self| rx.disable_rx_not_empty_interrupt(irq),
+        )
+        .await
+    }
+
+    /// Fill `buffer` with elements from RX FIFO, waiting for them as needed.
+    ///
+    /// See [`Self::read_async`].
+    pub async fn read_slice_async(&mut self, irq: PioIRQ, buffer: &mut [u32]) {
+        for word in buffer {
+            *word = self.read_async(irq).await;
+        }
+    }
+
+    /// Read one of the four RX FIFO entries directly.
+    ///
+    /// This is intended for state machines configured with [`Buffers::RxPut`], where the program
+    /// uses the RX FIFO as status registers. In other modes, the value returned is unspecified.
+    pub fn read_fifo_entry(&self, index: u8) -> u32 {
+        assert!(index < 4, "invalid RX FIFO entry");
+        // Safety: Read only access without side effect
+        unsafe { core::ptr::read_volatile(self.fifo_entry_address(index)) }
+    }
+
+    /// Write one of the four RX FIFO entries directly.
+    ///
+    /// This is intended for state machines configured with [`Buffers::RxGet`], where the program
+    /// uses the RX FIFO as configuration registers.
+    pub fn write_fifo_entry(&mut self, index: u8, value: u32) {
+        assert!(index < 4, "invalid RX FIFO entry");
+        // Safety: The entries of this state machine are only accessed by this Rx instance.
+        unsafe { core::ptr::write_volatile(self.fifo_entry_address(index), value) }
+    }
+
+    // RXFn_PUTGETm registers are laid out contiguously, 4 per state machine.
+    unsafe fn fifo_entry_address(&self, index: u8) -> *mut u32 {
+        self.block()
+            .rxf0_putget0()
+            .as_ptr()
+            .add(SM::id() * 4 + index as usize)
+    }
+
     /// Enable/Disable the autopush feature of the state machine.
     // Safety: This register is read by Rx, this is the only write.
     pub fn enable_autopush(&mut self, enable: bool) {
@@ -1392,6 +1748,26 @@ impl<SM: ValidStateMachine, RxSize: TransferSize> Rx<SM, RxSize> {
         unsafe { self.block().fstat().read().rxfull().bits() & (1 << SM::id()) != 0 }
     }
 
+    /// Checks if the state machine has stalled on full RX FIFO during a blocking PUSH, or an IN
+    /// with autopush enabled.
+    ///
+    /// **Note this is a sticky flag and may not reflect the current state of the machine.**
+    pub fn has_stalled(&self) -> bool {
+        let mask = 1 << SM::id();
+        // Safety: read-only access without side-effect
+        unsafe { self.block().fdebug().read().rxstall().bits() & mask == mask }
+    }
+
+    /// Clears the `rx_stalled` flag.
+    pub fn clear_stalled_flag(&self) {
+        let mask = 1 << SM::id();
+
+        // Safety: These bits are WC, only the one corresponding to this SM is set.
+        unsafe {
+            self.block().fdebug().write(|w| w.rxstall().bits(mask));
+        }
+    }
+
     /// Enable RX FIFO not empty interrupt.
     ///
     /// This interrupt is raised when the RX FIFO is not empty, i.e. one could read more data from it.
@@ -1507,14 +1883,15 @@ impl<SM: ValidStateMachine, TxSize: TransferSize> Tx<SM, TxSize> {
 
     /// Gets the FIFO's `DREQ` value.
     ///
-    /// This is a value between 0 and 39. Each FIFO on each state machine on
+    /// This is a value between 0 and 23. Each FIFO on each state machine on
     /// each PIO has a unique value.
     pub fn dreq_value(&self) -> u8 {
-        if self.block as usize == 0x5020_0000usize {
-            TREQ_SEL_A::PIO0_TX0 as u8 + (SM::id() as u8)
-        } else {
-            TREQ_SEL_A::PIO1_TX0 as u8 + (SM::id() as u8)
-        }
+        let base = match SM::PIO::id() {
+            0 => TREQ_SEL_A::PIO0_TX0,
+            1 => TREQ_SEL_A::PIO1_TX0,
+            _ => TREQ_SEL_A::PIO2_TX0,
+        };
+        base as u8 + (SM::id() as u8)
     }
 
     /// Write a u32 value to TX FIFO.
@@ -1524,6 +1901,35 @@ impl<SM: ValidStateMachine, TxSize: TransferSize> Tx<SM, TxSize> {
         self.write_generic(value)
     }
 
+    /// Write a u32 value to TX FIFO, waiting for room as needed.
+    ///
+    /// The future is woken by the interrupt `irq` of this block, whose handler must call
+    /// [`PIO::on_interrupt`](AsyncPeripheral::on_interrupt).
+    pub async fn write_async(&mut self, irq: PioIRQ, value: u32) {
+        CPFn::new(
+            self,
+            |tx: &mut Self| {
+                if tx.write(value) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            },
+            |tx: &mut Self| tx.enable_tx_not_full_interrupt(irq),
+            |tx: &mut Self| tx.disable_tx_not_full_interrupt(irq),
+        )
+        .await
+    }
+
+    /// Write all `values` to TX FIFO, waiting for room as needed.
+    ///
+    /// See [`Self::write_async`].
+    pub async fn write_slice_async(&mut self, irq: PioIRQ, values: &[u32]) {
+        for &value in values {
+            self.write_async(irq, value).await;
+        }
+    }
+
     /// Write a replicated u8 value to TX FIFO.
     ///
     /// Memory mapped register writes that are smaller than 32bits will trigger
@@ -1886,6 +2292,50 @@ pub enum MovStatusConfig {
     Irq(u8),
 }
 
+/// Selects the PIO block whose IRQ flag is targeted by an `IRQ` or `WAIT IRQ` instruction.
+///
+/// pio-proc 0.2 cannot assemble the RP2350 `prev` and `next` modifiers, so instructions
+/// using them can be built with [`encode_irq`] and [`encode_wait_irq`] instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IrqIndexMode {
+    /// The flag of the current PIO block.
+    Direct,
+    /// The flag of the previous PIO block (PIO0 wraps around to PIO2).
+    Prev,
+    /// The flag of the current PIO block, offset by the index of the executing state machine.
+    Rel,
+    /// The flag of the next PIO block (PIO2 wraps around to PIO0).
+    Next,
+}
+
+impl IrqIndexMode {
+    const fn bits(self) -> u16 {
+        match self {
+            IrqIndexMode::Direct => 0b00,
+            IrqIndexMode::Prev => 0b01,
+            IrqIndexMode::Rel => 0b10,
+            IrqIndexMode::Next => 0b11,
+        }
+    }
+}
+
+/// Encode an `IRQ` instruction, including the RP2350 `prev`/`next` modifiers.
+///
+/// The delay/side-set field is left as zero.
+pub const fn encode_irq(clear: bool, wait: bool, index: u8, mode: IrqIndexMode) -> u16 {
+    assert!(index < 8, "invalid IRQ flag index");
+    0b110 << 13 | (clear as u16) << 6 | (wait as u16) << 5 | mode.bits() << 3 | index as u16
+}
+
+/// Encode a `WAIT IRQ` instruction, including the RP2350 `prev`/`next` modifiers.
+///
+/// The delay/side-set field is left as zero.
+pub const fn encode_wait_irq(polarity: bool, index: u8, mode: IrqIndexMode) -> u16 {
+    assert!(index < 8, "invalid IRQ flag index");
+    0b001 << 13 | (polarity as u16) << 7 | 0b10 << 5 | mode.bits() << 3 | index as u16
+}
+
 /// Shift direction for input and output shifting.
 #[derive(Debug, Clone, Copy)]
 pub enum ShiftDirection {
@@ -1904,6 +2354,28 @@ impl ShiftDirection {
     }
 }
 
+/// A GPIO pin whose function is assigned to the PIO block `P`.
+///
+/// This is implemented for every [`Pin`] in `P`'s [`PinFunction`](PIOExt::PinFunction), and is
+/// accepted by the `*_checked` methods of [`PIOBuilder`] so that a pin configured for another
+/// function (or another PIO block) is rejected at compile time.
+pub trait PioPin<P: PIOExt>: Sealed {
+    /// GPIO number of the pin.
+    fn pin_num(&self) -> u8;
+}
+
+impl<I, F, PT, P> PioPin<P> for Pin<I, F, PT>
+where
+    I: PinId,
+    F: Function,
+    PT: PullType,
+    P: PIOExt<PinFunction = F>,
+{
+    fn pin_num(&self) -> u8 {
+        self.id().num
+    }
+}
+
 /// Builder to deploy a fully configured PIO program on one of the state
 /// machines.
 #[derive(Debug)]
@@ -1941,6 +2413,8 @@ pub struct PIOBuilder<P> {
     /// Enable autopush.
     autopush: bool,
 
+    /// Number of pins visible to `IN`, `MOV x, PINS` and `WAIT PIN` (RP2350 only).
+    in_count: u8,
     /// Number of pins asserted by a `SET`.
     set_count: u8,
     /// Number of pins asserted by an `OUT PINS`, `OUT PINDIRS` or `MOV PINS` instruction.
@@ -1953,6 +2427,8 @@ pub struct PIOBuilder<P> {
     set_base: u8,
     /// The first pin that is affected by `OUT PINS`, `OUT PINDIRS` or `MOV PINS` instructions.
     out_base: u8,
+    /// GPIO base the `*_checked` pin numbers were translated against, verified in `build`.
+    gpio_base: Option<GpioBase>,
 }
 
 /// Buffer sharing configuration.
@@ -1964,6 +2440,19 @@ pub enum Buffers {
     OnlyTx,
     /// The memory of the TX FIFO is given to the RX FIFO to double its depth.
     OnlyRx,
+    /// The RX FIFO is turned into four status registers.
+    ///
+    /// The state machine writes them with `mov rxfifo[...], isr` and the system reads them
+    /// through [`Rx::read_fifo_entry`]. The TX FIFO keeps working as usual.
+    RxPut,
+    /// The RX FIFO is turned into four configuration registers.
+    ///
+    /// The system writes them through [`Rx::write_fifo_entry`] and the state machine reads them
+    /// with `mov osr, rxfifo[...]`. The TX FIFO keeps working as usual.
+    RxGet,
+    /// The RX FIFO is turned into four scratch registers, readable and writable by the state
+    /// machine only.
+    RxPutGet,
 }
 
 /// Errors that occurred during `PIO::install`.
@@ -1973,6 +2462,15 @@ pub enum InstallError {
     NoSpace,
 }
 
+/// Errors that occurred during `DynStateMachines::claim_with_program`.
+#[derive(Debug)]
+pub enum ClaimError {
+    /// All state machines of the selected PIO are already claimed.
+    NoStateMachine,
+    /// There was not enough space for the instructions on the selected PIO.
+    NoSpace,
+}
+
 impl<P: PIOExt> PIOBuilder<P> {
     /// Set config settings based on information from the given [`InstalledProgram`].
     /// Additional configuration may be needed in addition to this.
@@ -1995,12 +2493,14 @@ impl<P: PIOExt> PIOBuilder<P> {
             in_shiftdir: ShiftDirection::Right,
             autopull: false,
             autopush: false,
+            in_count: 32,
             set_count: 5,
             out_count: 0,
             in_base: 0,
             side_set_base: 0,
             set_base: 0,
             out_base: 0,
+            gpio_base: None,
         }
     }
 
@@ -2029,12 +2529,14 @@ impl<P: PIOExt> PIOBuilder<P> {
             in_shiftdir: ShiftDirection::Left,
             autopull: false,
             autopush: false,
+            in_count: 32,
             set_count: 5,
             out_count: 0,
             in_base: 0,
             side_set_base: 0,
             set_base: 0,
             out_base: 0,
+            gpio_base: None,
         }
     }
 
@@ -2078,6 +2580,16 @@ impl<P: PIOExt> PIOBuilder<P> {
         self
     }
 
+    /// Set the number of pins visible to `IN PINS`, `MOV x, PINS` and `WAIT PIN` instructions.
+    ///
+    /// Pins beyond `count` read as zero, which saves masking them in the program. The default is
+    /// 32, i.e. all pins are visible.
+    pub fn in_count(mut self, count: u8) -> Self {
+        assert!(count != 0 && count <= 32);
+        self.in_count = count;
+        self
+    }
+
     /// Set the pin used by `JMP PIN` instruction.
     ///
     /// When the pin set by this function is high, the jump is taken, otherwise not.
@@ -2097,6 +2609,109 @@ impl<P: PIOExt> PIOBuilder<P> {
     }
     // TODO: Update documentation above.
 
+    /// Set the GPIO base of the PIO block the program will run on.
+    ///
+    /// The `*_checked` methods use it to translate GPIO numbers into PIO pin numbers, so it must be
+    /// called before them when the block is set to [`GpioBase::Gpio16`]. [`build`](Self::build)
+    /// panics if it does not match the block's [`PIO::gpio_base`].
+    pub fn gpio_base(mut self, base: GpioBase) -> Self {
+        self.gpio_base = Some(base);
+        self
+    }
+
+    /// Translate the GPIO number of `pin` into a pin number relative to the GPIO base.
+    fn pio_pin_index(&mut self, pin: &dyn PioPin<P>) -> u8 {
+        let base = *self.gpio_base.get_or_insert(GpioBase::Gpio0);
+        let num = pin.pin_num();
+        assert!(
+            num >= base.offset() && num - base.offset() < 32,
+            "GPIO is not visible to the PIO block with this GPIO base"
+        );
+        num - base.offset()
+    }
+
+    /// Check that `pins` are consecutive (modulo 32) and return the PIO pin number of the first one.
+    fn consecutive_pin_base(&mut self, pins: &[&dyn PioPin<P>]) -> u8 {
+        assert!(!pins.is_empty(), "at least one pin is required");
+        let base = self.pio_pin_index(pins[0]);
+        for (i, pin) in pins.iter().enumerate() {
+            assert!(
+                self.pio_pin_index(*pin) == (base + i as u8) % 32,
+                "PIO pins must be consecutive GPIOs"
+            );
+        }
+        base
+    }
+
+    /// Set the pins asserted by `SET` instruction from a list of consecutive GPIO pins.
+    ///
+    /// This is the typed counterpart of [`set_pins`](Self::set_pins): the pins must be set to this
+    /// PIO block's function, and the call panics if they are not consecutive or more than 5 pins
+    /// are given.
+    ///
+    /// ```no_run
+    /// # use rp235x_hal::{gpio::Pins, pac, pio::{PIOBuilder, PIOExt}, Sio};
+    /// # let mut pac = pac::Peripherals::take().unwrap();
+    /// # let sio = Sio::new(pac.SIO);
+    /// # let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+    /// # let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+    /// # let program = pio_proc::pio_asm!("set pins, 3").program;
+    /// # let installed = pio.install(&program).unwrap();
+    /// let led0 = pins.gpio2.into_function::<rp235x_hal::gpio::FunctionPio0>();
+    /// let led1 = pins.gpio3.into_function::<rp235x_hal::gpio::FunctionPio0>();
+    /// let (sm, _, _) = PIOBuilder::from_installed_program(installed)
+    ///     .set_pins_checked(&[&led0, &led1])
+    ///     .build(sm0);
+    /// ```
+    pub fn set_pins_checked(mut self, pins: &[&dyn PioPin<P>]) -> Self {
+        let base = self.consecutive_pin_base(pins);
+        self.set_pins(base, pins.len() as u8)
+    }
+
+    /// Set the pins asserted by `OUT` instruction from a list of consecutive GPIO pins.
+    ///
+    /// This is the typed counterpart of [`out_pins`](Self::out_pins). It panics if the pins are not
+    /// consecutive.
+    pub fn out_pins_checked(mut self, pins: &[&dyn PioPin<P>]) -> Self {
+        let base = self.consecutive_pin_base(pins);
+        self.out_pins(base, pins.len() as u8)
+    }
+
+    /// Set the pins used by `IN` instruction from a list of consecutive GPIO pins.
+    ///
+    /// This is the typed counterpart of [`in_pin_base`](Self::in_pin_base) and
+    /// [`in_count`](Self::in_count): only the given pins are visible to the state machine. It
+    /// panics if the pins are not consecutive.
+    pub fn in_pins_checked(mut self, pins: &[&dyn PioPin<P>]) -> Self {
+        let base = self.consecutive_pin_base(pins);
+        self.in_pin_base(base).in_count(pins.len() as u8)
+    }
+
+    /// Set the pin used by `JMP PIN` instruction.
+    ///
+    /// This is the typed counterpart of [`jmp_pin`](Self::jmp_pin).
+    pub fn jmp_pin_checked(mut self, pin: &dyn PioPin<P>) -> Self {
+        let index = self.pio_pin_index(pin);
+        self.jmp_pin(index)
+    }
+
+    /// Set the pins used by side-set instructions from a list of consecutive GPIO pins.
+    ///
+    /// This is the typed counterpart of [`side_set_pin_base`](Self::side_set_pin_base). It panics
+    /// if the pins are not consecutive, or if their number does not match the number of side-set
+    /// data bits of the installed program (excluding the optional enable bit).
+    pub fn side_set_pins_checked(mut self, pins: &[&dyn PioPin<P>]) -> Self {
+        let side_set = self.program.side_set;
+        let data_bits = side_set.bits() - u8::from(side_set.optional());
+        assert_eq!(
+            pins.len(),
+            usize::from(data_bits),
+            "side-set pin count must match the program's side-set bits"
+        );
+        let base = self.consecutive_pin_base(pins);
+        self.side_set_pin_base(base)
+    }
+
     /// Set buffer sharing.
     ///
     /// See [`Buffers`] for more information.
@@ -2202,6 +2817,16 @@ impl<P: PIOExt> PIOBuilder<P> {
     ) -> (StateMachine<(P, SM), Stopped>, Rx<(P, SM)>, Tx<(P, SM)>) {
         let offset = self.program.offset;
 
+        if let Some(base) = self.gpio_base {
+            // Safety: Read-only access to the block-wide GPIOBASE register.
+            let block_base = unsafe { (*sm.block).gpiobase().read().bits() } & 0x10;
+            assert_eq!(
+                u32::from(base.offset()),
+                block_base,
+                "PIO block GPIO base does not match the one used to configure the pins"
+            );
+        }
+
         // Stop the SM
         sm.set_enabled(false);
 
@@ -2246,13 +2871,21 @@ impl<P: PIOExt> PIOBuilder<P> {
             });
 
             sm.sm().sm_shiftctrl().write(|w| {
-                let (fjoin_rx, fjoin_tx) = match self.fifo_join {
-                    Buffers::RxTx => (false, false),
-                    Buffers::OnlyTx => (false, true),
-                    Buffers::OnlyRx => (true, false),
+                let (fjoin_rx, fjoin_tx, fjoin_rx_get, fjoin_rx_put) = match self.fifo_join {
+                    Buffers::RxTx => (false, false, false, false),
+                    Buffers::OnlyTx => (false, true, false, false),
+                    Buffers::OnlyRx => (true, false, false, false),
+                    Buffers::RxPut => (false, false, false, true),
+                    Buffers::RxGet => (false, false, true, false),
+                    Buffers::RxPutGet => (false, false, true, true),
                 };
                 w.fjoin_rx().bit(fjoin_rx);
                 w.fjoin_tx().bit(fjoin_tx);
+                w.fjoin_rx_get().bit(fjoin_rx_get);
+                w.fjoin_rx_put().bit(fjoin_rx_put);
+
+                // 32 is encoded as 0
+                w.in_count().bits(self.in_count & 0x1f);
 
                 // TODO: Encode 32 as zero, and error on 0
                 w.pull_thresh().bits(self.pull_threshold);
@@ -2311,4 +2944,30 @@ impl<P: PIOExt> PIOBuilder<P> {
             tx,
         )
     }
+
+    /// Build the config and deploy it to a state machine whose index is known at runtime.
+    #[allow(clippy::type_complexity)] // The return type cannot really be simplified.
+    pub fn build_dyn(
+        self,
+        sm: DynUninitStateMachine<P>,
+    ) -> (DynStateMachine<P, Stopped>, DynRx<P>, DynTx<P>) {
+        match sm {
+            DynUninitStateMachine::Sm0(sm) => {
+                let (sm, rx, tx) = self.build(sm);
+                (DynStateMachine::Sm0(sm), DynRx::Sm0(rx), DynTx::Sm0(tx))
+            }
+            DynUninitStateMachine::Sm1(sm) => {
+                let (sm, rx, tx) = self.build(sm);
+                (DynStateMachine::Sm1(sm), DynRx::Sm1(rx), DynTx::Sm1(tx))
+            }
+            DynUninitStateMachine::Sm2(sm) => {
+                let (sm, rx, tx) = self.build(sm);
+                (DynStateMachine::Sm2(sm), DynRx::Sm2(rx), DynTx::Sm2(tx))
+            }
+            DynUninitStateMachine::Sm3(sm) => {
+                let (sm, rx, tx) = self.build(sm);
+                (DynStateMachine::Sm3(sm), DynRx::Sm3(rx), DynTx::Sm3(tx))
+            }
+        }
+    }
 }
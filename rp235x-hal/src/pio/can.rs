@@ -0,0 +1,1501 @@
+//! CAN 2.0B controller implemented with PIO
+//!
+//! [`Can`] follows the design of [can2040]: the state machines handle the bit timing and the CPU
+//! handles the bit stuffing, the CRC and the fields of the frames, in [`Can::on_interrupt`].
+//! Three state machines are used:
+//! - the receiver samples the bus 16 times per bit, resynchronising on every edge, and pushes the
+//!   raw bits to the CPU 8 at a time,
+//! - the transmitter waits for the bus to be idle, then sends a frame prepared by the CPU. It
+//!   stops as soon as it reads a dominant bit while sending a recessive one, after losing the
+//!   arbitration or on a bit error,
+//! - the acknowledger drives the ACK slot when the last 32 bits received match the end of the
+//!   frame expected by the CPU. The CPU computes the CRC of a frame as soon as its data field is
+//!   received, so only frames with a correct CRC are acknowledged.
+//!
+//! Standard and extended, data and remote frames are supported. The frames received can be
+//! selected with up to [`MAX_FILTERS`] acceptance [`Filter`]s, and the receive and transmit
+//! error counters follow the rules of the CAN specification, up to going bus-off. Errors are only
+//! counted though: no error frame is sent to the other nodes.
+//!
+//! The CPU must keep up with the bus: the receiver FIFO holds 64 bits, and a frame can only be
+//! acknowledged if the end of its data field is processed before its CRC is received, 15 bits
+//! later. [`Can::on_interrupt`] is best called from the handler of a PIO interrupt enabled with
+//! [`Can::enable_interrupts`], it is also called by [`Can::transmit`] and [`Can::receive`].
+//!
+//! The three programs fill the instruction memory of the PIO block, the fourth state machine can
+//! only run one of them.
+//!
+//! The pins are connected to a CAN transceiver, like a TJA1051 or an SN65HVD230.
+//!
+//! With the `embedded-can` feature, [`Can`] implements `embedded_can::nb::Can` and [`Frame`]
+//! implements `embedded_can::Frame`.
+//!
+//! ```no_run
+//! use fugit::RateExtU32;
+//! use rp235x_hal::{
+//!     gpio::{FunctionPio0, Pins},
+//!     pac,
+//!     pio::{
+//!         can::{Can, Filter, Frame, Id},
+//!         PIOExt,
+//!     },
+//!     Sio,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let sio = Sio::new(pac.SIO);
+//! let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+//! let rx = pins.gpio4.into_function::<FunctionPio0>();
+//! let tx = pins.gpio5.into_function::<FunctionPio0>();
+//! let (mut pio, sm0, sm1, sm2, _) = pac.PIO0.split(&mut pac.RESETS);
+//! let mut can = Can::new(&mut pio, sm0, sm1, sm2, &rx, &tx, 500.kHz(), 150.MHz()).unwrap();
+//! // Only receive the standard identifiers 0x100 to 0x1ff.
+//! can.set_filters(&[Filter::standard(0x100, 0x700)]);
+//!
+//! let frame = Frame::new(Id::Standard(0x123), &[1, 2, 3]).unwrap();
+//! nb::block!(can.transmit(&frame)).unwrap();
+//! if let Ok(frame) = nb::block!(can.receive()) {
+//!     let _ = (frame.id(), frame.data());
+//! }
+//! ```
+//!
+//! [can2040]: https://github.com/KevinOConnor/can2040
+use fugit::HertzU32;
+use pio::{Assembler, InSource, JmpCondition, MovDestination, MovOperation, MovSource};
+use pio::{OutDestination, SetDestination, WaitSource};
+
+use super::{
+    InstallError, PIOBuilder, PIOExt, PinDir, PinState, PioIRQ, PioPin, Running, Rx,
+    ShiftDirection, StateMachine, StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+
+/// State machine cycles per bit.
+const CYCLES_PER_BIT: u32 = 16;
+
+/// Number of consecutive recessive bits after which the bus is idle: ACK delimiter, end of frame
+/// and intermission.
+const IDLE_BITS: u8 = 11;
+
+/// Generator polynomial of the CRC-15.
+const CRC_POLYNOMIAL: u16 = 0x4599;
+
+/// Length of the CRC field.
+const CRC_BITS: u8 = 15;
+
+/// Bits from the start of frame to the DLC of a standard frame.
+const STANDARD_HEADER: u8 = 19;
+
+/// Bits from the start of frame to the DLC of an extended frame.
+const EXTENDED_HEADER: u8 = 39;
+
+/// Words sent to the transmitter per frame: an 8-bit length and up to 148 stuffed bits.
+const FRAME_WORDS: usize = 5;
+
+/// Number of received frames buffered.
+const RX_QUEUE_LEN: usize = 8;
+
+/// Maximum number of acceptance filters.
+pub const MAX_FILTERS: usize = 8;
+
+/// Receiver program.
+///
+/// The bus is sampled 7 cycles after a falling edge is detected, then every 16 cycles until the
+/// next one. Only the recessive to dominant edges are used to resynchronise, as allowed by ISO
+/// 11898-1: the bit stuffing guarantees one every 10 bits.
+fn rx_program() -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut sample = a.label();
+    let mut high = a.label();
+    let mut poll = a.label();
+    let mut next = a.label();
+
+    // Falling edge, or start of the program.
+    a.bind(&mut wrap_target);
+    a.nop_with_delay(4);
+    a.bind(&mut sample);
+    a.r#in(InSource::PINS, 1);
+    // Raise flag 4 + the index of the state machine at each sample point.
+    a.irq(false, false, 4, true);
+    a.jmp(JmpCondition::PinHigh, &mut high);
+    a.jmp_with_delay(JmpCondition::Always, &mut sample, 12);
+    // Look for a falling edge until the next sample point.
+    a.bind(&mut high);
+    a.set_with_delay(SetDestination::X, 4, 1);
+    a.bind(&mut poll);
+    a.jmp(JmpCondition::PinHigh, &mut next);
+    a.jmp(JmpCondition::Always, &mut wrap_target);
+    a.bind(&mut next);
+    a.jmp(JmpCondition::XDecNonZero, &mut poll);
+    a.jmp(JmpCondition::Always, &mut sample);
+    a.bind(&mut wrap_source);
+
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Transmitter program.
+///
+/// Each frame starts with the number of bits minus one, on 8 bits, followed by the bits MSB
+/// first. The frame is sent once the bus has been recessive for 11 bits. Once done, the number of
+/// bits left is pushed: `u32::MAX` if all the bits were sent, or the index of the bit that read
+/// dominant while sending a recessive one, counted from the end. The rest of the frame is then
+/// left in the FIFO.
+fn tx_program() -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut idle = a.label();
+    let mut idle_loop = a.label();
+    let mut idle_high = a.label();
+    let mut bit_loop = a.label();
+    let mut dominant = a.label();
+    let mut next = a.label();
+    let mut done = a.label();
+
+    a.bind(&mut wrap_target);
+    a.pull(false, true);
+    a.out(OutDestination::Y, 8);
+    // Check the bus every half bit for 11 bits.
+    a.bind(&mut idle);
+    a.set(SetDestination::X, 21);
+    a.bind(&mut idle_loop);
+    a.jmp(JmpCondition::PinHigh, &mut idle_high);
+    a.jmp(JmpCondition::Always, &mut idle);
+    a.bind(&mut idle_high);
+    a.jmp_with_delay(JmpCondition::XDecNonZero, &mut idle_loop, 6);
+
+    a.bind(&mut bit_loop);
+    a.out(OutDestination::X, 1);
+    a.mov_with_delay(MovDestination::PINS, MovOperation::None, MovSource::X, 7);
+    a.jmp(JmpCondition::XIsZero, &mut dominant);
+    a.jmp(JmpCondition::PinHigh, &mut next);
+    a.jmp(JmpCondition::Always, &mut done);
+    a.bind(&mut dominant);
+    a.nop();
+    a.bind(&mut next);
+    a.jmp_with_delay(JmpCondition::YDecNonZero, &mut bit_loop, 4);
+    // Autopushed.
+    a.bind(&mut done);
+    a.r#in(InSource::Y, 32);
+    a.bind(&mut wrap_source);
+
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Acknowledger program, sampling the bus when the receiver raises `flag`.
+///
+/// The last 32 bits sampled are compared to the last word pulled: on a match, the bus is driven
+/// dominant for the next bit.
+fn ack_program(flag: u8) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+
+    a.bind(&mut wrap_target);
+    a.wait(1, WaitSource::IRQ, flag, false);
+    a.r#in(InSource::PINS, 1);
+    // Keep X if nothing was pulled.
+    a.pull(false, false);
+    a.mov(MovDestination::X, MovOperation::None, MovSource::OSR);
+    a.mov(MovDestination::Y, MovOperation::None, MovSource::ISR);
+    a.jmp(JmpCondition::XNotEqualY, &mut wrap_target);
+    a.set_with_delay(SetDestination::PINS, 0, 15);
+    a.set(SetDestination::PINS, 1);
+    a.bind(&mut wrap_source);
+
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Update a CRC-15 with a bit.
+fn crc15(crc: u16, bit: bool) -> u16 {
+    let crc = if ((crc >> 14) & 1 != 0) != bit {
+        (crc << 1) ^ CRC_POLYNOMIAL
+    } else {
+        crc << 1
+    };
+    crc & 0x7fff
+}
+
+/// Identifier of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Id {
+    /// 11-bit identifier.
+    Standard(u16),
+    /// 29-bit identifier.
+    Extended(u32),
+}
+
+impl Id {
+    fn is_valid(&self) -> bool {
+        match *self {
+            Id::Standard(id) => id <= 0x7ff,
+            Id::Extended(id) => id <= 0x1fff_ffff,
+        }
+    }
+}
+
+/// CAN 2.0 frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Frame {
+    id: Id,
+    remote: bool,
+    dlc: u8,
+    data: [u8; 8],
+}
+
+impl Frame {
+    /// Create a data frame. Returns `None` if the identifier is out of range or if there are more
+    /// than 8 bytes of data.
+    pub fn new(id: Id, data: &[u8]) -> Option<Self> {
+        if !id.is_valid() || data.len() > 8 {
+            return None;
+        }
+        let mut frame = Self {
+            id,
+            remote: false,
+            dlc: data.len() as u8,
+            data: [0; 8],
+        };
+        frame.data[..data.len()].copy_from_slice(data);
+        Some(frame)
+    }
+
+    /// Create a remote frame requesting `dlc` bytes. Returns `None` if the identifier is out of
+    /// range or if `dlc` is more than 8.
+    pub fn new_remote(id: Id, dlc: u8) -> Option<Self> {
+        if !id.is_valid() || dlc > 8 {
+            return None;
+        }
+        Some(Self {
+            id,
+            remote: true,
+            dlc,
+            data: [0; 8],
+        })
+    }
+
+    /// Identifier of the frame.
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    /// Is this a remote frame?
+    pub fn is_remote(&self) -> bool {
+        self.remote
+    }
+
+    /// Data length code. Frames received can have a code of 9 to 15, meaning 8 bytes.
+    pub fn dlc(&self) -> u8 {
+        self.dlc
+    }
+
+    /// Data of the frame, empty for a remote frame.
+    pub fn data(&self) -> &[u8] {
+        if self.remote {
+            &[]
+        } else {
+            &self.data[..usize::from(self.dlc.min(8))]
+        }
+    }
+
+    /// Bits from the start of frame to the end of the data field, with their number.
+    fn bits(&self) -> (u128, u8) {
+        let mut bits = 0;
+        let mut count = 0;
+        let mut put = |value: u32, len: u8| {
+            bits = (bits << len) | u128::from(value & ((1 << len) - 1));
+            count += len;
+        };
+        // Start of frame.
+        put(0, 1);
+        match self.id {
+            Id::Standard(id) => {
+                put(id.into(), 11);
+                put(self.remote.into(), 1);
+                // IDE, r0.
+                put(0, 2);
+            }
+            Id::Extended(id) => {
+                put(id >> 18, 11);
+                // SRR, IDE.
+                put(0b11, 2);
+                put(id, 18);
+                put(self.remote.into(), 1);
+                // r1, r0.
+                put(0, 2);
+            }
+        }
+        put(self.dlc.into(), 4);
+        for &byte in self.data() {
+            put(byte.into(), 8);
+        }
+        (bits, count)
+    }
+}
+
+/// Acceptance filter, selecting the frames received by their identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Filter {
+    id: Id,
+    mask: u32,
+}
+
+impl Filter {
+    /// Accept the standard frames whose identifier matches `id` on the bits set in `mask`.
+    pub fn standard(id: u16, mask: u16) -> Self {
+        Self {
+            id: Id::Standard(id),
+            mask: mask.into(),
+        }
+    }
+
+    /// Accept the extended frames whose identifier matches `id` on the bits set in `mask`.
+    pub fn extended(id: u32, mask: u32) -> Self {
+        Self {
+            id: Id::Extended(id),
+            mask,
+        }
+    }
+
+    fn accepts(&self, id: Id) -> bool {
+        match (self.id, id) {
+            (Id::Standard(a), Id::Standard(b)) => u32::from(a ^ b) & self.mask == 0,
+            (Id::Extended(a), Id::Extended(b)) => (a ^ b) & self.mask == 0,
+            _ => false,
+        }
+    }
+}
+
+/// CAN error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// Frames were lost, because the receive queue was full or the raw bits weren't processed in
+    /// time.
+    Overrun,
+    /// The transmit error counter went over 255. The node recovers after seeing the bus idle 128
+    /// times.
+    BusOff,
+}
+
+/// Error state of the node, derived from its error counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ErrorState {
+    /// Both counters are below 128.
+    Active,
+    /// One of the counters is 128 or more.
+    Passive,
+    /// The transmit error counter is over 255, the node doesn't transmit.
+    BusOff,
+}
+
+/// Receive and transmit error counters.
+#[derive(Debug, Default)]
+struct ErrorCounters {
+    rec: u8,
+    tec: u16,
+    /// Recessive bits in a row seen while bus-off.
+    recessive: u8,
+    /// Sequences of 11 recessive bits seen while bus-off.
+    idle: u8,
+}
+
+impl ErrorCounters {
+    fn state(&self) -> ErrorState {
+        if self.tec > 255 {
+            ErrorState::BusOff
+        } else if self.tec >= 128 || self.rec >= 128 {
+            ErrorState::Passive
+        } else {
+            ErrorState::Active
+        }
+    }
+
+    fn rx_ok(&mut self) {
+        self.rec = if self.rec > 127 {
+            127
+        } else {
+            self.rec.saturating_sub(1)
+        };
+    }
+
+    fn rx_error(&mut self) {
+        self.rec = self.rec.saturating_add(1);
+    }
+
+    fn tx_ok(&mut self) {
+        self.tec = self.tec.saturating_sub(1);
+    }
+
+    fn tx_error(&mut self) {
+        self.tec += 8;
+        self.recessive = 0;
+        self.idle = 0;
+    }
+
+    /// A frame wasn't acknowledged. The counter doesn't increase when error passive, so that a
+    /// node alone on the bus doesn't go bus-off.
+    fn ack_error(&mut self) {
+        if self.state() == ErrorState::Active {
+            self.tx_error();
+        }
+    }
+
+    /// Count a bit seen while bus-off, returning whether the node recovered.
+    fn bus_off_bit(&mut self, bit: bool) -> bool {
+        self.recessive = if bit { self.recessive + 1 } else { 0 };
+        if self.recessive == IDLE_BITS {
+            self.recessive = 0;
+            self.idle += 1;
+            if self.idle == 128 {
+                *self = Self::default();
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Bit stuffing state: a bit of the opposite level is inserted after 5 identical bits.
+#[derive(Debug, Clone, Copy)]
+struct Stuffer {
+    last: bool,
+    run: u8,
+}
+
+impl Stuffer {
+    /// The bus is recessive before the start of frame.
+    fn new() -> Self {
+        Self { last: true, run: 0 }
+    }
+
+    fn count(&mut self, bit: bool) {
+        if bit == self.last {
+            self.run += 1;
+        } else {
+            self.last = bit;
+            self.run = 1;
+        }
+    }
+
+    /// Emit `bit`, followed by a stuff bit if it is the fifth identical bit in a row.
+    fn push(&mut self, bit: bool, mut emit: impl FnMut(bool)) {
+        emit(bit);
+        self.count(bit);
+        if self.run == 5 {
+            emit(!bit);
+            self.count(!bit);
+        }
+    }
+}
+
+/// Bits of a frame for the transmitter, see [`tx_program`].
+#[derive(Debug, Clone, Copy)]
+struct Encoded {
+    words: [u32; FRAME_WORDS],
+    /// Bits up to the end of the arbitration field, losing on them isn't an error.
+    arbitration_bits: u8,
+}
+
+impl Encoded {
+    fn new(frame: &Frame) -> Self {
+        let (bits, count) = frame.bits();
+        let crc = (0..count)
+            .rev()
+            .fold(0, |crc, i| crc15(crc, (bits >> i) & 1 != 0));
+        let (bits, count) = ((bits << CRC_BITS) | u128::from(crc), count + CRC_BITS);
+        let arbitration = match frame.id {
+            Id::Standard(_) => 13,
+            Id::Extended(_) => 33,
+        };
+
+        let mut writer = Writer {
+            words: [0; FRAME_WORDS],
+            len: 8,
+        };
+        let mut stuffer = Stuffer::new();
+        let mut arbitration_bits = 0;
+        for i in (0..count).rev() {
+            stuffer.push((bits >> i) & 1 != 0, |bit| writer.put(bit));
+            if count - i == arbitration {
+                arbitration_bits = writer.len - 8;
+            }
+        }
+        // CRC delimiter.
+        writer.put(true);
+        writer.words[0] |= (writer.len as u32 - 9) << 24;
+        Self {
+            words: writer.words,
+            arbitration_bits: arbitration_bits as u8,
+        }
+    }
+
+    /// Number of bits sent.
+    fn len(&self) -> u32 {
+        (self.words[0] >> 24) + 1
+    }
+}
+
+/// Words filled MSB first.
+struct Writer {
+    words: [u32; FRAME_WORDS],
+    len: usize,
+}
+
+impl Writer {
+    fn put(&mut self, bit: bool) {
+        if bit {
+            self.words[self.len / 32] |= 1 << (31 - self.len % 32);
+        }
+        self.len += 1;
+    }
+}
+
+/// Error detected while receiving a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BusError {
+    Stuff,
+    Crc,
+    Form,
+}
+
+/// Event produced by the [`Decoder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Event {
+    /// The data field of `frame` was received, it must be acknowledged if the last 32 bits up
+    /// to the CRC delimiter are `expected`.
+    DataComplete { frame: Frame, expected: u32 },
+    /// A frame was received, and acknowledged by a node if `acked`.
+    Received { frame: Frame, acked: bool },
+    /// The frame being received is invalid.
+    Error(BusError),
+}
+
+/// Field expected next by the [`Decoder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// Waiting for 11 recessive bits.
+    WaitIdle,
+    /// Waiting for a start of frame.
+    Idle,
+    /// Stuffed part of the frame, from the start of frame to the end of the CRC.
+    Stuffed,
+    CrcDelimiter,
+    AckSlot,
+    AckDelimiter,
+    /// End of frame, with the number of bits received.
+    Eof(u8),
+}
+
+/// Decoder of the raw bits received.
+#[derive(Debug)]
+struct Decoder {
+    field: Field,
+    /// Last 32 raw bits received.
+    history: u32,
+    /// Recessive bits in a row.
+    recessive: u8,
+    stuffer: Stuffer,
+    /// Bits of the frame without stuffing, and their number.
+    bits: u128,
+    count: u8,
+    /// Length of the frame up to the DLC, and up to the end of the CRC, once known.
+    header_len: u8,
+    total_len: u8,
+    crc: u16,
+    acked: bool,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        Self {
+            field: Field::WaitIdle,
+            history: 0,
+            recessive: 0,
+            stuffer: Stuffer::new(),
+            bits: 0,
+            count: 0,
+            header_len: 0,
+            total_len: 0,
+            crc: 0,
+            acked: false,
+        }
+    }
+
+    /// Forget the frame being received.
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Process 8 bits, MSB first, at once if the bus is idle. Returns `false` otherwise.
+    fn skip_idle(&mut self, byte: u32) -> bool {
+        if self.field != Field::Idle || byte & 0xff != 0xff {
+            return false;
+        }
+        self.history = (self.history << 8) | 0xff;
+        self.recessive = self.recessive.saturating_add(8);
+        true
+    }
+
+    fn push(&mut self, bit: bool) -> Option<Event> {
+        self.history = (self.history << 1) | u32::from(bit);
+        self.recessive = if bit {
+            self.recessive.saturating_add(1)
+        } else {
+            0
+        };
+        match self.field {
+            Field::WaitIdle => {
+                if self.recessive >= IDLE_BITS {
+                    self.field = Field::Idle;
+                }
+                None
+            }
+            Field::Idle => {
+                if bit {
+                    return None;
+                }
+                *self = Self {
+                    field: Field::Stuffed,
+                    history: self.history,
+                    ..Self::new()
+                };
+                self.stuffer.count(bit);
+                self.unstuffed(bit)
+            }
+            Field::Stuffed => {
+                if self.stuffer.run == 5 {
+                    if bit == self.stuffer.last {
+                        return self.error(BusError::Stuff);
+                    }
+                    self.stuffer.count(bit);
+                    if self.count == self.total_len {
+                        self.field = Field::CrcDelimiter;
+                    }
+                    return None;
+                }
+                self.stuffer.count(bit);
+                self.unstuffed(bit)
+            }
+            Field::CrcDelimiter => self.expect_recessive(bit, Field::AckSlot),
+            Field::AckSlot => {
+                self.acked = !bit;
+                self.field = Field::AckDelimiter;
+                None
+            }
+            Field::AckDelimiter => self.expect_recessive(bit, Field::Eof(0)),
+            // The frame is valid once the last but one bit of the end of frame is received.
+            Field::Eof(5) if bit => {
+                self.field = Field::WaitIdle;
+                Some(Event::Received {
+                    frame: self.frame(),
+                    acked: self.acked,
+                })
+            }
+            Field::Eof(n) => self.expect_recessive(bit, Field::Eof(n + 1)),
+        }
+    }
+
+    fn expect_recessive(&mut self, bit: bool, next: Field) -> Option<Event> {
+        if !bit {
+            return self.error(BusError::Form);
+        }
+        self.field = next;
+        None
+    }
+
+    fn error(&mut self, error: BusError) -> Option<Event> {
+        self.field = Field::WaitIdle;
+        Some(Event::Error(error))
+    }
+
+    fn unstuffed(&mut self, bit: bool) -> Option<Event> {
+        self.bits = (self.bits << 1) | u128::from(bit);
+        self.count += 1;
+        if self.total_len == 0 || self.count <= self.total_len - CRC_BITS {
+            self.crc = crc15(self.crc, bit);
+        }
+        // IDE bit.
+        if self.count == 14 {
+            self.header_len = if bit {
+                EXTENDED_HEADER
+            } else {
+                STANDARD_HEADER
+            };
+        }
+        if self.count == self.header_len {
+            let frame = self.frame();
+            self.total_len = self.header_len + 8 * frame.data().len() as u8 + CRC_BITS;
+        }
+        if self.total_len == 0 {
+            return None;
+        }
+        if self.count == self.total_len - CRC_BITS {
+            return Some(Event::DataComplete {
+                frame: self.frame(),
+                expected: self.expected(),
+            });
+        }
+        if self.count == self.total_len {
+            if self.bits as u16 & 0x7fff != self.crc {
+                return self.error(BusError::Crc);
+            }
+            // Otherwise, a stuff bit follows the CRC.
+            if self.stuffer.run != 5 {
+                self.field = Field::CrcDelimiter;
+            }
+        }
+        None
+    }
+
+    /// Field of the frame starting at bit `start`.
+    fn field(&self, start: u8, len: u8) -> u32 {
+        (self.bits >> (self.count - start - len)) as u32 & ((1 << len) - 1)
+    }
+
+    /// Frame received, once its header is complete.
+    fn frame(&self) -> Frame {
+        let (id, remote) = if self.header_len == EXTENDED_HEADER {
+            let id = (self.field(1, 11) << 18) | self.field(14, 18);
+            (Id::Extended(id), self.field(32, 1) != 0)
+        } else {
+            (
+                Id::Standard(self.field(1, 11) as u16),
+                self.field(12, 1) != 0,
+            )
+        };
+        let mut frame = Frame {
+            id,
+            remote,
+            dlc: self.field(self.header_len - 4, 4) as u8,
+            data: [0; 8],
+        };
+        let len = frame.data().len();
+        for (i, byte) in frame.data[..len].iter_mut().enumerate() {
+            if self.count >= self.header_len + 8 * (i as u8 + 1) {
+                *byte = self.field(self.header_len + 8 * i as u8, 8) as u8;
+            }
+        }
+        frame
+    }
+
+    /// Last 32 bits expected up to the CRC delimiter, once the data field is received.
+    fn expected(&self) -> u32 {
+        let mut expected = self.history;
+        let mut put = |bit: bool| expected = (expected << 1) | u32::from(bit);
+        let mut stuffer = self.stuffer;
+        if stuffer.run == 5 {
+            put(!stuffer.last);
+            stuffer.count(!stuffer.last);
+        }
+        for i in (0..CRC_BITS).rev() {
+            stuffer.push((self.crc >> i) & 1 != 0, &mut put);
+        }
+        put(true);
+        expected
+    }
+}
+
+/// Progress of the frame being transmitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TxState {
+    Idle,
+    /// The transmitter is waiting for the bus to be idle, or sending the frame.
+    Sending,
+    /// The frame was sent, the acknowledgement is checked when it is received.
+    Sent,
+}
+
+/// CAN 2.0B node on three PIO state machines.
+pub struct Can<P: PIOExt, RX: StateMachineIndex, TX: StateMachineIndex, ACK: StateMachineIndex> {
+    rx_sm: StateMachine<(P, RX), Running>,
+    rx: Rx<(P, RX)>,
+    rx_tx: Tx<(P, RX)>,
+    tx_sm: StateMachine<(P, TX), Running>,
+    tx_status: Rx<(P, TX)>,
+    tx: Tx<(P, TX)>,
+    ack_sm: StateMachine<(P, ACK), Running>,
+    ack_rx: Rx<(P, ACK)>,
+    ack: Tx<(P, ACK)>,
+    decoder: Decoder,
+    counters: ErrorCounters,
+    tx_state: TxState,
+    pending: Option<(Frame, Encoded)>,
+    queue: [Frame; RX_QUEUE_LEN],
+    queue_start: usize,
+    queue_len: usize,
+    filters: [Filter; MAX_FILTERS],
+    filter_count: usize,
+    overrun: bool,
+}
+
+impl<P: PIOExt, RX: StateMachineIndex, TX: StateMachineIndex, ACK: StateMachineIndex>
+    Can<P, RX, TX, ACK>
+{
+    /// Install the programs and start the receiver on `rx_sm`, the transmitter on `tx_sm` and the
+    /// acknowledger on `ack_sm`.
+    ///
+    /// `system_clock` is the frequency of the system clock, used to derive the bit timing. It
+    /// must be at least 16 times `bitrate`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pio: &mut PIO<P>,
+        rx_sm: UninitStateMachine<(P, RX)>,
+        tx_sm: UninitStateMachine<(P, TX)>,
+        ack_sm: UninitStateMachine<(P, ACK)>,
+        rx_pin: &dyn PioPin<P>,
+        tx_pin: &dyn PioPin<P>,
+        bitrate: HertzU32,
+        system_clock: HertzU32,
+    ) -> Result<Self, InstallError> {
+        let rx_program = pio.install(&rx_program())?;
+        let tx_program = match pio.install(&tx_program()) {
+            Ok(program) => program,
+            Err(e) => {
+                pio.uninstall(rx_program);
+                return Err(e);
+            }
+        };
+        let ack_program = match pio.install(&ack_program(4 + RX::id() as u8)) {
+            Ok(program) => program,
+            Err(e) => {
+                pio.uninstall(rx_program);
+                pio.uninstall(tx_program);
+                return Err(e);
+            }
+        };
+        let divisor = (u64::from(system_clock.to_Hz()) << 8)
+            / (u64::from(bitrate.to_Hz()) * u64::from(CYCLES_PER_BIT));
+        let (int, frac) = ((divisor >> 8) as u16, divisor as u8);
+        let gpio_base = pio.gpio_base();
+
+        let (rx_sm, rx, rx_tx) = PIOBuilder::from_installed_program(rx_program)
+            .gpio_base(gpio_base)
+            .in_pins_checked(&[rx_pin])
+            .jmp_pin_checked(rx_pin)
+            .in_shift_direction(ShiftDirection::Left)
+            .autopush(true)
+            .push_threshold(8)
+            .buffers(super::Buffers::OnlyRx)
+            .clock_divisor_fixed_point(int, frac)
+            .build(rx_sm);
+
+        let (mut tx_sm, tx_status, tx) = PIOBuilder::from_installed_program(tx_program)
+            .gpio_base(gpio_base)
+            .out_pins_checked(&[tx_pin])
+            .set_pins_checked(&[tx_pin])
+            .jmp_pin_checked(rx_pin)
+            .out_shift_direction(ShiftDirection::Left)
+            .autopull(true)
+            .autopush(true)
+            .clock_divisor_fixed_point(int, frac)
+            .build(tx_sm);
+        // Keep the bus recessive until a frame is sent.
+        tx_sm.set_pins([(tx_pin.pin_num() - gpio_base.offset(), PinState::High)]);
+        tx_sm.set_pindirs([(tx_pin.pin_num() - gpio_base.offset(), PinDir::Output)]);
+
+        let (mut ack_sm, ack_rx, ack) = PIOBuilder::from_installed_program(ack_program)
+            .gpio_base(gpio_base)
+            .in_pins_checked(&[rx_pin])
+            .set_pins_checked(&[tx_pin])
+            .in_shift_direction(ShiftDirection::Left)
+            .clock_divisor_fixed_point(int, frac)
+            .build(ack_sm);
+        // `mov isr, ~null`: the bus is assumed idle, the disarmed value of X can't match.
+        ack_sm.exec_instruction(pio::Instruction {
+            operands: pio::InstructionOperands::MOV {
+                destination: MovDestination::ISR,
+                op: MovOperation::Invert,
+                source: MovSource::NULL,
+            },
+            delay: 0,
+            side_set: None,
+        });
+
+        Ok(Self {
+            rx_sm: rx_sm.start(),
+            rx,
+            rx_tx,
+            tx_sm: tx_sm.start(),
+            tx_status,
+            tx,
+            ack_sm: ack_sm.start(),
+            ack_rx,
+            ack,
+            decoder: Decoder::new(),
+            counters: ErrorCounters::default(),
+            tx_state: TxState::Idle,
+            pending: None,
+            queue: [Frame {
+                id: Id::Standard(0),
+                remote: false,
+                dlc: 0,
+                data: [0; 8],
+            }; RX_QUEUE_LEN],
+            queue_start: 0,
+            queue_len: 0,
+            filters: [Filter::standard(0, 0); MAX_FILTERS],
+            filter_count: 0,
+            overrun: false,
+        })
+    }
+
+    /// Stop the node, uninstall its programs and return the state machines.
+    #[allow(clippy::type_complexity)]
+    pub fn free(
+        self,
+        pio: &mut PIO<P>,
+    ) -> (
+        UninitStateMachine<(P, RX)>,
+        UninitStateMachine<(P, TX)>,
+        UninitStateMachine<(P, ACK)>,
+    ) {
+        let (rx_sm, rx_program) = self.rx_sm.uninit(self.rx, self.rx_tx);
+        let (tx_sm, tx_program) = self.tx_sm.uninit(self.tx_status, self.tx);
+        let (ack_sm, ack_program) = self.ack_sm.uninit(self.ack_rx, self.ack);
+        pio.uninstall(rx_program);
+        pio.uninstall(tx_program);
+        pio.uninstall(ack_program);
+        (rx_sm, tx_sm, ack_sm)
+    }
+
+    /// Enable the interrupts calling for [`Can::on_interrupt`] on `irq`.
+    pub fn enable_interrupts(&self, irq: PioIRQ) {
+        self.rx.enable_rx_not_empty_interrupt(irq);
+        self.tx_status.enable_rx_not_empty_interrupt(irq);
+    }
+
+    /// Disable the interrupts enabled by [`Can::enable_interrupts`].
+    pub fn disable_interrupts(&self, irq: PioIRQ) {
+        self.rx.disable_rx_not_empty_interrupt(irq);
+        self.tx_status.disable_rx_not_empty_interrupt(irq);
+    }
+
+    /// Only receive the frames accepted by one of `filters`, or all the frames if empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than [`MAX_FILTERS`] filters.
+    pub fn set_filters(&mut self, filters: &[Filter]) {
+        assert!(filters.len() <= MAX_FILTERS, "too many filters");
+        self.filters[..filters.len()].copy_from_slice(filters);
+        self.filter_count = filters.len();
+    }
+
+    /// Receive error counter.
+    pub fn receive_error_count(&self) -> u8 {
+        self.counters.rec
+    }
+
+    /// Transmit error counter.
+    pub fn transmit_error_count(&self) -> u16 {
+        self.counters.tec
+    }
+
+    /// Error state of the node.
+    pub fn error_state(&self) -> ErrorState {
+        self.counters.state()
+    }
+
+    /// Is a frame waiting to be sent or acknowledged?
+    pub fn is_transmitting(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Process the bits received and the result of the transmissions.
+    pub fn on_interrupt(&mut self) {
+        while let Some(status) = self.tx_status.read() {
+            self.transmitted(status);
+        }
+        while let Some(byte) = self.rx.read() {
+            if self.counters.state() == ErrorState::BusOff {
+                let recovered = (0..8).rev().fold(false, |r, i| {
+                    self.counters.bus_off_bit((byte >> i) & 1 != 0) | r
+                });
+                if recovered {
+                    self.start_tx();
+                }
+            }
+            if self.decoder.skip_idle(byte) {
+                continue;
+            }
+            for i in (0..8).rev() {
+                if let Some(event) = self.decoder.push((byte >> i) & 1 != 0) {
+                    self.handle(event);
+                }
+            }
+        }
+        // Only check the flag once the FIFO is drained, the state machine sets it again as long as
+        // the FIFO is full.
+        if self.rx.has_stalled() {
+            // Bits were lost, the frame being received can't be decoded.
+            self.rx.clear_stalled_flag();
+            self.decoder.reset();
+            self.overrun = true;
+            if self.tx_state == TxState::Sent {
+                self.start_tx();
+            }
+        }
+    }
+
+    /// Queue a frame for transmission.
+    ///
+    /// Returns `WouldBlock` while the previous frame isn't sent and acknowledged. It is sent
+    /// again until it is, after losing the arbitration or on errors.
+    pub fn transmit(&mut self, frame: &Frame) -> nb::Result<(), Error> {
+        self.on_interrupt();
+        if self.counters.state() == ErrorState::BusOff {
+            return Err(nb::Error::Other(Error::BusOff));
+        }
+        if self.pending.is_some() {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.pending = Some((*frame, Encoded::new(frame)));
+        self.start_tx();
+        Ok(())
+    }
+
+    /// Take the oldest frame received.
+    ///
+    /// Returns [`Error::Overrun`] once after frames were lost.
+    pub fn receive(&mut self) -> nb::Result<Frame, Error> {
+        self.on_interrupt();
+        if core::mem::take(&mut self.overrun) {
+            return Err(nb::Error::Other(Error::Overrun));
+        }
+        if self.queue_len == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        let frame = self.queue[self.queue_start];
+        self.queue_start = (self.queue_start + 1) % RX_QUEUE_LEN;
+        self.queue_len -= 1;
+        Ok(frame)
+    }
+
+    fn start_tx(&mut self) {
+        self.tx_state = TxState::Idle;
+        if self.counters.state() == ErrorState::BusOff {
+            return;
+        }
+        if let Some((_, encoded)) = &self.pending {
+            for &word in &encoded.words {
+                while !self.tx.write(word) {}
+            }
+            self.tx_state = TxState::Sending;
+        }
+    }
+
+    fn transmitted(&mut self, status: u32) {
+        let Some((_, encoded)) = self.pending else {
+            return;
+        };
+        if self.tx_state != TxState::Sending {
+            return;
+        }
+        if status == u32::MAX {
+            self.tx_state = TxState::Sent;
+            return;
+        }
+        if encoded.len() - 1 - status >= encoded.arbitration_bits.into() {
+            self.counters.tx_error();
+        }
+        // Drop the rest of the frame. The transmitter is waiting for the bus to be idle, while the
+        // frame winning the arbitration is sent.
+        self.tx_sm.clear_fifos();
+        self.tx_sm.restart();
+        self.start_tx();
+    }
+
+    fn is_own(&self, frame: &Frame) -> bool {
+        self.tx_state != TxState::Idle && matches!(self.pending, Some((own, _)) if own == *frame)
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::DataComplete { frame, expected } => {
+                if !self.is_own(&frame) {
+                    self.ack.write(expected);
+                }
+            }
+            Event::Received { frame, acked } if self.is_own(&frame) => {
+                if acked {
+                    self.counters.tx_ok();
+                    self.pending = None;
+                    self.tx_state = TxState::Idle;
+                } else {
+                    self.counters.ack_error();
+                    self.start_tx();
+                }
+            }
+            Event::Received { frame, .. } => {
+                self.counters.rx_ok();
+                let filters = &self.filters[..self.filter_count];
+                if !filters.is_empty() && !filters.iter().any(|f| f.accepts(frame.id)) {
+                    return;
+                }
+                if self.queue_len == RX_QUEUE_LEN {
+                    self.overrun = true;
+                    return;
+                }
+                self.queue[(self.queue_start + self.queue_len) % RX_QUEUE_LEN] = frame;
+                self.queue_len += 1;
+            }
+            Event::Error(_) => {
+                if self.tx_state == TxState::Sent {
+                    self.counters.tx_error();
+                    self.start_tx();
+                } else {
+                    self.counters.rx_error();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "embedded-can")]
+impl From<embedded_can::Id> for Id {
+    fn from(id: embedded_can::Id) -> Self {
+        match id {
+            embedded_can::Id::Standard(id) => Id::Standard(id.as_raw()),
+            embedded_can::Id::Extended(id) => Id::Extended(id.as_raw()),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-can")]
+impl From<Id> for embedded_can::Id {
+    fn from(id: Id) -> Self {
+        // Frames can only be created with valid identifiers.
+        match id {
+            Id::Standard(id) => embedded_can::StandardId::new(id).unwrap().into(),
+            Id::Extended(id) => embedded_can::ExtendedId::new(id).unwrap().into(),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-can")]
+impl embedded_can::Frame for Frame {
+    fn new(id: impl Into<embedded_can::Id>, data: &[u8]) -> Option<Self> {
+        Frame::new(id.into().into(), data)
+    }
+
+    fn new_remote(id: impl Into<embedded_can::Id>, dlc: usize) -> Option<Self> {
+        Frame::new_remote(id.into().into(), dlc.try_into().ok()?)
+    }
+
+    fn is_extended(&self) -> bool {
+        matches!(self.id, Id::Extended(_))
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        self.remote
+    }
+
+    fn id(&self) -> embedded_can::Id {
+        self.id.into()
+    }
+
+    fn dlc(&self) -> usize {
+        self.dlc.into()
+    }
+
+    fn data(&self) -> &[u8] {
+        Frame::data(self)
+    }
+}
+
+#[cfg(feature = "embedded-can")]
+impl embedded_can::Error for Error {
+    fn kind(&self) -> embedded_can::ErrorKind {
+        match self {
+            Error::Overrun => embedded_can::ErrorKind::Overrun,
+            Error::BusOff => embedded_can::ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(feature = "embedded-can")]
+impl<P: PIOExt, RX: StateMachineIndex, TX: StateMachineIndex, ACK: StateMachineIndex>
+    embedded_can::nb::Can for Can<P, RX, TX, ACK>
+{
+    type Frame = Frame;
+    type Error = Error;
+
+    fn transmit(&mut self, frame: &Frame) -> nb::Result<Option<Frame>, Error> {
+        Can::transmit(self, frame).map(|()| None)
+    }
+
+    fn receive(&mut self) -> nb::Result<Frame, Error> {
+        Can::receive(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pac;
+    use crate::pio::emulator::Emulator;
+
+    /// Stuffed bits of a frame, up to the CRC delimiter.
+    fn raw_bits(frame: &Frame) -> impl Iterator<Item = bool> {
+        let encoded = Encoded::new(frame);
+        (8..8 + encoded.len() as usize)
+            .map(move |i| encoded.words[i / 32] & (1 << (31 - i % 32)) != 0)
+    }
+
+    /// Feed bits to the decoder, collecting the events.
+    fn decode(decoder: &mut Decoder, bits: impl Iterator<Item = bool>) -> [Option<Event>; 4] {
+        let mut events = [None; 4];
+        let mut count = 0;
+        for bit in bits {
+            if let Some(event) = decoder.push(bit) {
+                events[count] = Some(event);
+                count += 1;
+            }
+        }
+        events
+    }
+
+    fn idle_decoder() -> Decoder {
+        let mut decoder = Decoder::new();
+        decode(&mut decoder, [true; 11].into_iter());
+        assert_eq!(decoder.field, Field::Idle);
+        decoder
+    }
+
+    #[test]
+    fn programs_fit() {
+        let len = rx_program().code.len() + tx_program().code.len() + ack_program(4).code.len();
+        assert_eq!(len, 32);
+    }
+
+    #[test]
+    fn crc() {
+        let crc = b"123456789".iter().fold(0, |crc, &byte| {
+            (0..8)
+                .rev()
+                .fold(crc, |crc, i| crc15(crc, (byte >> i) & 1 != 0))
+        });
+        assert_eq!(crc, 0x059e);
+    }
+
+    #[test]
+    fn stuffing() {
+        let mut stuffer = Stuffer::new();
+        let mut bits = 0u32;
+        let mut count = 0;
+        for bit in [
+            false, false, false, false, false, false, true, true, true, true, true,
+        ] {
+            stuffer.push(bit, |bit| {
+                bits = (bits << 1) | u32::from(bit);
+                count += 1;
+            });
+        }
+        // 00000 1 0 11111 0
+        assert_eq!((bits, count), (0b0_0000_1011_1110, 13));
+        // Frames with long runs of identical bits get stuff bits, and the longest frame fits.
+        let frame = Frame::new(Id::Extended(0x1fff_ffff), &[0xff; 8]).unwrap();
+        let encoded = Encoded::new(&frame);
+        assert!(encoded.len() > 118 + 1);
+        assert!(encoded.len() as usize <= FRAME_WORDS * 32 - 8);
+        assert_eq!(
+            Encoded::new(&Frame::new(Id::Standard(0), &[]).unwrap()).arbitration_bits,
+            15
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for frame in [
+            Frame::new(Id::Standard(0x123), &[0xde, 0xad, 0xbe, 0xef]).unwrap(),
+            Frame::new(Id::Standard(0), &[0; 8]).unwrap(),
+            Frame::new(Id::Extended(0x1abc_def0), &[0xff, 0x00, 0x55]).unwrap(),
+            Frame::new_remote(Id::Extended(0x7ff), 4).unwrap(),
+            Frame::new_remote(Id::Standard(0x7ff), 0).unwrap(),
+        ] {
+            let mut decoder = idle_decoder();
+            let mut expected = None;
+            for bit in raw_bits(&frame) {
+                if let Some(event) = decoder.push(bit) {
+                    match event {
+                        Event::DataComplete {
+                            frame: f,
+                            expected: e,
+                        } => {
+                            assert_eq!(f, frame);
+                            expected = Some(e);
+                        }
+                        event => panic!("unexpected {:?}", event),
+                    }
+                }
+            }
+            // The acknowledger matches at the CRC delimiter.
+            assert_eq!(expected, Some(decoder.history));
+            assert_eq!(decoder.field, Field::AckSlot);
+
+            let end = [false, true, true, true, true, true, true, true];
+            let events = decode(&mut decoder, end.into_iter());
+            assert_eq!(events[0], Some(Event::Received { frame, acked: true }));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let frame = Frame::new(Id::Standard(0x555), &[0x12, 0x34]).unwrap();
+        let len = raw_bits(&frame).count();
+
+        // A flipped CRC bit.
+        let mut decoder = idle_decoder();
+        let bits = raw_bits(&frame)
+            .enumerate()
+            .map(|(i, bit)| bit ^ (i == len - 3));
+        let events = decode(&mut decoder, bits);
+        assert!(matches!(events[0], Some(Event::DataComplete { .. })));
+        assert!(matches!(
+            events[1],
+            Some(Event::Error(BusError::Crc | BusError::Stuff))
+        ));
+
+        // 6 dominant bits, like an error flag.
+        let mut decoder = idle_decoder();
+        let bits = raw_bits(&frame).take(20).chain([false; 6]);
+        let events = decode(&mut decoder, bits);
+        assert_eq!(events[0], Some(Event::Error(BusError::Stuff)));
+        assert_eq!(decoder.field, Field::WaitIdle);
+
+        // Dominant end of frame.
+        let mut decoder = idle_decoder();
+        let bits = raw_bits(&frame).chain([true, true, true, false]);
+        let events = decode(&mut decoder, bits);
+        assert_eq!(events[1], Some(Event::Error(BusError::Form)));
+    }
+
+    #[test]
+    fn counters() {
+        let mut counters = ErrorCounters::default();
+        for _ in 0..16 {
+            counters.tx_error();
+        }
+        assert_eq!(counters.state(), ErrorState::Passive);
+        counters.ack_error();
+        assert_eq!(counters.tec, 128);
+        for _ in 0..16 {
+            counters.tx_error();
+        }
+        assert_eq!(counters.state(), ErrorState::BusOff);
+        for i in 0..128 * 11 {
+            assert_eq!(counters.bus_off_bit(true), i == 128 * 11 - 1);
+        }
+        assert_eq!(counters.state(), ErrorState::Active);
+        assert_eq!(counters.tec, 0);
+
+        let filter = Filter::standard(0x100, 0x700);
+        assert!(filter.accepts(Id::Standard(0x1ab)));
+        assert!(!filter.accepts(Id::Standard(0x2ab)));
+        assert!(!filter.accepts(Id::Extended(0x1ab)));
+    }
+
+    #[test]
+    fn loopback() {
+        let frame = Frame::new(Id::Extended(0x0123_4567), &[0x00, 0xff, 0x81]).unwrap();
+        let encoded = Encoded::new(&frame);
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let tx_program = pio.install(&tx_program()).unwrap();
+        let rx_program = pio.install(&rx_program()).unwrap();
+        let mut tx = pio.build(
+            PIOBuilder::from_installed_program(tx_program)
+                .out_pins(0, 1)
+                .set_pins(0, 1)
+                .jmp_pin(1)
+                .out_shift_direction(ShiftDirection::Left)
+                .autopull(true)
+                .autopush(true),
+        );
+        tx.set_pindirs(1);
+        // `set pins, 1`
+        tx.exec_instruction(0xe001);
+        let mut rx = pio.build(
+            PIOBuilder::from_installed_program(rx_program)
+                .in_pin_base(1)
+                .jmp_pin(1)
+                .in_shift_direction(ShiftDirection::Left)
+                .autopush(true)
+                .push_threshold(8),
+        );
+
+        let mut words = encoded.words.into_iter().peekable();
+        let mut decoder = Decoder::new();
+        let mut received = None;
+        let mut status = None;
+        for _ in 0..(11 + encoded.len() + 20) * CYCLES_PER_BIT {
+            let bus = tx.pin_levels() & 1 != 0;
+            tx.set_input(1, bus);
+            rx.set_input(1, bus);
+            if words.peek().is_some_and(|&word| tx.push_tx(word)) {
+                words.next();
+            }
+            tx.step();
+            rx.step();
+            status = status.or(tx.pop_rx());
+            while let Some(byte) = rx.pop_rx() {
+                for i in (0..8).rev() {
+                    match decoder.push((byte >> i) & 1 != 0) {
+                        Some(Event::Received { frame, acked }) => received = Some((frame, acked)),
+                        Some(Event::Error(e)) => panic!("{:?}", e),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        assert_eq!(status, Some(u32::MAX));
+        // Nobody acknowledged it.
+        assert_eq!(received, Some((frame, false)));
+    }
+
+    #[test]
+    fn arbitration() {
+        let frame = Frame::new(Id::Standard(0x7ff), &[]).unwrap();
+        let encoded = Encoded::new(&frame);
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let tx_program = pio.install(&tx_program()).unwrap();
+        let mut tx = pio.build(
+            PIOBuilder::from_installed_program(tx_program)
+                .out_pins(0, 1)
+                .set_pins(0, 1)
+                .jmp_pin(1)
+                .out_shift_direction(ShiftDirection::Left)
+                .autopull(true)
+                .autopush(true),
+        );
+        tx.set_pindirs(1);
+        tx.exec_instruction(0xe001);
+        tx.push_tx(encoded.words[0]);
+        tx.push_tx(encoded.words[1]);
+
+        // Another node sends a dominant bit in the first bit of the identifier.
+        let mut bit = 0;
+        let mut status = None;
+        for cycle in 0..40 * CYCLES_PER_BIT {
+            if status.is_some() {
+                break;
+            }
+            let own = tx.pin_levels() & 1 != 0;
+            if bit == 0 && !own {
+                bit = cycle;
+            }
+            let other =
+                bit != 0 && cycle >= bit + CYCLES_PER_BIT && cycle < bit + 2 * CYCLES_PER_BIT;
+            tx.set_input(1, own && !other);
+            tx.step();
+            status = status.or(tx.pop_rx());
+        }
+        // Lost on the bit after the start of frame, leaving the bus recessive.
+        assert_eq!(status.map(|s| encoded.len() - 1 - s), Some(1));
+        assert!(tx.pin_levels() & 1 != 0);
+    }
+}
@@ -0,0 +1,381 @@
+//! Introspection of PIO state machines and programs.
+//!
+//! [`StateMachine::debug_snapshot`](super::StateMachine::debug_snapshot) captures the registers
+//! which are useful to find out why a program got stuck, and [`PIO::disassemble`] turns an
+//! installed program back into pioasm-like text for logging.
+use core::fmt;
+
+use pio::{Instruction, SideSet, Wrap};
+
+use super::{InstalledProgram, PIOExt, PIO};
+
+/// Snapshot of the state of a state machine, see
+/// [`StateMachine::debug_snapshot`](super::StateMachine::debug_snapshot).
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DebugSnapshot {
+    /// Address of the instruction currently being executed.
+    pub pc: u8,
+    /// Instruction currently being executed, including instructions inserted via `EXEC`.
+    pub instruction: DisassembledInstruction,
+    /// The current instruction is stalled.
+    pub stalled: bool,
+    /// Number of words in the TX FIFO.
+    pub tx_level: u8,
+    /// Number of words in the RX FIFO.
+    pub rx_level: u8,
+    /// The state machine stalled on an empty TX FIFO (FDEBUG.TXSTALL, see
+    /// [`Tx::has_stalled`](super::Tx::has_stalled)).
+    pub tx_stall: bool,
+    /// A write to a full TX FIFO was ignored (FDEBUG.TXOVER).
+    pub tx_overflow: bool,
+    /// A read from an empty RX FIFO returned garbage (FDEBUG.RXUNDER).
+    pub rx_underflow: bool,
+    /// The state machine stalled on a full RX FIFO (FDEBUG.RXSTALL).
+    pub rx_stall: bool,
+    /// Current output levels driven by the PIO block on all GPIOs (DBG_PADOUT).
+    pub pad_out: u32,
+    /// Current output enables driven by the PIO block on all GPIOs (DBG_PADOE).
+    pub pad_oe: u32,
+}
+
+/// A raw PIO instruction, printed in pioasm syntax.
+#[derive(Debug, Clone, Copy)]
+pub struct DisassembledInstruction {
+    raw: u16,
+    side_set: SideSet,
+}
+
+const JMP_CONDITIONS: [&str; 8] = ["", "!x", "x--", "!y", "y--", "x!=y", "pin", "!osre"];
+const IN_SOURCES: [Option<&str>; 8] = [
+    Some("pins"),
+    Some("x"),
+    Some("y"),
+    Some("null"),
+    None,
+    None,
+    Some("isr"),
+    Some("osr"),
+];
+const OUT_DESTINATIONS: [&str; 8] = ["pins", "x", "y", "null", "pindirs", "pc", "isr", "exec"];
+const MOV_DESTINATIONS: [Option<&str>; 8] = [
+    Some("pins"),
+    Some("x"),
+    Some("y"),
+    Some("pindirs"),
+    Some("exec"),
+    Some("pc"),
+    Some("isr"),
+    Some("osr"),
+];
+const MOV_OPERATIONS: [Option<&str>; 4] = [Some(""), Some("!"), Some("::"), None];
+const MOV_SOURCES: [Option<&str>; 8] = [
+    Some("pins"),
+    Some("x"),
+    Some("y"),
+    Some("null"),
+    None,
+    Some("status"),
+    Some("isr"),
+    Some("osr"),
+];
+const SET_DESTINATIONS: [Option<&str>; 8] = [
+    Some("pins"),
+    Some("x"),
+    Some("y"),
+    None,
+    Some("pindirs"),
+    None,
+    None,
+    None,
+];
+
+impl DisassembledInstruction {
+    /// Wrap the raw `instruction` of a program using the given side-set configuration.
+    pub fn new(instruction: u16, side_set: SideSet) -> Self {
+        Self {
+            raw: instruction,
+            side_set,
+        }
+    }
+
+    /// The raw instruction.
+    pub fn raw(&self) -> u16 {
+        self.raw
+    }
+
+    /// Decode the instruction.
+    pub fn decode(&self) -> Option<Instruction> {
+        Instruction::decode(self.raw, self.side_set)
+    }
+
+    /// Delay cycles and side-set value (if any) of the instruction.
+    fn delay_and_side_set(&self) -> (u8, Option<u8>) {
+        let field = ((self.raw >> 8) & 0x1f) as u8;
+        let bits = self.side_set.bits();
+        let delay = field & (0x1f >> bits);
+        if bits == 0 || (self.side_set.optional() && field & 0x10 == 0) {
+            return (delay, None);
+        }
+        let data_bits = bits - u8::from(self.side_set.optional());
+        let side = (field >> (5 - bits)) & ((1 << data_bits) - 1);
+        (delay, Some(side))
+    }
+
+    /// Write the operation and its operands, or return `None` for reserved encodings.
+    fn write_operation(&self, f: &mut fmt::Formatter<'_>) -> Option<fmt::Result> {
+        let operands = (self.raw & 0xff) as u8;
+        let select = usize::from(operands >> 5);
+        let index = operands & 0x1f;
+        let bit_count = if index == 0 { 32 } else { index };
+        Some(match self.raw >> 13 {
+            0b000 => match JMP_CONDITIONS[select] {
+                "" => write!(f, "jmp {}", index),
+                condition => write!(f, "jmp {}, {}", condition, index),
+            },
+            0b001 => {
+                let polarity = operands >> 7;
+                match select & 0b11 {
+                    0 => write!(f, "wait {} gpio {}", polarity, index),
+                    1 => write!(f, "wait {} pin {}", polarity, index),
+                    2 => {
+                        let (prefix, suffix) = irq_index_mode(index);
+                        write!(f, "wait {} irq {}{}{}", polarity, prefix, index & 7, suffix)
+                    }
+                    _ => match index & 0b11 {
+                        0 => write!(f, "wait {} jmppin", polarity),
+                        offset => write!(f, "wait {} jmppin + {}", polarity, offset),
+                    },
+                }
+            }
+            0b010 => write!(f, "in {}, {}", IN_SOURCES[select]?, bit_count),
+            0b011 => write!(f, "out {}, {}", OUT_DESTINATIONS[select], bit_count),
+            0b100 => {
+                if index & 0x10 != 0 {
+                    // RP2350 `mov rxfifo[], isr` and `mov osr, rxfifo[]`
+                    if operands & 0x60 != 0 || index & 0b0100 != 0 {
+                        return None;
+                    }
+                    let entry = RxFifoEntry(index);
+                    return Some(if operands & 0x80 == 0 {
+                        write!(f, "mov rxfifo[{}], isr", entry)
+                    } else {
+                        write!(f, "mov osr, rxfifo[{}]", entry)
+                    });
+                }
+                if index != 0 {
+                    return None;
+                }
+                let (name, condition) = if operands & 0x80 == 0 {
+                    ("push", " iffull")
+                } else {
+                    ("pull", " ifempty")
+                };
+                let condition = if operands & 0x40 != 0 { condition } else { "" };
+                let block = if operands & 0x20 != 0 {
+                    " block"
+                } else {
+                    " noblock"
+                };
+                write!(f, "{}{}{}", name, condition, block)
+            }
+            0b101 => {
+                // `mov y, y` is the canonical encoding of `nop`.
+                if operands == 0x42 {
+                    write!(f, "nop")
+                } else {
+                    let destination = MOV_DESTINATIONS[select]?;
+                    let operation = MOV_OPERATIONS[usize::from((operands >> 3) & 0b11)]?;
+                    let source = MOV_SOURCES[usize::from(operands & 0b111)]?;
+                    write!(f, "mov {}, {}{}", destination, operation, source)
+                }
+            }
+            0b110 => {
+                if operands & 0x80 != 0 {
+                    return None;
+                }
+                let mode = match (operands & 0x40 != 0, operands & 0x20 != 0) {
+                    (true, _) => "clear",
+                    (false, true) => "wait",
+                    (false, false) => "set",
+                };
+                let (prefix, suffix) = irq_index_mode(index);
+                write!(f, "irq {}{} {}{}", prefix, mode, index & 7, suffix)
+            }
+            _ => write!(f, "set {}, {}", SET_DESTINATIONS[select]?, index),
+        })
+    }
+}
+
+/// Prefix and suffix for the index mode bits of an IRQ index, see [`IrqIndexMode`].
+///
+/// [`IrqIndexMode`]: super::IrqIndexMode
+fn irq_index_mode(index: u8) -> (&'static str, &'static str) {
+    match (index >> 3) & 0b11 {
+        0b00 => ("", ""),
+        0b01 => ("prev ", ""),
+        0b10 => ("", " rel"),
+        _ => ("next ", ""),
+    }
+}
+
+/// Index of an RX FIFO entry accessed by `mov rxfifo[]`, either `y` or a constant.
+struct RxFifoEntry(u8);
+
+impl fmt::Display for RxFifoEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 & 0b1000 != 0 {
+            write!(f, "{}", self.0 & 0b11)
+        } else {
+            write!(f, "y")
+        }
+    }
+}
+
+impl fmt::Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.write_operation(f) {
+            None => write!(f, ".word {:#06x}", self.raw),
+            Some(result) => {
+                result?;
+                let (delay, side) = self.delay_and_side_set();
+                if let Some(side) = side {
+                    write!(f, " side {}", side)?;
+                }
+                if delay != 0 {
+                    write!(f, " [{}]", delay)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for DisassembledInstruction {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}", defmt::Display2Format(self))
+    }
+}
+
+/// An installed program, printed in pioasm syntax with one instruction per line.
+///
+/// Returned by [`PIO::disassemble`].
+#[derive(Debug, Clone, Copy)]
+pub struct Disassembly<'a> {
+    code: &'a [u16],
+    offset: u8,
+    side_set: SideSet,
+    wrap: Wrap,
+}
+
+impl Disassembly<'_> {
+    /// The instructions of the program, along with their address.
+    pub fn instructions(&self) -> impl Iterator<Item = (u8, DisassembledInstruction)> + '_ {
+        self.code.iter().enumerate().map(|(i, &raw)| {
+            (
+                self.offset + i as u8,
+                DisassembledInstruction::new(raw, self.side_set),
+            )
+        })
+    }
+}
+
+impl fmt::Display for Disassembly<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (address, instruction) in self.instructions() {
+            let i = address - self.offset;
+            if i == self.wrap.target {
+                writeln!(f, ".wrap_target")?;
+            }
+            writeln!(f, "{:2}: {}", address, instruction)?;
+            if i == self.wrap.source {
+                writeln!(f, ".wrap")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Disassembly<'_> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}", defmt::Display2Format(self))
+    }
+}
+
+impl<P: PIOExt> PIO<P> {
+    /// Disassemble a program installed in this block.
+    ///
+    /// The instruction memory cannot be read back, the instructions are taken from a copy kept
+    /// when the program was installed. Jump targets are shown relocated to the program's offset.
+    pub fn disassemble(&self, program: &InstalledProgram<P>) -> Disassembly<'_> {
+        let start = usize::from(program.offset);
+        Disassembly {
+            code: &self.instructions[start..start + usize::from(program.length)],
+            offset: program.offset,
+            side_set: program.side_set,
+            wrap: program.wrap,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::string::ToString;
+
+    use super::*;
+
+    fn disassemble(raw: u16, side_set: SideSet) -> std::string::String {
+        DisassembledInstruction::new(raw, side_set).to_string()
+    }
+
+    #[test]
+    fn disassemble_instructions() {
+        let none = SideSet::new(false, 0, false);
+        assert_eq!(disassemble(0x0045, none), "jmp x--, 5");
+        assert_eq!(disassemble(0x0003, none), "jmp 3");
+        assert_eq!(disassemble(0x20c3, none), "wait 1 irq 3");
+        assert_eq!(disassemble(0x4001, none), "in pins, 1");
+        assert_eq!(disassemble(0x6060, none), "out null, 32");
+        assert_eq!(disassemble(0x80a0, none), "pull block");
+        assert_eq!(disassemble(0x8020, none), "push block");
+        assert_eq!(disassemble(0xa042, none), "nop");
+        assert_eq!(disassemble(0xa02a, none), "mov x, !y");
+        assert_eq!(disassemble(0xc012, none), "irq set 2 rel");
+        assert_eq!(disassemble(0xff01, none), "set pins, 1 [31]");
+        assert_eq!(disassemble(0x4081, none), ".word 0x4081");
+        assert_eq!(disassemble(0xc00a, none), "irq prev set 2");
+        assert_eq!(disassemble(0x20e1, none), "wait 1 jmppin + 1");
+        assert_eq!(disassemble(0x8010, none), "mov rxfifo[y], isr");
+        assert_eq!(disassemble(0x809a, none), "mov osr, rxfifo[2]");
+    }
+
+    #[test]
+    fn disassemble_side_set() {
+        let mandatory = SideSet::new(false, 1, false);
+        assert_eq!(disassemble(0xbf42, mandatory), "nop side 1 [15]");
+        let optional = SideSet::new(true, 1, false);
+        assert_eq!(disassemble(0xa342, optional), "nop [3]");
+        assert_eq!(disassemble(0xb842, optional), "nop side 1");
+    }
+
+    #[test]
+    fn disassemble_program() {
+        let code = [0xe081, 0xe101, 0x0001];
+        let disassembly = Disassembly {
+            code: &code,
+            offset: 0,
+            side_set: SideSet::new(false, 0, false),
+            wrap: Wrap {
+                source: 1,
+                target: 1,
+            },
+        };
+        assert_eq!(
+            disassembly.to_string(),
+            " 0: set pindirs, 1\n.wrap_target\n 1: set pins, 1 [1]\n.wrap\n 2: jmp 1\n"
+        );
+    }
+}
@@ -0,0 +1,544 @@
+//! State machines indexed at runtime.
+//!
+//! [`PIOExt::split`] hands out the state machines as distinct types (`SM0` to `SM3`), which keeps
+//! most mistakes at compile time, but makes it impossible to store several PIO based drivers in an
+//! array or to pick a free state machine at runtime. The types in this module wrap the type-level
+//! state machines into enums, so that they can be handled at runtime instead.
+//!
+//! ```no_run
+//! use rp235x_hal::{pac, pio::{PIOBuilder, PIOExt}};
+//! let mut peripherals = pac::Peripherals::take().unwrap();
+//! let (mut pio, mut sms) = peripherals.PIO0.dyn_split(&mut peripherals.RESETS);
+//! let program = pio_proc::pio_asm!("set pins, 1 [31]", "set pins, 0 [31]").program;
+//! // Get any free state machine, along with space for the program.
+//! let (sm, installed) = sms.claim_with_program(&mut pio, &program).unwrap();
+//! let (sm, rx, tx) = PIOBuilder::from_installed_program(installed).build_dyn(sm);
+//! let sm = sm.start();
+//! ```
+//!
+//! The variants of the enums are public, so they can be matched on to access the APIs which only
+//! exist for the type-level state machines, such as DMA transfers or synchronized starts.
+use pio::{Instruction, Program};
+
+use super::{
+    ClaimError, DebugSnapshot, InstalledProgram, PIOExt, PinDir, PinState, PioIRQ, Running, Rx,
+    StateMachine, Stopped, Tx, UninitStateMachine, PIO, PIO_INSTRUCTION_COUNT, SM0, SM1, SM2, SM3,
+};
+use crate::dma::{TransferSize, Word};
+
+/// Calls `$body` on the type-level value held by any variant of a dynamic state machine enum.
+macro_rules! dispatch {
+    ($ty:ident, $value:expr, $inner:ident => $body:expr) => {
+        match $value {
+            $ty::Sm0($inner) => $body,
+            $ty::Sm1($inner) => $body,
+            $ty::Sm2($inner) => $body,
+            $ty::Sm3($inner) => $body,
+        }
+    };
+}
+
+/// Like `dispatch!`, but re-wraps the result in the same variant of `$out`.
+macro_rules! map {
+    ($ty:ident, $out:ident, $value:expr, $inner:ident => $body:expr) => {
+        match $value {
+            $ty::Sm0($inner) => $out::Sm0($body),
+            $ty::Sm1($inner) => $out::Sm1($body),
+            $ty::Sm2($inner) => $out::Sm2($body),
+            $ty::Sm3($inner) => $out::Sm3($body),
+        }
+    };
+}
+
+/// PIO state machine (uninitialized, without a program) whose index is known at runtime.
+pub enum DynUninitStateMachine<P: PIOExt> {
+    /// State machine 0.
+    Sm0(UninitStateMachine<(P, SM0)>),
+    /// State machine 1.
+    Sm1(UninitStateMachine<(P, SM1)>),
+    /// State machine 2.
+    Sm2(UninitStateMachine<(P, SM2)>),
+    /// State machine 3.
+    Sm3(UninitStateMachine<(P, SM3)>),
+}
+
+impl<P: PIOExt> DynUninitStateMachine<P> {
+    /// Index of the state machine within its PIO block (0..=3).
+    pub fn index(&self) -> usize {
+        match self {
+            Self::Sm0(_) => 0,
+            Self::Sm1(_) => 1,
+            Self::Sm2(_) => 2,
+            Self::Sm3(_) => 3,
+        }
+    }
+}
+
+impl<P: PIOExt> From<UninitStateMachine<(P, SM0)>> for DynUninitStateMachine<P> {
+    fn from(sm: UninitStateMachine<(P, SM0)>) -> Self {
+        Self::Sm0(sm)
+    }
+}
+
+impl<P: PIOExt> From<UninitStateMachine<(P, SM1)>> for DynUninitStateMachine<P> {
+    fn from(sm: UninitStateMachine<(P, SM1)>) -> Self {
+        Self::Sm1(sm)
+    }
+}
+
+impl<P: PIOExt> From<UninitStateMachine<(P, SM2)>> for DynUninitStateMachine<P> {
+    fn from(sm: UninitStateMachine<(P, SM2)>) -> Self {
+        Self::Sm2(sm)
+    }
+}
+
+impl<P: PIOExt> From<UninitStateMachine<(P, SM3)>> for DynUninitStateMachine<P> {
+    fn from(sm: UninitStateMachine<(P, SM3)>) -> Self {
+        Self::Sm3(sm)
+    }
+}
+
+/// PIO state machine with an associated program, whose index is known at runtime.
+///
+/// Returned by [`PIOBuilder::build_dyn`](super::PIOBuilder::build_dyn).
+pub enum DynStateMachine<P: PIOExt, State> {
+    /// State machine 0.
+    Sm0(StateMachine<(P, SM0), State>),
+    /// State machine 1.
+    Sm1(StateMachine<(P, SM1), State>),
+    /// State machine 2.
+    Sm2(StateMachine<(P, SM2), State>),
+    /// State machine 3.
+    Sm3(StateMachine<(P, SM3), State>),
+}
+
+impl<P: PIOExt, State> DynStateMachine<P, State> {
+    /// Index of the state machine within its PIO block (0..=3).
+    pub fn index(&self) -> usize {
+        match self {
+            Self::Sm0(_) => 0,
+            Self::Sm1(_) => 1,
+            Self::Sm2(_) => 2,
+            Self::Sm3(_) => 3,
+        }
+    }
+
+    /// Stops the state machine if it is still running and returns its program.
+    ///
+    /// Panics if `rx` and `tx` do not belong to this state machine.
+    pub fn uninit<RxSize: TransferSize, TxSize: TransferSize>(
+        self,
+        rx: DynRx<P, RxSize>,
+        tx: DynTx<P, TxSize>,
+    ) -> (DynUninitStateMachine<P>, InstalledProgram<P>) {
+        match (self, rx, tx) {
+            (Self::Sm0(sm), DynRx::Sm0(rx), DynTx::Sm0(tx)) => {
+                let (sm, program) = sm.uninit(rx, tx);
+                (sm.into(), program)
+            }
+            (Self::Sm1(sm), DynRx::Sm1(rx), DynTx::Sm1(tx)) => {
+                let (sm, program) = sm.uninit(rx, tx);
+                (sm.into(), program)
+            }
+            (Self::Sm2(sm), DynRx::Sm2(rx), DynTx::Sm2(tx)) => {
+                let (sm, program) = sm.uninit(rx, tx);
+                (sm.into(), program)
+            }
+            (Self::Sm3(sm), DynRx::Sm3(rx), DynTx::Sm3(tx)) => {
+                let (sm, program) = sm.uninit(rx, tx);
+                (sm.into(), program)
+            }
+            _ => panic!("Rx and Tx do not belong to this state machine"),
+        }
+    }
+
+    /// The address of the instruction currently being executed.
+    pub fn instruction_address(&self) -> u32 {
+        dispatch!(Self, self, sm => sm.instruction_address())
+    }
+
+    /// Execute the instruction immediately.
+    ///
+    /// See [`StateMachine::exec_instruction`].
+    pub fn exec_instruction(&mut self, instruction: Instruction) {
+        dispatch!(Self, self, sm => sm.exec_instruction(instruction))
+    }
+
+    /// Check if the current instruction is stalled.
+    pub fn stalled(&self) -> bool {
+        dispatch!(Self, self, sm => sm.stalled())
+    }
+
+    /// Capture the state of the state machine.
+    ///
+    /// See [`StateMachine::debug_snapshot`].
+    pub fn debug_snapshot(&self) -> DebugSnapshot {
+        dispatch!(Self, self, sm => sm.debug_snapshot())
+    }
+
+    /// Clear the sticky FDEBUG flags of this state machine.
+    pub fn clear_debug_flags(&mut self) {
+        dispatch!(Self, self, sm => sm.clear_debug_flags())
+    }
+
+    /// Clear both TX and RX FIFOs
+    pub fn clear_fifos(&mut self) {
+        dispatch!(Self, self, sm => sm.clear_fifos())
+    }
+
+    /// Drain Tx fifo.
+    pub fn drain_tx_fifo(&mut self) {
+        dispatch!(Self, self, sm => sm.drain_tx_fifo())
+    }
+
+    /// Change the clock divider of a state machine.
+    ///
+    /// See [`StateMachine::set_clock_divisor`].
+    pub fn set_clock_divisor(&mut self, divisor: f32) {
+        dispatch!(Self, self, sm => sm.set_clock_divisor(divisor))
+    }
+
+    /// Change the clock divider of a state machine using a 16.8 fixed point value.
+    ///
+    /// See [`StateMachine::clock_divisor_fixed_point`].
+    pub fn clock_divisor_fixed_point(&mut self, int: u16, frac: u8) {
+        dispatch!(Self, self, sm => sm.clock_divisor_fixed_point(int, frac))
+    }
+
+    /// Switch the state machine to another program, returning the previous one.
+    ///
+    /// See [`StateMachine::switch_program`].
+    pub fn switch_program(&mut self, program: InstalledProgram<P>) -> InstalledProgram<P> {
+        dispatch!(Self, self, sm => sm.switch_program(program))
+    }
+}
+
+impl<P: PIOExt> DynStateMachine<P, Stopped> {
+    /// Starts execution of the selected program.
+    pub fn start(self) -> DynStateMachine<P, Running> {
+        map!(Self, DynStateMachine, self, sm => sm.start())
+    }
+
+    /// Sets the pin state for the specified pins.
+    ///
+    /// See [`StateMachine::set_pins`].
+    pub fn set_pins(&mut self, pins: impl IntoIterator<Item = (u8, PinState)>) {
+        dispatch!(Self, self, sm => sm.set_pins(pins))
+    }
+
+    /// Set pin directions.
+    ///
+    /// See [`StateMachine::set_pindirs`].
+    pub fn set_pindirs(&mut self, pindirs: impl IntoIterator<Item = (u8, PinDir)>) {
+        dispatch!(Self, self, sm => sm.set_pindirs(pindirs))
+    }
+}
+
+impl<P: PIOExt> DynStateMachine<P, Running> {
+    /// Stops execution of the selected program.
+    pub fn stop(self) -> DynStateMachine<P, Stopped> {
+        map!(Self, DynStateMachine, self, sm => sm.stop())
+    }
+
+    /// Restarts the execution of the selected program from its wrap target.
+    pub fn restart(&mut self) {
+        dispatch!(Self, self, sm => sm.restart())
+    }
+}
+
+/// PIO RX FIFO handle whose state machine index is known at runtime.
+pub enum DynRx<P: PIOExt, RxSize = Word> {
+    /// RX FIFO of state machine 0.
+    Sm0(Rx<(P, SM0), RxSize>),
+    /// RX FIFO of state machine 1.
+    Sm1(Rx<(P, SM1), RxSize>),
+    /// RX FIFO of state machine 2.
+    Sm2(Rx<(P, SM2), RxSize>),
+    /// RX FIFO of state machine 3.
+    Sm3(Rx<(P, SM3), RxSize>),
+}
+
+impl<P: PIOExt, RxSize: TransferSize> DynRx<P, RxSize> {
+    /// Gets the FIFO's address.
+    ///
+    /// See [`Rx::fifo_address`].
+    pub fn fifo_address(&self) -> *const u32 {
+        dispatch!(Self, self, rx => rx.fifo_address())
+    }
+
+    /// Gets the FIFO's `DREQ` value.
+    pub fn dreq_value(&self) -> u8 {
+        dispatch!(Self, self, rx => rx.dreq_value())
+    }
+
+    /// Get the next element from RX FIFO.
+    ///
+    /// Returns `None` if the FIFO is empty.
+    pub fn read(&mut self) -> Option<u32> {
+        dispatch!(Self, self, rx => rx.read())
+    }
+
+    /// Wait for the next element of the RX FIFO.
+    ///
+    /// See [`Rx::read_async`].
+    pub async fn read_async(&mut self, irq: PioIRQ) -> u32 {
+        dispatch!(Self, self, rx => rx.read_async(irq).await)
+    }
+
+    /// Fill `buffer` with elements of the RX FIFO, waiting for them as needed.
+    ///
+    /// See [`Rx::read_slice_async`].
+    pub async fn read_slice_async(&mut self, irq: PioIRQ, buffer: &mut [u32]) {
+        dispatch!(Self, self, rx => rx.read_slice_async(irq, buffer).await)
+    }
+
+    /// Read one of the four RX FIFO entries directly.
+    ///
+    /// See [`Rx::read_fifo_entry`].
+    pub fn read_fifo_entry(&self, index: u8) -> u32 {
+        dispatch!(Self, self, rx => rx.read_fifo_entry(index))
+    }
+
+    /// Write one of the four RX FIFO entries directly.
+    ///
+    /// See [`Rx::write_fifo_entry`].
+    pub fn write_fifo_entry(&mut self, index: u8, value: u32) {
+        dispatch!(Self, self, rx => rx.write_fifo_entry(index, value))
+    }
+
+    /// Enable/Disable the autopush feature of the state machine.
+    pub fn enable_autopush(&mut self, enable: bool) {
+        dispatch!(Self, self, rx => rx.enable_autopush(enable))
+    }
+
+    /// Indicate if the rx FIFO is empty
+    pub fn is_empty(&self) -> bool {
+        dispatch!(Self, self, rx => rx.is_empty())
+    }
+
+    /// Indicate if the rx FIFO is full
+    pub fn is_full(&self) -> bool {
+        dispatch!(Self, self, rx => rx.is_full())
+    }
+
+    /// Checks if the state machine has stalled on full RX FIFO during a blocking PUSH, or an IN
+    /// with autopush enabled.
+    pub fn has_stalled(&self) -> bool {
+        dispatch!(Self, self, rx => rx.has_stalled())
+    }
+
+    /// Clears the `rx_stalled` flag.
+    pub fn clear_stalled_flag(&self) {
+        dispatch!(Self, self, rx => rx.clear_stalled_flag())
+    }
+
+    /// Enable RX FIFO not empty interrupt.
+    pub fn enable_rx_not_empty_interrupt(&self, id: PioIRQ) {
+        dispatch!(Self, self, rx => rx.enable_rx_not_empty_interrupt(id))
+    }
+
+    /// Disable RX FIFO not empty interrupt.
+    pub fn disable_rx_not_empty_interrupt(&self, id: PioIRQ) {
+        dispatch!(Self, self, rx => rx.disable_rx_not_empty_interrupt(id))
+    }
+
+    /// Force RX FIFO not empty interrupt.
+    pub fn force_rx_not_empty_interrupt(&self, id: PioIRQ, state: bool) {
+        dispatch!(Self, self, rx => rx.force_rx_not_empty_interrupt(id, state))
+    }
+
+    /// Set the transfer size used in DMA transfers.
+    pub fn transfer_size<RSZ: TransferSize>(self, size: RSZ) -> DynRx<P, RSZ> {
+        map!(Self, DynRx, self, rx => rx.transfer_size(size))
+    }
+}
+
+/// PIO TX FIFO handle whose state machine index is known at runtime.
+pub enum DynTx<P: PIOExt, TxSize = Word> {
+    /// TX FIFO of state machine 0.
+    Sm0(Tx<(P, SM0), TxSize>),
+    /// TX FIFO of state machine 1.
+    Sm1(Tx<(P, SM1), TxSize>),
+    /// TX FIFO of state machine 2.
+    Sm2(Tx<(P, SM2), TxSize>),
+    /// TX FIFO of state machine 3.
+    Sm3(Tx<(P, SM3), TxSize>),
+}
+
+impl<P: PIOExt, TxSize: TransferSize> DynTx<P, TxSize> {
+    /// Gets the FIFO's address.
+    ///
+    /// See [`Tx::fifo_address`].
+    pub fn fifo_address(&self) -> *const u32 {
+        dispatch!(Self, self, tx => tx.fifo_address())
+    }
+
+    /// Gets the FIFO's `DREQ` value.
+    pub fn dreq_value(&self) -> u8 {
+        dispatch!(Self, self, tx => tx.dreq_value())
+    }
+
+    /// Write an element to TX FIFO.
+    ///
+    /// Returns `true` if the value was written to FIFO, `false` otherwise.
+    pub fn write(&mut self, value: u32) -> bool {
+        dispatch!(Self, self, tx => tx.write(value))
+    }
+
+    /// Write an element to TX FIFO, waiting for space in the FIFO if needed.
+    ///
+    /// See [`Tx::write_async`].
+    pub async fn write_async(&mut self, irq: PioIRQ, value: u32) {
+        dispatch!(Self, self, tx => tx.write_async(irq, value).await)
+    }
+
+    /// Write all `values` to the TX FIFO, waiting for space in the FIFO as needed.
+    ///
+    /// See [`Tx::write_slice_async`].
+    pub async fn write_slice_async(&mut self, irq: PioIRQ, values: &[u32]) {
+        dispatch!(Self, self, tx => tx.write_slice_async(irq, values).await)
+    }
+
+    /// Write an u8 element to TX FIFO.
+    ///
+    /// See [`Tx::write_u8_replicated`].
+    pub fn write_u8_replicated(&mut self, value: u8) -> bool {
+        dispatch!(Self, self, tx => tx.write_u8_replicated(value))
+    }
+
+    /// Write an u16 element to TX FIFO.
+    ///
+    /// See [`Tx::write_u16_replicated`].
+    pub fn write_u16_replicated(&mut self, value: u16) -> bool {
+        dispatch!(Self, self, tx => tx.write_u16_replicated(value))
+    }
+
+    /// Checks if the state machine has stalled on empty TX FIFO during a blocking PULL, or an OUT
+    /// with autopull enabled.
+    pub fn has_stalled(&self) -> bool {
+        dispatch!(Self, self, tx => tx.has_stalled())
+    }
+
+    /// Clears the `tx_stalled` flag.
+    pub fn clear_stalled_flag(&self) {
+        dispatch!(Self, self, tx => tx.clear_stalled_flag())
+    }
+
+    /// Indicate if the tx FIFO is empty
+    pub fn is_empty(&self) -> bool {
+        dispatch!(Self, self, tx => tx.is_empty())
+    }
+
+    /// Indicate if the tx FIFO is full
+    pub fn is_full(&self) -> bool {
+        dispatch!(Self, self, tx => tx.is_full())
+    }
+
+    /// Enable TX FIFO not full interrupt.
+    pub fn enable_tx_not_full_interrupt(&self, id: PioIRQ) {
+        dispatch!(Self, self, tx => tx.enable_tx_not_full_interrupt(id))
+    }
+
+    /// Disable TX FIFO not full interrupt.
+    pub fn disable_tx_not_full_interrupt(&self, id: PioIRQ) {
+        dispatch!(Self, self, tx => tx.disable_tx_not_full_interrupt(id))
+    }
+
+    /// Force TX FIFO not full interrupt.
+    pub fn force_tx_not_full_interrupt(&self, id: PioIRQ) {
+        dispatch!(Self, self, tx => tx.force_tx_not_full_interrupt(id))
+    }
+
+    /// Set the transfer size used in DMA transfers.
+    pub fn transfer_size<RSZ: TransferSize>(self, size: RSZ) -> DynTx<P, RSZ> {
+        map!(Self, DynTx, self, tx => tx.transfer_size(size))
+    }
+}
+
+/// Set of the state machines of a PIO block, handed out at runtime.
+///
+/// Returned by [`PIOExt::dyn_split`].
+pub struct DynStateMachines<P: PIOExt> {
+    sms: [Option<DynUninitStateMachine<P>>; 4],
+}
+
+impl<P: PIOExt> DynStateMachines<P> {
+    pub(super) fn new(
+        sm0: UninitStateMachine<(P, SM0)>,
+        sm1: UninitStateMachine<(P, SM1)>,
+        sm2: UninitStateMachine<(P, SM2)>,
+        sm3: UninitStateMachine<(P, SM3)>,
+    ) -> Self {
+        Self {
+            sms: [
+                Some(sm0.into()),
+                Some(sm1.into()),
+                Some(sm2.into()),
+                Some(sm3.into()),
+            ],
+        }
+    }
+
+    /// Number of state machines which can currently be claimed.
+    pub fn available(&self) -> usize {
+        self.sms.iter().filter(|sm| sm.is_some()).count()
+    }
+
+    /// Claim any free state machine, starting with the lowest index.
+    pub fn claim(&mut self) -> Option<DynUninitStateMachine<P>> {
+        self.sms.iter_mut().find_map(Option::take)
+    }
+
+    /// Claim the state machine with the given index, if it is free.
+    pub fn claim_index(&mut self, index: usize) -> Option<DynUninitStateMachine<P>> {
+        self.sms.get_mut(index).and_then(Option::take)
+    }
+
+    /// Claim any free state machine and install `program` for it.
+    ///
+    /// Nothing is claimed nor installed if either of them is not available.
+    pub fn claim_with_program(
+        &mut self,
+        pio: &mut PIO<P>,
+        program: &Program<PIO_INSTRUCTION_COUNT>,
+    ) -> Result<(DynUninitStateMachine<P>, InstalledProgram<P>), ClaimError> {
+        let sm = self.claim().ok_or(ClaimError::NoStateMachine)?;
+        match pio.install(program) {
+            Ok(installed) => Ok((sm, installed)),
+            Err(_) => {
+                self.release(sm);
+                Err(ClaimError::NoSpace)
+            }
+        }
+    }
+
+    /// Give a state machine back, so that it can be claimed again.
+    pub fn release(&mut self, sm: DynUninitStateMachine<P>) {
+        let index = sm.index();
+        self.sms[index] = Some(sm);
+    }
+
+    /// Get back the type-level state machines, e.g. to pass them to [`PIO::free`].
+    ///
+    /// This fails if any of the state machines is still claimed.
+    #[allow(clippy::type_complexity)] // Required for symmetry with PIOExt::split().
+    pub fn into_split(
+        self,
+    ) -> Result<
+        (
+            UninitStateMachine<(P, SM0)>,
+            UninitStateMachine<(P, SM1)>,
+            UninitStateMachine<(P, SM2)>,
+            UninitStateMachine<(P, SM3)>,
+        ),
+        Self,
+    > {
+        use DynUninitStateMachine::{Sm0, Sm1, Sm2, Sm3};
+        match self.sms {
+            [Some(Sm0(sm0)), Some(Sm1(sm1)), Some(Sm2(sm2)), Some(Sm3(sm3))] => {
+                Ok((sm0, sm1, sm2, sm3))
+            }
+            sms => Err(Self { sms }),
+        }
+    }
+}
@@ -0,0 +1,772 @@
+//! I2S audio transmitter and receiver
+//!
+//! Neither chip has an I2S peripheral, this module implements it with one PIO state machine per
+//! direction, either as a master driving BCLK and LRCLK or as a slave following the clocks of
+//! another device. Use [`full_duplex`] to play and record on the same clock lines with two state
+//! machines.
+//!
+//! Audio is streamed with [`dma::double_buffer`](crate::dma::double_buffer) transfers: while one
+//! buffer is being sent (or received), the next one is already queued, so there is one buffer
+//! length of time to refill (or process) the buffer returned by [`I2sTxStream::next_buffer`]
+//! (or [`I2sRxStream::next_buffer`]).
+//!
+//! ## Sample format
+//!
+//! With 16 bits per sample, one FIFO word holds a frame: the left sample in its upper half and the
+//! right sample in its lower half. Otherwise, each sample takes a word, starting with the left
+//! channel. Transmitted samples are aligned to the most significant bit of their word, received
+//! samples to the least significant bit.
+//!
+//! ```no_run
+//! use fugit::RateExtU32;
+//! use rp235x_hal::{
+//!     dma::DMAExt,
+//!     gpio::{FunctionPio0, Pins},
+//!     pac,
+//!     pio::{
+//!         i2s::{I2sConfig, I2sTx, Role},
+//!         PIOExt,
+//!     },
+//!     Sio,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let sio = Sio::new(pac.SIO);
+//! let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+//! let bclk = pins.gpio10.into_function::<FunctionPio0>();
+//! let lrclk = pins.gpio11.into_function::<FunctionPio0>();
+//! let dout = pins.gpio12.into_function::<FunctionPio0>();
+//! let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+//! let dma = pac.DMA.split(&mut pac.RESETS);
+//!
+//! let config = I2sConfig::new(48.kHz(), 16, Role::Master);
+//! let i2s = I2sTx::new(&mut pio, sm0, config, &bclk, &lrclk, &dout, 150.MHz()).unwrap();
+//! let first = rp235x_hal::singleton!(: [u32; 256] = [0; 256]).unwrap();
+//! let second = rp235x_hal::singleton!(: [u32; 256] = [0; 256]).unwrap();
+//! let mut stream = i2s.stream((dma.ch0, dma.ch1), first, second);
+//! loop {
+//!     // Returns once a buffer was played, while the other one is playing.
+//!     stream.next_buffer_with(|buffer| {
+//!         // Fill `buffer` with the next frames.
+//!     });
+//! }
+//! ```
+use fugit::HertzU32;
+use pio::{Assembler, JmpCondition, SetDestination, SideSet, WaitSource};
+
+use super::{
+    GpioBase, InstallError, InstalledProgram, PIOBuilder, PIOExt, PinDir, PioPin, Running, Rx,
+    ShiftDirection, StateMachine, StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+use crate::dma::{
+    double_buffer::{self, ReadNext, WriteNext},
+    ReadTarget, SingleChannel, WriteTarget,
+};
+
+/// Role of a state machine on the BCLK and LRCLK lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Role {
+    /// Drive BCLK and LRCLK.
+    Master,
+    /// Follow BCLK and LRCLK driven by another device.
+    Slave,
+}
+
+/// I2S stream configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct I2sConfig {
+    /// Number of frames (one sample for each channel) per second. Ignored in slave role.
+    pub sample_rate: HertzU32,
+    /// Bits per sample, from 8 to 32.
+    pub bits_per_sample: u8,
+    /// Role on the clock lines.
+    pub role: Role,
+}
+
+impl I2sConfig {
+    /// Create a new configuration.
+    pub const fn new(sample_rate: HertzU32, bits_per_sample: u8, role: Role) -> Self {
+        Self {
+            sample_rate,
+            bits_per_sample,
+            role,
+        }
+    }
+
+    /// Clock divisor of a master state machine, as integer and 1/256th parts.
+    ///
+    /// The divisor is rounded to the nearest 1/256th: rates of the 48kHz family are usually exact
+    /// with a 12.288MHz multiple system clock, and rates of the 44.1kHz family with a 11.2896MHz
+    /// multiple. Otherwise the fractional divider adds a small jitter to BCLK, and the average
+    /// sample rate deviates from the requested one by up to 1/512th of a divisor step.
+    pub fn clock_divisor(&self, system_clock: HertzU32) -> (u16, u8) {
+        // Master programs run two instructions per bit.
+        let sm_clock =
+            u64::from(self.sample_rate.to_Hz()) * 2 * 2 * u64::from(self.bits_per_sample);
+        let divisor = (u64::from(system_clock.to_Hz()) * 256 + sm_clock / 2) / sm_clock;
+        ((divisor >> 8) as u16, divisor as u8)
+    }
+
+    /// Number of bits shifted per FIFO word.
+    fn word_bits(&self) -> u8 {
+        match self.bits_per_sample {
+            16 => 32,
+            bits => bits,
+        }
+    }
+
+    fn check(&self) {
+        assert!(
+            (8..=32).contains(&self.bits_per_sample),
+            "unsupported number of bits per sample"
+        );
+    }
+}
+
+/// Transmitter program for the master role, with BCLK and LRCLK on side-set pins.
+fn tx_master_program(bits: u8) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new_with_side_set(SideSet::new(false, 2, false));
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut left_loop = a.label();
+    let mut right_loop = a.label();
+    // Side-set bits are `0b<LRCLK><BCLK>`, data changes on falling BCLK edges. LRCLK changes one
+    // bit before the most significant bit of each sample.
+    a.set_with_side_set(SetDestination::X, bits - 2, 0b11);
+    a.bind(&mut wrap_target);
+    a.bind(&mut left_loop);
+    a.out_with_side_set(pio::OutDestination::PINS, 1, 0b00);
+    a.jmp_with_side_set(JmpCondition::XDecNonZero, &mut left_loop, 0b01);
+    a.out_with_side_set(pio::OutDestination::PINS, 1, 0b10);
+    a.set_with_side_set(SetDestination::X, bits - 2, 0b11);
+    a.bind(&mut right_loop);
+    a.out_with_side_set(pio::OutDestination::PINS, 1, 0b10);
+    a.jmp_with_side_set(JmpCondition::XDecNonZero, &mut right_loop, 0b11);
+    a.out_with_side_set(pio::OutDestination::PINS, 1, 0b00);
+    a.set_with_side_set(SetDestination::X, bits - 2, 0b01);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Receiver program for the master role, with BCLK and LRCLK on side-set pins.
+fn rx_master_program(bits: u8) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new_with_side_set(SideSet::new(false, 2, false));
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut left_loop = a.label();
+    let mut right_loop = a.label();
+    // Data is sampled while BCLK is high. Each channel starts with the low phase of its most
+    // significant bit, so that the first bit shifted into ISR is the left channel's MSB.
+    a.bind(&mut wrap_target);
+    a.set_with_side_set(SetDestination::X, bits - 3, 0b00);
+    a.bind(&mut left_loop);
+    a.r#in_with_side_set(pio::InSource::PINS, 1, 0b01);
+    a.jmp_with_side_set(JmpCondition::XDecNonZero, &mut left_loop, 0b00);
+    a.r#in_with_side_set(pio::InSource::PINS, 1, 0b01);
+    a.nop_with_side_set(0b10);
+    a.r#in_with_side_set(pio::InSource::PINS, 1, 0b11);
+    a.set_with_side_set(SetDestination::X, bits - 3, 0b10);
+    a.bind(&mut right_loop);
+    a.r#in_with_side_set(pio::InSource::PINS, 1, 0b11);
+    a.jmp_with_side_set(JmpCondition::XDecNonZero, &mut right_loop, 0b10);
+    a.r#in_with_side_set(pio::InSource::PINS, 1, 0b11);
+    a.nop_with_side_set(0b00);
+    a.r#in_with_side_set(pio::InSource::PINS, 1, 0b01);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Transmitter program for the slave role, watching BCLK and LRCLK with `wait gpio`.
+///
+/// `bclk` and `lrclk` are relative to the GPIO base of the block.
+fn tx_slave_program(bclk: u8, lrclk: u8) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    // Synchronize on the start of a frame: LRCLK falls one bit before the left channel's MSB.
+    a.wait(1, WaitSource::GPIO, lrclk, false);
+    a.wait(0, WaitSource::GPIO, lrclk, false);
+    a.bind(&mut wrap_target);
+    a.wait(1, WaitSource::GPIO, bclk, false);
+    a.wait(0, WaitSource::GPIO, bclk, false);
+    a.out(pio::OutDestination::PINS, 1);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Receiver program for the slave role, watching BCLK and LRCLK with `wait gpio`.
+///
+/// `bclk` and `lrclk` are relative to the GPIO base of the block.
+fn rx_slave_program(bclk: u8, lrclk: u8) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    // Synchronize on the start of a frame, and skip the last bit of the previous one.
+    a.wait(1, WaitSource::GPIO, lrclk, false);
+    a.wait(0, WaitSource::GPIO, lrclk, false);
+    a.wait(1, WaitSource::GPIO, bclk, false);
+    a.bind(&mut wrap_target);
+    a.wait(0, WaitSource::GPIO, bclk, false);
+    a.wait(1, WaitSource::GPIO, bclk, false);
+    a.r#in(pio::InSource::PINS, 1);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+fn tx_program<P: PIOExt>(
+    config: &I2sConfig,
+    bclk: &dyn PioPin<P>,
+    lrclk: &dyn PioPin<P>,
+    gpio_base: GpioBase,
+) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    config.check();
+    match config.role {
+        Role::Master => tx_master_program(config.bits_per_sample),
+        Role::Slave => tx_slave_program(
+            bclk.pin_num() - gpio_base.offset(),
+            lrclk.pin_num() - gpio_base.offset(),
+        ),
+    }
+}
+
+fn rx_program<P: PIOExt>(
+    config: &I2sConfig,
+    bclk: &dyn PioPin<P>,
+    lrclk: &dyn PioPin<P>,
+    gpio_base: GpioBase,
+) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    config.check();
+    match config.role {
+        Role::Master => rx_master_program(config.bits_per_sample),
+        Role::Slave => rx_slave_program(
+            bclk.pin_num() - gpio_base.offset(),
+            lrclk.pin_num() - gpio_base.offset(),
+        ),
+    }
+}
+
+/// Configure the clock divisor and the clock pins of a state machine.
+fn clocks<P: PIOExt>(
+    builder: PIOBuilder<P>,
+    config: &I2sConfig,
+    bclk: &dyn PioPin<P>,
+    lrclk: &dyn PioPin<P>,
+    system_clock: HertzU32,
+) -> PIOBuilder<P> {
+    match config.role {
+        Role::Master => {
+            let (int, frac) = config.clock_divisor(system_clock);
+            builder
+                .side_set_pins_checked(&[bclk, lrclk])
+                .clock_divisor_fixed_point(int, frac)
+        }
+        // Run at full speed to follow the clocks closely.
+        Role::Slave => builder.clock_divisor_fixed_point(1, 0),
+    }
+}
+
+/// Set the direction of the pins driven by a state machine.
+fn set_pindirs<P: PIOExt, SM: StateMachineIndex>(
+    sm: &mut StateMachine<(P, SM), super::Stopped>,
+    config: &I2sConfig,
+    gpio_base: GpioBase,
+    bclk: &dyn PioPin<P>,
+    lrclk: &dyn PioPin<P>,
+    data: Option<&dyn PioPin<P>>,
+) {
+    if config.role == Role::Master {
+        sm.set_pindirs([
+            (bclk.pin_num() - gpio_base.offset(), PinDir::Output),
+            (lrclk.pin_num() - gpio_base.offset(), PinDir::Output),
+        ]);
+    }
+    if let Some(data) = data {
+        sm.set_pindirs([(data.pin_num() - gpio_base.offset(), PinDir::Output)]);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_tx<P: PIOExt, SM: StateMachineIndex>(
+    installed: InstalledProgram<P>,
+    sm: UninitStateMachine<(P, SM)>,
+    config: &I2sConfig,
+    bclk: &dyn PioPin<P>,
+    lrclk: &dyn PioPin<P>,
+    dout: &dyn PioPin<P>,
+    system_clock: HertzU32,
+    gpio_base: GpioBase,
+) -> I2sTx<P, SM> {
+    // 0 stands for 32 bits.
+    let threshold = config.word_bits() % 32;
+    let builder = PIOBuilder::from_installed_program(installed)
+        .gpio_base(gpio_base)
+        .out_pins_checked(&[dout])
+        .out_shift_direction(ShiftDirection::Left)
+        .autopull(true)
+        .pull_threshold(threshold)
+        .buffers(super::Buffers::OnlyTx);
+    let (mut sm, rx, tx) = clocks(builder, config, bclk, lrclk, system_clock).build(sm);
+    set_pindirs(&mut sm, config, gpio_base, bclk, lrclk, Some(dout));
+    I2sTx {
+        sm: sm.start(),
+        rx,
+        tx,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_rx<P: PIOExt, SM: StateMachineIndex>(
+    installed: InstalledProgram<P>,
+    sm: UninitStateMachine<(P, SM)>,
+    config: &I2sConfig,
+    bclk: &dyn PioPin<P>,
+    lrclk: &dyn PioPin<P>,
+    din: &dyn PioPin<P>,
+    system_clock: HertzU32,
+    gpio_base: GpioBase,
+) -> I2sRx<P, SM> {
+    // 0 stands for 32 bits.
+    let threshold = config.word_bits() % 32;
+    let builder = PIOBuilder::from_installed_program(installed)
+        .gpio_base(gpio_base)
+        .in_pins_checked(&[din])
+        .in_shift_direction(ShiftDirection::Left)
+        .autopush(true)
+        .push_threshold(threshold)
+        .buffers(super::Buffers::OnlyRx);
+    let (mut sm, rx, tx) = clocks(builder, config, bclk, lrclk, system_clock).build(sm);
+    set_pindirs(&mut sm, config, gpio_base, bclk, lrclk, None);
+    I2sRx {
+        sm: sm.start(),
+        rx,
+        tx,
+    }
+}
+
+/// I2S transmitter.
+pub struct I2sTx<P: PIOExt, SM: StateMachineIndex> {
+    sm: StateMachine<(P, SM), Running>,
+    rx: Rx<(P, SM)>,
+    tx: Tx<(P, SM)>,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> I2sTx<P, SM> {
+    /// Install the transmitter program and start it on `sm`.
+    ///
+    /// In master role, `lrclk` must be the GPIO following `bclk`, and `system_clock` is used to
+    /// derive the bit clock from the sample rate.
+    ///
+    /// The state machine stalls, holding the clocks, until samples are written to its FIFO.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        config: I2sConfig,
+        bclk: &dyn PioPin<P>,
+        lrclk: &dyn PioPin<P>,
+        dout: &dyn PioPin<P>,
+        system_clock: HertzU32,
+    ) -> Result<Self, InstallError> {
+        let gpio_base = pio.gpio_base();
+        let installed = pio.install(&tx_program(&config, bclk, lrclk, gpio_base))?;
+        Ok(build_tx(
+            installed,
+            sm,
+            &config,
+            bclk,
+            lrclk,
+            dout,
+            system_clock,
+            gpio_base,
+        ))
+    }
+
+    /// Write one FIFO word, returning `false` if the FIFO is full.
+    pub fn write(&mut self, word: u32) -> bool {
+        self.tx.write(word)
+    }
+
+    /// Start streaming `first` then `second` with a double-buffered DMA transfer.
+    pub fn stream<CH1, CH2, B>(
+        self,
+        channels: (CH1, CH2),
+        first: B,
+        second: B,
+    ) -> I2sTxStream<P, SM, CH1, CH2, B>
+    where
+        CH1: SingleChannel,
+        CH2: SingleChannel,
+        B: ReadTarget<ReceivedWord = u32>,
+    {
+        let transfer = double_buffer::Config::new(channels, first, self.tx)
+            .start()
+            .read_next(second);
+        I2sTxStream {
+            sm: self.sm,
+            rx: self.rx,
+            transfer: Some(transfer),
+        }
+    }
+
+    /// Stop the transmitter, uninstall its program and return the state machine.
+    pub fn free(self, pio: &mut PIO<P>) -> UninitStateMachine<(P, SM)> {
+        let (sm, program) = self.sm.uninit(self.rx, self.tx);
+        pio.uninstall(program);
+        sm
+    }
+}
+
+/// I2S transmitter streaming buffers with DMA, see [`I2sTx::stream`].
+pub struct I2sTxStream<P, SM, CH1, CH2, B>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+    CH1: SingleChannel,
+    CH2: SingleChannel,
+    B: ReadTarget<ReceivedWord = u32>,
+{
+    sm: StateMachine<(P, SM), Running>,
+    rx: Rx<(P, SM)>,
+    // Only `None` while a buffer is being swapped.
+    #[allow(clippy::type_complexity)]
+    transfer: Option<double_buffer::Transfer<CH1, CH2, B, Tx<(P, SM)>, ReadNext<B>>>,
+}
+
+impl<P, SM, CH1, CH2, B> I2sTxStream<P, SM, CH1, CH2, B>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+    CH1: SingleChannel,
+    CH2: SingleChannel,
+    B: ReadTarget<ReceivedWord = u32>,
+{
+    /// Whether the current buffer was sent, so that [`Self::next_buffer`] won't block.
+    pub fn is_ready(&self) -> bool {
+        self.transfer.as_ref().expect("stream in use").is_done()
+    }
+
+    /// Wait until the current buffer was sent, queue `next` and return the sent buffer.
+    ///
+    /// `next` must be queued before the buffer now playing runs out, or the state machine stalls
+    /// with the clocks stopped.
+    pub fn next_buffer(&mut self, next: B) -> B {
+        let (sent, transfer) = self.transfer.take().expect("stream in use").wait();
+        self.transfer = Some(transfer.read_next(next));
+        sent
+    }
+
+    /// Wait until the current buffer was sent, refill it with `fill` and queue it again.
+    pub fn next_buffer_with(&mut self, fill: impl FnOnce(&mut B)) {
+        let (mut sent, transfer) = self.transfer.take().expect("stream in use").wait();
+        fill(&mut sent);
+        self.transfer = Some(transfer.read_next(sent));
+    }
+
+    /// Wait until both queued buffers were sent and return the transmitter, the DMA channels and
+    /// the buffers.
+    pub fn stop(self) -> (I2sTx<P, SM>, (CH1, CH2), B, B) {
+        let (first, transfer) = self.transfer.expect("stream in use").wait();
+        let (ch1, ch2, second, tx) = transfer.wait();
+        let i2s = I2sTx {
+            sm: self.sm,
+            rx: self.rx,
+            tx,
+        };
+        (i2s, (ch1, ch2), first, second)
+    }
+}
+
+/// I2S receiver.
+pub struct I2sRx<P: PIOExt, SM: StateMachineIndex> {
+    sm: StateMachine<(P, SM), Running>,
+    rx: Rx<(P, SM)>,
+    tx: Tx<(P, SM)>,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> I2sRx<P, SM> {
+    /// Install the receiver program and start it on `sm`.
+    ///
+    /// In master role, `lrclk` must be the GPIO following `bclk`, and `system_clock` is used to
+    /// derive the bit clock from the sample rate.
+    ///
+    /// Samples are dropped while the FIFO is full, until a read or a DMA transfer drains it.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        config: I2sConfig,
+        bclk: &dyn PioPin<P>,
+        lrclk: &dyn PioPin<P>,
+        din: &dyn PioPin<P>,
+        system_clock: HertzU32,
+    ) -> Result<Self, InstallError> {
+        let gpio_base = pio.gpio_base();
+        let installed = pio.install(&rx_program(&config, bclk, lrclk, gpio_base))?;
+        Ok(build_rx(
+            installed,
+            sm,
+            &config,
+            bclk,
+            lrclk,
+            din,
+            system_clock,
+            gpio_base,
+        ))
+    }
+
+    /// Read one FIFO word, if any.
+    pub fn read(&mut self) -> Option<u32> {
+        self.rx.read()
+    }
+
+    /// Start receiving into `first` then `second` with a double-buffered DMA transfer.
+    pub fn stream<CH1, CH2, B>(
+        self,
+        channels: (CH1, CH2),
+        first: B,
+        second: B,
+    ) -> I2sRxStream<P, SM, CH1, CH2, B>
+    where
+        CH1: SingleChannel,
+        CH2: SingleChannel,
+        B: WriteTarget<TransmittedWord = u32>,
+    {
+        let transfer = double_buffer::Config::new(channels, self.rx, first)
+            .start()
+            .write_next(second);
+        I2sRxStream {
+            sm: self.sm,
+            tx: self.tx,
+            transfer: Some(transfer),
+        }
+    }
+
+    /// Stop the receiver, uninstall its program and return the state machine.
+    pub fn free(self, pio: &mut PIO<P>) -> UninitStateMachine<(P, SM)> {
+        let (sm, program) = self.sm.uninit(self.rx, self.tx);
+        pio.uninstall(program);
+        sm
+    }
+}
+
+/// I2S receiver streaming buffers with DMA, see [`I2sRx::stream`].
+pub struct I2sRxStream<P, SM, CH1, CH2, B>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+    CH1: SingleChannel,
+    CH2: SingleChannel,
+    B: WriteTarget<TransmittedWord = u32>,
+{
+    sm: StateMachine<(P, SM), Running>,
+    tx: Tx<(P, SM)>,
+    // Only `None` while a buffer is being swapped.
+    #[allow(clippy::type_complexity)]
+    transfer: Option<double_buffer::Transfer<CH1, CH2, Rx<(P, SM)>, B, WriteNext<B>>>,
+}
+
+impl<P, SM, CH1, CH2, B> I2sRxStream<P, SM, CH1, CH2, B>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+    CH1: SingleChannel,
+    CH2: SingleChannel,
+    B: WriteTarget<TransmittedWord = u32>,
+{
+    /// Whether the current buffer was filled, so that [`Self::next_buffer`] won't block.
+    pub fn is_ready(&self) -> bool {
+        self.transfer.as_ref().expect("stream in use").is_done()
+    }
+
+    /// Wait until the current buffer was filled, queue `next` and return the filled buffer.
+    ///
+    /// `next` must be queued before the buffer now being filled is full, or samples are lost.
+    pub fn next_buffer(&mut self, next: B) -> B {
+        let (filled, transfer) = self.transfer.take().expect("stream in use").wait();
+        self.transfer = Some(transfer.write_next(next));
+        filled
+    }
+
+    /// Wait until both queued buffers were filled and return the receiver, the DMA channels and
+    /// the buffers.
+    pub fn stop(self) -> (I2sRx<P, SM>, (CH1, CH2), B, B) {
+        let (first, transfer) = self.transfer.expect("stream in use").wait();
+        let (ch1, ch2, rx, second) = transfer.wait();
+        let i2s = I2sRx {
+            sm: self.sm,
+            rx,
+            tx: self.tx,
+        };
+        (i2s, (ch1, ch2), first, second)
+    }
+}
+
+/// Set up a transmitter and a receiver sharing the same clock lines.
+///
+/// All pins must be visible to the PIO block with its current GPIO base.
+///
+/// The transmitter takes the role given in `config`, the receiver always follows the clocks as a
+/// slave, so that both directions are synchronous to the same frames.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn full_duplex<P, SM1, SM2>(
+    pio: &mut PIO<P>,
+    tx_sm: UninitStateMachine<(P, SM1)>,
+    rx_sm: UninitStateMachine<(P, SM2)>,
+    config: I2sConfig,
+    bclk: &dyn PioPin<P>,
+    lrclk: &dyn PioPin<P>,
+    dout: &dyn PioPin<P>,
+    din: &dyn PioPin<P>,
+    system_clock: HertzU32,
+) -> Result<(I2sTx<P, SM1>, I2sRx<P, SM2>), InstallError>
+where
+    P: PIOExt,
+    SM1: StateMachineIndex,
+    SM2: StateMachineIndex,
+{
+    let rx_config = I2sConfig {
+        role: Role::Slave,
+        ..config
+    };
+    let gpio_base = pio.gpio_base();
+    let tx_program = pio.install(&tx_program(&config, bclk, lrclk, gpio_base))?;
+    let rx_program = match pio.install(&rx_program(&rx_config, bclk, lrclk, gpio_base)) {
+        Ok(program) => program,
+        Err(e) => {
+            pio.uninstall(tx_program);
+            return Err(e);
+        }
+    };
+    // Start the receiver first, so that it doesn't miss the first frame sent.
+    let rx = build_rx(
+        rx_program,
+        rx_sm,
+        &rx_config,
+        bclk,
+        lrclk,
+        din,
+        system_clock,
+        gpio_base,
+    );
+    let tx = build_tx(
+        tx_program,
+        tx_sm,
+        &config,
+        bclk,
+        lrclk,
+        dout,
+        system_clock,
+        gpio_base,
+    );
+    Ok((tx, rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use fugit::RateExtU32;
+
+    use super::*;
+    use crate::pac;
+    use crate::pio::emulator::{EmulatedStateMachine, Emulator};
+
+    const BCLK: u32 = 1 << 0;
+    const LRCLK: u32 = 1 << 1;
+    const DATA: u32 = 1 << 2;
+
+    /// Run `sm` until `count` rising BCLK edges were seen, collecting the LRCLK and data levels
+    /// at each edge. `data_in` gives the data input level from the clock levels before the edge.
+    fn rising_edges(
+        sm: &mut EmulatedStateMachine,
+        count: usize,
+        mut data_in: impl FnMut(bool) -> bool,
+    ) -> ([bool; 32], [bool; 32]) {
+        let (mut lrclk, mut data) = ([false; 32], [false; 32]);
+        let mut bclk = false;
+        let mut edges = 0;
+        while edges < count {
+            let levels = sm.pin_levels();
+            if !bclk && levels & BCLK != 0 {
+                lrclk[edges] = levels & LRCLK != 0;
+                data[edges] = levels & DATA != 0;
+                edges += 1;
+            }
+            bclk = levels & BCLK != 0;
+            let input = if data_in(bclk) { DATA } else { 0 };
+            sm.set_inputs(input);
+            sm.step();
+        }
+        (lrclk, data)
+    }
+
+    fn bits(value: u8) -> impl Iterator<Item = bool> {
+        (0..8).rev().map(move |i| value & (1 << i) != 0)
+    }
+
+    /// LRCLK levels of a 8-bit frame, starting with the left channel's MSB.
+    const FRAME_LRCLK: [bool; 16] = [
+        false, false, false, false, false, false, false, true, true, true, true, true, true, true,
+        true, false,
+    ];
+
+    #[test]
+    fn tx_master_frame() {
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let installed = pio.install(&tx_master_program(8)).unwrap();
+        let mut sm = pio.build(
+            PIOBuilder::from_installed_program(installed)
+                .out_pins(2, 1)
+                .side_set_pin_base(0)
+                .out_shift_direction(ShiftDirection::Left)
+                .autopull(true)
+                .pull_threshold(8),
+        );
+        sm.set_pindirs(BCLK | LRCLK | DATA);
+        for word in [0xa5, 0x3c, 0x0f, 0xf0] {
+            sm.push_tx(word << 24);
+        }
+
+        // The first instruction raises BCLK before the first bit.
+        let (lrclk, data) = rising_edges(&mut sm, 25, |_| false);
+        assert_eq!(lrclk[1..17], FRAME_LRCLK);
+        let expected = bits(0xa5).chain(bits(0x3c)).chain(bits(0x0f));
+        assert!(data[1..25].iter().copied().eq(expected));
+    }
+
+    #[test]
+    fn rx_master_frame() {
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let installed = pio.install(&rx_master_program(8)).unwrap();
+        let mut sm = pio.build(
+            PIOBuilder::from_installed_program(installed)
+                .in_pin_base(2)
+                .side_set_pin_base(0)
+                .in_shift_direction(ShiftDirection::Left)
+                .autopush(true)
+                .push_threshold(8),
+        );
+        sm.set_pindirs(BCLK | LRCLK);
+
+        // Change the data input on falling BCLK edges, as a transmitter would.
+        let mut stream = bits(0xa5).chain(bits(0x3c)).chain(bits(0x81));
+        let mut level = false;
+        let mut bclk = true;
+        let (lrclk, _) = rising_edges(&mut sm, 24, |high| {
+            if bclk && !high {
+                level = stream.next().unwrap_or(false);
+            }
+            bclk = high;
+            level
+        });
+        assert_eq!(lrclk[..16], FRAME_LRCLK);
+        assert_eq!(sm.pop_rx(), Some(0xa5));
+        assert_eq!(sm.pop_rx(), Some(0x3c));
+    }
+
+    #[test]
+    fn clock_divisor() {
+        let config = I2sConfig::new(48.kHz(), 16, Role::Master);
+        // 125MHz / (48kHz * 16 bits * 2 channels * 2 cycles) = 40.69
+        assert_eq!(config.clock_divisor(125.MHz()), (40, 177));
+        let config = I2sConfig::new(44_100.Hz(), 16, Role::Master);
+        // 2 * 11.2896MHz / (44.1kHz * 16 bits * 2 channels * 2 cycles) = 8
+        assert_eq!(config.clock_divisor(22_579_200.Hz()), (8, 0));
+    }
+}
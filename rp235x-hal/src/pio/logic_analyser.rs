@@ -0,0 +1,591 @@
+//! Logic analyser capturing GPIOs with PIO and DMA
+//!
+//! [`LogicAnalyser`] samples up to 32 consecutive GPIOs at a fixed rate into a DMA ring buffer,
+//! until a condition on a trigger GPIO is met and a number of post-trigger samples were taken.
+//! The pins are read whatever function they are assigned to, so the signals of other peripherals
+//! can be captured.
+//!
+//! Two state machines are used. The sampler reads the pins every 3 cycles, at up to a third of
+//! the system clock, the samples being pushed to its RX FIFO and copied by a DMA channel into the
+//! buffer, overwriting the oldest ones. The trigger state machine waits for the pre-trigger
+//! samples to be taken, then for the trigger condition with a `wait` instruction, and stops the
+//! sampler once the post-trigger samples were taken.
+//!
+//! Samples are packed in 32-bit words, the first sample of a word in its least significant bits.
+//! The width of a sample is the number of pins rounded up to a power of two, its bit `n` being
+//! the level of GPIO `pin_base + n`. [`Samples`] gives the samples in order, starting at the
+//! oldest.
+//!
+//! ```no_run
+//! use fugit::RateExtU32;
+//! use rp235x_hal::{
+//!     dma::DMAExt,
+//!     pac,
+//!     pio::{
+//!         logic_analyser::{LogicAnalyser, Trigger},
+//!         PIOExt,
+//!     },
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let (mut pio, sm0, sm1, _, _) = pac.PIO0.split(&mut pac.RESETS);
+//! let dma = pac.DMA.split(&mut pac.RESETS);
+//! // Capture GPIO 0 to 7 at 10 MHz, triggering on a falling edge of GPIO 3.
+//! let mut analyser = LogicAnalyser::new(
+//!     &mut pio,
+//!     sm0,
+//!     sm1,
+//!     0,
+//!     8,
+//!     Trigger::Falling(3),
+//!     10.MHz(),
+//!     150.MHz(),
+//! )
+//! .unwrap();
+//!
+//! // The buffer must be aligned to its size.
+//! #[repr(C, align(1024))]
+//! struct Buffer([u32; 256]);
+//! static mut BUFFER: Buffer = Buffer([0; 256]);
+//! let buffer = unsafe { &mut (*core::ptr::addr_of_mut!(BUFFER)).0 };
+//!
+//! let capture = analyser.capture(dma.ch0, buffer, 100, 900);
+//! let (_ch0, samples) = capture.wait();
+//! for (i, sample) in samples.iter().enumerate() {
+//!     let gpio3 = sample & (1 << 3) != 0;
+//!     let _ = (i == samples.trigger_index(), gpio3);
+//! }
+//! ```
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use fugit::HertzU32;
+use pio::{
+    Assembler, InSource, Instruction, InstructionOperands, JmpCondition, MovDestination,
+    MovOperation, MovSource, WaitSource,
+};
+
+use super::{
+    InstallError, PIOBuilder, PIOExt, Running, Rx, ShiftDirection, StateMachine, StateMachineIndex,
+    Tx, UninitStateMachine, PIO,
+};
+use crate::dma::{ReadTarget, SingleChannel};
+
+/// Cycles of the sampler per sample.
+const CYCLES_PER_SAMPLE: u32 = 3;
+
+/// Largest ring buffer supported by the DMA, in bytes.
+const MAX_RING_BYTES: usize = 1 << 15;
+
+/// Condition starting the post-trigger samples, on a GPIO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Trigger {
+    /// The GPIO is high.
+    High(u8),
+    /// The GPIO is low.
+    Low(u8),
+    /// The GPIO goes from low to high.
+    Rising(u8),
+    /// The GPIO goes from high to low.
+    Falling(u8),
+}
+
+impl Trigger {
+    /// GPIO of the condition and the level waited for.
+    fn gpio_level(self) -> (u8, bool) {
+        match self {
+            Trigger::High(gpio) | Trigger::Rising(gpio) => (gpio, true),
+            Trigger::Low(gpio) | Trigger::Falling(gpio) => (gpio, false),
+        }
+    }
+
+    fn is_edge(self) -> bool {
+        matches!(self, Trigger::Rising(_) | Trigger::Falling(_))
+    }
+}
+
+/// Program sampling `width` pins every 3 cycles while the IRQ flag `flag` is clear, counting the
+/// samples down in X.
+fn sampler_program(width: u8, flag: u8) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    a.bind(&mut wrap_target);
+    a.wait(0, WaitSource::IRQ, flag, false);
+    a.r#in(InSource::PINS, width);
+    a.jmp(JmpCondition::XDecNonZero, &mut wrap_target);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Program clearing the IRQ flag `flag` to start the sampler, counting down the pre-trigger
+/// samples pulled first, waiting for the trigger, counting down the post-trigger samples pulled
+/// second and setting the flag again. A word is pushed once done.
+///
+/// `gpio` is the GPIO waited for, relative to the GPIO base of the block.
+fn trigger_program(
+    gpio: u8,
+    level: bool,
+    edge: bool,
+    flag: u8,
+) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut pre = a.label();
+    let mut post = a.label();
+    a.bind(&mut wrap_target);
+    a.pull(false, true);
+    a.mov(MovDestination::Y, MovOperation::None, MovSource::OSR);
+    a.pull(false, true);
+    a.mov(MovDestination::X, MovOperation::None, MovSource::OSR);
+    a.irq(true, false, flag, false);
+    // Both loops last a sample each iteration.
+    a.bind(&mut pre);
+    a.jmp_with_delay(JmpCondition::YDecNonZero, &mut pre, 2);
+    if edge {
+        a.wait(u8::from(!level), WaitSource::GPIO, gpio, false);
+    }
+    a.wait(u8::from(level), WaitSource::GPIO, gpio, false);
+    a.bind(&mut post);
+    a.jmp_with_delay(JmpCondition::XDecNonZero, &mut post, 2);
+    a.irq(false, false, flag, false);
+    a.push(false, true);
+    a.bind(&mut wrap_source);
+
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+fn instruction(operands: InstructionOperands) -> Instruction {
+    Instruction {
+        operands,
+        delay: 0,
+        side_set: None,
+    }
+}
+
+/// Reorder the words written to the ring `buffer` so that they start at index 0, `next` being
+/// the index of the next word the DMA would have written, and append the `partial` word left in
+/// the ISR. Returns the number of samples, `taken` being the number of samples taken.
+fn linearise(buffer: &mut [u32], next: usize, taken: u32, partial: u32, width: u8) -> usize {
+    let per_word = 32 / u32::from(width);
+    let full = (taken / per_word) as usize;
+    let rest = taken % per_word;
+    let mut words = full.min(buffer.len());
+    if full >= buffer.len() {
+        // The ring wrapped around, the oldest word is the next one to be overwritten.
+        buffer.rotate_left(next % buffer.len());
+    }
+    if rest != 0 {
+        if words == buffer.len() {
+            buffer.copy_within(1.., 0);
+            words -= 1;
+        }
+        // The samples were shifted in from the most significant bits.
+        buffer[words] = partial >> (32 - rest * u32::from(width));
+        return words * per_word as usize + rest as usize;
+    }
+    words * per_word as usize
+}
+
+/// Logic analyser sampling GPIOs with two state machines.
+pub struct LogicAnalyser<P: PIOExt, SM: StateMachineIndex, TRIG: StateMachineIndex> {
+    sampler: StateMachine<(P, SM), Running>,
+    rx: Rx<(P, SM)>,
+    tx: Tx<(P, SM)>,
+    trigger: StateMachine<(P, TRIG), Running>,
+    trigger_rx: Rx<(P, TRIG)>,
+    trigger_tx: Tx<(P, TRIG)>,
+    width: u8,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex, TRIG: StateMachineIndex> LogicAnalyser<P, SM, TRIG> {
+    /// Install the programs and start the state machines, waiting for a capture.
+    ///
+    /// `pin_count` GPIOs starting at `pin_base` are sampled at `sample_rate`, which can't exceed
+    /// a third of `system_clock`. The state machine `trigger_sm` raises the IRQ flag
+    /// `4 + TRIG::id()` while the sampler is stopped, this flag can't be used by other programs.
+    /// The sampled GPIOs and the trigger GPIO must be within the 32 GPIOs starting at the GPIO
+    /// base of the block.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pin_count` is 0 or greater than 32.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pio: &mut PIO<P>,
+        sampler_sm: UninitStateMachine<(P, SM)>,
+        trigger_sm: UninitStateMachine<(P, TRIG)>,
+        pin_base: u8,
+        pin_count: u8,
+        trigger: Trigger,
+        sample_rate: HertzU32,
+        system_clock: HertzU32,
+    ) -> Result<Self, InstallError> {
+        assert!((1..=32).contains(&pin_count));
+        let width = pin_count.next_power_of_two();
+        let flag = 4 + TRIG::id() as u8;
+        let (gpio, level) = trigger.gpio_level();
+        let gpio_base = pio.gpio_base();
+        let gpio = gpio - gpio_base.offset();
+        let sampler_program = pio.install(&sampler_program(width, flag))?;
+        let trigger_program =
+            match pio.install(&trigger_program(gpio, level, trigger.is_edge(), flag)) {
+                Ok(program) => program,
+                Err(e) => {
+                    pio.uninstall(sampler_program);
+                    return Err(e);
+                }
+            };
+
+        // Both state machines run at the same rate, so that their loops stay in step.
+        let divisor = (u64::from(system_clock.to_Hz()) << 8)
+            / (u64::from(sample_rate.to_Hz()) * u64::from(CYCLES_PER_SAMPLE));
+        let (int, frac) = ((divisor >> 8) as u16, divisor as u8);
+        // The sampler is held until the trigger state machine clears the flag.
+        pio.force_irq(1 << flag);
+        let (sampler, rx, tx) = PIOBuilder::from_installed_program(sampler_program)
+            .gpio_base(gpio_base)
+            .in_pin_base(pin_base - gpio_base.offset())
+            .in_shift_direction(ShiftDirection::Right)
+            .autopush(true)
+            .clock_divisor_fixed_point(int, frac)
+            .build(sampler_sm);
+        let (trigger, trigger_rx, trigger_tx) = PIOBuilder::from_installed_program(trigger_program)
+            .gpio_base(gpio_base)
+            .clock_divisor_fixed_point(int, frac)
+            .build(trigger_sm);
+
+        Ok(Self {
+            sampler: sampler.start(),
+            rx,
+            tx,
+            trigger: trigger.start(),
+            trigger_rx,
+            trigger_tx,
+            width,
+        })
+    }
+
+    /// Stop the state machines and uninstall the programs.
+    #[allow(clippy::type_complexity)]
+    pub fn free(
+        self,
+        pio: &mut PIO<P>,
+    ) -> (UninitStateMachine<(P, SM)>, UninitStateMachine<(P, TRIG)>) {
+        let flag = 4 + TRIG::id() as u8;
+        let (sampler_sm, sampler_program) = self.sampler.uninit(self.rx, self.tx);
+        let (trigger_sm, trigger_program) = self.trigger.uninit(self.trigger_rx, self.trigger_tx);
+        pio.uninstall(sampler_program);
+        pio.uninstall(trigger_program);
+        pio.clear_irq(1 << flag);
+        (sampler_sm, trigger_sm)
+    }
+
+    /// Width of a sample in bits, the number of pins rounded up to a power of two.
+    pub fn sample_width(&self) -> u8 {
+        self.width
+    }
+
+    /// Start a capture into `buffer` through the DMA channel `ch`.
+    ///
+    /// The trigger is armed once `pre` samples were taken, and the capture completes `post`
+    /// samples after the trigger condition is met. Older samples are overwritten when the
+    /// buffer is full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `buffer` isn't a power of two up to 8192 words, if `buffer`
+    /// isn't aligned to its size, or if `pre + post` samples don't fit in all but one word of
+    /// `buffer`.
+    pub fn capture<CH: SingleChannel>(
+        &mut self,
+        ch: CH,
+        buffer: &'static mut [u32],
+        pre: u32,
+        post: u32,
+    ) -> Capture<'_, P, SM, TRIG, CH> {
+        let bytes = core::mem::size_of_val(buffer);
+        assert!(bytes.is_power_of_two() && bytes <= MAX_RING_BYTES);
+        assert_eq!(buffer.as_ptr() as usize % bytes, 0);
+        let per_word = 32 / u32::from(self.width);
+        assert!(
+            u64::from(pre) + u64::from(post) <= (buffer.len() as u64 - 1) * u64::from(per_word)
+        );
+
+        // `mov x, ~null`: X counts the samples down from `u32::MAX`.
+        self.sampler
+            .exec_instruction(instruction(InstructionOperands::MOV {
+                destination: MovDestination::X,
+                op: MovOperation::Invert,
+                source: MovSource::NULL,
+            }));
+
+        // The DMA loops over the buffer until aborted.
+        let (from, _) = self.rx.rx_address_count();
+        let treq = <Rx<(P, SM)> as ReadTarget>::rx_treq().unwrap();
+        let regs = ch.ch();
+        regs.ch_read_addr().write(|w| unsafe { w.bits(from) });
+        regs.ch_trans_count().write(|w| unsafe {
+            w.count().bits(u32::MAX >> 4);
+            w.mode().endless();
+            w
+        });
+        regs.ch_al1_ctrl().write(|w| unsafe {
+            w.data_size().bits(2);
+            w.incr_read().bit(false);
+            w.incr_write().bit(true);
+            w.ring_sel().bit(true);
+            w.ring_size().bits(bytes.trailing_zeros() as u8);
+            w.treq_sel().bits(treq);
+            w.chain_to().bits(ch.id());
+            w.en().bit(true);
+            w
+        });
+        regs.ch_al2_write_addr_trig()
+            .write(|w| unsafe { w.bits(buffer.as_ptr() as u32) });
+        compiler_fence(Ordering::SeqCst);
+
+        // Pre-trigger, then post-trigger samples, each loop running one more time than the
+        // value pulled.
+        self.trigger_tx.write(pre.saturating_sub(1));
+        self.trigger_tx.write(post.saturating_sub(1));
+
+        Capture {
+            analyser: self,
+            ch,
+            buffer,
+            post,
+        }
+    }
+}
+
+/// Capture in progress, returned by [`LogicAnalyser::capture`].
+pub struct Capture<'a, P: PIOExt, SM: StateMachineIndex, TRIG: StateMachineIndex, CH: SingleChannel>
+{
+    analyser: &'a mut LogicAnalyser<P, SM, TRIG>,
+    ch: CH,
+    buffer: &'static mut [u32],
+    post: u32,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex, TRIG: StateMachineIndex, CH: SingleChannel>
+    Capture<'_, P, SM, TRIG, CH>
+{
+    /// Check if the post-trigger samples were taken.
+    pub fn is_done(&self) -> bool {
+        !self.analyser.trigger_rx.is_empty()
+    }
+
+    /// Block until the capture completes, returning the DMA channel and the samples.
+    pub fn wait(self) -> (CH, Samples) {
+        while !self.is_done() {}
+        let Self {
+            analyser,
+            ch,
+            buffer,
+            post,
+        } = self;
+        analyser.trigger_rx.read();
+
+        // The sampler is stopped, let the DMA copy the samples left in the FIFO.
+        while !analyser.rx.is_empty() {}
+        let chan_abort = unsafe { &*crate::pac::DMA::ptr() }.chan_abort();
+        chan_abort.write(|w| unsafe { w.chan_abort().bits(1 << ch.id()) });
+        while chan_abort.read().bits() != 0 {}
+        compiler_fence(Ordering::SeqCst);
+        let next = (ch.ch().ch_write_addr().read().bits() - buffer.as_ptr() as u32) as usize / 4;
+
+        // Read the samples left in the ISR, then the number of samples taken.
+        analyser
+            .sampler
+            .exec_instruction(instruction(InstructionOperands::PUSH {
+                if_full: false,
+                block: false,
+            }));
+        let partial = read_blocking(&mut analyser.rx);
+        analyser
+            .sampler
+            .exec_instruction(instruction(InstructionOperands::MOV {
+                destination: MovDestination::ISR,
+                op: MovOperation::None,
+                source: MovSource::X,
+            }));
+        analyser
+            .sampler
+            .exec_instruction(instruction(InstructionOperands::PUSH {
+                if_full: false,
+                block: false,
+            }));
+        let taken = !read_blocking(&mut analyser.rx);
+
+        let len = linearise(buffer, next, taken, partial, analyser.width);
+        let trigger = len.saturating_sub(post as usize);
+        (
+            ch,
+            Samples {
+                buffer,
+                width: analyser.width,
+                len,
+                trigger,
+            },
+        )
+    }
+}
+
+fn read_blocking<P: PIOExt, SM: StateMachineIndex>(rx: &mut Rx<(P, SM)>) -> u32 {
+    loop {
+        if let Some(word) = rx.read() {
+            return word;
+        }
+    }
+}
+
+/// Samples of a completed capture, oldest first.
+pub struct Samples {
+    buffer: &'static mut [u32],
+    width: u8,
+    len: usize,
+    trigger: usize,
+}
+
+impl Samples {
+    /// Number of samples.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if there are no samples.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Width of a sample in bits.
+    pub fn width(&self) -> u8 {
+        self.width
+    }
+
+    /// Index of the first sample taken after the trigger condition was met, within a sample.
+    pub fn trigger_index(&self) -> usize {
+        self.trigger
+    }
+
+    /// Sample at `index`, bit `n` being the level of GPIO `pin_base + n`.
+    pub fn get(&self, index: usize) -> Option<u32> {
+        if index >= self.len {
+            return None;
+        }
+        let per_word = 32 / usize::from(self.width);
+        let shift = (index % per_word) * usize::from(self.width);
+        let mask = u32::MAX >> (32 - u32::from(self.width));
+        Some((self.buffer[index / per_word] >> shift) & mask)
+    }
+
+    /// Iterate over the samples, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len).filter_map(|i| self.get(i))
+    }
+
+    /// Packed samples, the first sample of a word in its least significant bits.
+    pub fn words(&self) -> &[u32] {
+        let per_word = 32 / self.width as usize;
+        &self.buffer[..self.len.div_ceil(per_word)]
+    }
+
+    /// Release the buffer.
+    pub fn free(self) -> &'static mut [u32] {
+        self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pac;
+    use crate::pio::emulator::Emulator;
+
+    #[test]
+    fn programs_fit() {
+        let sampler = sampler_program(8, 4);
+        let trigger = trigger_program(3, false, true, 4);
+        assert!(sampler.code.len() + trigger.code.len() <= 32);
+        assert_eq!(trigger.code[4], 0xc044);
+        assert_eq!(trigger.code[9], 0xc004);
+    }
+
+    #[test]
+    fn sampler() {
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let program = pio.install(&sampler_program(4, 4)).unwrap();
+        let mut sm = pio.build(
+            PIOBuilder::from_installed_program(program)
+                .in_pin_base(2)
+                .in_shift_direction(ShiftDirection::Right)
+                .autopush(true),
+        );
+        // `mov x, ~null`
+        sm.exec_instruction(0xa02b);
+        for sample in 0..18 {
+            sm.set_inputs(sample << 2);
+            sm.run(CYCLES_PER_SAMPLE);
+        }
+        sm.force_irq(1 << 4);
+        sm.run(CYCLES_PER_SAMPLE);
+        assert_eq!(sm.pop_rx(), Some(0x7654_3210));
+        assert_eq!(sm.pop_rx(), Some(0xfedc_ba98));
+        assert_eq!(sm.pop_rx(), None);
+        assert_eq!(sm.isr(), (0x1000_0000, 8));
+        assert_eq!(!sm.x(), 18);
+    }
+
+    #[test]
+    fn trigger() {
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let program = pio.install(&trigger_program(2, true, true, 5)).unwrap();
+        let mut sm = pio.build(PIOBuilder::from_installed_program(program));
+        sm.force_irq(1 << 5);
+        sm.set_input(2, true);
+        sm.run(10);
+        assert_eq!(sm.irq_flags(), 1 << 5);
+
+        sm.push_tx(2);
+        sm.push_tx(3);
+        sm.run(5);
+        assert_eq!(sm.irq_flags(), 0);
+        // A rising edge is needed, the high level doesn't trigger.
+        sm.run(100);
+        assert_eq!(sm.irq_flags(), 0);
+        sm.set_input(2, false);
+        sm.run(10);
+        sm.set_input(2, true);
+        sm.run(1);
+        // The post-trigger loop runs 4 times, a sample each.
+        sm.run(4 * CYCLES_PER_SAMPLE);
+        assert_eq!(sm.irq_flags(), 0);
+        sm.run(1);
+        assert_eq!(sm.irq_flags(), 1 << 5);
+        assert_eq!(sm.pop_rx(), None);
+        sm.run(1);
+        assert_eq!(sm.pop_rx(), Some(0));
+    }
+
+    #[test]
+    fn linearise_words() {
+        // 8-bit samples, 4 per word. Not wrapped, with 2 samples left in the ISR.
+        let mut buffer = [0x0302_0100, 0x0706_0504, 0, 0];
+        let len = linearise(&mut buffer, 2, 10, 0x0908_0000, 8);
+        assert_eq!(len, 10);
+        assert_eq!(buffer[..3], [0x0302_0100, 0x0706_0504, 0x0908]);
+
+        // Wrapped, the oldest word is dropped to make room for the partial one.
+        let mut buffer = [0x1312_1110, 0x0706_0504, 0x0b0a_0908, 0x0f0e_0d0c];
+        let len = linearise(&mut buffer, 1, 21, 0x1400_0000, 8);
+        assert_eq!(len, 13);
+        assert_eq!(buffer, [0x0b0a_0908, 0x0f0e_0d0c, 0x1312_1110, 0x14]);
+
+        // Wrapped, without a partial word.
+        let mut buffer = [0x1312_1110, 0x0706_0504, 0x0b0a_0908, 0x0f0e_0d0c];
+        let len = linearise(&mut buffer, 1, 20, 0, 8);
+        assert_eq!(len, 16);
+        assert_eq!(buffer, [0x0706_0504, 0x0b0a_0908, 0x0f0e_0d0c, 0x1312_1110]);
+    }
+}
@@ -0,0 +1,451 @@
+//! PDM microphone capture
+//!
+//! Digital MEMS microphones output a 1-bit pulse density modulated (PDM) stream at the rate of
+//! the clock they are given, usually 1 to 3.2MHz. [`PdmMic`] drives that clock from a PIO state
+//! machine and captures the raw bitstream into its RX FIFO, 32 bits per word with the first bit
+//! received in the most significant bit. Long captures are streamed with double-buffered DMA
+//! transfers, like [`I2sRx`](super::i2s::I2sRx).
+//!
+//! [`PdmDecimator`] turns the bitstream into 16-bit PCM samples in software. It can use the
+//! interpolator 0 of the SIO to look up the bytes of the bitstream.
+//!
+//! ```no_run
+//! use fugit::RateExtU32;
+//! use rp235x_hal::{
+//!     dma::DMAExt,
+//!     gpio::{FunctionPio0, Pins},
+//!     pac,
+//!     pio::{
+//!         pdm::{PdmDecimator, PdmMic, SampleEdge},
+//!         PIOExt,
+//!     },
+//!     Sio,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let sio = Sio::new(pac.SIO);
+//! let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+//! let clk = pins.gpio2.into_function::<FunctionPio0>();
+//! let data = pins.gpio3.into_function::<FunctionPio0>();
+//! let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+//! let dma = pac.DMA.split(&mut pac.RESETS);
+//!
+//! // 3.072MHz / 64 = 48kHz
+//! let mic = PdmMic::new(
+//!     &mut pio,
+//!     sm0,
+//!     &clk,
+//!     &data,
+//!     SampleEdge::Rising,
+//!     3_072.kHz(),
+//!     150.MHz(),
+//! )
+//! .unwrap();
+//! let mut decimator = PdmDecimator::new(64);
+//! let first = rp235x_hal::singleton!(: [u32; 128] = [0; 128]).unwrap();
+//! let second = rp235x_hal::singleton!(: [u32; 128] = [0; 128]).unwrap();
+//! let mut spare = rp235x_hal::singleton!(: [u32; 128] = [0; 128]).unwrap();
+//! let mut stream = mic.stream((dma.ch0, dma.ch1), first, second);
+//! let mut pcm = [0i16; 64];
+//! loop {
+//!     let bits = stream.next_buffer(spare);
+//!     let samples = decimator.process(&bits[..], &mut pcm);
+//!     // Use `pcm[..samples]`.
+//!     spare = bits;
+//! }
+//! ```
+use fugit::HertzU32;
+use pio::{Assembler, SideSet};
+
+use super::{
+    InstallError, PIOBuilder, PIOExt, PinDir, PioPin, Running, Rx, ShiftDirection, StateMachine,
+    StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+use crate::{
+    dma::{
+        double_buffer::{self, WriteNext},
+        SingleChannel, WriteTarget,
+    },
+    sio::{Interp0, Lane, LaneCtrl},
+};
+
+/// Clock edge at which the data line is sampled.
+///
+/// Microphones select the edge after which they drive the data line with a pin, so that two of
+/// them can share it for stereo capture. See the microphone's datasheet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SampleEdge {
+    /// Sample just before the rising edge of the clock.
+    Rising,
+    /// Sample just before the falling edge of the clock.
+    Falling,
+}
+
+/// State machine cycles per PDM clock period.
+const CYCLES_PER_BIT: u32 = 4;
+
+/// Program driving the clock on the side-set pin and sampling the data line once per period.
+fn program(edge: SampleEdge) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    // Clock level after the sampled edge.
+    let after = match edge {
+        SampleEdge::Rising => 1,
+        SampleEdge::Falling => 0,
+    };
+    let mut a = Assembler::new_with_side_set(SideSet::new(false, 1, false));
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    a.bind(&mut wrap_target);
+    a.nop_with_side_set(1 - after);
+    a.r#in_with_side_set(pio::InSource::PINS, 1, 1 - after);
+    // Drop the samples while the FIFO is full rather than stalling the clock.
+    a.push_with_side_set(true, false, after);
+    a.nop_with_side_set(after);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// PDM microphone driver.
+pub struct PdmMic<P: PIOExt, SM: StateMachineIndex> {
+    sm: StateMachine<(P, SM), Running>,
+    rx: Rx<(P, SM)>,
+    tx: Tx<(P, SM)>,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> PdmMic<P, SM> {
+    /// Install the capture program and start it on `sm`, clocking the microphone on `clk` at
+    /// `pdm_clock`.
+    ///
+    /// `system_clock` is the frequency of the system clock, used to derive the clock divisor. The
+    /// PDM clock is rounded to the nearest rate the fractional divisor can produce.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        clk: &dyn PioPin<P>,
+        data: &dyn PioPin<P>,
+        edge: SampleEdge,
+        pdm_clock: HertzU32,
+        system_clock: HertzU32,
+    ) -> Result<Self, InstallError> {
+        let gpio_base = pio.gpio_base();
+        let installed = pio.install(&program(edge))?;
+
+        let sm_clock = u64::from(pdm_clock.to_Hz() * CYCLES_PER_BIT);
+        let divisor = (u64::from(system_clock.to_Hz()) * 256 + sm_clock / 2) / sm_clock;
+
+        let (mut sm, rx, tx) = PIOBuilder::from_installed_program(installed)
+            .gpio_base(gpio_base)
+            .side_set_pins_checked(&[clk])
+            .in_pins_checked(&[data])
+            .in_shift_direction(ShiftDirection::Left)
+            // 0 stands for 32 bits.
+            .push_threshold(0)
+            .buffers(super::Buffers::OnlyRx)
+            .clock_divisor_fixed_point((divisor >> 8) as u16, divisor as u8)
+            .build(sm);
+        sm.set_pindirs([(clk.pin_num() - gpio_base.offset(), PinDir::Output)]);
+
+        Ok(Self {
+            sm: sm.start(),
+            rx,
+            tx,
+        })
+    }
+
+    /// Read 32 bits of the bitstream, if available.
+    ///
+    /// The bitstream is dropped while the FIFO is full.
+    pub fn read(&mut self) -> Option<u32> {
+        self.rx.read()
+    }
+
+    /// Start capturing into `first` then `second` with a double-buffered DMA transfer.
+    pub fn stream<CH1, CH2, B>(
+        self,
+        channels: (CH1, CH2),
+        first: B,
+        second: B,
+    ) -> PdmStream<P, SM, CH1, CH2, B>
+    where
+        CH1: SingleChannel,
+        CH2: SingleChannel,
+        B: WriteTarget<TransmittedWord = u32>,
+    {
+        let transfer = double_buffer::Config::new(channels, self.rx, first)
+            .start()
+            .write_next(second);
+        PdmStream {
+            sm: self.sm,
+            tx: self.tx,
+            transfer: Some(transfer),
+        }
+    }
+
+    /// Stop the clock, uninstall the program and return the state machine.
+    pub fn free(self, pio: &mut PIO<P>) -> UninitStateMachine<(P, SM)> {
+        let (sm, program) = self.sm.uninit(self.rx, self.tx);
+        pio.uninstall(program);
+        sm
+    }
+}
+
+/// PDM capture into buffers with DMA, see [`PdmMic::stream`].
+pub struct PdmStream<P, SM, CH1, CH2, B>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+    CH1: SingleChannel,
+    CH2: SingleChannel,
+    B: WriteTarget<TransmittedWord = u32>,
+{
+    sm: StateMachine<(P, SM), Running>,
+    tx: Tx<(P, SM)>,
+    // Only `None` while a buffer is being swapped.
+    #[allow(clippy::type_complexity)]
+    transfer: Option<double_buffer::Transfer<CH1, CH2, Rx<(P, SM)>, B, WriteNext<B>>>,
+}
+
+impl<P, SM, CH1, CH2, B> PdmStream<P, SM, CH1, CH2, B>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+    CH1: SingleChannel,
+    CH2: SingleChannel,
+    B: WriteTarget<TransmittedWord = u32>,
+{
+    /// Whether the current buffer was filled, so that [`Self::next_buffer`] won't block.
+    pub fn is_ready(&self) -> bool {
+        self.transfer.as_ref().expect("stream in use").is_done()
+    }
+
+    /// Wait until the current buffer was filled, queue `next` and return the filled buffer.
+    ///
+    /// `next` must be queued before the buffer now being filled is full, or bits are lost.
+    pub fn next_buffer(&mut self, next: B) -> B {
+        let (filled, transfer) = self.transfer.take().expect("stream in use").wait();
+        self.transfer = Some(transfer.write_next(next));
+        filled
+    }
+
+    /// Wait until both queued buffers were filled and return the driver, the DMA channels and
+    /// the buffers.
+    pub fn stop(self) -> (PdmMic<P, SM>, (CH1, CH2), B, B) {
+        let (first, transfer) = self.transfer.expect("stream in use").wait();
+        let (ch1, ch2, rx, second) = transfer.wait();
+        let mic = PdmMic {
+            sm: self.sm,
+            rx,
+            tx: self.tx,
+        };
+        (mic, (ch1, ch2), first, second)
+    }
+}
+
+/// Number of bits set in each byte.
+static POPCOUNT: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = (i as u8).count_ones() as u8;
+        i += 1;
+    }
+    table
+};
+
+/// Order of the CIC filter.
+const ORDER: usize = 3;
+
+/// Decimation of a PDM bitstream into 16-bit PCM samples.
+///
+/// The bitstream is first summed over each byte, then goes through a 3rd order cascaded
+/// integrator-comb (CIC) filter decimating by the remaining factor. The CIC filter droops in the
+/// upper part of the output band and lets some noise alias back, which is usually fine for voice:
+/// run an FIR compensation filter on the PCM samples if flat response matters.
+///
+/// The output has no DC offset removal.
+#[derive(Debug, Clone)]
+pub struct PdmDecimator {
+    /// Number of bytes per output sample.
+    ratio: u16,
+    /// Left shift (or right shift if negative) scaling the filter's output to 16 bits.
+    shift: i8,
+    count: u16,
+    integrators: [i32; ORDER],
+    combs: [i32; ORDER],
+}
+
+impl PdmDecimator {
+    /// Create a decimator producing one PCM sample every `decimation` bits.
+    ///
+    /// `decimation` must be a power of two from 16 to 256: 64 turns a 3.072MHz bitstream into
+    /// 48kHz samples.
+    pub fn new(decimation: u16) -> Self {
+        assert!(
+            decimation.is_power_of_two() && (16..=256).contains(&decimation),
+            "unsupported decimation factor"
+        );
+        let ratio = decimation / 8;
+        // The byte sums range from -4 to 4 once centered, and the CIC filter has a gain of
+        // ratio^ORDER.
+        let gain_bits = 2 + ORDER as i8 * ratio.trailing_zeros() as i8;
+        Self {
+            ratio,
+            shift: 15 - gain_bits,
+            count: 0,
+            integrators: [0; ORDER],
+            combs: [0; ORDER],
+        }
+    }
+
+    /// Reset the filter state, for example after the bitstream was interrupted.
+    pub fn reset(&mut self) {
+        self.count = 0;
+        self.integrators = [0; ORDER];
+        self.combs = [0; ORDER];
+    }
+
+    /// Decimate `bits`, 32 bits per word with the first bit in the most significant bit, into
+    /// `pcm`, returning the number of samples written.
+    ///
+    /// Input which would produce samples past the end of `pcm` is dropped: `pcm` should hold at
+    /// least `32 * bits.len() / decimation` samples.
+    pub fn process(&mut self, bits: &[u32], pcm: &mut [i16]) -> usize {
+        let mut written = 0;
+        for word in bits {
+            for byte in word.to_be_bytes() {
+                self.push(POPCOUNT[usize::from(byte)], pcm, &mut written);
+            }
+        }
+        written
+    }
+
+    /// Same as [`Self::process`], using `interp` to compute the address of the bytes' bit counts.
+    ///
+    /// This overwrites the configuration of both lanes of the interpolator.
+    pub fn process_with_interp(
+        &mut self,
+        interp: &mut Interp0,
+        bits: &[u32],
+        pcm: &mut [i16],
+    ) -> usize {
+        let table = POPCOUNT.as_ptr() as u32;
+        // Lane 0 selects the upper byte of the accumulator 0, lane 1 its lower byte.
+        interp.get_lane0().set_ctrl(
+            LaneCtrl {
+                shift: 8,
+                mask_msb: 7,
+                ..LaneCtrl::new()
+            }
+            .encode(),
+        );
+        interp.get_lane1().set_ctrl(
+            LaneCtrl {
+                cross_input: true,
+                mask_msb: 7,
+                ..LaneCtrl::new()
+            }
+            .encode(),
+        );
+        interp.get_lane0().set_base(table);
+        interp.get_lane1().set_base(table);
+
+        let mut written = 0;
+        for word in bits {
+            for half in [word >> 16, word & 0xffff] {
+                interp.get_lane0().set_accum(half);
+                // Safety: The lanes' results are addresses of entries of `POPCOUNT`.
+                let (high, low) = unsafe {
+                    (
+                        *(interp.get_lane0().peek() as *const u8),
+                        *(interp.get_lane1().peek() as *const u8),
+                    )
+                };
+                self.push(high, pcm, &mut written);
+                self.push(low, pcm, &mut written);
+            }
+        }
+        written
+    }
+
+    /// Feed the bit count of one byte to the filter.
+    fn push(&mut self, ones: u8, pcm: &mut [i16], written: &mut usize) {
+        // Center the input around 0: bits stand for -1 and +1.
+        let mut value = i32::from(ones) - 4;
+        // The integrators wrap around, which the combs undo.
+        for integrator in &mut self.integrators {
+            *integrator = integrator.wrapping_add(value);
+            value = *integrator;
+        }
+        self.count += 1;
+        if self.count < self.ratio {
+            return;
+        }
+        self.count = 0;
+
+        for comb in &mut self.combs {
+            let delayed = *comb;
+            *comb = value;
+            value = value.wrapping_sub(delayed);
+        }
+        let sample = if self.shift >= 0 {
+            value << self.shift
+        } else {
+            value >> -self.shift
+        };
+        if let Some(out) = pcm.get_mut(*written) {
+            *out = sample.clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16;
+            *written += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pac;
+    use crate::pio::emulator::Emulator;
+
+    #[test]
+    fn capture_before_rising_edge() {
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let installed = pio.install(&program(SampleEdge::Rising)).unwrap();
+        let mut sm = pio.build(
+            PIOBuilder::from_installed_program(installed)
+                .side_set_pin_base(0)
+                .in_pin_base(1)
+                .in_shift_direction(ShiftDirection::Left)
+                .push_threshold(0),
+        );
+        sm.set_pindirs(0b01);
+        // Alternate the data line on each rising edge of the clock.
+        let mut clock = false;
+        let mut data = true;
+        for _ in 0..32 * CYCLES_PER_BIT {
+            sm.set_input(1, data);
+            sm.step();
+            let level = sm.pin_levels() & 1 != 0;
+            if !clock && level {
+                data = !data;
+            }
+            clock = level;
+        }
+        assert_eq!(sm.pop_rx(), Some(0xaaaa_aaaa));
+        assert_eq!(sm.pop_rx(), None);
+    }
+
+    #[test]
+    fn decimate_constant_density() {
+        let mut decimator = PdmDecimator::new(64);
+        let mut pcm = [0; 16];
+        // All ones, all zeros, then 50% density.
+        let mut bits = [0u32; 32];
+        bits[..8].fill(u32::MAX);
+        bits[16..].fill(0x5555_5555);
+        assert_eq!(decimator.process(&bits[..8], &mut pcm), 4);
+        // The filter settles after ORDER output samples.
+        assert_eq!(pcm[3], i16::MAX);
+        assert_eq!(decimator.process(&bits[8..16], &mut pcm), 4);
+        assert_eq!(pcm[3], i16::MIN);
+        assert_eq!(decimator.process(&bits[16..], &mut pcm), 8);
+        assert_eq!(pcm[7], 0);
+    }
+}
@@ -0,0 +1,244 @@
+//! Quadrature encoder driver
+//!
+//! Decodes the A/B signals of an incremental encoder in a PIO state machine, which keeps a signed
+//! 32-bit position in its Y register and pushes it to the RX FIFO continuously. The state machine
+//! runs at the system clock and samples the pins every 10 to 13 cycles, so step rates of several
+//! MHz are tracked without any CPU involvement.
+//!
+//! The program uses a computed jump table, so it must be installed at offset 0 of the PIO block's
+//! instruction memory: create the encoder before installing other programs in the same block.
+//!
+//! When an index pin is given, the position is reset to 0 while the index pulse is high, and
+//! [`QuadratureEncoder::index_detected`] reports that the pulse was seen.
+//!
+//! ```no_run
+//! use rp235x_hal::{
+//!     gpio::{FunctionPio0, Pins},
+//!     pac,
+//!     pio::{quadrature::QuadratureEncoder, PIOExt},
+//!     Sio, Timer, Watchdog,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let mut watchdog = Watchdog::new(pac.WATCHDOG);
+//! let clocks = rp235x_hal::clocks::init_clocks_and_plls(
+//!     12_000_000, pac.XOSC, pac.CLOCKS, pac.PLL_SYS, pac.PLL_USB, &mut pac.RESETS, &mut watchdog,
+//! )
+//! .ok()
+//! .unwrap();
+//! let timer = Timer::new_timer0(pac.TIMER0, &mut pac.RESETS, &clocks);
+//! let sio = Sio::new(pac.SIO);
+//! let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+//! let a = pins.gpio10.into_function::<FunctionPio0>();
+//! let b = pins.gpio11.into_function::<FunctionPio0>();
+//! let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+//! let mut encoder = QuadratureEncoder::new(&mut pio, sm0, &a, &b, None).unwrap();
+//! let position = encoder.position();
+//! let steps_per_second = encoder.velocity(&timer);
+//! ```
+use pio::{
+    Assembler, Instruction, InstructionOperands, JmpCondition, MovDestination, MovOperation,
+    MovSource,
+};
+
+use super::{
+    InstallError, PIOBuilder, PIOExt, PioPin, Running, Rx, ShiftDirection, StateMachine,
+    StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+use crate::timer::{Instant, Timer, TimerDevice};
+
+/// Number of words the RX FIFO can hold (it is joined with the TX FIFO).
+const FIFO_DEPTH: usize = 8;
+
+/// Direction of the step for each entry of the jump table, indexed by the previous and the current
+/// state of the pins (`0bAB_AB`). Entries 0b1110 (decrement) and 0b1111 (no step) are implemented
+/// in place, by the two instructions following the table.
+const TABLE: [i8; 14] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1];
+
+/// Program decoding the A/B pins, optionally resetting the position while the JMP pin is high.
+fn program(index: bool) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new();
+    let mut update = a.label();
+    let mut decrement = a.label();
+    let mut increment = a.label();
+    let mut increment_cont = a.label();
+    let mut wrap_source = a.label();
+    let mut index_pulse = a.label();
+
+    for step in TABLE {
+        let target = match step {
+            -1 => &mut decrement,
+            1 => &mut increment,
+            _ => &mut update,
+        };
+        a.jmp(JmpCondition::Always, target);
+    }
+    a.bind(&mut decrement);
+    // Jumps to the next address either way, so this just decrements Y.
+    a.jmp(JmpCondition::YDecNonZero, &mut update);
+
+    a.bind(&mut update);
+    a.mov(MovDestination::ISR, MovOperation::None, MovSource::Y);
+    a.push(false, false);
+    // Combine the previous state of the pins (kept in OSR) with the current one into ISR, and
+    // jump into the table.
+    a.out(pio::OutDestination::ISR, 2);
+    a.r#in(pio::InSource::PINS, 2);
+    a.mov(MovDestination::OSR, MovOperation::None, MovSource::ISR);
+    if index {
+        a.jmp(JmpCondition::PinHigh, &mut index_pulse);
+    }
+    a.mov(MovDestination::PC, MovOperation::None, MovSource::ISR);
+
+    // There is no increment instruction: negate, decrement and negate again.
+    a.bind(&mut increment);
+    a.mov(MovDestination::Y, MovOperation::Invert, MovSource::Y);
+    a.jmp(JmpCondition::YDecNonZero, &mut increment_cont);
+    a.bind(&mut increment_cont);
+    a.mov(MovDestination::Y, MovOperation::Invert, MovSource::Y);
+    a.bind(&mut wrap_source);
+
+    // Labels must be bound, even when unused.
+    a.bind(&mut index_pulse);
+    if index {
+        a.mov(MovDestination::Y, MovOperation::None, MovSource::NULL);
+        // Raise the flag of the state machine's own index.
+        a.irq(false, false, 0, true);
+        a.mov(MovDestination::PC, MovOperation::None, MovSource::ISR);
+    }
+
+    let mut program = a.assemble_with_wrap(wrap_source, update);
+    program.origin = Some(0);
+    program
+}
+
+/// PIO based quadrature encoder.
+pub struct QuadratureEncoder<P: PIOExt, SM: StateMachineIndex> {
+    sm: StateMachine<(P, SM), Running>,
+    rx: Rx<(P, SM)>,
+    tx: Tx<(P, SM)>,
+    last_sample: Option<(i32, Instant)>,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> QuadratureEncoder<P, SM> {
+    /// Install the decoder program at offset 0 and start it on `sm`.
+    ///
+    /// `a` and `b` must be consecutive GPIOs, in that order. If `index` is given, the position is
+    /// reset to 0 while it is high.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        a: &dyn PioPin<P>,
+        b: &dyn PioPin<P>,
+        index: Option<&dyn PioPin<P>>,
+    ) -> Result<Self, InstallError> {
+        let installed = pio.install(&program(index.is_some()))?;
+        let mut builder = PIOBuilder::from_installed_program(installed)
+            .gpio_base(pio.gpio_base())
+            .in_pins_checked(&[a, b])
+            .in_shift_direction(ShiftDirection::Left)
+            .out_shift_direction(ShiftDirection::Right)
+            .buffers(super::Buffers::OnlyRx);
+        if let Some(index) = index {
+            builder = builder.jmp_pin_checked(index);
+        }
+        let (mut sm, rx, tx) = builder.build(sm);
+        // Start counting from 0.
+        sm.exec_instruction(Instruction {
+            operands: InstructionOperands::MOV {
+                destination: MovDestination::Y,
+                op: MovOperation::None,
+                source: MovSource::NULL,
+            },
+            delay: 0,
+            side_set: None,
+        });
+
+        Ok(Self {
+            sm: sm.start(),
+            rx,
+            tx,
+            last_sample: None,
+        })
+    }
+
+    /// Current position of the encoder, in steps.
+    ///
+    /// Four steps are counted per period of the A/B signals.
+    pub fn position(&mut self) -> i32 {
+        // Samples are pushed without blocking, so the FIFO holds stale ones once it is full:
+        // drop them and wait for a fresh sample.
+        for _ in 0..FIFO_DEPTH {
+            if self.rx.read().is_none() {
+                break;
+            }
+        }
+        loop {
+            if let Some(position) = self.rx.read() {
+                return position as i32;
+            }
+        }
+    }
+
+    /// Average velocity since the previous call, in steps per second, measured with `timer`.
+    ///
+    /// The first call only records the current position and returns 0.
+    pub fn velocity<D: TimerDevice>(&mut self, timer: &Timer<D>) -> i32 {
+        let position = self.position();
+        let now = timer.get_counter();
+        let velocity = match self.last_sample {
+            Some((last_position, last)) => {
+                let elapsed = (now - last).to_micros();
+                if elapsed == 0 {
+                    0
+                } else {
+                    let steps = i64::from(position.wrapping_sub(last_position));
+                    (steps * 1_000_000 / elapsed as i64) as i32
+                }
+            }
+            None => 0,
+        };
+        self.last_sample = Some((position, now));
+        velocity
+    }
+
+    /// Whether an index pulse was seen since the previous call.
+    pub fn index_detected(&mut self) -> bool {
+        let flag = 1 << SM::id();
+        // Safety: Only the IRQ flag raised by this state machine is accessed, clearing it is
+        // atomic.
+        unsafe {
+            let block = &*self.rx.block;
+            let detected = block.irq().read().irq().bits() & flag != 0;
+            if detected {
+                block.irq().write(|w| w.irq().bits(flag));
+            }
+            detected
+        }
+    }
+
+    /// Stop decoding, uninstall the program and return the state machine.
+    pub fn free(self, pio: &mut PIO<P>) -> UninitStateMachine<(P, SM)> {
+        let (sm, program) = self.sm.uninit(self.rx, self.tx);
+        pio.uninstall(program);
+        sm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_layout() {
+        let with_index = program(true);
+        // The table is indexed with `mov pc, isr`, so it must start at address 0 and the
+        // decrement and update instructions must sit at the two last entries.
+        assert_eq!(with_index.origin, Some(0));
+        assert_eq!(with_index.code[1], 0x000e);
+        assert_eq!(with_index.code[14], 0x008f);
+        assert_eq!(with_index.wrap.target, 15);
+        assert_eq!(with_index.code[usize::from(with_index.wrap.source)], 0xa04a);
+        assert_eq!(with_index.code[with_index.code.len() - 2], 0xc010);
+        assert_eq!(program(false).code.len(), 24);
+    }
+}
@@ -0,0 +1,615 @@
+//! UART implemented with PIO
+//!
+//! Adds UART ports on top of the two hardware ones, using two state machines per port: one to
+//! transmit and one to receive. [`PioUart`] is configured with the same [`UartConfig`] as
+//! [`UartPeripheral`](crate::uart::UartPeripheral) and implements the same `embedded_io`,
+//! `embedded_hal_nb::serial` and `embedded_hal_0_2::serial` traits, reporting receive errors
+//! with [`ReadErrorType`].
+//!
+//! Parity is computed and checked by the CPU. A framing error is reported when the first stop bit
+//! is low, and a break when the whole character is low. An overrun is reported once characters
+//! were lost because the RX FIFO was full.
+//!
+//! ```no_run
+//! use fugit::RateExtU32;
+//! use rp235x_hal::{
+//!     gpio::{FunctionPio0, Pins},
+//!     pac,
+//!     pio::{uart::PioUart, PIOExt},
+//!     uart::{DataBits, StopBits, UartConfig},
+//!     Sio,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let sio = Sio::new(pac.SIO);
+//! let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+//! let tx = pins.gpio4.into_function::<FunctionPio0>();
+//! let rx = pins.gpio5.into_function::<FunctionPio0>();
+//! let (mut pio, sm0, sm1, _, _) = pac.PIO0.split(&mut pac.RESETS);
+//!
+//! let config = UartConfig::new(9600.Hz(), DataBits::Eight, None, StopBits::One);
+//! let mut uart = PioUart::new(&mut pio, sm0, sm1, &tx, &rx, config, 150.MHz()).unwrap();
+//! uart.write_full_blocking(b"Hello World!\r\n");
+//! ```
+use core::{convert::Infallible, fmt};
+
+use embedded_hal_0_2::serial as eh0;
+use embedded_hal_nb::serial::{ErrorType, Read, Write};
+use fugit::HertzU32;
+use nb::Error::{Other, WouldBlock};
+use pio::{Assembler, JmpCondition, MovDestination, MovOperation, MovSource, SetDestination};
+
+use super::{
+    InstallError, PIOBuilder, PIOExt, PinDir, PinState, PioPin, Running, Rx, ShiftDirection,
+    StateMachine, StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+use crate::uart::{DataBits, Parity, ReadErrorType, StopBits, UartConfig};
+
+/// State machine cycles per bit.
+const CYCLES_PER_BIT: u32 = 8;
+
+/// Transmitter program, shifting out `bits` bits after a start bit.
+///
+/// Parity and extra stop bits are shifted out as data bits.
+fn tx_program(bits: u8) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new_with_side_set(pio::SideSet::new(true, 1, false));
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut bit_loop = a.label();
+    a.bind(&mut wrap_target);
+    // The line is held high while the FIFO is empty.
+    a.pull_with_side_set(false, true, 1);
+    // Start bit
+    a.set_with_delay_and_side_set(SetDestination::X, bits - 1, 7, 0);
+    a.bind(&mut bit_loop);
+    a.out(pio::OutDestination::PINS, 1);
+    a.jmp_with_delay(JmpCondition::XDecNonZero, &mut bit_loop, 6);
+    // Stop bit, sent before stalling on the next `pull` so that the port is only idle once it
+    // is over.
+    a.nop_with_delay_and_side_set(7, 1);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Receiver program, sampling `bits` bits after a start bit and checking the stop bit.
+///
+/// Characters with a valid stop bit are pushed as is, in the upper bits of the word. Otherwise
+/// they are pushed inverted, which sets the lower bits of the word. Characters received while
+/// the FIFO is full are dropped, which sets the RX stall flag, and the state machine carries on.
+fn rx_program(bits: u8) -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut bit_loop = a.label();
+    let mut good_stop = a.label();
+    a.bind(&mut wrap_target);
+    a.wait(0, pio::WaitSource::PIN, 0, false);
+    // Wait until the middle of the first data bit.
+    a.set_with_delay(SetDestination::X, bits - 1, 10);
+    a.bind(&mut bit_loop);
+    a.r#in(pio::InSource::PINS, 1);
+    a.jmp_with_delay(JmpCondition::XDecNonZero, &mut bit_loop, 6);
+    a.jmp(JmpCondition::PinHigh, &mut good_stop);
+    // Framing error or break: flag the character and wait for the line to return to idle.
+    a.mov(MovDestination::ISR, MovOperation::Invert, MovSource::ISR);
+    a.push(false, false);
+    a.wait(1, pio::WaitSource::PIN, 0, false);
+    a.jmp(JmpCondition::Always, &mut wrap_target);
+    a.bind(&mut good_stop);
+    a.push(false, false);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// Character format derived from a [`UartConfig`].
+struct Format {
+    data_bits: u8,
+    /// `Some(true)` for odd parity, `Some(false)` for even parity.
+    odd_parity: Option<bool>,
+    extra_stop_bits: u8,
+}
+
+impl Format {
+    fn new(config: &UartConfig) -> Self {
+        let data_bits = match config.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        let odd_parity = match config.parity {
+            None => None,
+            Some(Parity::Odd) => Some(true),
+            Some(Parity::Even) => Some(false),
+        };
+        let extra_stop_bits = match config.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1,
+        };
+        Self {
+            data_bits,
+            odd_parity,
+            extra_stop_bits,
+        }
+    }
+
+    /// Bits received after the start bit, up to the first stop bit.
+    fn rx_bits(&self) -> u8 {
+        self.data_bits + u8::from(self.odd_parity.is_some())
+    }
+
+    /// Bits sent after the start bit, up to the last stop bit.
+    fn tx_bits(&self) -> u8 {
+        self.rx_bits() + self.extra_stop_bits
+    }
+
+    /// The parity bit to send along `data`.
+    fn parity_bit(&self, data: u8) -> u32 {
+        let ones = data.count_ones();
+        match self.odd_parity {
+            Some(false) => ones % 2,
+            Some(true) => 1 - ones % 2,
+            None => 0,
+        }
+    }
+
+    /// Build the word shifted out by the transmitter for `data`, LSB first.
+    fn encode(&self, data: u8) -> u32 {
+        let data = data & self.data_mask();
+        let stop_bits = (1 << self.extra_stop_bits) - 1;
+        u32::from(data) | self.parity_bit(data) << self.data_bits | stop_bits << self.rx_bits()
+    }
+
+    /// Decode a word pushed by the receiver.
+    fn decode(&self, word: u32) -> Result<u8, ReadErrorType> {
+        let shift = 32 - self.rx_bits();
+        // Characters with a framing error are pushed inverted.
+        if word & 1 != 0 {
+            return Err(if (!word >> shift) == 0 {
+                ReadErrorType::Break
+            } else {
+                ReadErrorType::Framing
+            });
+        }
+        let bits = word >> shift;
+        let data = (bits as u8) & self.data_mask();
+        if self.odd_parity.is_some() && (bits >> self.data_bits) & 1 != self.parity_bit(data) {
+            return Err(ReadErrorType::Parity);
+        }
+        Ok(data)
+    }
+
+    fn data_mask(&self) -> u8 {
+        (0xff_u16 >> (8 - self.data_bits)) as u8
+    }
+}
+
+/// Clock divisor for `baudrate`, as integer and 1/256th parts.
+fn clock_divisor(baudrate: HertzU32, system_clock: HertzU32) -> (u16, u8) {
+    let sm_clock = u64::from(baudrate.to_Hz()) * u64::from(CYCLES_PER_BIT);
+    let divisor = (u64::from(system_clock.to_Hz()) * 256 + sm_clock / 2) / sm_clock;
+    ((divisor >> 8) as u16, divisor as u8)
+}
+
+/// UART port implemented on two PIO state machines.
+pub struct PioUart<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> {
+    format: Format,
+    tx_sm: StateMachine<(P, TX), Running>,
+    tx_rx: Rx<(P, TX)>,
+    tx: Tx<(P, TX)>,
+    rx_sm: StateMachine<(P, RX), Running>,
+    rx: Rx<(P, RX)>,
+    rx_tx: Tx<(P, RX)>,
+    read_error: Option<ReadErrorType>,
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> PioUart<P, TX, RX> {
+    /// Install the transmitter and receiver programs and start them on `tx_sm` and `rx_sm`.
+    ///
+    /// `system_clock` is the frequency of the system clock, used to derive the baudrate.
+    pub fn new(
+        pio: &mut PIO<P>,
+        tx_sm: UninitStateMachine<(P, TX)>,
+        rx_sm: UninitStateMachine<(P, RX)>,
+        tx_pin: &dyn PioPin<P>,
+        rx_pin: &dyn PioPin<P>,
+        config: UartConfig,
+        system_clock: HertzU32,
+    ) -> Result<Self, InstallError> {
+        let format = Format::new(&config);
+        let tx_program = pio.install(&tx_program(format.tx_bits()))?;
+        let rx_program = match pio.install(&rx_program(format.rx_bits())) {
+            Ok(program) => program,
+            Err(e) => {
+                pio.uninstall(tx_program);
+                return Err(e);
+            }
+        };
+        let (int, frac) = clock_divisor(config.baudrate, system_clock);
+        let gpio_base = pio.gpio_base();
+
+        let (mut tx_sm, tx_rx, tx) = PIOBuilder::from_installed_program(tx_program)
+            .gpio_base(gpio_base)
+            .out_pins_checked(&[tx_pin])
+            .side_set_pins_checked(&[tx_pin])
+            .out_shift_direction(ShiftDirection::Right)
+            .buffers(super::Buffers::OnlyTx)
+            .clock_divisor_fixed_point(int, frac)
+            .build(tx_sm);
+        // Keep the line idle until the state machine starts.
+        tx_sm.set_pins([(tx_pin.pin_num() - gpio_base.offset(), PinState::High)]);
+        tx_sm.set_pindirs([(tx_pin.pin_num() - gpio_base.offset(), PinDir::Output)]);
+
+        let (rx_sm, rx, rx_tx) = PIOBuilder::from_installed_program(rx_program)
+            .gpio_base(gpio_base)
+            .in_pins_checked(&[rx_pin])
+            .jmp_pin_checked(rx_pin)
+            .in_shift_direction(ShiftDirection::Right)
+            .buffers(super::Buffers::OnlyRx)
+            .clock_divisor_fixed_point(int, frac)
+            .build(rx_sm);
+
+        Ok(Self {
+            format,
+            tx_sm: tx_sm.start(),
+            tx_rx,
+            tx,
+            rx_sm: rx_sm.start(),
+            rx,
+            rx_tx,
+            read_error: None,
+        })
+    }
+
+    /// Stop the port, uninstall its programs and return the state machines.
+    #[allow(clippy::type_complexity)]
+    pub fn free(
+        self,
+        pio: &mut PIO<P>,
+    ) -> (UninitStateMachine<(P, TX)>, UninitStateMachine<(P, RX)>) {
+        let (tx_sm, tx_program) = self.tx_sm.uninit(self.tx_rx, self.tx);
+        let (rx_sm, rx_program) = self.rx_sm.uninit(self.rx, self.rx_tx);
+        pio.uninstall(tx_program);
+        pio.uninstall(rx_program);
+        (tx_sm, rx_sm)
+    }
+
+    /// Is there space in the TX FIFO for another character?
+    pub fn uart_is_writable(&self) -> bool {
+        !self.tx.is_full()
+    }
+
+    /// Is a character being sent, or waiting in the TX FIFO?
+    pub fn uart_is_busy(&self) -> bool {
+        !(self.tx.is_empty() && self.tx.has_stalled())
+    }
+
+    /// Is there a character, or an error, to read?
+    pub fn uart_is_readable(&self) -> bool {
+        !self.rx.is_empty() || self.rx.has_stalled()
+    }
+
+    /// Write as many characters from `data` as the TX FIFO accepts, returning the remaining ones.
+    ///
+    /// Returns `Err(WouldBlock)` if no character could be written.
+    pub fn write_raw<'d>(&mut self, data: &'d [u8]) -> nb::Result<&'d [u8], Infallible> {
+        let mut remaining = data;
+        while let [byte, rest @ ..] = remaining {
+            if !self.tx.write(self.format.encode(*byte)) {
+                break;
+            }
+            remaining = rest;
+        }
+        if remaining.len() == data.len() {
+            return Err(WouldBlock);
+        }
+        // The state machine sets the flag again once it is done with the characters written.
+        self.tx.clear_stalled_flag();
+        Ok(remaining)
+    }
+
+    /// Read as many characters as available into `buffer`, returning how many were read.
+    ///
+    /// Returns `Err(WouldBlock)` if no character was available.
+    pub fn read_raw(&mut self, buffer: &mut [u8]) -> nb::Result<usize, ReadErrorType> {
+        let mut read = 0;
+        for byte in buffer.iter_mut() {
+            match self.read_one() {
+                Ok(data) => *byte = data,
+                Err(WouldBlock) => break,
+                Err(Other(e)) if read == 0 => return Err(Other(e)),
+                Err(Other(e)) => {
+                    // Report the error on the next read.
+                    self.read_error = Some(e);
+                    break;
+                }
+            }
+            read += 1;
+        }
+        if read == 0 {
+            Err(WouldBlock)
+        } else {
+            Ok(read)
+        }
+    }
+
+    /// Write all of `data`, blocking until the TX FIFO accepted the last character.
+    pub fn write_full_blocking(&mut self, data: &[u8]) {
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            if let Ok(rest) = self.write_raw(remaining) {
+                remaining = rest;
+            }
+        }
+    }
+
+    /// Fill `buffer`, blocking until enough characters were received.
+    pub fn read_full_blocking(&mut self, buffer: &mut [u8]) -> Result<(), ReadErrorType> {
+        let mut offset = 0;
+        while offset != buffer.len() {
+            offset += nb::block!(self.read_raw(&mut buffer[offset..]))?;
+        }
+        Ok(())
+    }
+
+    fn read_one(&mut self) -> nb::Result<u8, ReadErrorType> {
+        if let Some(e) = self.read_error.take() {
+            return Err(Other(e));
+        }
+        // The receiver dropped characters on a full FIFO. The flag stays set until cleared, and
+        // the characters still in the FIFO are read next.
+        if self.rx.has_stalled() {
+            self.rx.clear_stalled_flag();
+            return Err(Other(ReadErrorType::Overrun));
+        }
+        let word = self.rx.read().ok_or(WouldBlock)?;
+        self.format.decode(word).map_err(Other)
+    }
+
+    fn flush_one(&self) -> nb::Result<(), Infallible> {
+        if self.uart_is_busy() {
+            Err(WouldBlock)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> eh0::Read<u8> for PioUart<P, TX, RX> {
+    type Error = ReadErrorType;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        self.read_one()
+    }
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> ErrorType for PioUart<P, TX, RX> {
+    type Error = ReadErrorType;
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> Read<u8> for PioUart<P, TX, RX> {
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        self.read_one()
+    }
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> eh0::Write<u8>
+    for PioUart<P, TX, RX>
+{
+    type Error = Infallible;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.write_raw(&[word]).map(|_| ())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.flush_one()
+    }
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> Write<u8> for PioUart<P, TX, RX> {
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.write_raw(&[word]).map(|_| ()).map_err(|e| match e {
+            WouldBlock => WouldBlock,
+            Other(v) => match v {},
+        })
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.flush_one().map_err(|e| match e {
+            WouldBlock => WouldBlock,
+            Other(v) => match v {},
+        })
+    }
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> fmt::Write for PioUart<P, TX, RX> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_full_blocking(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> embedded_io::ErrorType
+    for PioUart<P, TX, RX>
+{
+    type Error = ReadErrorType;
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> embedded_io::Read
+    for PioUart<P, TX, RX>
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        nb::block!(self.read_raw(buf))
+    }
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> embedded_io::ReadReady
+    for PioUart<P, TX, RX>
+{
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.uart_is_readable() || self.read_error.is_some())
+    }
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> embedded_io::Write
+    for PioUart<P, TX, RX>
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.write_full_blocking(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        nb::block!(self.flush_one()).unwrap(); // Infallible
+        Ok(())
+    }
+}
+
+impl<P: PIOExt, TX: StateMachineIndex, RX: StateMachineIndex> embedded_io::WriteReady
+    for PioUart<P, TX, RX>
+{
+    fn write_ready(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.uart_is_writable())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fugit::RateExtU32;
+
+    use super::*;
+    use crate::pac;
+    use crate::pio::emulator::{EmulatedStateMachine, Emulator};
+
+    fn format(data_bits: DataBits, parity: Option<Parity>, stop_bits: StopBits) -> Format {
+        Format::new(&UartConfig::new(115_200.Hz(), data_bits, parity, stop_bits))
+    }
+
+    #[test]
+    fn encode_decode() {
+        let even = format(DataBits::Seven, Some(Parity::Even), StopBits::Two);
+        assert_eq!(even.tx_bits(), 9);
+        // 'A' has two bits set: parity bit clear, then an extra stop bit.
+        assert_eq!(even.encode(b'A'), 0x41 | 1 << 8);
+        assert!(matches!(even.decode(0x41 << 24), Ok(b'A')));
+        assert!(matches!(
+            even.decode(0xc1 << 24),
+            Err(ReadErrorType::Parity)
+        ));
+        let odd = format(DataBits::Eight, Some(Parity::Odd), StopBits::One);
+        assert_eq!(odd.encode(b'A'), 0x141);
+        // Framing error and break, pushed inverted.
+        let none = format(DataBits::Eight, None, StopBits::One);
+        assert!(matches!(
+            none.decode(!(0x41 << 24)),
+            Err(ReadErrorType::Framing)
+        ));
+        assert!(matches!(none.decode(!0), Err(ReadErrorType::Break)));
+    }
+
+    #[test]
+    fn loopback() {
+        let format = format(DataBits::Eight, Some(Parity::Even), StopBits::Two);
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let tx_program = pio.install(&tx_program(format.tx_bits())).unwrap();
+        let rx_program = pio.install(&rx_program(format.rx_bits())).unwrap();
+        let mut tx = pio.build(
+            PIOBuilder::from_installed_program(tx_program)
+                .out_pins(0, 1)
+                .side_set_pin_base(0)
+                .out_shift_direction(ShiftDirection::Right),
+        );
+        tx.set_pindirs(1);
+        let mut rx = pio.build(
+            PIOBuilder::from_installed_program(rx_program)
+                .in_pin_base(1)
+                .jmp_pin(1)
+                .in_shift_direction(ShiftDirection::Right),
+        );
+
+        let mut received = [0; 3];
+        let mut count = 0;
+        for byte in [0x55, 0x00, 0xc3] {
+            tx.push_tx(format.encode(byte));
+        }
+        for _ in 0..3 * 12 * CYCLES_PER_BIT {
+            tx.step();
+            rx.set_input(1, tx.pin_levels() & 1 != 0);
+            rx.step();
+            if let Some(word) = rx.pop_rx() {
+                received[count] = format.decode(word).unwrap();
+                count += 1;
+            }
+        }
+        assert_eq!(received[..count], [0x55, 0x00, 0xc3]);
+    }
+
+    #[test]
+    fn stop_bit_before_stall() {
+        let format = format(DataBits::Eight, None, StopBits::One);
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let tx_program = pio.install(&tx_program(format.tx_bits())).unwrap();
+        let mut tx = pio.build(
+            PIOBuilder::from_installed_program(tx_program)
+                .out_pins(0, 1)
+                .side_set_pin_base(0)
+                .out_shift_direction(ShiftDirection::Right),
+        );
+        tx.set_pindirs(1);
+        tx.push_tx(format.encode(0x00));
+        // Pull, then start bit and data bits, all low.
+        tx.run(1 + 9 * CYCLES_PER_BIT);
+        assert!(!tx.stalled());
+        // The stop bit is sent before stalling on the empty FIFO.
+        for _ in 0..CYCLES_PER_BIT {
+            tx.step();
+            assert_eq!(tx.pin_levels() & 1, 1);
+            assert!(!tx.stalled());
+        }
+        tx.step();
+        assert!(tx.stalled());
+    }
+
+    #[test]
+    fn overrun() {
+        let format = format(DataBits::Eight, None, StopBits::One);
+        let mut pio = Emulator::<pac::PIO0>::new();
+        let rx_program = pio.install(&rx_program(format.rx_bits())).unwrap();
+        let mut rx = pio.build(
+            PIOBuilder::from_installed_program(rx_program)
+                .in_pin_base(0)
+                .jmp_pin(0)
+                .in_shift_direction(ShiftDirection::Right),
+        );
+        fn send(rx: &mut EmulatedStateMachine, byte: u8) {
+            // Start bit, data bits and stop bit, then some idle time.
+            let word = u32::from(byte) << 1 | 0x1f << 9;
+            for bit in 0..14 {
+                rx.set_input(0, word >> bit & 1 != 0);
+                rx.run(CYCLES_PER_BIT);
+            }
+        }
+        rx.set_input(0, true);
+        rx.run(CYCLES_PER_BIT);
+        // The last two characters don't fit in the FIFO.
+        for byte in 0..6 {
+            send(&mut rx, byte);
+        }
+        for byte in 0..4 {
+            assert_eq!(
+                rx.pop_rx().map(|word| format.decode(word).unwrap()),
+                Some(byte)
+            );
+        }
+        assert_eq!(rx.pop_rx(), None);
+        // The receiver carries on with the next character.
+        send(&mut rx, 0xa5);
+        assert_eq!(
+            rx.pop_rx().map(|word| format.decode(word).unwrap()),
+            Some(0xa5)
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! WS2812 / SK6812 addressable LED driver
+//!
+//! Drives a chain of LEDs from one PIO state machine, with the frame being sent by a DMA channel
+//! from a `'static` buffer of one word per LED. The byte order of the LEDs is selected with the
+//! [`ColorOrder`] type parameter: [`Grb`] for WS2812, [`Grbw`] for RGBW SK6812 variants.
+//!
+//! ```no_run
+//! use fugit::RateExtU32;
+//! use rp235x_hal::{
+//!     dma::DMAExt,
+//!     gpio::{FunctionPio0, Pins},
+//!     pac,
+//!     pio::{ws2812::{Grb, Ws2812}, PIOExt},
+//!     Sio,
+//! };
+//! let mut pac = pac::Peripherals::take().unwrap();
+//! let sio = Sio::new(pac.SIO);
+//! let pins = Pins::new(pac.IO_BANK0, pac.PADS_BANK0, sio.gpio_bank0, &mut pac.RESETS);
+//! let led_pin = pins.gpio16.into_function::<FunctionPio0>();
+//! let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+//! let dma = pac.DMA.split(&mut pac.RESETS);
+//! let buffer = rp235x_hal::singleton!(: [u32; 8] = [0; 8]).unwrap();
+//! let mut leds =
+//!     Ws2812::<_, _, _, Grb, 8>::new(&mut pio, sm0, dma.ch0, buffer, &led_pin, 150.MHz())
+//!         .unwrap();
+//! // Red, green and blue, all other LEDs are turned off.
+//! leds.write_colors([[32, 0, 0], [0, 32, 0], [0, 0, 32]]);
+//! ```
+//!
+//! With the `smart-leds-trait` feature, the driver also implements
+//! `smart_leds_trait::SmartLedsWrite`.
+use core::marker::PhantomData;
+
+use fugit::HertzU32;
+
+use super::{
+    InstallError, PIOBuilder, PIOExt, PinDir, PioPin, Running, Rx, ShiftDirection, StateMachine,
+    StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+use crate::{
+    dma::{single_buffer, SingleChannel},
+    typelevel::Sealed,
+};
+
+/// Cycles spent in the start, data and stop phases of each bit.
+const T1: u8 = 2;
+const T2: u8 = 5;
+const T3: u8 = 3;
+/// Bit rate of the LED data line.
+const BIT_RATE: u32 = 800_000;
+/// Time the data line is held low for the LEDs to latch a frame, in microseconds.
+///
+/// 280µs is required by the current WS2812B revision, older LEDs need less.
+const RESET_TIME_US: u32 = 280;
+
+/// Order in which the color components are shifted out to the LEDs.
+pub trait ColorOrder: Sealed {
+    /// Color of one LED, as `[red, green, blue]` or `[red, green, blue, white]`.
+    type Color;
+    /// Number of bits shifted out per LED.
+    const BITS: u8;
+    /// Pack `color` into the most significant bits of a FIFO word.
+    fn encode(color: Self::Color) -> u32;
+}
+
+/// Green, red, blue: WS2812 and most of its clones.
+pub struct Grb;
+/// Red, green, blue.
+pub struct Rgb;
+/// Green, red, blue, white: RGBW SK6812.
+pub struct Grbw;
+/// Red, green, blue, white.
+pub struct Rgbw;
+
+impl Sealed for Grb {}
+impl Sealed for Rgb {}
+impl Sealed for Grbw {}
+impl Sealed for Rgbw {}
+
+impl ColorOrder for Grb {
+    type Color = [u8; 3];
+    const BITS: u8 = 24;
+    fn encode([r, g, b]: [u8; 3]) -> u32 {
+        u32::from_be_bytes([g, r, b, 0])
+    }
+}
+
+impl ColorOrder for Rgb {
+    type Color = [u8; 3];
+    const BITS: u8 = 24;
+    fn encode([r, g, b]: [u8; 3]) -> u32 {
+        u32::from_be_bytes([r, g, b, 0])
+    }
+}
+
+impl ColorOrder for Grbw {
+    type Color = [u8; 4];
+    const BITS: u8 = 32;
+    fn encode([r, g, b, w]: [u8; 4]) -> u32 {
+        u32::from_be_bytes([g, r, b, w])
+    }
+}
+
+impl ColorOrder for Rgbw {
+    type Color = [u8; 4];
+    const BITS: u8 = 32;
+    fn encode([r, g, b, w]: [u8; 4]) -> u32 {
+        u32::from_be_bytes([r, g, b, w])
+    }
+}
+
+/// Program shifting out one bit every `T1 + T2 + T3` cycles on the side-set pin.
+fn program() -> pio::Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let side_set = pio::SideSet::new(false, 1, false);
+    let mut a = pio::Assembler::new_with_side_set(side_set);
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut do_zero = a.label();
+    a.bind(&mut wrap_target);
+    // Stop bit
+    a.out_with_delay_and_side_set(pio::OutDestination::X, 1, T3 - 1, 0);
+    // Start bit
+    a.jmp_with_delay_and_side_set(pio::JmpCondition::XIsZero, &mut do_zero, T1 - 1, 1);
+    // Data bit = 1
+    a.jmp_with_delay_and_side_set(pio::JmpCondition::Always, &mut wrap_target, T2 - 1, 1);
+    a.bind(&mut do_zero);
+    // Data bit = 0
+    a.nop_with_delay_and_side_set(T2 - 1, 0);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// WS2812 / SK6812 driver for a chain of up to `N` LEDs.
+pub struct Ws2812<P, SM, CH, O, const N: usize>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+    CH: SingleChannel,
+    O: ColorOrder,
+{
+    sm: StateMachine<(P, SM), Running>,
+    rx: Rx<(P, SM)>,
+    // Only `None` while a frame is being sent.
+    #[allow(clippy::type_complexity)]
+    transfer: Option<(CH, &'static mut [u32; N], Tx<(P, SM)>)>,
+    reset_cycles: u32,
+    _order: PhantomData<O>,
+}
+
+impl<P, SM, CH, O, const N: usize> Ws2812<P, SM, CH, O, N>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+    CH: SingleChannel,
+    O: ColorOrder,
+{
+    /// Install the driver's program and start it on `sm`, driving the LEDs on `pin`.
+    ///
+    /// `system_clock` is the frequency of the system clock, used to derive the bit timing and
+    /// the reset latch time.
+    /// Below 8 MHz, the bits are longer than specified, which most LEDs still accept.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        channel: CH,
+        buffer: &'static mut [u32; N],
+        pin: &dyn PioPin<P>,
+        system_clock: HertzU32,
+    ) -> Result<Self, InstallError> {
+        let installed = pio.install(&program())?;
+
+        // Run the state machine at (T1 + T2 + T3) cycles per bit.
+        let clock = system_clock.to_Hz();
+        let bit_clock = BIT_RATE * u32::from(T1 + T2 + T3);
+        let (int, frac) = match clock / bit_clock {
+            // An integer part of 0 would divide by 65536, run at full speed instead.
+            0 => (1, 0),
+            int => (
+                int,
+                ((clock % bit_clock) as u64 * 256 / bit_clock as u64) as u8,
+            ),
+        };
+
+        let gpio_base = pio.gpio_base();
+        let (mut sm, rx, tx) = PIOBuilder::from_installed_program(installed)
+            .buffers(super::Buffers::OnlyTx)
+            .gpio_base(gpio_base)
+            .side_set_pins_checked(&[pin])
+            .out_shift_direction(ShiftDirection::Left)
+            .autopull(true)
+            .pull_threshold(O::BITS)
+            .clock_divisor_fixed_point(int as u16, frac)
+            .build(sm);
+        sm.set_pindirs([(pin.pin_num() - gpio_base.offset(), PinDir::Output)]);
+
+        Ok(Self {
+            sm: sm.start(),
+            rx,
+            transfer: Some((channel, buffer, tx)),
+            reset_cycles: clock / 1_000_000 * RESET_TIME_US,
+            _order: PhantomData,
+        })
+    }
+
+    /// Send a frame to the LEDs and wait until they latched it.
+    ///
+    /// LEDs beyond the end of `colors` are turned off, colors beyond the `N`-th one are ignored.
+    pub fn write_colors(&mut self, colors: impl IntoIterator<Item = O::Color>) {
+        let (channel, buffer, tx) = self.transfer.take().expect("driver in use");
+        let mut colors = colors.into_iter();
+        for word in buffer.iter_mut() {
+            *word = colors.next().map_or(0, O::encode);
+        }
+
+        let (channel, buffer, tx) = single_buffer::Config::new(channel, buffer, tx)
+            .start()
+            .wait();
+        // The FIFO and the OSR are still being shifted out. The state machine stalls again once
+        // the last bit left them and the line is low.
+        tx.clear_stalled_flag();
+        while !tx.has_stalled() {}
+        crate::arch::delay(self.reset_cycles);
+
+        self.transfer = Some((channel, buffer, tx));
+    }
+
+    /// Stop the driver, uninstall its program and return the resources it used.
+    pub fn free(
+        self,
+        pio: &mut PIO<P>,
+    ) -> (UninitStateMachine<(P, SM)>, CH, &'static mut [u32; N]) {
+        let (channel, buffer, tx) = self.transfer.expect("driver in use");
+        let (sm, program) = self.sm.uninit(self.rx, tx);
+        pio.uninstall(program);
+        (sm, channel, buffer)
+    }
+}
+
+#[cfg(feature = "smart-leds-trait")]
+macro_rules! smart_leds_write {
+    ($($order:ident: $color:ty => |$c:ident| $encode:expr,)+) => {
+        $(
+            impl<P, SM, CH, const N: usize> smart_leds_trait::SmartLedsWrite
+                for Ws2812<P, SM, CH, $order, N>
+            where
+                P: PIOExt,
+                SM: StateMachineIndex,
+                CH: SingleChannel,
+            {
+                type Error = core::convert::Infallible;
+                type Color = $color;
+
+                fn write<T, I>(&mut self, iterator: T) -> Result<(), Self::Error>
+                where
+                    T: IntoIterator<Item = I>,
+                    I: Into<Self::Color>,
+                {
+                    self.write_colors(iterator.into_iter().map(|color| {
+                        let $c: $color = color.into();
+                        $encode
+                    }));
+                    Ok(())
+                }
+            }
+        )+
+    };
+}
+
+#[cfg(feature = "smart-leds-trait")]
+smart_leds_write! {
+    Grb: smart_leds_trait::RGB8 => |c| [c.r, c.g, c.b],
+    Rgb: smart_leds_trait::RGB8 => |c| [c.r, c.g, c.b],
+    Grbw: smart_leds_trait::RGBW<u8> => |c| [c.r, c.g, c.b, c.a.0],
+    Rgbw: smart_leds_trait::RGBW<u8> => |c| [c.r, c.g, c.b, c.a.0],
+}